@@ -0,0 +1,197 @@
+//! Attributed variables, as extension point for constraint solvers
+//!
+//! Variable may carry attributes - terms keyed by modules, which are
+//! just idents. Built-in constraints keep their state in attributes of
+//! their own modules, and any other module may be used by `put_attr/3`
+//! or by hooks. Hook registered on machine for module is called
+//! whenever variable with attribute in this module is bound - to other
+//! term, or to another variable - before the next goal is called. Hook
+//! may veto the binding, or add goals to prove before execution
+//! continues.
+
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error, TermBuilder};
+
+/// Term on machine heap, valid only while hook which got it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermRef(pub(crate) Cell);
+
+/// Hook called when variable with attribute in its module is bound
+///
+/// Hook gets the attribute and term variable was bound to. Returning
+/// `false` vetoes the binding, so the unification fails.
+pub type Hook<C> =
+    Box<dyn FnMut(&mut Attributes<C>, TermRef, TermRef) -> Result<bool, Error> + Send>;
+
+/// Access to terms and attributes of machine, given to hooks
+pub struct Attributes<'a, C> {
+    storage: &'a mut Storage<C>,
+    goals: &'a mut Vec<Cell>,
+}
+
+impl<'a, C: ConstDomain> Attributes<'a, C> {
+    pub(crate) fn new(storage: &'a mut Storage<C>, goals: &'a mut Vec<Cell>) -> Self {
+        Self { storage, goals }
+    }
+
+    /// Returns attribute of variable in given module, or `None` if term
+    /// is not an unbound variable, or if it has no such attribute
+    pub fn get(&self, var: TermRef, module: usize) -> Result<Option<TermRef>, Error> {
+        match self.storage.deref_cell(var.0)? {
+            Cell::Ref(addr) => Ok(self.storage.attribute(addr, module).map(TermRef)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sets attribute of variable in given module, or removes it if
+    /// value is `None`
+    ///
+    /// Fails with `Error::NotVariable` if term is not an unbound
+    /// variable
+    pub fn put(&mut self, var: TermRef, module: usize, value: Option<TermRef>) -> Result<(), Error> {
+        let addr = self.storage.attributed_var(var.0)?;
+        let value = value.map(|value| self.storage.deref_cell(value.0)).transpose()?;
+        self.storage.put_attribute(addr, module, value);
+        Ok(())
+    }
+
+    /// Returns functor (ident and arity) of structure, list or
+    /// constant, or `None` for other terms
+    pub fn functor(&self, term: TermRef) -> Result<Option<(usize, usize)>, Error> {
+        match self.storage.deref_cell(term.0)? {
+            Cell::Ref(_) => Ok(None),
+            cell => self.storage.functor(cell),
+        }
+    }
+
+    /// Returns arguments of structure, or head and tail of list - other
+    /// terms have no arguments
+    pub fn arguments(&self, term: TermRef) -> Result<Vec<TermRef>, Error> {
+        let (addr, arity) = match self.storage.deref_cell(term.0)? {
+            Cell::Struct(addr) => (addr + 1, self.storage.funct(addr)?.1),
+            Cell::List(addr) => (addr, 2),
+            _ => return Ok(vec![]),
+        };
+
+        (addr..addr + arity)
+            .map(|addr| self.storage.cell(addr).map(TermRef))
+            .collect()
+    }
+
+    /// Builds given term, with variables built with ids of their
+    /// addresses
+    pub fn build_term<Builder: TermBuilder<C>>(
+        &self,
+        term: TermRef,
+        builder: &mut Builder,
+    ) -> Result<Builder::Term, Error> {
+        self.storage.build_term(term.0, builder)
+    }
+
+    /// Creates new unbound variable
+    pub fn variable(&mut self) -> TermRef {
+        TermRef(self.storage.push_var())
+    }
+
+    pub fn constant(&mut self, ident: usize) -> TermRef {
+        TermRef(Cell::Con(ident))
+    }
+
+    pub fn integer(&mut self, value: isize) -> TermRef {
+        TermRef(Cell::Int(value))
+    }
+
+    /// Creates structure of given subterms, or constant if there is
+    /// none
+    pub fn structure(
+        &mut self,
+        ident: usize,
+        subterms: impl IntoIterator<Item = TermRef>,
+    ) -> TermRef {
+        let subterms: Vec<_> = subterms.into_iter().collect();
+        if subterms.is_empty() {
+            return self.constant(ident);
+        }
+
+        let structure = self.storage.push_struct(ident, subterms.len());
+        for subterm in subterms {
+            self.storage.push_cell(subterm.0);
+        }
+        TermRef(structure)
+    }
+
+    /// Unifies given terms
+    ///
+    /// Bound attributed variables are woken like by any other
+    /// unification, once this and other hooks return.
+    pub fn unify(&mut self, left: TermRef, right: TermRef) -> Result<(), Error> {
+        self.storage.unify(left.0, right.0)
+    }
+
+    /// Adds goal to prove once hooks of all bound variables are
+    /// called, before execution continues
+    pub fn add_goal(&mut self, goal: TermRef) {
+        self.goals.push(goal.0);
+    }
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Sets attribute of variable in module, for `put_attr/3`
+    ///
+    /// Fails with `Error::NotVariable` if term is not an unbound
+    /// variable, and with `Error::InvalidModule` if module is not a
+    /// constant
+    pub(crate) fn put_attr(&mut self, var: Cell, module: Cell, value: Cell) -> Result<(), Error> {
+        let addr = self.attributed_var(var)?;
+        let module = self.module(module)?;
+        let value = self.deref_cell(value)?;
+        self.put_attribute(addr, module, Some(value));
+        Ok(())
+    }
+
+    /// Unifies value with attribute of variable in module, for
+    /// `get_attr/3`
+    ///
+    /// Fails if term is not a variable, or if it has no such attribute
+    pub(crate) fn get_attr(&mut self, var: Cell, module: Cell, value: Cell) -> Result<(), Error> {
+        let module = self.module(module)?;
+        let attribute = match self.deref_cell(var)? {
+            Cell::Ref(addr) => self.attribute(addr, module),
+            _ => None,
+        };
+
+        match attribute {
+            Some(attribute) => self.unify(attribute, value),
+            None => Err(Error::UnificationFailure),
+        }
+    }
+
+    /// Removes attribute of variable in module, for `del_attr/2`
+    ///
+    /// Term which is not a variable has no attributes to remove
+    pub(crate) fn del_attr(&mut self, var: Cell, module: Cell) -> Result<(), Error> {
+        let module = self.module(module)?;
+        if let Cell::Ref(addr) = self.deref_cell(var)? {
+            if self.attribute(addr, module).is_some() {
+                self.put_attribute(addr, module, None);
+            }
+        }
+        Ok(())
+    }
+
+    // Address of unbound variable which attribute is set
+    fn attributed_var(&self, var: Cell) -> Result<usize, Error> {
+        match self.deref_cell(var)? {
+            Cell::Ref(addr) => Ok(addr),
+            _ => Err(Error::NotVariable),
+        }
+    }
+
+    // Ident of attribute module
+    fn module(&self, module: Cell) -> Result<usize, Error> {
+        match self.deref_cell(module)? {
+            Cell::Con(module) => Ok(module),
+            _ => Err(Error::InvalidModule),
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Built-in predicates and arithmetic functions
+//!
+//! Built-ins have reserved idents at the very end of ident space, so
+//! they don't collide with idents chosen for user terms. Goals with
+//! built-in functors are executed by machine itself instead of calling
+//! knowledge procedures, and structures with arithmetic functors are
+//! evaluated by them. Control constructs (cut, conjunction, disjunction,
+//! if-then-else and negation) are compiled into clause code instead of being
+//! called, and goal terms proven by `call/1` reach them through library
+//! clauses.
+
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
+#[cfg(feature = "bigint")]
+use num_integer::Integer as _;
+#[cfg(feature = "bigint")]
+use num_traits::{Signed, ToPrimitive, Zero};
+use std::cmp::Ordering;
+
+const BASE: usize = usize::MAX - 0xff;
+
+/// `is/2` - unifies first argument with value of arithmetic expression
+/// given as second one
+pub const IS: usize = BASE;
+/// `</2` - arithmetic less than
+pub const LT: usize = BASE + 1;
+/// `=</2` - arithmetic less or equal
+pub const LE: usize = BASE + 2;
+/// `>/2` - arithmetic greater than
+pub const GT: usize = BASE + 3;
+/// `>=/2` - arithmetic greater or equal
+pub const GE: usize = BASE + 4;
+/// `=:=/2` - arithmetic equality
+pub const EQ: usize = BASE + 5;
+/// `=\=/2` - arithmetic inequality
+pub const NE: usize = BASE + 6;
+/// `true/0` - always succeeds
+pub const TRUE: usize = BASE + 7;
+/// `fail/0` - always fails
+pub const FAIL: usize = BASE + 8;
+
+/// `!/0` - cut, discarding choice points created since clause was
+/// called
+pub const CUT: usize = BASE + 9;
+/// `','/2` - conjunction of goals
+pub const AND: usize = BASE + 10;
+/// `;/2` - disjunction of goals, or if-then-else if its left side is
+/// `->/2`
+pub const OR: usize = BASE + 11;
+/// `->/2` - if-then, proving second goal only for first solution of
+/// the first one
+pub const IF: usize = BASE + 12;
+/// `\\+/1` - negation as failure, succeeding only if goal has no
+/// solution
+pub const NOT: usize = BASE + 13;
+
+/// `dif/2` - constraint that terms are different, suspended until they
+/// become either identical or non-unifiable
+pub const DIF: usize = BASE + 14;
+/// `call/1` - proves goal given as term
+pub const CALL: usize = BASE + 15;
+
+/// `+/2` - addition
+pub const ADD: usize = BASE + 16;
+/// `-/2` - subtraction, and `-/1` - negation
+pub const SUB: usize = BASE + 17;
+/// `*/2` - multiplication
+pub const MUL: usize = BASE + 18;
+/// `///2` - integer division, truncating toward zero
+pub const DIV: usize = BASE + 19;
+/// `mod/2` - modulo, with sign of divisor
+pub const MOD: usize = BASE + 20;
+/// `abs/1` - absolute value
+pub const ABS: usize = BASE + 21;
+/// `min/2` - smaller of two values
+pub const MIN: usize = BASE + 22;
+/// `max/2` - greater of two values
+pub const MAX: usize = BASE + 23;
+
+/// `in/2` - finite domain of variable
+pub const IN: usize = BASE + 40;
+/// `../2` - integer range, bounded by integers, `inf` or `sup`
+pub const RANGE: usize = BASE + 41;
+/// `\//2` - union of domains
+pub const UNION: usize = BASE + 42;
+/// `inf` - infinite lower bound of range
+pub const INF: usize = BASE + 43;
+/// `sup` - infinite upper bound of range
+pub const SUP: usize = BASE + 44;
+/// `#=/2` - finite domain equality constraint
+pub const FD_EQ: usize = BASE + 45;
+/// `#\=/2` - finite domain inequality constraint
+pub const FD_NE: usize = BASE + 46;
+/// `#</2` - finite domain less than constraint
+pub const FD_LT: usize = BASE + 47;
+/// `#=</2` - finite domain less or equal constraint
+pub const FD_LE: usize = BASE + 48;
+/// `#>/2` - finite domain greater than constraint
+pub const FD_GT: usize = BASE + 49;
+/// `#>=/2` - finite domain greater or equal constraint
+pub const FD_GE: usize = BASE + 50;
+/// `all_different/1` - constraint that integers of list are pairwise
+/// different
+pub const ALL_DIFFERENT: usize = BASE + 51;
+/// `label/1` - binds variables of list to values of their domains, in
+/// ascending order on backtracking
+pub const LABEL: usize = BASE + 52;
+/// `indomain/1` - binds variable to values of its domain, in
+/// ascending order on backtracking
+pub const INDOMAIN: usize = BASE + 53;
+/// `fd_inf/2` - unifies second argument with lower bound of domain of
+/// the first one
+pub const FD_INF: usize = BASE + 54;
+
+/// `{}/1` - linear equalities and inequalities over rationals, given
+/// as comparisons or conjunction of them
+pub const LINEAR: usize = BASE + 55;
+/// `//2` - rational division, by constant in linear constraints, and
+/// of non-integer rational values
+pub const RDIV: usize = BASE + 56;
+
+/// `sat/1` - boolean constraint that expression of `0`, `1` and
+/// variables is true
+pub const SAT: usize = BASE + 57;
+/// `taut/2` - unifies second argument with `1` if boolean expression
+/// is true, or with `0` if it is false, for all solutions of boolean
+/// constraints
+pub const TAUT: usize = BASE + 58;
+/// `labeling/1` - binds boolean variables of list to `0` and `1`, in
+/// this order on backtracking
+pub const LABELING: usize = BASE + 59;
+/// `~/1` - boolean negation
+pub const BNOT: usize = BASE + 60;
+/// `#/2` - boolean exclusive disjunction
+pub const XOR: usize = BASE + 61;
+
+/// `put_attr/3` - sets attribute of variable in module given as
+/// constant
+pub const PUT_ATTR: usize = BASE + 62;
+/// `get_attr/3` - unifies third argument with attribute of variable
+/// in module, failing if it has none
+pub const GET_ATTR: usize = BASE + 63;
+/// `del_attr/2` - removes attribute of variable in module
+pub const DEL_ATTR: usize = BASE + 64;
+
+/// `freeze/2` - delays goal until variable is bound
+pub const FREEZE: usize = BASE + 65;
+/// `when/2` - delays goal until condition holds
+pub const WHEN: usize = BASE + 66;
+/// `nonvar/1` - `when/2` condition that term is not an unbound
+/// variable
+pub const NONVAR: usize = BASE + 67;
+/// `ground/1` - `when/2` condition that term has no unbound variables
+pub const GROUND: usize = BASE + 68;
+/// `?=/2` - `when/2` condition that terms are either identical or
+/// non-unifiable
+pub const DECIDED: usize = BASE + 69;
+/// `'$suspend'/4` - unifies its last argument with goal to call if
+/// condition holds, or suspends delayed goal on variables of condition,
+/// and `'$suspend'/2` - goal suspended on variable, with flag bound
+/// once it is woken
+pub const SUSPEND: usize = BASE + 70;
+/// `'$call'/2` - proves goal given as term, where cut discards choice
+/// points above level given as second argument
+pub const META_CALL: usize = BASE + 71;
+
+/// `[]` - empty list
+pub const NIL: usize = BASE + 32;
+/// `'[|]'/2` - list cell, which is kept as list instead of structure,
+/// and this functor is only reported for it
+pub const CONS: usize = BASE + 33;
+
+/// Predicate executed by machine itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Builtin {
+    True,
+    Fail,
+    Is,
+    Dif,
+    In,
+    // Linear constraint with its ident
+    Linear(usize),
+    AllDifferent,
+    FdInf,
+    Rational,
+    Sat,
+    Taut,
+    PutAttr,
+    GetAttr,
+    DelAttr,
+    Suspend,
+    // Comparison with its ident
+    Compare(usize),
+}
+
+impl Builtin {
+    /// Returns built-in predicate for given functor, if there is one
+    pub(crate) fn new(ident: usize, arity: usize) -> Option<Self> {
+        match (ident, arity) {
+            (TRUE, 0) => Some(Self::True),
+            (FAIL, 0) => Some(Self::Fail),
+            (IS, 2) => Some(Self::Is),
+            (DIF, 2) => Some(Self::Dif),
+            (IN, 2) => Some(Self::In),
+            (FD_EQ..=FD_GE, 2) => Some(Self::Linear(ident)),
+            (ALL_DIFFERENT, 1) => Some(Self::AllDifferent),
+            (FD_INF, 2) => Some(Self::FdInf),
+            (LINEAR, 1) => Some(Self::Rational),
+            (SAT, 1) => Some(Self::Sat),
+            (TAUT, 2) => Some(Self::Taut),
+            (PUT_ATTR, 3) => Some(Self::PutAttr),
+            (GET_ATTR, 3) => Some(Self::GetAttr),
+            (DEL_ATTR, 2) => Some(Self::DelAttr),
+            (SUSPEND, 4) => Some(Self::Suspend),
+            (LT..=NE, 2) => Some(Self::Compare(ident)),
+            _ => None,
+        }
+    }
+}
+
+/// Checks if comparison with given ident holds for given ordering
+/// of its arguments
+pub(crate) fn compare(ident: usize, ordering: Ordering) -> bool {
+    match ident {
+        LT => ordering == Ordering::Less,
+        LE => ordering != Ordering::Greater,
+        GT => ordering == Ordering::Greater,
+        GE => ordering != Ordering::Less,
+        EQ => ordering == Ordering::Equal,
+        _ => ordering != Ordering::Equal,
+    }
+}
+
+/// Value of evaluated expression
+#[cfg(not(feature = "bigint"))]
+pub(crate) type Integer = isize;
+
+/// Value of evaluated expression - with big integers all arithmetic is
+/// done with arbitrary precision, so it never overflows
+#[cfg(feature = "bigint")]
+pub(crate) type Integer = BigInt;
+
+// Applies arithmetic function to its evaluated arguments
+#[cfg(not(feature = "bigint"))]
+fn apply(ident: usize, args: &[isize]) -> Result<isize, Error> {
+    let value = match (ident, args) {
+        (ADD, [a, b]) => a.checked_add(*b),
+        (SUB, [a, b]) => a.checked_sub(*b),
+        (SUB, [a]) => a.checked_neg(),
+        (MUL, [a, b]) => a.checked_mul(*b),
+        (DIV, [_, 0]) | (MOD, [_, 0]) => return Err(Error::ZeroDivisor),
+        (DIV, [a, b]) => a.checked_div(*b),
+        (MOD, [a, b]) => a
+            .checked_rem(*b)
+            .map(|r| if r != 0 && (r < 0) != (*b < 0) { r + b } else { r }),
+        (ABS, [a]) => a.checked_abs(),
+        (MIN, [a, b]) => Some(*a.min(b)),
+        (MAX, [a, b]) => Some(*a.max(b)),
+        _ => return Err(Error::NotEvaluable(ident, args.len())),
+    };
+
+    value.ok_or(Error::IntegerOverflow)
+}
+
+// Applies arithmetic function to its evaluated arguments
+#[cfg(feature = "bigint")]
+fn apply(ident: usize, args: &[BigInt]) -> Result<BigInt, Error> {
+    let value = match (ident, args) {
+        (ADD, [a, b]) => a + b,
+        (SUB, [a, b]) => a - b,
+        (SUB, [a]) => -a,
+        (MUL, [a, b]) => a * b,
+        (DIV, [_, b]) | (MOD, [_, b]) if b.is_zero() => return Err(Error::ZeroDivisor),
+        (DIV, [a, b]) => a / b,
+        (MOD, [a, b]) => a.mod_floor(b),
+        (ABS, [a]) => a.abs(),
+        (MIN, [a, b]) => a.min(b).clone(),
+        (MAX, [a, b]) => a.max(b).clone(),
+        _ => return Err(Error::NotEvaluable(ident, args.len())),
+    };
+
+    Ok(value)
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Evaluates arithmetic expression of given cell
+    ///
+    /// Expression is evaluated without recursion, so it may be
+    /// arbitrarily deep
+    pub(crate) fn evaluate(&self, cell: Cell) -> Result<Integer, Error> {
+        // Evaluated values which are not yet arguments of their functions
+        let mut values = vec![];
+        // Expressions to evaluate, and if their arguments are already
+        // evaluated
+        let mut pending = vec![(cell, false)];
+
+        while let Some((cell, ready)) = pending.pop() {
+            match self.deref_cell(cell)? {
+                // Integer is converted only with big integers
+                #[allow(clippy::useless_conversion)]
+                Cell::Int(value) => values.push(value.into()),
+                #[cfg(feature = "bigint")]
+                Cell::BigInt(idx) => values.push(self.bigint(idx)?.clone()),
+                Cell::Ref(_) => return Err(Error::Instantiation),
+                Cell::Con(ident) => return Err(Error::NotEvaluable(ident, 0)),
+                Cell::Value(_) => return Err(Error::NotEvaluableValue),
+                Cell::Struct(addr) if ready => {
+                    let (ident, arity) = self.funct(addr)?;
+                    let args = values.split_off(values.len() - arity);
+                    values.push(apply(ident, &args)?);
+                }
+                Cell::Struct(addr) => {
+                    let (ident, arity) = self.funct(addr)?;
+                    // Function is checked upfront, so its arguments
+                    // are not evaluated in vain
+                    if !matches!((ident, arity), (ADD..=MAX, 1..=2)) {
+                        return Err(Error::NotEvaluable(ident, arity));
+                    }
+
+                    pending.push((cell, true));
+                    for i in (1..=arity).rev() {
+                        pending.push((self.cell(addr + i)?, false));
+                    }
+                }
+                Cell::List(_) => return Err(Error::NotEvaluable(CONS, 2)),
+                Cell::Funct(_, _) => return Err(Error::MalformedTerm),
+            }
+        }
+
+        values.pop().ok_or(Error::MalformedTerm)
+    }
+
+    /// Returns cell of evaluated integer
+    #[cfg(not(feature = "bigint"))]
+    pub(crate) fn integer(&mut self, value: Integer) -> Cell {
+        Cell::Int(value)
+    }
+
+    /// Returns cell of evaluated integer, which is stored aside of
+    /// cells only if it doesn't fit in `isize`
+    #[cfg(feature = "bigint")]
+    pub(crate) fn integer(&mut self, value: Integer) -> Cell {
+        match value.to_isize() {
+            Some(value) => Cell::Int(value),
+            None => self.push_bigint(value),
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Boolean constraints (CLP(B))
+//!
+//! Boolean variables are attributed variables, which `sat` attribute is
+//! a list of constraints suspended on them - expressions which have to
+//! be true. Expressions are terms on the heap, so they are dropped on
+//! backtracking like any other term. Whenever constraint is posted or
+//! its variable is bound, conjunction of all constraints connected to
+//! it by shared variables is built into binary decision diagram, which
+//! is false if they are unsatisfiable. Variables which diagram allows
+//! only one value are bound to it.
+//!
+//! Labeling is searched by library predicates, linked into every
+//! knowledge, so its alternatives are tried by ordinary backtracking.
+
+use crate::builtin::{ADD, BNOT, EQ, GE, GT, LABELING, LE, LT, MUL, NE, NIL, SAT, XOR};
+use crate::statement::{RuleBuilder, Statement, StatementBuilder};
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+use std::collections::{BTreeMap, HashMap};
+
+const FALSE: usize = 0;
+const TRUE: usize = 1;
+
+/// Reduced ordered binary decision diagram, which nodes are indices,
+/// `FALSE` and `TRUE` being terminal ones
+struct Bdd {
+    /// Variable index, and nodes for its false and true value, of every
+    /// node - terminals have no variable
+    nodes: Vec<(usize, usize, usize)>,
+    /// Every distinct node stored once, so equal functions are equal
+    /// nodes
+    unique: HashMap<(usize, usize, usize), usize>,
+}
+
+impl Default for Bdd {
+    fn default() -> Self {
+        Self {
+            nodes: vec![(usize::MAX, FALSE, FALSE), (usize::MAX, TRUE, TRUE)],
+            unique: HashMap::new(),
+        }
+    }
+}
+
+impl Bdd {
+    fn node(&mut self, var: usize, low: usize, high: usize) -> usize {
+        if low == high {
+            return low;
+        }
+
+        let nodes = &mut self.nodes;
+        *self.unique.entry((var, low, high)).or_insert_with(|| {
+            nodes.push((var, low, high));
+            nodes.len() - 1
+        })
+    }
+
+    fn variable(&mut self, var: usize) -> usize {
+        self.node(var, FALSE, TRUE)
+    }
+
+    /// Diagram of given node with variable fixed to given value
+    fn restrict(&mut self, node: usize, var: usize, value: bool) -> usize {
+        let mut memo = HashMap::new();
+        self.restrict_memo(node, var, value, &mut memo)
+    }
+
+    fn restrict_memo(
+        &mut self,
+        node: usize,
+        var: usize,
+        value: bool,
+        memo: &mut HashMap<usize, usize>,
+    ) -> usize {
+        let (v, low, high) = self.nodes[node];
+        // Variables are ordered, so later ones don't depend on it
+        if v == usize::MAX || v > var {
+            return node;
+        }
+        if v == var {
+            return if value { high } else { low };
+        }
+        if let Some(result) = memo.get(&node) {
+            return *result;
+        }
+
+        let low = self.restrict_memo(low, var, value, memo);
+        let high = self.restrict_memo(high, var, value, memo);
+        let result = self.node(v, low, high);
+        memo.insert(node, result);
+        result
+    }
+
+    /// Diagram of given boolean operation applied to diagrams
+    fn apply(&mut self, op: fn(bool, bool) -> bool, a: usize, b: usize) -> usize {
+        let mut memo = HashMap::new();
+        self.apply_memo(op, a, b, &mut memo)
+    }
+
+    fn apply_memo(
+        &mut self,
+        op: fn(bool, bool) -> bool,
+        a: usize,
+        b: usize,
+        memo: &mut HashMap<(usize, usize), usize>,
+    ) -> usize {
+        if a <= TRUE && b <= TRUE {
+            return if op(a == TRUE, b == TRUE) {
+                TRUE
+            } else {
+                FALSE
+            };
+        }
+        if let Some(result) = memo.get(&(a, b)) {
+            return *result;
+        }
+
+        let (va, a0, a1) = self.nodes[a];
+        let (vb, b0, b1) = self.nodes[b];
+        // Terminal has no variable, so it is its own cofactor
+        let var = va.min(vb);
+        let (a0, a1) = if va == var { (a0, a1) } else { (a, a) };
+        let (b0, b1) = if vb == var { (b0, b1) } else { (b, b) };
+
+        let low = self.apply_memo(op, a0, b0, memo);
+        let high = self.apply_memo(op, a1, b1, memo);
+        let result = self.node(var, low, high);
+        memo.insert((a, b), result);
+        result
+    }
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Posts boolean constraint that given expression is true, and
+    /// propagates it
+    pub(crate) fn sat(&mut self, expr: Cell) -> Result<(), Error> {
+        let expr = self.deref_cell(expr)?;
+        // Expression is validated before it is suspended
+        self.boolean(&mut Bdd::default(), &mut BTreeMap::new(), expr)?;
+
+        for var in self.variables(expr)? {
+            let constraints = self.attribute(var, SAT).unwrap_or(Cell::Con(NIL));
+            let list = self.cons(expr, constraints);
+            self.put_attribute(var, SAT, Some(list));
+        }
+
+        self.propagate_sat(vec![expr])
+    }
+
+    /// Unifies second cell with `1` if expression of the first one is
+    /// true for all solutions of boolean constraints, or with `0` if
+    /// it is false for all of them
+    ///
+    /// Fails if expression may be both
+    pub(crate) fn taut(&mut self, expr: Cell, truth: Cell) -> Result<(), Error> {
+        let mut bdd = Bdd::default();
+        let mut indices = BTreeMap::new();
+        let expr = self.boolean(&mut bdd, &mut indices, expr)?;
+        let vars = indices.keys().copied().collect();
+        let (constraints, _) = self.component(vec![], vars)?;
+        let store = self.conjunction(&mut bdd, &mut indices, &constraints)?;
+
+        let negated = bdd.apply(|a, b| a && !b, store, expr);
+        if negated == FALSE {
+            return self.unify(truth, Cell::Int(1));
+        }
+        if bdd.apply(|a, b| a && b, store, expr) == FALSE {
+            return self.unify(truth, Cell::Int(0));
+        }
+        Err(Error::UnificationFailure)
+    }
+
+    /// Propagates boolean constraints of variable on given address after
+    /// it was bound
+    ///
+    /// Variable bound to another one passes its constraints to it
+    pub(crate) fn wake_sat(&mut self, var: usize) -> Result<(), Error> {
+        let constraints = match self.attribute(var, SAT) {
+            Some(constraints) => self.goals(constraints)?,
+            None => return Ok(()),
+        };
+
+        match self.deref_cell(Cell::Ref(var))? {
+            Cell::Ref(other) if other != var => {
+                let mut merged = self.attribute(other, SAT).unwrap_or(Cell::Con(NIL));
+                for constraint in constraints.iter().rev() {
+                    merged = self.cons(*constraint, merged);
+                }
+                self.put_attribute(other, SAT, Some(merged));
+            }
+            Cell::Ref(_) | Cell::Int(0) | Cell::Int(1) => (),
+            _ => return Err(Error::UnificationFailure),
+        }
+
+        self.propagate_sat(constraints)
+    }
+
+    /// Pushes residual goals of boolean constraints suspended on unbound
+    /// variables, which are not true for all their values yet
+    pub(crate) fn sat_residual_goals(&mut self) -> Result<Vec<Cell>, Error> {
+        let mut constraints = vec![];
+        for (var, list) in self.with_attribute(SAT) {
+            if self.deref_cell(Cell::Ref(var))? != Cell::Ref(var) {
+                continue;
+            }
+            for constraint in self.goals(list)? {
+                if !constraints.contains(&constraint) {
+                    constraints.push(constraint);
+                }
+            }
+        }
+
+        let mut goals = vec![];
+        for constraint in constraints {
+            let mut bdd = Bdd::default();
+            if self.boolean(&mut bdd, &mut BTreeMap::new(), constraint)? != TRUE {
+                goals.push(self.push_struct(SAT, 1));
+                self.push_cell(constraint);
+            }
+        }
+        Ok(goals)
+    }
+
+    // Checks if given constraints, and all constraints connected to them,
+    // are satisfiable, and binds variables which they allow only one
+    // value
+    fn propagate_sat(&mut self, constraints: Vec<Cell>) -> Result<(), Error> {
+        let (constraints, vars) = self.component(constraints, vec![])?;
+        let mut bdd = Bdd::default();
+        let mut indices = BTreeMap::new();
+        let store = self.conjunction(&mut bdd, &mut indices, &constraints)?;
+        if store == FALSE {
+            return Err(Error::UnificationFailure);
+        }
+
+        for var in vars {
+            let index = match indices.get(&var) {
+                Some(index) => *index,
+                None => continue,
+            };
+            let value = match (
+                bdd.restrict(store, index, false),
+                bdd.restrict(store, index, true),
+            ) {
+                (FALSE, _) => 1,
+                (_, FALSE) => 0,
+                _ => continue,
+            };
+            if let Cell::Ref(var) = self.deref_cell(Cell::Ref(var))? {
+                self.bind(var, Cell::Int(value))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Collects given constraints and constraints of given variables,
+    // and then constraints of their variables until no new one is
+    // found, returning them with their unbound variables
+    fn component(
+        &self,
+        mut pending: Vec<Cell>,
+        vars: Vec<usize>,
+    ) -> Result<(Vec<Cell>, Vec<usize>), Error> {
+        let mut constraints = vec![];
+        let mut visited = vec![];
+        let mut queue = vars;
+
+        loop {
+            if let Some(constraint) = pending.pop() {
+                if !constraints.contains(&constraint) {
+                    constraints.push(constraint);
+                    queue.extend(self.variables(constraint)?);
+                }
+            } else if let Some(var) = queue.pop() {
+                if !visited.contains(&var) {
+                    visited.push(var);
+                    if let Some(list) = self.attribute(var, SAT) {
+                        pending.extend(self.goals(list)?);
+                    }
+                }
+            } else {
+                return Ok((constraints, visited));
+            }
+        }
+    }
+
+    // Builds diagram of conjunction of given constraints
+    fn conjunction(
+        &self,
+        bdd: &mut Bdd,
+        indices: &mut BTreeMap<usize, usize>,
+        constraints: &[Cell],
+    ) -> Result<usize, Error> {
+        let mut store = TRUE;
+        for constraint in constraints {
+            let node = self.boolean(bdd, indices, *constraint)?;
+            store = bdd.apply(|a, b| a && b, store, node);
+        }
+        Ok(store)
+    }
+
+    // Builds diagram of boolean expression of given term - `0`, `1`,
+    // variable, `~/1` negation, `+/2` disjunction, `*/2` conjunction,
+    // `#/2` exclusive disjunction, or comparison of truth values
+    //
+    // Variables get indices in order they are found, unless they have
+    // them already. Expression is read without recursion, like
+    // evaluated arithmetic expression.
+    fn boolean(
+        &self,
+        bdd: &mut Bdd,
+        indices: &mut BTreeMap<usize, usize>,
+        cell: Cell,
+    ) -> Result<usize, Error> {
+        let mut values = vec![];
+        let mut pending = vec![(cell, false)];
+
+        while let Some((cell, ready)) = pending.pop() {
+            match self.deref_cell(cell)? {
+                Cell::Int(0) => values.push(FALSE),
+                Cell::Int(1) => values.push(TRUE),
+                Cell::Ref(var) => {
+                    let next = indices.len();
+                    let index = *indices.entry(var).or_insert(next);
+                    values.push(bdd.variable(index));
+                }
+                Cell::Struct(addr) if ready => {
+                    let (ident, arity) = self.funct(addr)?;
+                    let args = values.split_off(values.len() - arity);
+                    let op: fn(bool, bool) -> bool = match ident {
+                        BNOT => |a, _| !a,
+                        ADD => |a, b| a || b,
+                        MUL => |a, b| a && b,
+                        XOR | NE => |a, b| a != b,
+                        EQ => |a, b| a == b,
+                        LE => |a, b| !a || b,
+                        GE => |a, b| a || !b,
+                        LT => |a, b| !a && b,
+                        _ => |a, b| a && !b,
+                    };
+                    let value = match args[..] {
+                        [a] => bdd.apply(op, a, TRUE),
+                        [a, b] => bdd.apply(op, a, b),
+                        _ => return Err(Error::MalformedTerm),
+                    };
+                    values.push(value);
+                }
+                Cell::Struct(addr) => {
+                    let (ident, arity) = self.funct(addr)?;
+                    if !matches!(
+                        (ident, arity),
+                        (BNOT, 1) | (ADD | MUL | XOR | EQ | NE | LE | GE | LT | GT, 2)
+                    ) {
+                        return Err(Error::InvalidBoolean);
+                    }
+
+                    pending.push((cell, true));
+                    for i in (1..=arity).rev() {
+                        pending.push((self.cell(addr + i)?, false));
+                    }
+                }
+                _ => return Err(Error::InvalidBoolean),
+            }
+        }
+
+        values.pop().ok_or(Error::MalformedTerm)
+    }
+}
+
+/// Library predicates searching for labeling, by trying `0` and then
+/// `1` for every variable:
+///
+/// ```text
+/// labeling([]).
+/// labeling([0 | Vs]) :- labeling(Vs).
+/// labeling([1 | Vs]) :- labeling(Vs).
+/// ```
+pub(crate) fn library<C: ConstDomain>() -> Vec<Statement<'static, C>> {
+    let mut statements = vec![];
+
+    let mut builder = StatementBuilder::default();
+    let nil = builder.nil();
+    let labeling = builder.structure(LABELING, vec![nil]);
+    statements.push(builder.build(labeling));
+
+    for value in [0, 1] {
+        let mut builder = RuleBuilder::default();
+        let v = builder.integer(value);
+        let vs = builder.variable();
+        let list = builder.cons(v, vs);
+        let head = builder.structure(LABELING, vec![list]);
+        let labeling = builder.structure(LABELING, vec![vs]);
+        statements.push(builder.rule(head, vec![labeling]));
+    }
+
+    statements
+}
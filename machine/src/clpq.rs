@@ -0,0 +1,992 @@
+//! Linear constraints over rationals (CLP(Q))
+//!
+//! Constraints are kept in a general simplex tableau, which rows define
+//! slack columns as linear combinations of variable columns. Posting a
+//! constraint only bounds a column, and satisfiability is checked
+//! incrementally by pivoting basic columns back within their bounds.
+//! Pivoting keeps rows equivalent, so only bounds are trailed and
+//! restored on backtracking - rows of undone constraints are left with
+//! unbounded slack columns, which never make tableau unsatisfiable.
+//!
+//! Constrained variables are attributed variables, which `{}` attribute
+//! is index of their column. Variable which value is determined by
+//! equalities - its column is bounded to single value, or it depends
+//! only on such columns - is bound to it. Constraints left when query is solved are
+//! projected onto query variables, by eliminating all other columns.
+
+use crate::builtin::{ADD, AND, CONS, EQ, GE, GT, LE, LINEAR, LT, MUL, RDIV, SUB};
+use crate::rational::{gcd, Rational};
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// Rational with coefficient of infinitesimal delta, so strict bounds
+/// are non-strict bounds shifted by delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Delta {
+    real: Rational,
+    delta: Rational,
+}
+
+impl Delta {
+    const ZERO: Self = Self::exact(Rational::ZERO);
+
+    const fn exact(real: Rational) -> Self {
+        Self {
+            real,
+            delta: Rational::ZERO,
+        }
+    }
+
+    fn add(self, other: Self) -> Result<Self, Error> {
+        Ok(Self {
+            real: self.real.add(other.real)?,
+            delta: self.delta.add(other.delta)?,
+        })
+    }
+
+    fn sub(self, other: Self) -> Result<Self, Error> {
+        Ok(Self {
+            real: self.real.sub(other.real)?,
+            delta: self.delta.sub(other.delta)?,
+        })
+    }
+
+    fn scale(self, factor: Rational) -> Result<Self, Error> {
+        Ok(Self {
+            real: self.real.mul(factor)?,
+            delta: self.delta.mul(factor)?,
+        })
+    }
+}
+
+/// Linear combination of columns, by their indices
+type Coefs = BTreeMap<usize, Rational>;
+
+// Adds given coefficients multiplied by factor, dropping columns which
+// coefficients become zero
+fn add_scaled(coefs: &mut Coefs, other: &Coefs, factor: Rational) -> Result<(), Error> {
+    for (col, coef) in other {
+        let sum = coefs
+            .get(col)
+            .copied()
+            .unwrap_or(Rational::ZERO)
+            .add(coef.mul(factor)?)?;
+        if sum.is_zero() {
+            coefs.remove(col);
+        } else {
+            coefs.insert(*col, sum);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct Column {
+    lower: Option<Delta>,
+    upper: Option<Delta>,
+    /// Current assignment, always within bounds of nonbasic column
+    value: Delta,
+    /// Row in which column is basic
+    row: Option<usize>,
+    /// Variable columns which combination slack column is, `None` for
+    /// variable column
+    definition: Option<Coefs>,
+}
+
+/// Basic column as linear combination of nonbasic ones
+#[derive(Debug, Clone)]
+struct Row {
+    basic: usize,
+    coefs: Coefs,
+}
+
+/// Simplex tableau of linear constraints
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Simplex {
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+}
+
+impl Simplex {
+    /// Adds unbounded variable column, returning its index
+    fn add_column(&mut self) -> usize {
+        self.columns.push(Column {
+            lower: None,
+            upper: None,
+            value: Delta::ZERO,
+            row: None,
+            definition: None,
+        });
+        self.columns.len() - 1
+    }
+
+    /// Adds unbounded slack column, which is given combination of
+    /// variable columns, returning its index
+    fn add_row(&mut self, definition: &Coefs) -> Result<usize, Error> {
+        let mut coefs = Coefs::new();
+        let mut value = Delta::ZERO;
+        for (col, coef) in definition {
+            let column = &self.columns[*col];
+            value = value.add(column.value.scale(*coef)?)?;
+            match column.row {
+                Some(row) => add_scaled(&mut coefs, &self.rows[row].coefs, *coef)?,
+                None => add_scaled(&mut coefs, &[(*col, Rational::ONE)].into(), *coef)?,
+            }
+        }
+
+        let col = self.columns.len();
+        self.columns.push(Column {
+            lower: None,
+            upper: None,
+            value,
+            row: Some(self.rows.len()),
+            definition: Some(definition.clone()),
+        });
+        self.rows.push(Row { basic: col, coefs });
+        Ok(col)
+    }
+
+    /// Returns upper or lower bound of column
+    fn bound(&self, col: usize, upper: bool) -> Option<Delta> {
+        let column = &self.columns[col];
+        if upper {
+            column.upper
+        } else {
+            column.lower
+        }
+    }
+
+    /// Sets upper or lower bound of column, returning the previous one
+    pub(crate) fn set_bound(
+        &mut self,
+        col: usize,
+        upper: bool,
+        bound: Option<Delta>,
+    ) -> Option<Delta> {
+        let column = &mut self.columns[col];
+        if upper {
+            std::mem::replace(&mut column.upper, bound)
+        } else {
+            std::mem::replace(&mut column.lower, bound)
+        }
+    }
+
+    /// The only value of column, if its bounds allow exactly one
+    fn fixed(&self, col: usize) -> Option<Rational> {
+        let column = &self.columns[col];
+        match (column.lower, column.upper) {
+            (Some(lower), Some(upper)) if lower == upper => Some(lower.real),
+            _ => None,
+        }
+    }
+
+    /// The only value of column which equalities allow - if it is
+    /// bounded to single value, or if it is basic column depending
+    /// only on such columns
+    ///
+    /// Has to be called after `settle`, so columns bounded to single
+    /// value are nonbasic wherever possible.
+    fn implied(&self, col: usize) -> Option<Rational> {
+        if let Some(value) = self.fixed(col) {
+            return Some(value);
+        }
+
+        let row = self.columns[col].row?;
+        let determined = self.rows[row]
+            .coefs
+            .keys()
+            .all(|col| self.fixed(*col).is_some());
+        determined.then_some(self.columns[col].value.real)
+    }
+
+    // Pivots basic columns bounded to single value out of basis, while
+    // their rows depend on any other nonbasic column, so columns which
+    // equalities determine become basic ones depending only on columns
+    // bounded to single value
+    //
+    // Columns bounded to single value never become basic again, so it
+    // always terminates. Pivoting keeps assignment, and column leaving
+    // basis is already at its only value, as tableau is satisfied.
+    fn settle(&mut self) -> Result<(), Error> {
+        loop {
+            let pivot = self.rows.iter().enumerate().find_map(|(row, Row { basic, coefs })| {
+                self.fixed(*basic)?;
+                coefs
+                    .keys()
+                    .find(|col| self.fixed(**col).is_none())
+                    .map(|col| (row, *col))
+            });
+
+            match pivot {
+                Some((row, col)) => self.pivot(row, col)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Moves nonbasic column within its bounds
+    fn fit(&mut self, col: usize) -> Result<(), Error> {
+        let column = &self.columns[col];
+        if column.row.is_some() {
+            return Ok(());
+        }
+        match (column.lower, column.upper) {
+            (Some(lower), _) if column.value < lower => self.update(col, lower),
+            (_, Some(upper)) if column.value > upper => self.update(col, upper),
+            _ => Ok(()),
+        }
+    }
+
+    // Assigns nonbasic column, updating basic columns depending on it
+    fn update(&mut self, col: usize, value: Delta) -> Result<(), Error> {
+        let change = value.sub(self.columns[col].value)?;
+        for row in &self.rows {
+            if let Some(coef) = row.coefs.get(&col) {
+                let basic = &mut self.columns[row.basic];
+                basic.value = basic.value.add(change.scale(*coef)?)?;
+            }
+        }
+        self.columns[col].value = value;
+        Ok(())
+    }
+
+    /// Pivots tableau until all columns are within their bounds, and
+    /// returns false if it is impossible
+    ///
+    /// Always the lowest violated basic column and the lowest nonbasic
+    /// column able to fix it are pivoted (Bland's rule), so it never
+    /// cycles.
+    fn check(&mut self) -> Result<bool, Error> {
+        loop {
+            let violated = self
+                .rows
+                .iter()
+                .enumerate()
+                .filter_map(|(row, Row { basic, .. })| {
+                    let column = &self.columns[*basic];
+                    match (column.lower, column.upper) {
+                        (Some(lower), _) if column.value < lower => Some((*basic, row, lower)),
+                        (_, Some(upper)) if column.value > upper => Some((*basic, row, upper)),
+                        _ => None,
+                    }
+                })
+                .min_by_key(|(basic, _, _)| *basic);
+
+            let (basic, row, bound) = match violated {
+                Some(violated) => violated,
+                None => return Ok(true),
+            };
+            let increase = self.columns[basic].value < bound;
+
+            let entering = self.rows[row].coefs.iter().find(|(col, coef)| {
+                let column = &self.columns[**col];
+                if increase != coef.is_negative() {
+                    column.upper.is_none_or(|upper| column.value < upper)
+                } else {
+                    column.lower.is_none_or(|lower| column.value > lower)
+                }
+            });
+
+            match entering {
+                Some((col, coef)) => {
+                    // Entering column moves so basic one meets its bound
+                    let (col, coef) = (*col, *coef);
+                    let change = bound
+                        .sub(self.columns[basic].value)?
+                        .scale(Rational::ONE.div(coef)?)?;
+                    self.update(col, self.columns[col].value.add(change)?)?;
+                    self.pivot(row, col)?;
+                }
+                None => return Ok(false),
+            }
+        }
+    }
+
+    // Swaps basic column of row with given nonbasic one
+    fn pivot(&mut self, row: usize, col: usize) -> Result<(), Error> {
+        let basic = self.rows[row].basic;
+        let mut coefs = std::mem::take(&mut self.rows[row].coefs);
+        let coef = coefs.remove(&col).ok_or(Error::MalformedTerm)?;
+
+        let inverse = Rational::ONE.div(coef)?;
+        let mut solved: Coefs = [(basic, inverse)].into();
+        for (other, coef) in coefs {
+            solved.insert(other, coef.mul(inverse)?.neg()?);
+        }
+
+        for other in &mut self.rows {
+            if let Some(coef) = other.coefs.remove(&col) {
+                add_scaled(&mut other.coefs, &solved, coef)?;
+            }
+        }
+
+        self.rows[row] = Row {
+            basic: col,
+            coefs: solved,
+        };
+        self.columns[basic].row = None;
+        self.columns[col].row = Some(row);
+        Ok(())
+    }
+
+    /// Constraints which bounds of columns impose on variable columns
+    fn constraints(&self) -> Result<Vec<Constraint>, Error> {
+        let mut constraints = vec![];
+        for (col, column) in self.columns.iter().enumerate() {
+            let definition = match &column.definition {
+                Some(definition) => definition.clone(),
+                None => [(col, Rational::ONE)].into(),
+            };
+
+            if let Some(value) = self.fixed(col) {
+                constraints.push(Constraint {
+                    coefs: definition,
+                    constant: value.neg()?,
+                    relation: Relation::Eq,
+                });
+                continue;
+            }
+            if let Some(lower) = column.lower {
+                let mut coefs = Coefs::new();
+                add_scaled(&mut coefs, &definition, Rational::ONE.neg()?)?;
+                constraints.push(Constraint {
+                    coefs,
+                    constant: lower.real,
+                    relation: Relation::strict(!lower.delta.is_zero()),
+                });
+            }
+            if let Some(upper) = column.upper {
+                constraints.push(Constraint {
+                    coefs: definition,
+                    constant: upper.real.neg()?,
+                    relation: Relation::strict(!upper.delta.is_zero()),
+                });
+            }
+        }
+        Ok(constraints)
+    }
+}
+
+/// Relation of linear constraint to zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Relation {
+    Eq,
+    Le,
+    Lt,
+}
+
+impl Relation {
+    fn strict(strict: bool) -> Self {
+        if strict {
+            Self::Lt
+        } else {
+            Self::Le
+        }
+    }
+}
+
+/// Combination of columns and constant in relation to zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Constraint {
+    coefs: Coefs,
+    constant: Rational,
+    relation: Relation,
+}
+
+impl Constraint {
+    // Adds other constraint multiplied by factor, which is positive
+    // unless other one is an equality
+    fn add_scaled(&mut self, other: &Self, factor: Rational) -> Result<(), Error> {
+        add_scaled(&mut self.coefs, &other.coefs, factor)?;
+        self.constant = self.constant.add(other.constant.mul(factor)?)?;
+        if other.relation == Relation::Lt {
+            self.relation = Relation::Lt;
+        }
+        Ok(())
+    }
+
+    fn scale(mut self, factor: Rational) -> Result<Self, Error> {
+        for coef in self.coefs.values_mut() {
+            *coef = coef.mul(factor)?;
+        }
+        self.constant = self.constant.mul(factor)?;
+        Ok(self)
+    }
+
+    // Eliminates column by substituting it with its value given by
+    // equality
+    fn substitute(&mut self, col: usize, equality: &Self) -> Result<(), Error> {
+        if let Some(coef) = self.coefs.get(&col) {
+            let factor = coef.div(equality.coefs[&col])?.neg()?;
+            self.add_scaled(equality, factor)?;
+        }
+        Ok(())
+    }
+
+    // Scales constraint to coprime integer coefficients, with positive
+    // leading coefficient of equality
+    fn normalize(self) -> Result<Self, Error> {
+        let (mut num, mut den) = (0, 1);
+        for value in self.coefs.values().chain([&self.constant]) {
+            num = gcd(num, value.numer());
+            let g = gcd(den, value.denom());
+            den = (den / g)
+                .checked_mul(value.denom())
+                .ok_or(Error::IntegerOverflow)?;
+        }
+        if num == 0 {
+            return Ok(self);
+        }
+
+        let leading = self.coefs.values().next().copied();
+        let factor = match (self.relation, leading) {
+            (Relation::Eq, Some(coef)) if coef.is_negative() => Rational::new(-den, num)?,
+            _ => Rational::new(den, num)?,
+        };
+        self.scale(factor)
+    }
+
+    // Checks if inequality with the same coefficients is implied by
+    // this one
+    fn implies(&self, other: &Self) -> bool {
+        self.constant > other.constant
+            || (self.constant == other.constant && other.relation != Relation::Lt)
+    }
+}
+
+// Eliminates columns which are not kept from constraints - first by
+// substitution from equalities, then from inequalities by combining
+// every pair bounding column from opposite sides (Fourier-Motzkin)
+fn project(
+    constraints: Vec<Constraint>,
+    keep: impl Fn(usize) -> bool,
+) -> Result<Vec<Constraint>, Error> {
+    let (mut equalities, mut inequalities): (Vec<_>, Vec<_>) = constraints
+        .into_iter()
+        .partition(|constraint| constraint.relation == Relation::Eq);
+
+    let eliminated =
+        |constraint: &Constraint| constraint.coefs.keys().copied().find(|col| !keep(*col));
+    while let Some((i, col)) = equalities
+        .iter()
+        .enumerate()
+        .find_map(|(i, equality)| Some((i, eliminated(equality)?)))
+    {
+        let equality = equalities.swap_remove(i);
+        for constraint in equalities.iter_mut().chain(&mut inequalities) {
+            constraint.substitute(col, &equality)?;
+        }
+    }
+
+    inequalities = simplify(inequalities)?;
+    while let Some(col) = inequalities.iter().find_map(eliminated) {
+        let (bounding, mut rest): (Vec<_>, Vec<_>) = inequalities
+            .into_iter()
+            .partition(|inequality| inequality.coefs.contains_key(&col));
+        let (lower, upper): (Vec<_>, Vec<_>) = bounding
+            .into_iter()
+            .partition(|inequality| inequality.coefs[&col].is_negative());
+
+        for upper in &upper {
+            for lower in &lower {
+                let mut combined = upper.clone().scale(lower.coefs[&col].neg()?)?;
+                combined.add_scaled(lower, upper.coefs[&col])?;
+                rest.push(combined);
+            }
+        }
+        inequalities = simplify(rest)?;
+    }
+
+    // Every equality is solved for its last column, and substituted
+    // into other constraints, so columns determined by equalities are
+    // left alone in them
+    for i in 0..equalities.len() {
+        let equality = equalities[i].clone();
+        if let Some(col) = equality.coefs.keys().next_back().copied() {
+            for (j, constraint) in equalities.iter_mut().enumerate() {
+                if j != i {
+                    constraint.substitute(col, &equality)?;
+                }
+            }
+            for constraint in &mut inequalities {
+                constraint.substitute(col, &equality)?;
+            }
+        }
+    }
+
+    equalities.extend(inequalities);
+    simplify(equalities)
+}
+
+// Normalizes constraints, dropping ones without columns, which hold
+// as constraints are satisfiable, and ones implied by others
+//
+// Opposite non-strict inequalities are joined into equality
+fn simplify(constraints: Vec<Constraint>) -> Result<Vec<Constraint>, Error> {
+    let mut simplified: Vec<Constraint> = vec![];
+    for constraint in constraints {
+        let constraint = constraint.normalize()?;
+        if constraint.coefs.is_empty() {
+            continue;
+        }
+
+        let equality = constraint.relation == Relation::Eq;
+        match simplified.iter_mut().find(|other| {
+            other.coefs == constraint.coefs && (other.relation == Relation::Eq) == equality
+        }) {
+            Some(other) if !equality && constraint.implies(other) => *other = constraint,
+            Some(_) => (),
+            None => simplified.push(constraint),
+        }
+    }
+
+    let mut i = 0;
+    while i < simplified.len() {
+        if simplified[i].relation == Relation::Le {
+            let opposite = simplified[i].clone().scale(Rational::ONE.neg()?)?;
+            // Opposite of earlier inequality would have been found
+            // already, so it is always a later one
+            if let Some(j) = simplified.iter().position(|other| *other == opposite) {
+                simplified.remove(j);
+                let equality = Constraint {
+                    relation: Relation::Eq,
+                    ..simplified[i].clone()
+                };
+                simplified[i] = equality.normalize()?;
+            }
+        }
+        i += 1;
+    }
+
+    Ok(simplified)
+}
+
+/// Linear expression - variables (by their addresses) with their
+/// coefficients, and constant
+#[derive(Debug, Clone)]
+struct Expression {
+    vars: Coefs,
+    constant: Rational,
+}
+
+impl Expression {
+    fn constant(value: Rational) -> Self {
+        Self {
+            vars: Coefs::new(),
+            constant: value,
+        }
+    }
+
+    fn variable(addr: usize) -> Self {
+        Self {
+            vars: [(addr, Rational::ONE)].into(),
+            constant: Rational::ZERO,
+        }
+    }
+
+    fn add(mut self, other: &Self) -> Result<Self, Error> {
+        add_scaled(&mut self.vars, &other.vars, Rational::ONE)?;
+        self.constant = self.constant.add(other.constant)?;
+        Ok(self)
+    }
+
+    fn scale(mut self, factor: Rational) -> Result<Self, Error> {
+        if factor.is_zero() {
+            return Ok(Self::constant(Rational::ZERO));
+        }
+        for coef in self.vars.values_mut() {
+            *coef = coef.mul(factor)?;
+        }
+        self.constant = self.constant.mul(factor)?;
+        Ok(self)
+    }
+
+    fn sub(self, other: &Self) -> Result<Self, Error> {
+        self.add(&other.clone().scale(Rational::ONE.neg()?)?)
+    }
+
+    /// Product of expressions, if at most one has variables
+    fn mul(self, other: Self) -> Result<Self, Error> {
+        match () {
+            _ if self.vars.is_empty() => other.scale(self.constant),
+            _ if other.vars.is_empty() => self.scale(other.constant),
+            _ => Err(Error::InvalidConstraint),
+        }
+    }
+
+    /// Quotient of expressions, if divisor is constant
+    fn div(self, other: Self) -> Result<Self, Error> {
+        if !other.vars.is_empty() {
+            return Err(Error::InvalidConstraint);
+        }
+        self.scale(Rational::ONE.div(other.constant)?)
+    }
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Posts linear constraints of given term - comparison of linear
+    /// expressions, or conjunction of them - and checks if all posted
+    /// constraints are still satisfiable
+    pub(crate) fn post_q(&mut self, cell: Cell) -> Result<(), Error> {
+        let mut pending = vec![cell];
+
+        while let Some(cell) = pending.pop() {
+            let addr = match self.deref_cell(cell)? {
+                Cell::Struct(addr) => addr,
+                Cell::Ref(_) => return Err(Error::Instantiation),
+                _ => return Err(Error::InvalidConstraint),
+            };
+            let (ident, arity) = self.funct(addr)?;
+            if arity != 2 {
+                return Err(Error::InvalidConstraint);
+            }
+
+            let (left, right) = (self.cell(addr + 1)?, self.cell(addr + 2)?);
+            if ident == AND {
+                pending.push(right);
+                pending.push(left);
+                continue;
+            }
+
+            let left = self.expression(left)?;
+            let right = self.expression(right)?;
+            let (expression, relation) = match ident {
+                EQ => (left.sub(&right)?, Relation::Eq),
+                LE => (left.sub(&right)?, Relation::Le),
+                LT => (left.sub(&right)?, Relation::Lt),
+                GE => (right.sub(&left)?, Relation::Le),
+                GT => (right.sub(&left)?, Relation::Lt),
+                _ => return Err(Error::InvalidConstraint),
+            };
+            self.constrain(&expression, relation)?;
+        }
+
+        self.satisfy()
+    }
+
+    /// Constrains column of variable on given address after it was
+    /// bound, to its value or to column of variable it was bound to
+    pub(crate) fn wake_q(&mut self, var: usize) -> Result<(), Error> {
+        let col = match self.attribute(var, LINEAR) {
+            Some(Cell::Int(col)) => col,
+            _ => return Ok(()),
+        };
+
+        match self.deref_cell(Cell::Ref(var))? {
+            Cell::Ref(other) if other == var => Ok(()),
+            Cell::Ref(other) if self.attribute(other, LINEAR).is_none() => {
+                self.put_attribute(other, LINEAR, Some(Cell::Int(col)));
+                Ok(())
+            }
+            Cell::Ref(other) => {
+                let expression = Expression::variable(var).sub(&Expression::variable(other))?;
+                self.constrain(&expression, Relation::Eq)?;
+                self.satisfy()
+            }
+            cell => {
+                let value = Expression::constant(self.rational(cell)?);
+                let expression = Expression::variable(var).sub(&value)?;
+                self.constrain(&expression, Relation::Eq)?;
+                self.satisfy()
+            }
+        }
+    }
+
+    /// Pushes residual goals of linear constraints, projected onto
+    /// unbound variables occurring in terms of given cells
+    pub(crate) fn q_residual_goals(&mut self, cells: &[Cell]) -> Result<Vec<Cell>, Error> {
+        if self.with_attribute(LINEAR).is_empty() {
+            return Ok(vec![]);
+        }
+
+        // Variables of kept columns
+        let mut vars = BTreeMap::new();
+        for cell in cells {
+            for var in self.variables(*cell)? {
+                if let Some(Cell::Int(col)) = self.attribute(var, LINEAR) {
+                    vars.insert(col as usize, var);
+                }
+            }
+        }
+
+        let constraints = project(self.simplex().constraints()?, |col| vars.contains_key(&col))?;
+        constraints
+            .iter()
+            .map(|constraint| self.push_constraint(constraint, &vars))
+            .collect()
+    }
+
+    // Column of variable on given address, added if it has none
+    fn column(&mut self, var: usize) -> usize {
+        match self.attribute(var, LINEAR) {
+            Some(Cell::Int(col)) => col as usize,
+            _ => {
+                let col = self.simplex_mut().add_column();
+                self.put_attribute(var, LINEAR, Some(Cell::Int(col as isize)));
+                col
+            }
+        }
+    }
+
+    // Bounds column of linear expression in given relation to zero -
+    // column of its only variable, or new slack column
+    fn constrain(&mut self, expression: &Expression, relation: Relation) -> Result<(), Error> {
+        let mut definition = Coefs::new();
+        for (var, coef) in &expression.vars {
+            let col = self.column(*var);
+            add_scaled(&mut definition, &[(col, *coef)].into(), Rational::ONE)?;
+        }
+
+        let (col, coef) = match definition.iter().next() {
+            None => {
+                let holds = match relation {
+                    Relation::Eq => expression.constant.is_zero(),
+                    Relation::Le => expression.constant <= Rational::ZERO,
+                    Relation::Lt => expression.constant < Rational::ZERO,
+                };
+                return if holds {
+                    Ok(())
+                } else {
+                    Err(Error::UnificationFailure)
+                };
+            }
+            Some((col, coef)) if definition.len() == 1 => (*col, *coef),
+            Some(_) => (self.simplex_mut().add_row(&definition)?, Rational::ONE),
+        };
+
+        // Dividing by negative coefficient flips relation
+        let value = Delta::exact(expression.constant.neg()?.div(coef)?);
+        let upper = !coef.is_negative();
+        match relation {
+            Relation::Eq => {
+                self.restrict(col, true, value)?;
+                self.restrict(col, false, value)
+            }
+            Relation::Le => self.restrict(col, upper, value),
+            Relation::Lt => {
+                let delta = Rational::integer(if upper { -1 } else { 1 });
+                let value = Delta { delta, ..value };
+                self.restrict(col, upper, value)
+            }
+        }
+    }
+
+    // Tightens upper or lower bound of column, failing if bounds leave
+    // no value to it
+    fn restrict(&mut self, col: usize, upper: bool, bound: Delta) -> Result<(), Error> {
+        let simplex = self.simplex();
+        let implied = match simplex.bound(col, upper) {
+            Some(current) if upper => current <= bound,
+            Some(current) => current >= bound,
+            None => false,
+        };
+        if implied {
+            return Ok(());
+        }
+        let empty = match simplex.bound(col, !upper) {
+            Some(other) if upper => bound < other,
+            Some(other) => bound > other,
+            None => false,
+        };
+        if empty {
+            return Err(Error::UnificationFailure);
+        }
+
+        self.set_bound(col, upper, Some(bound));
+        self.simplex_mut().fit(col)
+    }
+
+    // Checks if posted constraints are satisfiable, and binds
+    // constrained variables which values equalities determine
+    //
+    // Variable is determined if its column is bounded to single value,
+    // or if other columns bounded so fix it through tableau rows, like
+    // both variables of `{X + Y =:= 10}, {X - Y =:= 2}`.
+    fn satisfy(&mut self) -> Result<(), Error> {
+        let simplex = self.simplex_mut();
+        if !simplex.check()? {
+            return Err(Error::UnificationFailure);
+        }
+        simplex.settle()?;
+
+        for (var, col) in self.with_attribute(LINEAR) {
+            let col = match col {
+                Cell::Int(col) => col as usize,
+                _ => continue,
+            };
+            if let Cell::Ref(var) = self.deref_cell(Cell::Ref(var))? {
+                if let Some(value) = self.simplex().implied(col) {
+                    let value = self.push_rational(value)?;
+                    self.bind(var, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reads linear expression of given term
+    //
+    // Expression is read without recursion, like evaluated arithmetic
+    // expression
+    fn expression(&self, cell: Cell) -> Result<Expression, Error> {
+        let mut values = vec![];
+        let mut pending = vec![(cell, false)];
+
+        while let Some((cell, ready)) = pending.pop() {
+            match self.deref_cell(cell)? {
+                Cell::Int(value) => values.push(Expression::constant(Rational::integer(value))),
+                Cell::Ref(var) => values.push(Expression::variable(var)),
+                Cell::Struct(addr) if ready => {
+                    let (ident, arity) = self.funct(addr)?;
+                    let mut args = values.split_off(values.len() - arity).into_iter();
+                    let (a, b) = (args.next(), args.next());
+                    let value = match (ident, a, b) {
+                        (ADD, Some(a), Some(b)) => a.add(&b)?,
+                        (SUB, Some(a), Some(b)) => a.sub(&b)?,
+                        (SUB, Some(a), None) => a.scale(Rational::ONE.neg()?)?,
+                        (MUL, Some(a), Some(b)) => a.mul(b)?,
+                        (RDIV, Some(a), Some(b)) => a.div(b)?,
+                        _ => return Err(Error::NotEvaluable(ident, arity)),
+                    };
+                    values.push(value);
+                }
+                Cell::Struct(addr) => {
+                    let (ident, arity) = self.funct(addr)?;
+                    if !matches!((ident, arity), (ADD | SUB | MUL | RDIV, 2) | (SUB, 1)) {
+                        return Err(Error::NotEvaluable(ident, arity));
+                    }
+
+                    pending.push((cell, true));
+                    for i in (1..=arity).rev() {
+                        pending.push((self.cell(addr + i)?, false));
+                    }
+                }
+                Cell::Con(ident) => return Err(Error::NotEvaluable(ident, 0)),
+                Cell::Value(_) => return Err(Error::NotEvaluableValue),
+                Cell::List(_) => return Err(Error::NotEvaluable(CONS, 2)),
+                Cell::Funct(_, _) => return Err(Error::MalformedTerm),
+                #[cfg(feature = "bigint")]
+                Cell::BigInt(_) => return Err(Error::IntegerOverflow),
+            }
+        }
+
+        values.pop().ok_or(Error::MalformedTerm)
+    }
+
+    // Reads rational value of given term - integer, or quotient of
+    // integers - failing for any other term
+    fn rational(&self, cell: Cell) -> Result<Rational, Error> {
+        match cell {
+            Cell::Int(value) => Ok(Rational::integer(value)),
+            Cell::Struct(addr) if self.funct(addr)? == (RDIV, 2) => {
+                let num = self.deref_cell(self.cell(addr + 1)?)?;
+                let den = self.deref_cell(self.cell(addr + 2)?)?;
+                match (num, den) {
+                    (Cell::Int(num), Cell::Int(den)) => Rational::new(num as i128, den as i128),
+                    _ => Err(Error::UnificationFailure),
+                }
+            }
+            #[cfg(feature = "bigint")]
+            Cell::BigInt(_) => Err(Error::IntegerOverflow),
+            _ => Err(Error::UnificationFailure),
+        }
+    }
+
+    // Pushes term of given rational - integer, or quotient of integers
+    fn push_rational(&mut self, value: Rational) -> Result<Cell, Error> {
+        let num = integer(value.numer())?;
+        if value.denom() == 1 {
+            return Ok(Cell::Int(num));
+        }
+
+        let cell = self.push_struct(RDIV, 2);
+        self.push_cell(Cell::Int(num));
+        self.push_cell(Cell::Int(integer(value.denom())?));
+        Ok(cell)
+    }
+
+    // Pushes `{}/1` goal of projected constraint, with variables of its
+    // columns on the left side, and constant on the right side
+    fn push_constraint(
+        &mut self,
+        constraint: &Constraint,
+        vars: &BTreeMap<usize, usize>,
+    ) -> Result<Cell, Error> {
+        // Constraint of single variable bounds it by rational value
+        let constraint = match constraint.coefs.values().collect::<Vec<_>>()[..] {
+            [coef] if coef.is_negative() => {
+                constraint.clone().scale(Rational::ONE.div(coef.neg()?)?)?
+            }
+            [coef] => constraint.clone().scale(Rational::ONE.div(*coef)?)?,
+            _ => constraint.clone(),
+        };
+
+        // Constraint reads better flipped if it has mostly negative
+        // coefficients, or negative constant on the right side
+        let negative = constraint
+            .coefs
+            .values()
+            .filter(|coef| coef.is_negative())
+            .count();
+        let flipped = match (2 * negative).cmp(&constraint.coefs.len()) {
+            Ordering::Greater => true,
+            Ordering::Equal => !constraint.constant.is_negative() && !constraint.constant.is_zero(),
+            Ordering::Less => false,
+        };
+        let (constraint, ident) = match (constraint.relation, flipped) {
+            (Relation::Eq, false) => (constraint, EQ),
+            (Relation::Le, false) => (constraint, LE),
+            (Relation::Lt, false) => (constraint, LT),
+            (Relation::Eq, true) => (constraint.scale(Rational::ONE.neg()?)?, EQ),
+            (Relation::Le, true) => (constraint.scale(Rational::ONE.neg()?)?, GE),
+            (Relation::Lt, true) => (constraint.scale(Rational::ONE.neg()?)?, GT),
+        };
+
+        let mut left = None;
+        // Positive terms go first, so they aren't negated
+        let mut terms: Vec<_> = constraint.coefs.iter().collect();
+        terms.sort_by_key(|(_, coef)| coef.is_negative());
+        for (col, coef) in terms {
+            let var = Cell::Ref(vars[col]);
+            let magnitude = integer(coef.numer().abs())?;
+            let term = if magnitude == 1 {
+                var
+            } else {
+                let term = self.push_struct(MUL, 2);
+                self.push_cell(Cell::Int(magnitude));
+                self.push_cell(var);
+                term
+            };
+
+            left = Some(match left {
+                None if coef.is_negative() => {
+                    let negation = self.push_struct(SUB, 1);
+                    self.push_cell(term);
+                    negation
+                }
+                None => term,
+                Some(left) => {
+                    let sum = self.push_struct(if coef.is_negative() { SUB } else { ADD }, 2);
+                    self.push_cell(left);
+                    self.push_cell(term);
+                    sum
+                }
+            });
+        }
+
+        let right = self.push_rational(constraint.constant.neg()?)?;
+        let comparison = self.push_struct(ident, 2);
+        self.push_cell(left.ok_or(Error::MalformedTerm)?);
+        self.push_cell(right);
+        let goal = self.push_struct(LINEAR, 1);
+        self.push_cell(comparison);
+        Ok(goal)
+    }
+}
+
+fn integer(value: i128) -> Result<isize, Error> {
+    isize::try_from(value).map_err(|_| Error::IntegerOverflow)
+}
@@ -0,0 +1,701 @@
+use crate::builtin::{self, AND, CUT, IF, NOT, OR};
+use crate::operation::Register;
+use crate::program::{Program, ProgramBuilder};
+use crate::storage::ConstDomain;
+use crate::Error;
+use bitvec::{bitbox, bitvec, boxed::BitBox};
+
+/// Node of term graph, created by statement and query builders
+///
+/// Structure and list subterms are indexes of other nodes in the graph
+#[derive(Clone)]
+pub(crate) enum Term<C = usize> {
+    Var,
+    Const(usize),
+    Int(isize),
+    Value(C),
+    Struct(usize, Vec<usize>),
+    // Head and tail
+    List([usize; 2]),
+}
+
+/// Compiles term graph into program
+///
+/// Arguments of called procedures are passed in first X registers
+/// (argument registers). Variables occurring in more than one goal
+/// (where clause head is part of first goal) are permanent, and are
+/// kept in environment as Y registers. All other variables, and
+/// nested structures and lists are kept in temporary X registers, allocated
+/// above all argument registers, so they are never overwritten when
+/// arguments are passed.
+struct Compiler<'a, C> {
+    terms: &'a [Term<C>],
+    program: ProgramBuilder<C>,
+    // Register assigned to every variable - permanent ones are
+    // assigned upfront, temporary on first occurrence
+    registers: Vec<Option<Register>>,
+    // Variables which already occurred in compiled code
+    seen: BitBox,
+    // First temporary register
+    temps: usize,
+    // Next free temporary register
+    next_temp: usize,
+    // Number of permanent registers
+    permanent: usize,
+    // Register with choice points level which cut discards choice
+    // points above, `None` if cut is a neck cut
+    cut: Option<Register>,
+    // Next free permanent register for if-then-else choice points
+    // level
+    next_mark: usize,
+}
+
+impl<'a, C: ConstDomain> Compiler<'a, C> {
+    // Creates compiler for clause or query with given goals, and
+    // permanent variables in given order
+    //
+    // Permanent registers are assigned to saved cut level first, if
+    // any cut needs it, then to permanent variables, and then to
+    // if-then-else levels.
+    fn new(terms: &'a [Term<C>], goals: &[usize], permanent: &[usize], temps: usize) -> Self {
+        let level = goals
+            .iter()
+            .skip_while(|goal| matches!(terms[**goal], Term::Const(CUT)))
+            .any(|goal| cuts(terms, *goal));
+        let level = usize::from(level);
+
+        let mut registers = vec![None; terms.len()];
+        for (y, var) in permanent.iter().enumerate() {
+            registers[*var] = Some(Register::Y(level + y));
+        }
+        let marks: usize = goals.iter().map(|goal| marks(terms, *goal)).sum();
+
+        Self {
+            terms,
+            program: ProgramBuilder::default(),
+            registers,
+            seen: bitbox![0; terms.len()],
+            temps,
+            next_temp: temps,
+            permanent: level + permanent.len() + marks,
+            cut: (level > 0).then_some(Register::Y(0)),
+            next_mark: level + permanent.len(),
+        }
+    }
+
+    fn temp(&mut self) -> usize {
+        self.next_temp += 1;
+        self.next_temp - 1
+    }
+
+    fn mark(&mut self) -> Register {
+        self.next_mark += 1;
+        Register::Y(self.next_mark - 1)
+    }
+
+    // Temporary registers are not valid after call, so they can
+    // be reused
+    fn reset_temps(&mut self) {
+        self.next_temp = self.temps;
+    }
+
+    // Returns register assigned to variable, and if it is its
+    // first occurrence
+    fn variable(&mut self, var: usize) -> (Register, bool) {
+        let first = !self.seen.get(var).unwrap_or(false);
+        self.seen.set(var, true);
+
+        let reg = match self.registers[var] {
+            Some(reg) => reg,
+            None => {
+                let reg = Register::X(self.temp());
+                self.registers[var] = Some(reg);
+                reg
+            }
+        };
+
+        (reg, first)
+    }
+
+    // Unifies clause head with arguments in argument registers
+    fn head(&mut self, head: usize) {
+        let terms = self.terms;
+        let mut pending = vec![];
+
+        if let Term::Struct(_, args) = &terms[head] {
+            for (areg, arg) in args.iter().enumerate() {
+                match &terms[*arg] {
+                    Term::Var => {
+                        match self.variable(*arg) {
+                            (reg, true) => self.program.get_variable(reg, areg),
+                            (reg, false) => self.program.get_value(reg, areg),
+                        };
+                    }
+                    Term::Const(ident) => {
+                        self.program.get_constant(*ident, areg);
+                    }
+                    Term::Int(value) => {
+                        self.program.get_integer(*value, areg);
+                    }
+                    Term::Value(value) => {
+                        self.program.get_domain(value.clone(), areg);
+                    }
+                    Term::Struct(_, _) | Term::List(_) => pending.push((areg, *arg)),
+                }
+            }
+        }
+
+        while let Some((xreg, node)) = pending.pop() {
+            let subterms = match &terms[node] {
+                Term::Struct(ident, subterms) => {
+                    self.program.get_structure(*ident, subterms.len(), xreg);
+                    &subterms[..]
+                }
+                Term::List(subterms) => {
+                    self.program.get_list(xreg);
+                    &subterms[..]
+                }
+                _ => continue,
+            };
+
+            for subterm in subterms {
+                match &terms[*subterm] {
+                    Term::Var => {
+                        match self.variable(*subterm) {
+                            (reg, true) => self.program.unify_variable(reg),
+                            (reg, false) => self.program.unify_value(reg),
+                        };
+                    }
+                    Term::Const(ident) => {
+                        self.program.unify_constant(*ident);
+                    }
+                    Term::Int(value) => {
+                        self.program.unify_integer(*value);
+                    }
+                    Term::Value(value) => {
+                        self.program.unify_domain(value.clone());
+                    }
+                    Term::Struct(_, _) | Term::List(_) => {
+                        let xreg = self.temp();
+                        self.program.unify_variable(Register::X(xreg));
+                        pending.push((xreg, *subterm));
+                    }
+                }
+            }
+        }
+    }
+
+    // Builds structure or list in given X register, building its
+    // nested structures and lists first
+    //
+    // Nested terms are built without recursion, so they may be
+    // arbitrarily deep
+    fn structure(&mut self, node: usize, xreg: usize) {
+        let terms = self.terms;
+        let subterms_of = |node: usize| match &terms[node] {
+            Term::Struct(_, subterms) => &subterms[..],
+            Term::List(subterms) => &subterms[..],
+            _ => &[][..],
+        };
+
+        // Structures being built, with registers of their subterms
+        // built so far (`None` for subterms which are not nested)
+        let mut frames = vec![(node, xreg, vec![])];
+
+        while let Some((node, xreg, nested)) = frames.pop() {
+            let subterms = subterms_of(node);
+
+            if let Some(subterm) = subterms.get(nested.len()) {
+                let reg = match terms[*subterm] {
+                    Term::Var | Term::Const(_) | Term::Int(_) | Term::Value(_) => None,
+                    Term::Struct(_, _) | Term::List(_) => Some(self.temp()),
+                };
+
+                let mut nested = nested;
+                nested.push(reg);
+                frames.push((node, xreg, nested));
+                if let Some(reg) = reg {
+                    frames.push((*subterm, reg, vec![]));
+                }
+                continue;
+            }
+
+            match &terms[node] {
+                Term::Struct(ident, _) => self.program.put_structure(*ident, subterms.len(), xreg),
+                _ => self.program.put_list(xreg),
+            };
+
+            for (subterm, nested) in subterms.iter().zip(nested) {
+                match (&terms[*subterm], nested) {
+                    (_, Some(xreg)) => {
+                        self.program.set_value(Register::X(xreg));
+                    }
+                    (Term::Const(ident), None) => {
+                        self.program.set_constant(*ident);
+                    }
+                    (Term::Int(value), None) => {
+                        self.program.set_integer(*value);
+                    }
+                    (Term::Value(value), None) => {
+                        self.program.set_domain(value.clone());
+                    }
+                    _ => {
+                        match self.variable(*subterm) {
+                            (reg, true) => self.program.set_variable(reg),
+                            (reg, false) => self.program.set_value(reg),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    // Puts goal arguments into argument registers, and returns goal
+    // functor to be called
+    fn goal(&mut self, goal: usize) -> (usize, usize) {
+        let terms = self.terms;
+        let (ident, args) = match &terms[goal] {
+            Term::Struct(ident, args) => (*ident, &args[..]),
+            Term::Const(ident) => (*ident, &[][..]),
+            // Variable goal is proven as `call(G)`
+            Term::Var => (builtin::CALL, std::slice::from_ref(&goal)),
+            Term::Int(_) | Term::Value(_) | Term::List(_) => {
+                unreachable!("Goals are checked to be callable when they are built")
+            }
+        };
+
+        for (areg, arg) in args.iter().enumerate() {
+            match &terms[*arg] {
+                Term::Var => {
+                    match self.variable(*arg) {
+                        (reg, true) => self.program.put_variable(reg, areg),
+                        (reg, false) => self.program.put_value(reg, areg),
+                    };
+                }
+                Term::Const(ident) => {
+                    self.program.put_constant(*ident, areg);
+                }
+                Term::Int(value) => {
+                    self.program.put_integer(*value, areg);
+                }
+                Term::Value(value) => {
+                    self.program.put_domain(value.clone(), areg);
+                }
+                Term::Struct(_, _) | Term::List(_) => self.structure(*arg, areg),
+            }
+        }
+
+        (ident, args.len())
+    }
+
+    // Compiles body goals in environment, unifying clause head first
+    // if any
+    //
+    // Permanent variables still used after every goal are given by
+    // `live`, and they have to be the first ones - without it
+    // environment is never trimmed. Last goal of clause
+    // is executed after discarding its environment, so recursion runs
+    // in constant environment stack.
+    fn body(&mut self, head: Option<usize>, body: &[usize], live: Option<&[usize]>) {
+        // Environment has to be allocated before head unification,
+        // as some permanent variables may occur in head
+        self.program.allocate(self.permanent);
+        if let Some(level) = self.cut {
+            self.program.get_level(level);
+        }
+        if let Some(head) = head {
+            self.head(head);
+        }
+
+        // Variables first occurring in alternative branches are created
+        // upfront, so they are the same in every branch
+        if body.iter().any(|goal| branching(self.terms, *goal)) {
+            for var in 0..self.terms.len() {
+                if let (Some(reg @ Register::Y(_)), false) = (self.registers[var], self.seen[var]) {
+                    self.seen.set(var, true);
+                    self.program.put_variable(reg, 0);
+                }
+            }
+        }
+
+        for (idx, goal) in body.iter().enumerate() {
+            if control(self.terms, *goal) {
+                self.control(*goal);
+                continue;
+            }
+
+            let (ident, arity) = self.goal(*goal);
+
+            // Query environment is never discarded, as query variables
+            // are read from it after query is solved
+            if head.is_some() && idx == body.len() - 1 {
+                self.program.deallocate();
+                self.program.execute(ident, arity);
+                return;
+            }
+
+            // Saved cut level is never trimmed
+            let live = live.map_or(self.permanent, |live| {
+                live[idx] + usize::from(self.cut.is_some())
+            });
+            self.program.call(ident, arity, live);
+            self.reset_temps();
+        }
+        self.program.deallocate();
+        self.program.proceed();
+    }
+
+    // Compiles goal which may be a control construct
+    //
+    // Inside of control constructs environment is never trimmed, as
+    // all variables are permanent anyway
+    fn control(&mut self, goal: usize) {
+        let terms = self.terms;
+        match &terms[goal] {
+            Term::Const(CUT) => {
+                match self.cut {
+                    Some(level) => self.program.cut(level),
+                    None => self.program.neck_cut(),
+                };
+            }
+            Term::Struct(AND, args) if args.len() == 2 => {
+                self.control(args[0]);
+                self.control(args[1]);
+            }
+            Term::Struct(OR, args) if args.len() == 2 => match &terms[args[0]] {
+                Term::Struct(IF, cond) if cond.len() == 2 => {
+                    self.if_then_else(cond[0], cond[1], Some(args[1]))
+                }
+                _ => self.disjunction(args[0], args[1]),
+            },
+            Term::Struct(IF, args) if args.len() == 2 => self.if_then_else(args[0], args[1], None),
+            Term::Struct(NOT, args) if args.len() == 1 => self.negation(args[0]),
+            _ => {
+                let (ident, arity) = self.goal(goal);
+                self.program.call(ident, arity, self.permanent);
+                self.reset_temps();
+            }
+        }
+    }
+
+    // Proves left goal, and right one on backtracking
+    fn disjunction(&mut self, left: usize, right: usize) {
+        let branch = self.program.len();
+        self.program.try_else(0);
+        self.control(left);
+
+        let end = self.program.len();
+        self.program.jump(0).resolve(branch).trust_me();
+        self.control(right);
+        self.program.resolve(end);
+    }
+
+    // Proves then goal for first solution of condition, or else goal if
+    // there is no such solution. Cut in condition is local to it.
+    fn if_then_else(&mut self, cond: usize, then: usize, otherwise: Option<usize>) {
+        let level = self.mark();
+        self.program.mark(level);
+
+        let cut = self.cut;
+        let otherwise = match otherwise {
+            Some(otherwise) => {
+                let branch = self.program.len();
+                self.program.try_else(0);
+                // Cut in condition discards only its own choice points,
+                // keeping else branch
+                if cuts(self.terms, cond) {
+                    let local = self.mark();
+                    self.program.mark(local);
+                    self.cut = Some(local);
+                } else {
+                    self.cut = Some(level);
+                }
+                Some((branch, otherwise))
+            }
+            None => {
+                self.cut = Some(level);
+                None
+            }
+        };
+
+        self.control(cond);
+        self.cut = cut;
+        self.program.cut(level);
+        self.control(then);
+
+        if let Some((branch, otherwise)) = otherwise {
+            let end = self.program.len();
+            self.program.jump(0).resolve(branch).trust_me();
+            self.control(otherwise);
+            self.program.resolve(end);
+        }
+    }
+
+    // Proves goal, failing on its first solution and succeeding if
+    // there is none. Cut in goal is local to it.
+    fn negation(&mut self, goal: usize) {
+        let level = self.mark();
+        self.program.mark(level);
+
+        let branch = self.program.len();
+        self.program.try_else(0);
+        let cut = self.cut;
+        if cuts(self.terms, goal) {
+            let local = self.mark();
+            self.program.mark(local);
+            self.cut = Some(local);
+        }
+
+        self.control(goal);
+        self.cut = cut;
+        self.program
+            .cut(level)
+            .call(builtin::FAIL, 0, self.permanent)
+            .resolve(branch)
+            .trust_me();
+    }
+}
+
+// Checks if goal is a control construct, which is compiled instead of
+// being called
+fn control<C>(terms: &[Term<C>], goal: usize) -> bool {
+    match &terms[goal] {
+        Term::Const(CUT) => true,
+        Term::Struct(AND | OR | IF, args) => args.len() == 2,
+        Term::Struct(NOT, args) => args.len() == 1,
+        _ => false,
+    }
+}
+
+// Checks if goal has alternative branches
+fn branching<C>(terms: &[Term<C>], goal: usize) -> bool {
+    match &terms[goal] {
+        Term::Struct(OR | IF, args) if args.len() == 2 => true,
+        Term::Struct(NOT, args) if args.len() == 1 => true,
+        Term::Struct(AND, args) if args.len() == 2 => {
+            args.iter().any(|arg| branching(terms, *arg))
+        }
+        _ => false,
+    }
+}
+
+// Checks if goal cuts choice points of its clause - cut in if-then-else
+// condition or in negated goal is local to it
+fn cuts<C>(terms: &[Term<C>], goal: usize) -> bool {
+    match &terms[goal] {
+        Term::Const(CUT) => true,
+        Term::Struct(AND | OR, args) if args.len() == 2 => args.iter().any(|arg| cuts(terms, *arg)),
+        Term::Struct(IF, args) if args.len() == 2 => cuts(terms, args[1]),
+        _ => false,
+    }
+}
+
+// Number of permanent registers needed for if-then-else choice points
+// levels in goal
+fn marks<C>(terms: &[Term<C>], goal: usize) -> usize {
+    let sum = |args: &[usize]| args.iter().map(|arg| marks(terms, *arg)).sum::<usize>();
+    match &terms[goal] {
+        Term::Struct(AND, args) if args.len() == 2 => sum(args),
+        Term::Struct(OR, args) if args.len() == 2 => match &terms[args[0]] {
+            Term::Struct(IF, cond) if cond.len() == 2 => {
+                1 + usize::from(cuts(terms, cond[0])) + sum(cond) + marks(terms, args[1])
+            }
+            _ => sum(args),
+        },
+        Term::Struct(IF, args) if args.len() == 2 => 1 + sum(args),
+        Term::Struct(NOT, args) if args.len() == 1 => {
+            1 + usize::from(cuts(terms, args[0])) + sum(args)
+        }
+        _ => 0,
+    }
+}
+
+// Flattens conjunctions of body goals
+fn goals<C>(terms: &[Term<C>], body: &[usize]) -> Vec<usize> {
+    let mut goals = vec![];
+    let mut pending: Vec<_> = body.iter().rev().copied().collect();
+
+    while let Some(goal) = pending.pop() {
+        match &terms[goal] {
+            Term::Struct(AND, args) if args.len() == 2 => pending.extend(args.iter().rev()),
+            _ => goals.push(goal),
+        }
+    }
+
+    goals
+}
+
+// Goals called by given goal, through control constructs
+fn calls<C>(terms: &[Term<C>], goal: usize, calls: &mut Vec<usize>) {
+    match &terms[goal] {
+        Term::Const(CUT) => (),
+        Term::Struct(AND | OR | IF, args) if args.len() == 2 => {
+            for arg in args {
+                self::calls(terms, *arg, calls);
+            }
+        }
+        Term::Struct(NOT, args) if args.len() == 1 => self::calls(terms, args[0], calls),
+        _ => calls.push(goal),
+    }
+}
+
+/// Checks that all goals called by given body goals, through control
+/// constructs, are structures, constants or variables
+///
+/// Fails with `Error::NotCallable` otherwise
+pub(crate) fn callable<C>(terms: &[Term<C>], body: &[usize]) -> Result<(), Error> {
+    let mut called = vec![];
+    for goal in body {
+        calls(terms, *goal, &mut called);
+    }
+
+    let callable = called
+        .into_iter()
+        .all(|goal| matches!(terms[goal], Term::Struct(_, _) | Term::Const(_) | Term::Var));
+
+    if callable {
+        Ok(())
+    } else {
+        Err(Error::NotCallable)
+    }
+}
+
+// Collects all variables occurring in term, in order of occurrence
+fn variables<C>(terms: &[Term<C>], term: usize, vars: &mut Vec<usize>) {
+    let mut pending = vec![term];
+
+    while let Some(term) = pending.pop() {
+        match &terms[term] {
+            Term::Var => vars.push(term),
+            Term::Const(_) | Term::Int(_) | Term::Value(_) => (),
+            Term::Struct(_, subterms) => pending.extend(subterms.iter().rev()),
+            Term::List(subterms) => pending.extend(subterms.iter().rev()),
+        }
+    }
+}
+
+// Number of arguments registers needed to call all given goals
+fn arguments<C>(terms: &[Term<C>], goals: impl Iterator<Item = usize>) -> usize {
+    goals
+        .map(|goal| match &terms[goal] {
+            Term::Struct(_, args) => args.len(),
+            Term::Var => 1,
+            Term::Const(_) | Term::Int(_) | Term::Value(_) | Term::List(_) => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Compiles clause with given head and body goals
+///
+/// Clause without body is a fact, and doesn't need any environment.
+/// Permanent variables are ordered by their last occurrence, so ones
+/// not used anymore are trimmed from environment by calls. If body
+/// branches, all variables are permanent and environment is never
+/// trimmed.
+pub(crate) fn clause<C: ConstDomain>(
+    terms: &[Term<C>],
+    head: usize,
+    body: &[usize],
+) -> Program<'static, C> {
+    let body = goals(terms, body);
+    let mut called = vec![];
+    for goal in &body {
+        calls(terms, *goal, &mut called);
+    }
+    let temps = arguments(terms, std::iter::once(head).chain(called));
+
+    if body.is_empty() {
+        let mut compiler = Compiler::new(terms, &body, &[], temps);
+        compiler.head(head);
+        compiler.program.proceed();
+        return compiler.program.build();
+    }
+
+    // Head is a part of first chunk, and every call ends its chunk
+    let mut chunks = vec![vec![]];
+    variables(terms, head, &mut chunks[0]);
+    let mut goal_chunks = vec![];
+    for goal in &body {
+        goal_chunks.push(chunks.len() - 1);
+        variables(terms, *goal, chunks.last_mut().unwrap());
+        if !matches!(terms[*goal], Term::Const(CUT)) {
+            chunks.push(vec![]);
+        }
+    }
+
+    if body.iter().any(|goal| branching(terms, *goal)) {
+        let vars = unique(chunks.into_iter().flatten());
+        let mut compiler = Compiler::new(terms, &body, &vars, temps);
+        compiler.body(Some(head), &body, None);
+        return compiler.program.build();
+    }
+
+    // Permanent variables, with last chunks they occur in
+    let mut permanent = vec![];
+    for (idx, chunk) in chunks.iter().enumerate() {
+        for var in chunk {
+            let later = chunks[idx + 1..].iter().any(|c| c.contains(var));
+            if later && !permanent.iter().any(|(v, _)| v == var) {
+                let last = chunks.iter().rposition(|c| c.contains(var)).unwrap_or(idx);
+                permanent.push((*var, last));
+            }
+        }
+    }
+
+    // Variables used longer come first, so environment can be trimmed
+    // to variables still used after every goal
+    permanent.sort_by_key(|(_, last)| std::cmp::Reverse(*last));
+    let live: Vec<_> = goal_chunks
+        .iter()
+        .map(|chunk| permanent.iter().filter(|(_, last)| last > chunk).count())
+        .collect();
+    let vars: Vec<_> = permanent.into_iter().map(|(var, _)| var).collect();
+
+    let mut compiler = Compiler::new(terms, &body, &vars, temps);
+    compiler.body(Some(head), &body, Some(&live));
+    compiler.program.build()
+}
+
+// Removes repeated variables, keeping their first occurrences
+fn unique(vars: impl IntoIterator<Item = usize>) -> Vec<usize> {
+    let mut unique = vec![];
+    for var in vars {
+        if !unique.contains(&var) {
+            unique.push(var);
+        }
+    }
+    unique
+}
+
+/// Compiles query with given goals
+///
+/// All query variables are permanent, so they are available in query
+/// environment after solving it. Returns compiled program, and
+/// permanent variable assigned to every query variable
+pub(crate) fn query<C: ConstDomain>(
+    terms: &[Term<C>],
+    body: &[usize],
+) -> (Program<'static, C>, Vec<Option<usize>>) {
+    let body = goals(terms, body);
+    let mut vars = vec![];
+    let mut called = vec![];
+    for goal in &body {
+        variables(terms, *goal, &mut vars);
+        calls(terms, *goal, &mut called);
+    }
+
+    let temps = arguments(terms, called.into_iter());
+    let mut compiler = Compiler::new(terms, &body, &unique(vars), temps);
+    let vars = compiler
+        .registers
+        .iter()
+        .map(|reg| match reg {
+            Some(Register::Y(y)) => Some(*y),
+            _ => None,
+        })
+        .collect();
+    compiler.body(None, &body, None);
+
+    (compiler.program.build(), vars)
+}
@@ -0,0 +1,96 @@
+//! Control constructs called as goal terms
+//!
+//! Control constructs in clause bodies are compiled in place, but goal
+//! terms proven by `call/1` are only known when they are called. They
+//! are proven by `'$call'/2` library clauses instead, which second
+//! argument is the cut level recorded when `call/1` was entered. Cut
+//! anywhere in called goal discards choice points above this level, so
+//! it is local to the called goal as a whole.
+
+use crate::builtin::{AND, CALL, FAIL, IF, META_CALL, NOT, OR};
+use crate::statement::{RuleBuilder, Statement, StatementBuilder};
+use crate::storage::ConstDomain;
+
+/// Library clauses of control constructs:
+///
+/// ```text
+/// '$call'(','(A, B), L) :- '$call'(A, L), '$call'(B, L).
+/// '$call'(;(->(C, T), E), L) :- !, (call(C) -> '$call'(T, L) ; '$call'(E, L)).
+/// '$call'(;(A, _), L) :- '$call'(A, L).
+/// '$call'(;(_, B), L) :- '$call'(B, L).
+/// '$call'(->(C, T), L) :- call(C), !, '$call'(T, L).
+/// '$call'(\+(G), _) :- call(G), !, fail.
+/// '$call'(\+(_), _).
+/// ```
+///
+/// Cut itself is performed by machine when `'$call'/2` resolves it.
+/// Conditions are proven by `call/1`, so cut in them is local to them.
+pub(crate) fn library<C: ConstDomain>() -> Vec<Statement<'static, C>> {
+    let mut statements = vec![];
+
+    let mut builder = RuleBuilder::default();
+    let a = builder.variable();
+    let b = builder.variable();
+    let l = builder.variable();
+    let and = builder.structure(AND, vec![a, b]);
+    let head = builder.structure(META_CALL, vec![and, l]);
+    let call_a = builder.structure(META_CALL, vec![a, l]);
+    let call_b = builder.structure(META_CALL, vec![b, l]);
+    statements.push(builder.rule(head, vec![call_a, call_b]));
+
+    let mut builder = RuleBuilder::default();
+    let c = builder.variable();
+    let t = builder.variable();
+    let e = builder.variable();
+    let l = builder.variable();
+    let cond = builder.structure(IF, vec![c, t]);
+    let or = builder.structure(OR, vec![cond, e]);
+    let head = builder.structure(META_CALL, vec![or, l]);
+    let cut = builder.cut();
+    let call_c = builder.structure(CALL, vec![c]);
+    let call_t = builder.structure(META_CALL, vec![t, l]);
+    let call_e = builder.structure(META_CALL, vec![e, l]);
+    let body = builder.if_then_else(call_c, call_t, call_e);
+    statements.push(builder.rule(head, vec![cut, body]));
+
+    for left in [true, false] {
+        let mut builder = RuleBuilder::default();
+        let a = builder.variable();
+        let b = builder.variable();
+        let l = builder.variable();
+        let or = builder.structure(OR, vec![a, b]);
+        let head = builder.structure(META_CALL, vec![or, l]);
+        let call = builder.structure(META_CALL, vec![if left { a } else { b }, l]);
+        statements.push(builder.rule(head, vec![call]));
+    }
+
+    let mut builder = RuleBuilder::default();
+    let c = builder.variable();
+    let t = builder.variable();
+    let l = builder.variable();
+    let cond = builder.structure(IF, vec![c, t]);
+    let head = builder.structure(META_CALL, vec![cond, l]);
+    let call_c = builder.structure(CALL, vec![c]);
+    let cut = builder.cut();
+    let call_t = builder.structure(META_CALL, vec![t, l]);
+    statements.push(builder.rule(head, vec![call_c, cut, call_t]));
+
+    let mut builder = RuleBuilder::default();
+    let g = builder.variable();
+    let l = builder.variable();
+    let not = builder.structure(NOT, vec![g]);
+    let head = builder.structure(META_CALL, vec![not, l]);
+    let call = builder.structure(CALL, vec![g]);
+    let cut = builder.cut();
+    let fail = builder.constant(FAIL);
+    statements.push(builder.rule(head, vec![call, cut, fail]));
+
+    let mut builder = StatementBuilder::default();
+    let g = builder.variable();
+    let l = builder.variable();
+    let not = builder.structure(NOT, vec![g]);
+    let head = builder.structure(META_CALL, vec![not, l]);
+    statements.push(builder.build(head));
+
+    statements
+}
@@ -0,0 +1,188 @@
+//! Delayed goals, suspended until their variables are bound
+//!
+//! `freeze/2` and `when/2` goals which condition doesn't hold yet are
+//! suspended on variables which binding may make it hold. Suspended
+//! goal is kept in list of `builtin::SUSPEND` attribute of every such
+//! variable, together with flag shared by all of them. Binding any of
+//! them binds the flag, and calls suspended goal again - which either
+//! proves the delayed goal, or suspends it once more, if variable was
+//! only bound to another one, or if condition still doesn't hold.
+
+use crate::builtin::{AND, CALL, DECIDED, FREEZE, GROUND, NIL, NONVAR, OR, SUSPEND, TRUE, WHEN};
+use crate::statement::{RuleBuilder, Statement};
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+
+impl<C: ConstDomain> Storage<C> {
+    /// Unifies result with goal if condition holds, or suspends
+    /// `suspended` goal on variables of condition and unifies result
+    /// with `true`, for `'$suspend'/4`
+    ///
+    /// Fails with `Error::InvalidCondition` if condition is not one
+    /// of `when/2` conditions.
+    pub(crate) fn suspend(
+        &mut self,
+        cond: Cell,
+        suspended: Cell,
+        goal: Cell,
+        result: Cell,
+    ) -> Result<(), Error> {
+        let vars = match self.condition(cond)? {
+            None => return self.unify(result, goal),
+            Some(vars) => vars,
+        };
+
+        let flag = self.push_var();
+        let suspended = self.deref_cell(suspended)?;
+        let entry = self.push_struct(SUSPEND, 2);
+        self.push_cell(flag);
+        self.push_cell(suspended);
+
+        for var in vars {
+            let entries = self
+                .attribute(var, SUSPEND)
+                .unwrap_or(Cell::Con(NIL));
+            let list = Cell::List(self.len());
+            self.push_cell(entry);
+            self.push_cell(entries);
+            self.put_attribute(var, SUSPEND, Some(list));
+        }
+
+        self.unify(result, Cell::Con(TRUE))
+    }
+
+    /// Returns goals suspended on variable on given address which are
+    /// not woken yet, in order of suspending them, and marks them as
+    /// woken
+    pub(crate) fn wake_suspended(&mut self, var: usize) -> Result<Vec<Cell>, Error> {
+        let mut goals = vec![];
+        for (flag, goal) in self.suspended(var)? {
+            self.unify(flag, Cell::Con(TRUE))?;
+            goals.push(goal);
+        }
+
+        goals.reverse();
+        Ok(goals)
+    }
+
+    /// Returns goals still suspended after query is solved
+    pub(crate) fn suspended_goals(&self) -> Result<Vec<Cell>, Error> {
+        let mut flags = vec![];
+        let mut goals = vec![];
+        for (var, _) in self.with_attribute(SUSPEND) {
+            let mut suspended = self.suspended(var)?;
+            suspended.reverse();
+            for (flag, goal) in suspended {
+                if !flags.contains(&flag) {
+                    flags.push(flag);
+                    goals.push(goal);
+                }
+            }
+        }
+
+        Ok(goals)
+    }
+
+    // Flags and goals suspended on variable on given address which
+    // are not woken yet, latest first
+    fn suspended(&self, var: usize) -> Result<Vec<(Cell, Cell)>, Error> {
+        let mut suspended = vec![];
+        let mut entries = self.attribute(var, SUSPEND);
+        while let Some(Cell::List(addr)) = entries {
+            if let Cell::Struct(entry) = self.cell(addr)? {
+                let flag = self.deref_cell(self.cell(entry + 1)?)?;
+                if let Cell::Ref(_) = flag {
+                    suspended.push((flag, self.cell(entry + 2)?));
+                }
+            }
+            entries = Some(self.cell(addr + 1)?);
+        }
+        Ok(suspended)
+    }
+
+    // Checks condition of `when/2`
+    //
+    // Returns `None` if condition holds, or variables which binding
+    // may make it hold otherwise
+    fn condition(&mut self, cond: Cell) -> Result<Option<Vec<usize>>, Error> {
+        let addr = match self.deref_cell(cond)? {
+            Cell::Struct(addr) => addr,
+            Cell::Ref(_) => return Err(Error::Instantiation),
+            _ => return Err(Error::InvalidCondition),
+        };
+
+        let (ident, arity) = self.funct(addr)?;
+        let args = (1..=arity)
+            .map(|idx| self.cell(addr + idx))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (ident, args.as_slice()) {
+            (NONVAR, [term]) => match self.deref_cell(*term)? {
+                Cell::Ref(var) => Ok(Some(vec![var])),
+                _ => Ok(None),
+            },
+            // Condition is checked again once any variable is bound,
+            // so it is enough to wait for the first one
+            (GROUND, [term]) => {
+                let vars = self.variables(*term)?;
+                Ok(vars.first().map(|var| vec![*var]))
+            }
+            (DECIDED, [left, right]) => match self.unifier(*left, *right)? {
+                Some(vars) if !vars.is_empty() => Ok(Some(vars)),
+                _ => Ok(None),
+            },
+            (AND, [left, right]) => match self.condition(*left)? {
+                None => self.condition(*right),
+                vars => Ok(vars),
+            },
+            (OR, [left, right]) => {
+                let (left, right) = match (self.condition(*left)?, self.condition(*right)?) {
+                    (Some(left), Some(right)) => (left, right),
+                    _ => return Ok(None),
+                };
+
+                let mut vars = left;
+                for var in right {
+                    if !vars.contains(&var) {
+                        vars.push(var);
+                    }
+                }
+                Ok(Some(vars))
+            }
+            _ => Err(Error::InvalidCondition),
+        }
+    }
+}
+
+/// Library clauses of delayed goals:
+///
+/// ```text
+/// freeze(X, G) :- '$suspend'(nonvar(X), freeze(X, G), G, R), call(R).
+/// when(C, G) :- '$suspend'(C, when(C, G), G, R), call(R).
+/// ```
+pub(crate) fn library<C: ConstDomain>() -> Vec<Statement<'static, C>> {
+    let mut statements = vec![];
+
+    let mut builder = RuleBuilder::default();
+    let x = builder.variable();
+    let g = builder.variable();
+    let r = builder.variable();
+    let head = builder.structure(FREEZE, vec![x, g]);
+    let cond = builder.structure(NONVAR, vec![x]);
+    let suspended = builder.structure(FREEZE, vec![x, g]);
+    let suspend = builder.structure(SUSPEND, vec![cond, suspended, g, r]);
+    let call = builder.structure(CALL, vec![r]);
+    statements.push(builder.rule(head, vec![suspend, call]));
+
+    let mut builder = RuleBuilder::default();
+    let c = builder.variable();
+    let g = builder.variable();
+    let r = builder.variable();
+    let head = builder.structure(WHEN, vec![c, g]);
+    let suspended = builder.structure(WHEN, vec![c, g]);
+    let suspend = builder.structure(SUSPEND, vec![c, suspended, g, r]);
+    let call = builder.structure(CALL, vec![r]);
+    statements.push(builder.rule(head, vec![suspend, call]));
+
+    statements
+}
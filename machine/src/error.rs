@@ -0,0 +1,110 @@
+use crate::operation::Register;
+
+/// Error occurred while running query
+///
+/// Failure (`UnificationFailure`) is part of normal execution - it
+/// makes machine backtrack, and query without any solution just doesn't
+/// succeed. All other errors means that called predicate is unknown,
+/// that machine or program is malformed, or that built-in predicate was
+/// called with invalid arguments, and they abort the query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Terms could not be unified
+    UnificationFailure,
+    /// Called procedure (ident and arity) has no clauses
+    UnknownPredicate(usize, usize),
+    /// No valid operation on given program address
+    MalformedBytecode(usize),
+    /// Register out of allocated ones, or permanent register used
+    /// out of any environment
+    InvalidRegister(Register),
+    /// Heap address out of the heap, or not pointing to expected cell
+    InvalidAddress(usize),
+    /// Variable on given address would be bound to term containing
+    /// it, with occurs check set to `OccursCheck::Error`
+    OccursCheck(usize),
+    /// Structure functor found where term was expected
+    MalformedTerm,
+    /// Query part doesn't belong to the query
+    InvalidQueryRef(usize),
+    /// Query has no solution, so there is no term to build
+    NoSolution,
+    /// Arithmetic expression contains unbound variable, or unbound
+    /// variable is called as goal
+    Instantiation,
+    /// Term (ident and arity) is not an arithmetic function
+    NotEvaluable(usize, usize),
+    /// Constant domain value found in arithmetic expression
+    NotEvaluableValue,
+    /// Term is not a finite domain - integer, range or union of them
+    InvalidDomain,
+    /// Term is not a linear constraint over rationals - equality or
+    /// inequality of linear expressions, or conjunction of them
+    InvalidConstraint,
+    /// Term is not a boolean expression - truth value, variable, or
+    /// boolean operation on them
+    InvalidBoolean,
+    /// Term is not a condition of `when/2` - `nonvar/1`, `ground/1`,
+    /// `?=/2`, or conjunction or disjunction of them
+    InvalidCondition,
+    /// Goal given as term is not a constant or structure
+    NotCallable,
+    /// Attribute module is not a constant
+    InvalidModule,
+    /// Attribute can't be set, as term is not an unbound variable
+    NotVariable,
+    /// Integer division by zero
+    ZeroDivisor,
+    /// Arithmetic result doesn't fit in integer
+    IntegerOverflow,
+    /// Predicate (ident and arity) depends negatively on itself, so
+    /// knowledge is not stratified
+    NotStratified(usize, usize),
+}
+
+impl Error {
+    /// Returns true if error is a failure, which makes machine to
+    /// backtrack instead of aborting the query
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Self::UnificationFailure)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnificationFailure => write!(f, "Unification failure"),
+            Self::UnknownPredicate(ident, arity) => {
+                write!(f, "Unknown predicate _{}/{}", ident, arity)
+            }
+            Self::MalformedBytecode(addr) => write!(f, "Malformed bytecode at {}", addr),
+            Self::InvalidRegister(reg) => write!(f, "Invalid register {:?}", reg),
+            Self::InvalidAddress(addr) => write!(f, "Invalid heap address {}", addr),
+            Self::OccursCheck(addr) => {
+                write!(f, "Variable {} would be bound to term containing it", addr)
+            }
+            Self::MalformedTerm => write!(f, "Functor found where term was expected"),
+            Self::InvalidQueryRef(qref) => write!(f, "Invalid query reference {}", qref),
+            Self::NoSolution => write!(f, "Query has no solution"),
+            Self::Instantiation => write!(f, "Unbound variable in arithmetic expression or goal"),
+            Self::NotEvaluable(ident, arity) => {
+                write!(f, "_{}/{} is not an arithmetic function", ident, arity)
+            }
+            Self::NotEvaluableValue => write!(f, "Constant value is not an arithmetic function"),
+            Self::InvalidDomain => write!(f, "Term is not a finite domain"),
+            Self::InvalidConstraint => write!(f, "Term is not a linear constraint"),
+            Self::InvalidBoolean => write!(f, "Term is not a boolean expression"),
+            Self::InvalidCondition => write!(f, "Term is not a delay condition"),
+            Self::NotCallable => write!(f, "Term is not a callable goal"),
+            Self::InvalidModule => write!(f, "Attribute module is not a constant"),
+            Self::NotVariable => write!(f, "Term is not an unbound variable"),
+            Self::ZeroDivisor => write!(f, "Division by zero"),
+            Self::IntegerOverflow => write!(f, "Integer overflow"),
+            Self::NotStratified(ident, arity) => {
+                write!(f, "_{}/{} depends negatively on itself", ident, arity)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
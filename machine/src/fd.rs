@@ -0,0 +1,792 @@
+//! Finite domain constraints (CLP(FD))
+//!
+//! Domain variables are attributed variables, which `in` attribute is
+//! a list of their domain followed by constraints suspended on them.
+//! Both are terms on the heap, so they are dropped on backtracking like
+//! any other term. Constraints are linear relations of integer
+//! expressions and `all_different/1`, and they propagate by narrowing
+//! domain bounds of their variables. Variable which domain narrows to
+//! single value is bound to it, waking constraints suspended on it.
+//!
+//! Labeling is searched by library predicates, linked into every
+//! knowledge, so its alternatives are tried by ordinary backtracking.
+
+use crate::builtin::{ADD, MUL, SUB};
+use crate::builtin::{
+    ALL_DIFFERENT, FD_EQ, FD_GE, FD_INF, FD_LE, FD_LT, FD_NE, IN, INDOMAIN, INF, LABEL, NIL, RANGE,
+    SUP, UNION,
+};
+use crate::statement::{RuleBuilder, Statement, StatementBuilder};
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+use std::collections::BTreeMap;
+
+/// Set of integers, as sorted disjoint intervals - `isize::MIN` lower
+/// bound and `isize::MAX` upper bound are infinite
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Domain(Vec<(isize, isize)>);
+
+impl Domain {
+    fn full() -> Self {
+        Self(vec![(isize::MIN, isize::MAX)])
+    }
+
+    fn range(lo: isize, hi: isize) -> Self {
+        if lo <= hi {
+            Self(vec![(lo, hi)])
+        } else {
+            Self(vec![])
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        *self == Self::full()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Lowest and highest value, `None` for empty domain
+    fn bounds(&self) -> Option<(isize, isize)> {
+        Some((self.0.first()?.0, self.0.last()?.1))
+    }
+
+    /// The only value of domain, if it has exactly one
+    fn single(&self) -> Option<isize> {
+        match self.0[..] {
+            [(lo, hi)] if lo == hi => Some(lo),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, value: isize) -> bool {
+        self.0.iter().any(|(lo, hi)| *lo <= value && value <= *hi)
+    }
+
+    /// Number of values, `None` if domain is infinite
+    fn size(&self) -> Option<usize> {
+        self.0.iter().try_fold(0usize, |size, (lo, hi)| {
+            if *lo == isize::MIN || *hi == isize::MAX {
+                return None;
+            }
+            size.checked_add(hi.abs_diff(*lo))?.checked_add(1)
+        })
+    }
+
+    fn intersect(&self, other: &Self) -> Self {
+        let mut intervals = vec![];
+        let (mut i, mut j) = (0, 0);
+        while let (Some((l1, h1)), Some((l2, h2))) = (self.0.get(i), other.0.get(j)) {
+            let (lo, hi) = (*l1.max(l2), *h1.min(h2));
+            if lo <= hi {
+                intervals.push((lo, hi));
+            }
+            if h1 < h2 {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        Self(intervals)
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let mut all: Vec<_> = self.0.iter().chain(&other.0).copied().collect();
+        all.sort_unstable();
+
+        let mut intervals: Vec<(isize, isize)> = vec![];
+        for (lo, hi) in all {
+            match intervals.last_mut() {
+                Some(last) if lo <= last.1.saturating_add(1) => last.1 = last.1.max(hi),
+                _ => intervals.push((lo, hi)),
+            }
+        }
+        Self(intervals)
+    }
+
+    fn remove(&self, value: isize) -> Self {
+        let mut intervals = vec![];
+        for (lo, hi) in &self.0 {
+            if *lo <= value && value <= *hi {
+                if *lo < value {
+                    intervals.push((*lo, value - 1));
+                }
+                if value < *hi {
+                    intervals.push((value + 1, *hi));
+                }
+            } else {
+                intervals.push((*lo, *hi));
+            }
+        }
+        Self(intervals)
+    }
+}
+
+/// Relation of linear constraint to zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Relation {
+    Eq,
+    Ne,
+    Le,
+}
+
+/// Linear expression - variables (by their addresses) with their
+/// coefficients, and constant
+#[derive(Debug, Clone, Default)]
+struct Linear {
+    vars: BTreeMap<usize, isize>,
+    constant: isize,
+}
+
+impl Linear {
+    fn constant(value: isize) -> Self {
+        Self {
+            vars: BTreeMap::new(),
+            constant: value,
+        }
+    }
+
+    fn variable(addr: usize) -> Self {
+        Self {
+            vars: [(addr, 1)].into(),
+            constant: 0,
+        }
+    }
+
+    fn add(mut self, other: &Self) -> Result<Self, Error> {
+        for (var, coef) in &other.vars {
+            let sum = self.vars.get(var).unwrap_or(&0).checked_add(*coef);
+            match sum.ok_or(Error::IntegerOverflow)? {
+                0 => self.vars.remove(var),
+                sum => self.vars.insert(*var, sum),
+            };
+        }
+        self.constant = checked(self.constant.checked_add(other.constant))?;
+        Ok(self)
+    }
+
+    fn scale(mut self, factor: isize) -> Result<Self, Error> {
+        if factor == 0 {
+            return Ok(Self::default());
+        }
+        for coef in self.vars.values_mut() {
+            *coef = checked(coef.checked_mul(factor))?;
+        }
+        self.constant = checked(self.constant.checked_mul(factor))?;
+        Ok(self)
+    }
+
+    fn sub(self, other: &Self) -> Result<Self, Error> {
+        self.add(&other.clone().scale(-1)?)
+    }
+
+    /// Product of expressions, `None` if both have variables
+    fn mul(self, other: Self) -> Result<Option<Self>, Error> {
+        match () {
+            _ if self.vars.is_empty() => other.scale(self.constant).map(Some),
+            _ if other.vars.is_empty() => self.scale(other.constant).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn checked(value: Option<isize>) -> Result<isize, Error> {
+    value.ok_or(Error::IntegerOverflow)
+}
+
+// Integer division rounding toward negative infinity
+fn floor_div(n: i128, d: i128) -> i128 {
+    let q = n / d;
+    if n % d != 0 && (n < 0) != (d < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+// Integer division rounding toward positive infinity
+fn ceil_div(n: i128, d: i128) -> i128 {
+    -floor_div(-n, d)
+}
+
+// Bound which doesn't fit in `isize` is infinite
+fn clamp(value: i128) -> isize {
+    value.clamp(isize::MIN as i128, isize::MAX as i128) as isize
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Narrows domain of variable of given cell to given domain term
+    pub(crate) fn fd_in(&mut self, cell: Cell, domain: Cell) -> Result<(), Error> {
+        let domain = self.domain(domain)?;
+        let mut queue = vec![];
+        self.narrow(cell, &domain, &mut queue)?;
+        self.propagate(queue)
+    }
+
+    /// Posts constraint with given ident and arguments, suspending it
+    /// on all its variables, and propagates it
+    pub(crate) fn post(&mut self, ident: usize, args: &[Cell]) -> Result<(), Error> {
+        let goal = self.push_struct(ident, args.len());
+        for arg in args {
+            let arg = self.deref_cell(*arg)?;
+            self.push_cell(arg);
+        }
+
+        for var in self.variables(goal)? {
+            let (domain, constraints) = self.fd_attribute(var)?;
+            let constraints = self.cons(goal, constraints);
+            self.set_fd_attribute(var, domain, constraints);
+        }
+
+        self.propagate(vec![goal])
+    }
+
+    /// Unifies second cell with lower bound of domain of the first one
+    ///
+    /// Fails with `Error::Instantiation` if domain has no lower bound
+    pub(crate) fn fd_inf(&mut self, cell: Cell, inf: Cell) -> Result<(), Error> {
+        match self.deref_cell(cell)? {
+            Cell::Ref(var) => {
+                let (domain, _) = self.fd_attribute(var)?;
+                match self.domain(domain)?.bounds() {
+                    Some((lo, _)) if lo != isize::MIN => self.unify(inf, Cell::Int(lo)),
+                    _ => Err(Error::Instantiation),
+                }
+            }
+            cell @ Cell::Int(_) => self.unify(inf, cell),
+            _ => Err(Error::UnificationFailure),
+        }
+    }
+
+    /// Wakes constraints of domain variable on given address after it
+    /// was bound
+    ///
+    /// Variable bound to another one passes its domain and constraints
+    /// to it
+    pub(crate) fn wake_fd(&mut self, var: usize) -> Result<(), Error> {
+        let (domain, constraints) = match self.attribute(var, IN) {
+            Some(Cell::List(addr)) => (self.domain(self.cell(addr)?)?, self.cell(addr + 1)?),
+            _ => return Ok(()),
+        };
+
+        if let Cell::Ref(other) = self.deref_cell(Cell::Ref(var))? {
+            let (other_domain, mut merged) = self.fd_attribute(other)?;
+            for goal in self.goals(constraints)?.into_iter().rev() {
+                merged = self.cons(goal, merged);
+            }
+            self.set_fd_attribute(other, other_domain, merged);
+        }
+
+        let mut queue = self.goals(constraints)?;
+        self.narrow(Cell::Ref(var), &domain, &mut queue)?;
+        self.propagate(queue)
+    }
+
+    /// Pushes residual goals of constraints suspended on unbound
+    /// variables - their domains, and constraints which are not
+    /// entailed by them yet
+    pub(crate) fn fd_residual_goals(&mut self) -> Result<Vec<Cell>, Error> {
+        let mut domains = vec![];
+        let mut constraints = vec![];
+
+        for (var, attribute) in self.with_attribute(IN) {
+            let addr = match attribute {
+                Cell::List(addr) => addr,
+                _ => return Err(Error::MalformedTerm),
+            };
+            if self.deref_cell(Cell::Ref(var))? != Cell::Ref(var) {
+                continue;
+            }
+
+            let domain = self.cell(addr)?;
+            if !self.domain(domain)?.is_full() {
+                domains.push((var, domain));
+            }
+            for goal in self.goals(self.cell(addr + 1)?)? {
+                if !constraints.contains(&goal) && !self.entailed(goal)? {
+                    constraints.push(goal);
+                }
+            }
+        }
+
+        let mut goals = vec![];
+        for (var, domain) in domains {
+            goals.push(self.push_struct(IN, 2));
+            self.push_cell(Cell::Ref(var));
+            self.push_cell(domain);
+        }
+        goals.extend(constraints);
+        Ok(goals)
+    }
+
+    // Domain term and constraints list of variable on given address,
+    // plain variable has full domain and no constraints
+    fn fd_attribute(&mut self, var: usize) -> Result<(Cell, Cell), Error> {
+        match self.attribute(var, IN) {
+            Some(Cell::List(addr)) => Ok((self.cell(addr)?, self.cell(addr + 1)?)),
+            Some(_) => Err(Error::MalformedTerm),
+            None => Ok((self.push_domain(&Domain::full()), Cell::Con(NIL))),
+        }
+    }
+
+    fn set_fd_attribute(&mut self, var: usize, domain: Cell, constraints: Cell) {
+        let attribute = self.cons(domain, constraints);
+        self.put_attribute(var, IN, Some(attribute));
+    }
+
+    /// Pushes list cell with given head and tail
+    pub(crate) fn cons(&mut self, head: Cell, tail: Cell) -> Cell {
+        let list = Cell::List(self.len());
+        self.push_cell(head);
+        self.push_cell(tail);
+        list
+    }
+
+    /// Items of list of given cell, up to its first non-list tail
+    pub(crate) fn goals(&self, mut list: Cell) -> Result<Vec<Cell>, Error> {
+        let mut goals = vec![];
+        while let Cell::List(addr) = self.deref_cell(list)? {
+            goals.push(self.deref_cell(self.cell(addr)?)?);
+            list = self.cell(addr + 1)?;
+        }
+        Ok(goals)
+    }
+
+    /// Addresses of unbound variables occurring in term of given cell
+    ///
+    /// Every subterm is visited once, so it terminates for cyclic terms
+    pub(crate) fn variables(&self, cell: Cell) -> Result<Vec<usize>, Error> {
+        let mut vars = vec![];
+        let mut visited = std::collections::HashSet::new();
+        let mut pending = vec![cell];
+
+        while let Some(cell) = pending.pop() {
+            match self.deref_cell(cell)? {
+                Cell::Ref(var) if !vars.contains(&var) => vars.push(var),
+                cell @ (Cell::Struct(_) | Cell::List(_)) if !visited.insert(cell) => (),
+                Cell::Struct(addr) => {
+                    let (_, arity) = self.funct(addr)?;
+                    for i in (1..=arity).rev() {
+                        pending.push(self.cell(addr + i)?);
+                    }
+                }
+                Cell::List(addr) => {
+                    pending.push(self.cell(addr + 1)?);
+                    pending.push(self.cell(addr)?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(vars)
+    }
+
+    // Reads domain of given term - integer, range of integers, `inf`
+    // and `sup`, or union of domains
+    fn domain(&self, cell: Cell) -> Result<Domain, Error> {
+        let mut domain = Domain(vec![]);
+        let mut pending = vec![cell];
+
+        while let Some(cell) = pending.pop() {
+            let part = match self.deref_cell(cell)? {
+                Cell::Int(value) => Domain::range(value, value),
+                Cell::Struct(addr) => match self.funct(addr)? {
+                    (UNION, 2) => {
+                        pending.push(self.cell(addr + 1)?);
+                        pending.push(self.cell(addr + 2)?);
+                        continue;
+                    }
+                    (RANGE, 2) => {
+                        let lo = match self.deref_cell(self.cell(addr + 1)?)? {
+                            Cell::Int(lo) => lo,
+                            Cell::Con(INF) => isize::MIN,
+                            _ => return Err(Error::InvalidDomain),
+                        };
+                        let hi = match self.deref_cell(self.cell(addr + 2)?)? {
+                            Cell::Int(hi) => hi,
+                            Cell::Con(SUP) => isize::MAX,
+                            _ => return Err(Error::InvalidDomain),
+                        };
+                        Domain::range(lo, hi)
+                    }
+                    _ => return Err(Error::InvalidDomain),
+                },
+                _ => return Err(Error::InvalidDomain),
+            };
+            domain = domain.union(&part);
+        }
+
+        Ok(domain)
+    }
+
+    // Pushes term of given domain - union of its intervals, and returns
+    // its cell
+    fn push_domain(&mut self, domain: &Domain) -> Cell {
+        let mut term = None;
+        for (lo, hi) in &domain.0 {
+            let interval = if lo == hi {
+                Cell::Int(*lo)
+            } else {
+                let range = self.push_struct(RANGE, 2);
+                self.push_cell(match *lo {
+                    isize::MIN => Cell::Con(INF),
+                    lo => Cell::Int(lo),
+                });
+                self.push_cell(match *hi {
+                    isize::MAX => Cell::Con(SUP),
+                    hi => Cell::Int(hi),
+                });
+                range
+            };
+
+            term = Some(match term {
+                Some(left) => {
+                    let union = self.push_struct(UNION, 2);
+                    self.push_cell(left);
+                    self.push_cell(interval);
+                    union
+                }
+                None => interval,
+            });
+        }
+
+        // Empty domain is never stored, as narrowing to it fails
+        term.unwrap_or(Cell::Con(NIL))
+    }
+
+    // Narrows domain of term of given cell, queueing constraints of
+    // variable which domain changed
+    //
+    // Variable narrowed to single value is bound to it instead, so its
+    // constraints are woken by binding
+    fn narrow(&mut self, cell: Cell, domain: &Domain, queue: &mut Vec<Cell>) -> Result<(), Error> {
+        let var = match self.deref_cell(cell)? {
+            Cell::Int(value) if domain.contains(value) => return Ok(()),
+            Cell::Ref(var) => var,
+            _ => return Err(Error::UnificationFailure),
+        };
+
+        let (current, constraints) = self.fd_attribute(var)?;
+        let current = self.domain(current)?;
+        let narrowed = current.intersect(domain);
+        if narrowed == current {
+            return Ok(());
+        }
+
+        match narrowed.single() {
+            _ if narrowed.is_empty() => Err(Error::UnificationFailure),
+            Some(value) => self.bind(var, Cell::Int(value)),
+            None => {
+                let domain = self.push_domain(&narrowed);
+                self.set_fd_attribute(var, domain, constraints);
+                for goal in self.goals(constraints)? {
+                    if !queue.contains(&goal) {
+                        queue.push(goal);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Propagates queued constraints, and constraints of variables which
+    // domains they narrow, until no domain changes
+    fn propagate(&mut self, mut queue: Vec<Cell>) -> Result<(), Error> {
+        while let Some(goal) = queue.pop() {
+            let addr = match goal {
+                Cell::Struct(addr) => addr,
+                _ => return Err(Error::MalformedTerm),
+            };
+
+            match self.funct(addr)? {
+                (ALL_DIFFERENT, 1) => self.prune_all_different(self.cell(addr + 1)?, &mut queue)?,
+                (FD_EQ..=FD_GE, 2) => {
+                    // Non-linear constraint waits until enough of its
+                    // variables are bound
+                    if let Some((linear, relation)) = self.constraint(goal)? {
+                        self.prune_linear(&linear, relation, &mut queue)?;
+                    }
+                }
+                _ => return Err(Error::MalformedTerm),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reads linear constraint of given goal, as linear expression in
+    // relation to zero, `None` if it is not linear
+    fn constraint(&self, goal: Cell) -> Result<Option<(Linear, Relation)>, Error> {
+        let addr = match goal {
+            Cell::Struct(addr) => addr,
+            _ => return Err(Error::MalformedTerm),
+        };
+        let ident = match self.funct(addr)? {
+            (ident @ FD_EQ..=FD_GE, 2) => ident,
+            _ => return Ok(None),
+        };
+
+        let left = self.linear(self.cell(addr + 1)?)?;
+        let right = self.linear(self.cell(addr + 2)?)?;
+        let (left, right) = match (left, right) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Ok(None),
+        };
+
+        let constraint = match ident {
+            FD_EQ => (left.sub(&right)?, Relation::Eq),
+            FD_NE => (left.sub(&right)?, Relation::Ne),
+            FD_LE => (left.sub(&right)?, Relation::Le),
+            FD_LT => (left.sub(&right)?.add(&Linear::constant(1))?, Relation::Le),
+            FD_GE => (right.sub(&left)?, Relation::Le),
+            _ => (right.sub(&left)?.add(&Linear::constant(1))?, Relation::Le),
+        };
+        Ok(Some(constraint))
+    }
+
+    // Checks if constraint of given goal holds for all values of
+    // domains of its variables
+    fn entailed(&mut self, goal: Cell) -> Result<bool, Error> {
+        let (linear, relation) = match self.constraint(goal)? {
+            Some(constraint) => constraint,
+            None => return Ok(false),
+        };
+
+        // Lowest and highest value of expression, `None` if unbounded
+        let (mut min, mut max) = (Some(linear.constant as i128), Some(linear.constant as i128));
+        for (var, coef) in &linear.vars {
+            let (domain, _) = self.fd_attribute(*var)?;
+            let (lo, hi) = self
+                .domain(domain)?
+                .bounds()
+                .ok_or(Error::UnificationFailure)?;
+            let bound = |bound| match bound {
+                isize::MIN | isize::MAX => None,
+                bound => Some(*coef as i128 * bound as i128),
+            };
+            let (low, high) = if *coef > 0 {
+                (bound(lo), bound(hi))
+            } else {
+                (bound(hi), bound(lo))
+            };
+            min = min.zip(low).map(|(min, low)| min + low);
+            max = max.zip(high).map(|(max, high)| max + high);
+        }
+
+        Ok(match relation {
+            Relation::Le => max.is_some_and(|max| max <= 0),
+            Relation::Ne => min.is_some_and(|min| min > 0) || max.is_some_and(|max| max < 0),
+            Relation::Eq => false,
+        })
+    }
+
+    // Reads linear expression of given term, `None` if it is not linear
+    //
+    // Expression is read without recursion, like evaluated arithmetic
+    // expression
+    fn linear(&self, cell: Cell) -> Result<Option<Linear>, Error> {
+        let mut values = vec![];
+        let mut pending = vec![(cell, false)];
+
+        while let Some((cell, ready)) = pending.pop() {
+            match self.deref_cell(cell)? {
+                Cell::Int(value) => values.push(Some(Linear::constant(value))),
+                Cell::Ref(var) => values.push(Some(Linear::variable(var))),
+                Cell::Struct(addr) if ready => {
+                    let (ident, arity) = self.funct(addr)?;
+                    let args = values.split_off(values.len() - arity);
+                    let value = match (ident, &args[..]) {
+                        (_, [None, ..]) | (_, [_, None]) => None,
+                        (ADD, [Some(a), Some(b)]) => Some(a.clone().add(b)?),
+                        (SUB, [Some(a), Some(b)]) => Some(a.clone().sub(b)?),
+                        (SUB, [Some(a)]) => Some(a.clone().scale(-1)?),
+                        (MUL, [Some(a), Some(b)]) => a.clone().mul(b.clone())?,
+                        _ => return Err(Error::NotEvaluable(ident, arity)),
+                    };
+                    values.push(value);
+                }
+                Cell::Struct(addr) => {
+                    let (ident, arity) = self.funct(addr)?;
+                    if !matches!((ident, arity), (ADD | SUB | MUL, 2) | (SUB, 1)) {
+                        return Err(Error::NotEvaluable(ident, arity));
+                    }
+
+                    pending.push((cell, true));
+                    for i in (1..=arity).rev() {
+                        pending.push((self.cell(addr + i)?, false));
+                    }
+                }
+                Cell::Con(ident) => return Err(Error::NotEvaluable(ident, 0)),
+                Cell::Value(_) => return Err(Error::NotEvaluableValue),
+                Cell::List(_) => return Err(Error::NotEvaluable(crate::builtin::CONS, 2)),
+                Cell::Funct(_, _) => return Err(Error::MalformedTerm),
+                #[cfg(feature = "bigint")]
+                Cell::BigInt(_) => return Err(Error::IntegerOverflow),
+            }
+        }
+
+        values.pop().ok_or(Error::MalformedTerm)
+    }
+
+    // Narrows bounds of variables of linear expression in given relation
+    // to zero
+    fn prune_linear(
+        &mut self,
+        linear: &Linear,
+        relation: Relation,
+        queue: &mut Vec<Cell>,
+    ) -> Result<(), Error> {
+        match relation {
+            Relation::Le => self.prune_le(linear, queue),
+            Relation::Eq => {
+                self.prune_le(linear, queue)?;
+                self.prune_le(&linear.clone().scale(-1)?, queue)
+            }
+            // Inequality only removes value of its last variable
+            Relation::Ne => match linear.vars.iter().collect::<Vec<_>>()[..] {
+                [] if linear.constant == 0 => Err(Error::UnificationFailure),
+                [(var, coef)] if linear.constant % coef == 0 => {
+                    let value = checked((-linear.constant).checked_div(*coef))?;
+                    let (domain, _) = self.fd_attribute(*var)?;
+                    let domain = self.domain(domain)?.remove(value);
+                    self.narrow(Cell::Ref(*var), &domain, queue)
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    // Narrows bounds of variables, so linear expression may not be
+    // greater than zero
+    //
+    // Every term is bounded by constant and lowest values of other
+    // terms, so only terms with at most one unbounded other term
+    // are narrowed
+    fn prune_le(&mut self, linear: &Linear, queue: &mut Vec<Cell>) -> Result<(), Error> {
+        // Lowest value of every term, `None` if it is unbounded
+        let mut terms = vec![];
+        for (var, coef) in &linear.vars {
+            let (domain, _) = self.fd_attribute(*var)?;
+            let (lo, hi) = self
+                .domain(domain)?
+                .bounds()
+                .ok_or(Error::UnificationFailure)?;
+            let min = match (*coef > 0, lo, hi) {
+                (true, isize::MIN, _) | (false, _, isize::MAX) => None,
+                (true, lo, _) => Some(*coef as i128 * lo as i128),
+                (false, _, hi) => Some(*coef as i128 * hi as i128),
+            };
+            terms.push((*var, *coef, min));
+        }
+
+        let unbounded = terms.iter().filter(|(_, _, min)| min.is_none()).count();
+        let total =
+            terms.iter().filter_map(|(_, _, min)| *min).sum::<i128>() + linear.constant as i128;
+        if unbounded == 0 && total > 0 {
+            return Err(Error::UnificationFailure);
+        }
+
+        for (var, coef, min) in terms {
+            // Lowest value of everything but this term
+            let rest = match (min, unbounded) {
+                (Some(min), 0) => total - min,
+                (None, 1) => total,
+                _ => continue,
+            };
+
+            let domain = if coef > 0 {
+                Domain::range(isize::MIN, clamp(floor_div(-rest, coef as i128)))
+            } else {
+                Domain::range(clamp(ceil_div(-rest, coef as i128)), isize::MAX)
+            };
+            self.narrow(Cell::Ref(var), &domain, queue)?;
+        }
+
+        Ok(())
+    }
+
+    // Removes values of bound items of list from domains of unbound
+    // ones, and fails if unbound items have fewer values left than
+    // there is of them
+    fn prune_all_different(&mut self, list: Cell, queue: &mut Vec<Cell>) -> Result<(), Error> {
+        let mut values = vec![];
+        let mut vars = vec![];
+        for item in self.goals(list)? {
+            match item {
+                Cell::Int(value) if values.contains(&value) => {
+                    return Err(Error::UnificationFailure)
+                }
+                Cell::Int(value) => values.push(value),
+                Cell::Ref(var) => vars.push(var),
+                _ => return Err(Error::UnificationFailure),
+            }
+        }
+
+        let mut union = Domain(vec![]);
+        for var in &vars {
+            let (domain, _) = self.fd_attribute(*var)?;
+            let domain = values
+                .iter()
+                .fold(self.domain(domain)?, |domain, value| domain.remove(*value));
+            union = union.union(&domain);
+            self.narrow(Cell::Ref(*var), &domain, queue)?;
+        }
+
+        match union.size() {
+            Some(size) if size < vars.len() => Err(Error::UnificationFailure),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Library predicates searching for labeling, by trying values of
+/// domain in ascending order:
+///
+/// ```text
+/// label([]).
+/// label([X | Xs]) :- indomain(X), label(Xs).
+/// indomain(X) :- fd_inf(X, V), indomain(X, V).
+/// indomain(V, V).
+/// indomain(X, V) :- X #\= V, indomain(X).
+/// ```
+pub(crate) fn library<C: ConstDomain>() -> Vec<Statement<'static, C>> {
+    let mut statements = vec![];
+
+    let mut builder = StatementBuilder::default();
+    let nil = builder.nil();
+    let label = builder.structure(LABEL, vec![nil]);
+    statements.push(builder.build(label));
+
+    let mut builder = RuleBuilder::default();
+    let x = builder.variable();
+    let xs = builder.variable();
+    let list = builder.cons(x, xs);
+    let head = builder.structure(LABEL, vec![list]);
+    let indomain = builder.structure(INDOMAIN, vec![x]);
+    let label = builder.structure(LABEL, vec![xs]);
+    statements.push(builder.rule(head, vec![indomain, label]));
+
+    let mut builder = RuleBuilder::default();
+    let x = builder.variable();
+    let v = builder.variable();
+    let head = builder.structure(INDOMAIN, vec![x]);
+    let inf = builder.structure(FD_INF, vec![x, v]);
+    let indomain = builder.structure(INDOMAIN, vec![x, v]);
+    statements.push(builder.rule(head, vec![inf, indomain]));
+
+    let mut builder = StatementBuilder::default();
+    let v = builder.variable();
+    let indomain = builder.structure(INDOMAIN, vec![v, v]);
+    statements.push(builder.build(indomain));
+
+    let mut builder = RuleBuilder::default();
+    let x = builder.variable();
+    let v = builder.variable();
+    let head = builder.structure(INDOMAIN, vec![x, v]);
+    let ne = builder.structure(FD_NE, vec![x, v]);
+    let indomain = builder.structure(INDOMAIN, vec![x]);
+    statements.push(builder.rule(head, vec![ne, indomain]));
+
+    statements
+}
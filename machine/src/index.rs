@@ -0,0 +1,192 @@
+//! Clause indexing
+//!
+//! Procedures are indexed by their first argument with switch
+//! instructions, and groups of clauses which can't be told apart by
+//! it are further indexed by discrimination trees over whole clause
+//! heads.
+
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+use std::collections::HashMap;
+
+/// Symbol of term, by which clauses are indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Key {
+    /// Unbound variable, matching every term
+    Var,
+    Const(usize),
+    Int(isize),
+    /// Constant domain value or big integer, which are not indexed
+    /// by their values
+    Value,
+    List,
+    Struct(usize, usize),
+}
+
+impl Key {
+    /// Key in `SwitchOnConstant` table
+    pub(crate) fn constant(self) -> Option<(usize, usize)> {
+        match self {
+            Self::Const(ident) => Some((0, ident)),
+            Self::Int(value) => Some((1, value as usize)),
+            _ => None,
+        }
+    }
+
+    /// Key in `SwitchOnStructure` table
+    pub(crate) fn structure(self) -> Option<(usize, usize)> {
+        match self {
+            Self::Struct(ident, arity) => Some((ident, arity)),
+            _ => None,
+        }
+    }
+
+    /// Number of subterms following symbol in flattened term
+    fn arity(self) -> usize {
+        match self {
+            Self::List => 2,
+            Self::Struct(_, arity) => arity,
+            _ => 0,
+        }
+    }
+}
+
+impl<C: ConstDomain> Storage<C> {
+    /// Gives index key of term of given cell
+    pub(crate) fn key(&self, cell: Cell) -> Result<Key, Error> {
+        match self.deref_cell(cell)? {
+            Cell::Ref(_) => Ok(Key::Var),
+            Cell::Con(ident) => Ok(Key::Const(ident)),
+            Cell::Int(value) => Ok(Key::Int(value)),
+            #[cfg(feature = "bigint")]
+            Cell::BigInt(_) => Ok(Key::Value),
+            Cell::Value(_) => Ok(Key::Value),
+            Cell::List(_) => Ok(Key::List),
+            Cell::Struct(addr) => {
+                let (ident, arity) = self.funct(addr)?;
+                Ok(Key::Struct(ident, arity))
+            }
+            Cell::Funct(_, _) => Err(Error::MalformedTerm),
+        }
+    }
+
+    // Subterms of term of given cell, in order of flattening
+    fn subterms(&self, cell: Cell) -> Result<Vec<Cell>, Error> {
+        match self.deref_cell(cell)? {
+            Cell::List(addr) => Ok(vec![self.cell(addr)?, self.cell(addr + 1)?]),
+            Cell::Struct(addr) => {
+                let (_, arity) = self.funct(addr)?;
+                (1..=arity).map(|i| self.cell(addr + i)).collect()
+            }
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+/// Discrimination tree node
+#[derive(Default)]
+struct Node {
+    // Child nodes by symbol on next position of flattened head
+    children: HashMap<Key, usize>,
+    // Addresses of clauses which heads end in this node
+    clauses: Vec<usize>,
+}
+
+/// Discrimination tree over clause heads
+///
+/// Every clause head arguments are flattened in pre-order into their
+/// symbols, and clauses sharing prefix of symbols share path in the
+/// tree. Retrieving clauses for call follows only paths which symbols
+/// may unify with call arguments, so only positions where call or
+/// clause has a variable branch the search.
+#[derive(Default)]
+pub(crate) struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Builds tree of given clause heads (flattened arguments) and
+    /// addresses
+    pub(crate) fn new<'a>(clauses: impl IntoIterator<Item = (&'a [Key], usize)>) -> Self {
+        let mut tree = Self {
+            nodes: vec![Node::default()],
+        };
+
+        for (head, addr) in clauses {
+            let mut node = 0;
+            for key in head {
+                node = match tree.nodes[node].children.get(key) {
+                    Some(child) => *child,
+                    None => {
+                        tree.nodes.push(Node::default());
+                        let child = tree.nodes.len() - 1;
+                        tree.nodes[node].children.insert(*key, child);
+                        child
+                    }
+                };
+            }
+            tree.nodes[node].clauses.push(addr);
+        }
+
+        tree
+    }
+
+    /// Retrieves addresses of clauses which heads may unify with
+    /// given call arguments, in order of clauses
+    ///
+    /// Retrieved clauses are only candidates - arguments are not
+    /// unified, so eg. variable occurring twice in head is not checked.
+    /// Search is not recursive, and arguments are only followed as deep
+    /// as clause heads go, so cyclic arguments are fine.
+    pub(crate) fn retrieve<C: ConstDomain>(
+        &self,
+        storage: &Storage<C>,
+        args: &[Cell],
+    ) -> Result<Vec<usize>, Error> {
+        let mut clauses = vec![];
+        // Nodes to visit, with call subterms still to match (in
+        // reverse order), and number of clause subterms to skip as
+        // they are matched by call variable
+        let mut pending = vec![(0, args.iter().rev().copied().collect::<Vec<_>>(), 0)];
+
+        while let Some((node, mut terms, skip)) = pending.pop() {
+            let node = &self.nodes[node];
+
+            if skip > 0 {
+                for (key, child) in &node.children {
+                    pending.push((*child, terms.clone(), skip - 1 + key.arity()));
+                }
+                continue;
+            }
+
+            let term = match terms.pop() {
+                Some(term) => term,
+                None => {
+                    clauses.extend(&node.clauses);
+                    continue;
+                }
+            };
+
+            match storage.key(term)? {
+                Key::Var => {
+                    for (key, child) in &node.children {
+                        pending.push((*child, terms.clone(), key.arity()));
+                    }
+                }
+                key => {
+                    if let Some(child) = node.children.get(&Key::Var) {
+                        pending.push((*child, terms.clone(), 0));
+                    }
+
+                    if let Some(child) = node.children.get(&key) {
+                        terms.extend(storage.subterms(term)?.into_iter().rev());
+                        pending.push((*child, terms, 0));
+                    }
+                }
+            }
+        }
+
+        clauses.sort_unstable();
+        Ok(clauses)
+    }
+}
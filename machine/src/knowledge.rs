@@ -1,32 +1,342 @@
+use crate::{clpb, control, coroutine, fd};
+use crate::index::{Key, Tree};
 use crate::Program;
+use crate::program::ProgramBuilder;
 use crate::statement::Statement;
+use crate::storage::ConstDomain;
+use crate::Error;
 use derivative::Derivative;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::OnceLock;
+
+/// Switch target of terms no clause matches
+pub(crate) const FAIL: usize = usize::MAX - 1;
+
+/// Statements linked together into single program
+///
+/// Every procedure (all clauses of single functor) is a chain of its
+/// clauses in order of adding them, where every clause but last is
+/// preceded with choice instruction pointing to the next one.
+/// Procedures with more than one clause are entered through
+/// `SwitchOnTerm` on their first argument, if any clause has it bound,
+/// so calls with bound first argument try only clauses which may match.
+/// Clauses which still can't be told apart, but have other bound
+/// positions in their heads, are retrieved from discrimination tree.
+pub(crate) struct Code<C = usize> {
+    pub(crate) program: Program<'static, C>,
+    // Procedures entry points by their functors
+    procedures: HashMap<(usize, usize), usize>,
+    // Discrimination trees used by `SwitchOnHead`
+    trees: Vec<Tree>,
+}
+
+impl<C> Code<C> {
+    /// Returns entry point of procedure for given functor
+    pub(crate) fn procedure(&self, functor: (usize, usize)) -> Option<usize> {
+        self.procedures.get(&functor).copied()
+    }
+
+    /// Returns discrimination tree with given index
+    pub(crate) fn tree(&self, index: usize) -> Option<&Tree> {
+        self.trees.get(index)
+    }
+}
+
+/// Flattened head arguments and address of clause
+type Clause<'a> = (&'a [Key], usize);
+
+/// Symbol of clause first argument
+fn first(clause: &Clause) -> Key {
+    clause.0.first().copied().unwrap_or(Key::Var)
+}
+
+/// Checks if clauses have bound positions beyond symbols of their
+/// first arguments, so discrimination tree may tell them apart
+fn deep(clauses: &[Clause]) -> bool {
+    clauses
+        .iter()
+        .any(|(head, _)| head.iter().skip(1).any(|key| *key != Key::Var))
+}
 
 #[derive(Derivative)]
-#[derivative(Default)]
-pub struct Knowledge<'a> {
-    programs: Vec<Program<'a>>,
+#[derivative(Default(bound = ""))]
+pub struct Knowledge<'a, C = usize> {
+    statements: Vec<Statement<'a, C>>,
+    // Linked lazily on first query after knowledge changed
+    code: OnceLock<Code<C>>,
 }
 
 impl<'a> Knowledge<'a> {
     pub fn new() -> Self {
         Default::default()
     }
+}
 
-    pub fn add(&mut self, fact: Statement<'a>) -> &mut Self {
-        self.programs.push(fact.program);
+impl<'a, C: ConstDomain> Knowledge<'a, C> {
+    /// Adds fact or rule to knowledge
+    pub fn add(&mut self, statement: Statement<'a, C>) -> &mut Self {
+        self.statements.push(statement);
+        self.code.take();
         self
     }
 
+    /// Checks if knowledge is stratified, so no predicate depends on
+    /// itself negatively
+    ///
+    /// Predicate depends negatively on ones called under negation or in
+    /// if-then-else condition, and on everything they depend on.
+    /// Negation of non-stratified predicate may have no consistent
+    /// meaning (eg. `p :- \+ p.`), so it should be rejected. Fails
+    /// with `Error::NotStratified` for the first such predicate.
+    pub fn check_stratification(&self) -> Result<(), Error> {
+        let mut graph: HashMap<_, Vec<_>> = HashMap::new();
+        for statement in &self.statements {
+            if let Some(functor) = statement.functor {
+                let callees = statement.calls.iter().map(|(callee, _)| *callee);
+                graph.entry(functor).or_default().extend(callees);
+            }
+        }
+
+        for statement in &self.statements {
+            let functor = match statement.functor {
+                Some(functor) => functor,
+                None => continue,
+            };
+
+            let negative = statement.calls.iter().filter(|(_, negative)| *negative);
+            for (callee, _) in negative {
+                if reaches(&graph, *callee, functor) {
+                    return Err(Error::NotStratified(functor.0, functor.1));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn code(&self) -> &Code<C> {
+        self.code.get_or_init(|| self.link())
+    }
+
     pub(crate) fn x_registers(&self) -> usize {
-        self.programs
-            .iter()
-            .map(|p| p.x_registers())
-            .max()
-            .unwrap_or(0)
+        self.code().program.x_registers()
     }
 
-    pub(crate) fn programs(&self) -> impl Iterator<Item=&Program> {
-        self.programs.iter()
+    // Links all statements into single program
+    //
+    // Statements with variable head are never called, so they are
+    // not linked at all. Library predicates are linked after all
+    // statements.
+    fn link(&self) -> Code<C> {
+        let library: Vec<_> = control::library()
+            .into_iter()
+            .chain(fd::library())
+            .chain(clpb::library())
+            .chain(coroutine::library())
+            .collect();
+        let statements: Vec<&Statement<C>> = self.statements.iter().chain(&library).collect();
+
+        // Clauses of every functor, and functors in order of their
+        // first clauses
+        let mut functors = vec![];
+        let mut clauses_of: HashMap<_, Vec<&Statement<C>>> = HashMap::new();
+        for statement in statements {
+            if let Some(functor) = statement.functor {
+                let clauses = clauses_of.entry(functor).or_default();
+                if clauses.is_empty() {
+                    functors.push(functor);
+                }
+                clauses.push(statement);
+            }
+        }
+
+        let mut program = ProgramBuilder::default();
+        let mut procedures = HashMap::new();
+        let mut trees = vec![];
+
+        for functor in functors {
+            let clauses = clauses_of.remove(&functor).unwrap_or_default();
+
+            let entry = program.len();
+            let mut heads = vec![];
+
+            let last = clauses.len() - 1;
+            for (idx, clause) in clauses.into_iter().enumerate() {
+                // Choice instruction size + clause
+                let alternative = program.len() + 2 + clause.program.len();
+                match idx {
+                    _ if last == 0 => (),
+                    0 => {
+                        program.try_me_else(alternative);
+                    }
+                    _ if idx == last => {
+                        program.trust_me();
+                    }
+                    _ => {
+                        program.retry_me_else(alternative);
+                    }
+                }
+
+                // Clauses are entered by indexing after their choice
+                // instructions
+                heads.push((&clause.head[..], program.len()));
+                program.append(&clause.program);
+            }
+
+            let entry = match () {
+                _ if last == 0 => entry,
+                _ if heads.iter().any(|clause| first(clause) != Key::Var) => {
+                    index(&mut program, &mut trees, entry, &heads)
+                }
+                _ if deep(&heads) => chain(&mut program, &mut trees, &heads),
+                _ => entry,
+            };
+            procedures.insert(functor, entry);
+        }
+
+        Code {
+            program: program.build(),
+            procedures,
+            trees,
+        }
+    }
+}
+
+/// Checks if predicate calls other one, directly or through other
+/// predicates
+fn reaches(
+    graph: &HashMap<(usize, usize), Vec<(usize, usize)>>,
+    from: (usize, usize),
+    to: (usize, usize),
+) -> bool {
+    let mut visited = HashSet::new();
+    let mut pending = vec![from];
+
+    while let Some(functor) = pending.pop() {
+        if functor == to {
+            return true;
+        }
+
+        if visited.insert(functor) {
+            pending.extend(graph.get(&functor).into_iter().flatten());
+        }
+    }
+
+    false
+}
+
+// Links chain trying given clauses in order, and returns its address
+//
+// Single clause is entered directly, without choice point, and
+// clauses which may be told apart by their heads are retrieved from
+// discrimination tree
+fn chain<C: ConstDomain>(
+    program: &mut ProgramBuilder<C>,
+    trees: &mut Vec<Tree>,
+    clauses: &[Clause],
+) -> usize {
+    let addr = program.len();
+    match clauses {
+        [] => return FAIL,
+        [(_, clause)] => return *clause,
+        _ if deep(clauses) => {
+            trees.push(Tree::new(clauses.iter().copied()));
+            program.switch_on_head(trees.len() - 1);
+        }
+        [(_, first), rest @ .., (_, last)] => {
+            program.try_clause(*first);
+            for (_, clause) in rest {
+                program.retry_clause(*clause);
+            }
+            program.trust_clause(*last);
+        }
+    }
+    addr
+}
+
+// Links chains of clauses which may match every table key of
+// clauses first arguments, and returns switch table of them
+//
+// Clauses with variable first argument match every key
+fn table<'a, C: ConstDomain>(
+    program: &mut ProgramBuilder<C>,
+    trees: &mut Vec<Tree>,
+    clauses: &[Clause<'a>],
+    table_key: fn(Key) -> Option<(usize, usize)>,
+) -> Vec<((usize, usize), usize)> {
+    let mut groups: BTreeMap<_, Vec<Clause<'a>>> = clauses
+        .iter()
+        .filter_map(|clause| table_key(first(clause)))
+        .map(|key| (key, vec![]))
+        .collect();
+
+    for clause in clauses {
+        match table_key(first(clause)) {
+            Some(key) => groups.entry(key).or_default().push(*clause),
+            None if first(clause) == Key::Var => {
+                groups.values_mut().for_each(|group| group.push(*clause))
+            }
+            None => (),
+        }
     }
+
+    groups
+        .into_iter()
+        .map(|(key, group)| (key, chain(program, trees, &group)))
+        .collect()
+}
+
+// Links indexing of procedure with given clauses, which chain of all
+// clauses starts on given address, and returns procedure entry point
+//
+// Indexing instructions are linked after clauses, so they only refer
+// to already known addresses
+fn index<C: ConstDomain>(
+    program: &mut ProgramBuilder<C>,
+    trees: &mut Vec<Tree>,
+    all: usize,
+    clauses: &[Clause],
+) -> usize {
+    let matching = |pred: fn(Key) -> bool| -> Vec<Clause> {
+        clauses
+            .iter()
+            .filter(|clause| first(clause) == Key::Var || pred(first(clause)))
+            .copied()
+            .collect()
+    };
+
+    // Call with unbound first argument may still be told apart by
+    // other ones
+    let all = if deep(clauses) {
+        chain(program, trees, clauses)
+    } else {
+        all
+    };
+
+    // Switch tables default to clauses with variable first argument
+    let vars = chain(program, trees, &matching(|_| false));
+
+    let constants = table(program, trees, clauses, Key::constant);
+    let constant = if constants.is_empty() {
+        vars
+    } else {
+        let addr = program.len();
+        program.switch_on_constant(constants, vars);
+        addr
+    };
+
+    let list = chain(program, trees, &matching(|key| key == Key::List));
+
+    let structures = table(program, trees, clauses, Key::structure);
+    let structure = if structures.is_empty() {
+        vars
+    } else {
+        let addr = program.len();
+        program.switch_on_structure(structures, vars);
+        addr
+    };
+
+    let entry = program.len();
+    program.switch_on_term(all, constant, list, structure);
+    entry
 }
@@ -1,6 +1,17 @@
+pub mod attribute;
+pub mod builtin;
+mod clpb;
+mod clpq;
+mod compiler;
+mod control;
+mod coroutine;
+mod error;
+mod fd;
+mod index;
 mod machine;
 mod operation;
 mod program;
+mod rational;
 pub mod query;
 pub mod statement;
 mod storage;
@@ -9,9 +20,14 @@ pub mod term_builder;
 mod test_utils;
 pub mod knowledge;
 
+pub use error::Error;
 pub use machine::Machine;
 use operation::Operation;
+pub use operation::Register;
 use program::Program;
 use storage::Cell;
+pub use storage::{ConstDomain, OccursCheck};
 pub use term_builder::TermBuilder;
 pub use knowledge::Knowledge;
+#[cfg(feature = "bigint")]
+pub use num_bigint::BigInt;
@@ -1,27 +1,119 @@
-use crate::query::{Query, QueryResult};
-use crate::storage::{Cell, Storage};
-use crate::{Operation, Program};
+use crate::attribute::{Attributes, Hook, TermRef};
+use crate::builtin::{self, Builtin};
+use crate::index::Key;
+use crate::knowledge::{Code, FAIL};
+use crate::operation::Register;
+use crate::query::{Failure, Query, QueryResult};
+use crate::storage::{Cell, Clash, ConstDomain, Environment, OccursCheck, Storage};
+use crate::{Error, Operation, Program};
 use crate::Knowledge;
 
+/// Continuation of query itself - reaching it means that query
+/// is solved
+const HALT: usize = usize::MAX;
+
 enum UnificationState {
     Read,
     Write,
 }
 
-pub struct Machine {
-    storage: Storage,
+/// Choice point frame, created when procedure has more than one
+/// clause to try
+struct ChoicePoint {
+    args: Vec<Cell>,    // Argument registers on procedure entry
+    alternative: usize, // Next clause to try
+    heap: usize,        // Heap top on choice point creation
+    trail: usize,       // Trail top on choice point creation
+    env: Option<usize>, // Environment on procedure entry
+    cp: usize,          // Continuation on procedure entry
+    stack: usize,       // Environment stack top on choice point creation
+    b0: usize,          // Cut barrier on procedure entry
+    // Clauses retrieved from discrimination tree still to try, in
+    // reverse order
+    candidates: Vec<usize>,
+    #[cfg(feature = "bigint")]
+    bigints: usize,     // Big integers on choice point creation
+}
+
+/// Storage with copies of terms bound to query variables, and of
+/// residual goals
+type Copied<C> = (Storage<C>, Vec<Cell>, Vec<Cell>);
+
+/// Code to be executed - linked knowledge, followed by query
+struct Executable<'a, C> {
+    knowledge: &'a Code<C>,
+    query: &'a Program<'a, C>,
+}
+
+impl<'a, C: ConstDomain> Executable<'a, C> {
+    fn query_entry(&self) -> usize {
+        self.knowledge.program.len()
+    }
+
+    fn operation(&self, addr: usize) -> Result<Operation, Error> {
+        if addr < self.query_entry() {
+            self.knowledge.program.operation(addr)
+        } else {
+            self.query
+                .operation(addr - self.query_entry())
+                .map_err(|_| Error::MalformedBytecode(addr))
+        }
+    }
+
+    // Constant domain value with given index, used by operation
+    // on given address
+    fn value(&self, addr: usize, index: usize) -> Result<&C, Error> {
+        let value = if addr < self.query_entry() {
+            self.knowledge.program.value(index)
+        } else {
+            self.query.value(index)
+        };
+
+        value.ok_or(Error::MalformedBytecode(addr))
+    }
+
+    // Searches table of switch operation on given address
+    fn switch(&self, addr: usize, key: (usize, usize)) -> Result<Option<usize>, Error> {
+        if addr < self.query_entry() {
+            self.knowledge.program.switch(addr, key)
+        } else {
+            self.query
+                .switch(addr - self.query_entry(), key)
+                .map_err(|_| Error::MalformedBytecode(addr))
+        }
+    }
+}
+
+pub struct Machine<C = usize> {
+    storage: Storage<C>,
     preg: usize,                         // Instruction pointer register
+    cpreg: usize,                        // Continuation pointer register
     sreg: usize,                         // S register
+    ereg: Option<usize>,                 // Current environment register
     unification_state: UnificationState, // Read/Write state for unification
+    choice_points: Vec<ChoicePoint>,     // Choice points stack
+    args: usize,                         // Number of argument registers
+    building: Option<Cell>,              // Structure or list built in write mode
+    b0: usize,                           // Choice points on procedure call, kept by cut
+    occurs_check: OccursCheck,           // Default occurs check mode
+    hooks: Vec<(usize, Hook<C>)>,        // Attribute hooks with their modules
 }
 
-impl Default for Machine {
+impl<C: ConstDomain> Default for Machine<C> {
     fn default() -> Self {
         Self {
             storage: Storage::new(),
             preg: 0,
+            cpreg: HALT,
             sreg: 0,
+            ereg: None,
             unification_state: UnificationState::Read,
+            choice_points: vec![],
+            args: 0,
+            building: None,
+            b0: 0,
+            occurs_check: OccursCheck::Off,
+            hooks: vec![],
         }
     }
 }
@@ -30,226 +122,3858 @@ impl Machine {
     pub fn new() -> Self {
         Default::default()
     }
+}
 
-    #[cfg(test)]
-    pub(crate) fn with_storage(storage: Storage) -> Self {
-        Self {
-            storage,
-            ..Default::default()
+impl<C: ConstDomain> Machine<C> {
+    /// Sets occurs check mode for queries which doesn't set their own
+    pub fn set_occurs_check(&mut self, occurs_check: OccursCheck) -> &mut Self {
+        self.occurs_check = occurs_check;
+        self
+    }
+
+    /// Adds hook called whenever variable with attribute in given
+    /// module is bound
+    ///
+    /// Hooks are called in order of adding them, before the next goal
+    /// after binding is called, or on procedure exit. Goals added by
+    /// hooks are proven right after that.
+    pub fn add_attribute_hook(
+        &mut self,
+        module: usize,
+        hook: impl FnMut(&mut Attributes<C>, TermRef, TermRef) -> Result<bool, Error> + Send + 'static,
+    ) -> &mut Self {
+        self.hooks.push((module, Box::new(hook)));
+        self
+    }
+
+    /// Runs code from current instruction until query is solved,
+    /// backtracking to the next alternative on every failure
+    ///
+    /// Returns false if there is no alternative left to backtrack
+    /// to. Fails if program is malformed.
+    fn solve(&mut self, code: &Code<C>, query: &Program<C>) -> Result<bool, Error> {
+        let code = Executable {
+            knowledge: code,
+            query,
+        };
+
+        while self.preg != HALT {
+            let op = code.operation(self.preg)?;
+
+            match self.perform_op(op, &code) {
+                Ok(()) => (),
+                Err(err) if err.is_failure() => {
+                    if !self.backtrack() {
+                        return Ok(false);
+                    }
+                    // Only clash causing the final failure is reported
+                    self.storage.clear_clash();
+                }
+                Err(err) => return Err(err),
+            }
         }
+
+        Ok(true)
     }
 
-    fn run(&mut self, program: &Program) {
-        self.preg = 0;
-        while let Some(op) = program.operation(self.preg) {
-            self.perform_op(op);
+    /// Searches for next solution of query, by backtracking from
+    /// the last one
+    ///
+    /// Returns false if there is no more solutions
+    pub(crate) fn resume(&mut self, code: &Code<C>, query: &Program<C>) -> Result<bool, Error> {
+        if self.backtrack() {
+            self.solve(code, query)
+        } else {
+            Ok(false)
         }
     }
 
-    pub(crate) fn storage(&self) -> &Storage {
-        &self.storage
+    /// Copies terms bound to query variables, and residual goals of
+    /// constraints, after query is solved
+    pub(crate) fn solution(&mut self) -> Result<Copied<C>, Error> {
+        // Query environment is the first one allocated, and it is
+        // never discarded, as it is protected by every choice point
+        let vars = self
+            .storage
+            .environment(0)
+            .ok_or(Error::InvalidRegister(Register::Y(0)))?
+            .vars
+            .clone();
+        let goals = self.residual_goals(&vars)?;
+
+        let cells: Vec<_> = vars.iter().chain(&goals).copied().collect();
+        let vars = vars.len();
+        let (storage, mut cells) = self.storage.copy_terms(&cells)?;
+        let goals = cells.split_off(vars);
+        Ok((storage, cells, goals))
+    }
+
+    /// Copies terms which clash caused query failure
+    fn failure(&self) -> Result<Option<Failure<C>>, Error> {
+        let clash = if let Some(clash) = self.storage.clash() {
+            clash
+        } else {
+            return Ok(None);
+        };
+
+        let path = self.storage.clash_path(&clash)?;
+        let (storage, functors, cells) = match clash {
+            Clash::Terms { terms: (c1, c2), .. } => {
+                let (storage, cells) = self.storage.copy_terms(&[c1, c2])?;
+                let functors = (self.storage.functor(c1)?, self.storage.functor(c2)?);
+                (storage, functors, (cells[0], cells[1]))
+            }
+            // Expected term is built only in the copy, with variables
+            // as its arguments
+            Clash::Expected { term, functor } => {
+                let (mut storage, cells) = self.storage.copy_terms(&[term])?;
+                let expected = match functor {
+                    (builtin::CONS, 2) => Cell::List(storage.len()),
+                    (ident, arity) => storage.push_struct(ident, arity),
+                };
+                for _ in 0..functor.1 {
+                    storage.push_var();
+                }
+                let functors = (self.storage.functor(term)?, Some(functor));
+                (storage, functors, (cells[0], expected))
+            }
+        };
+
+        Ok(Some(Failure {
+            functors,
+            path,
+            cells,
+            storage,
+        }))
+    }
+
+    /// Moves execution to alternative clause of last choice point
+    ///
+    /// Returns false if there is no choice point left
+    fn backtrack(&mut self) -> bool {
+        let candidate = match self.choice_points.last_mut() {
+            Some(choice_point) => choice_point.candidates.pop(),
+            None => return false,
+        };
+
+        match candidate {
+            // Retrieved clauses have no choice instructions, so
+            // choice point is restored here
+            Some(clause) => {
+                let last = self
+                    .choice_points
+                    .last()
+                    .is_none_or(|choice_point| choice_point.candidates.is_empty());
+                if last {
+                    self.pop_choice_point();
+                } else {
+                    self.restore_choice_point();
+                }
+                self.preg = clause;
+            }
+            None => {
+                if let Some(choice_point) = self.choice_points.last() {
+                    self.preg = choice_point.alternative;
+                }
+            }
+        }
+
+        true
     }
 
+    /// Runs query against given knowledge, up to its first solution
+    ///
+    /// Query without solution is not an error - it just doesn't
+    /// succeed. Fails if knowledge or query program is malformed.
     pub fn query<'a>(
         &'a mut self,
-        query: Query,
-        knowledge: &Knowledge
-    ) -> QueryResult {
+        query: Query<'a, C>,
+        knowledge: &'a Knowledge<C>
+    ) -> Result<QueryResult<'a, C>, Error> {
+        let code = knowledge.code();
         let regs = std::cmp::max(
             query.program.x_registers(),
             knowledge.x_registers()
         );
 
         self.storage.reset(regs);
+        self.storage
+            .set_occurs_check(query.occurs_check.unwrap_or(self.occurs_check));
+        self.choice_points.clear();
+        self.b0 = 0;
+        self.ereg = None;
+        self.cpreg = HALT;
+        self.preg = code.program.len();
 
-        self.run(&query.program);
-        if query.top_level != 0 {
-            // 0 register should contain top level structure
-            self.storage[0] = self.storage[query.top_level];
-        }
-
-        for fact in knowledge.programs().take(1) {
-            self.run(fact);
-        }
+        let succeeded = self.solve(code, &query.program)?;
+        let failure = if succeeded { None } else { self.failure()? };
 
-        let regs = query.program.x_registers();
-        QueryResult {
+        let mut result = QueryResult {
             machine: self,
-            regs: self.storage.registers()[0..regs].to_vec(),
+            code,
+            program: query.program,
+            terms: query.terms.into(),
+            vars: query.vars.into(),
+            first: None,
+            failure,
+            started: false,
+            exhausted: false,
+        };
+
+        if succeeded {
+            result.first = Some(result.solution()?);
         }
+
+        Ok(result)
+    }
+
+    /// Reads register value
+    ///
+    /// Fails if register is out of allocated ones, or if permanent
+    /// register is read out of any environment
+    fn register(&self, reg: Register) -> Result<Cell, Error> {
+        let cell = match reg {
+            Register::X(xreg) => self.storage.get(xreg),
+            Register::Y(yreg) => self
+                .ereg
+                .and_then(|e| self.storage.environment(e))
+                .and_then(|env| env.vars.get(yreg)),
+        };
+
+        cell.copied().ok_or(Error::InvalidRegister(reg))
+    }
+
+    /// Writes register value
+    ///
+    /// Fails if register is out of allocated ones, or if permanent
+    /// register is written out of any environment
+    fn set_register(&mut self, reg: Register, cell: Cell) -> Result<(), Error> {
+        let ereg = self.ereg;
+        let target = match reg {
+            Register::X(xreg) => self.storage.get_mut(xreg),
+            Register::Y(yreg) => ereg
+                .and_then(move |e| self.storage.environment_mut(e))
+                .and_then(|env| env.vars.get_mut(yreg)),
+        };
+
+        *target.ok_or(Error::InvalidRegister(reg))? = cell;
+        Ok(())
     }
 
-    pub(crate) fn perform_op(&mut self, op: Operation) -> bool {
+    fn perform_op(&mut self, op: Operation, code: &Executable<C>) -> Result<(), Error> {
         let res = match op {
             Operation::PutStructure(ident, arity, xreg) => self.put_structure(ident, arity, xreg),
-            Operation::SetVariable(xreg) => self.set_variable(xreg),
-            Operation::SetValue(xreg) => self.set_value(xreg),
+            Operation::SetVariable(reg) => self.set_variable(reg),
+            Operation::SetValue(reg) => self.set_value(reg),
             Operation::GetStructure(ident, arity, xreg) => self.get_structure(ident, arity, xreg),
-            Operation::UnifyVariable(xreg) => self.unify_variable(xreg),
-            Operation::UnifyValue(xreg) => self.unify_value(xreg),
+            Operation::UnifyVariable(reg) => self.unify_variable(reg),
+            Operation::UnifyValue(reg) => self.unify_value(reg),
+            Operation::PutVariable(reg, areg) => self.put_variable(reg, areg),
+            Operation::PutValue(reg, areg) => self.put_value(reg, areg),
+            Operation::GetVariable(reg, areg) => self.get_variable(reg, areg),
+            Operation::GetValue(reg, areg) => self.get_value(reg, areg),
+            Operation::Call(ident, arity, live) => self.call(code.knowledge, ident, arity, live, op),
+            Operation::Execute(ident, arity) => self.execute(code.knowledge, ident, arity),
+            Operation::Proceed => self.proceed(code.knowledge),
+            Operation::Allocate(permanent) => self.allocate(permanent),
+            Operation::Deallocate => self.deallocate(),
+            Operation::TryMeElse(alternative) => self.try_me_else(alternative),
+            Operation::RetryMeElse(alternative) => self.retry_me_else(alternative),
+            Operation::TrustMe => self.trust_me(),
+            Operation::PutConstant(ident, xreg) => self.put_constant(ident, xreg),
+            Operation::GetConstant(ident, xreg) => self.get_constant(ident, xreg),
+            Operation::SetConstant(ident) => self.set_constant(ident),
+            Operation::UnifyConstant(ident) => self.unify_constant(ident),
+            Operation::PutInteger(value, xreg) => self.put_integer(value, xreg),
+            Operation::GetInteger(value, xreg) => self.get_integer(value, xreg),
+            Operation::SetInteger(value) => self.set_integer(value),
+            Operation::UnifyInteger(value) => self.unify_integer(value),
+            Operation::PutDomain(index, xreg) => self.put_domain(code, index, xreg),
+            Operation::GetDomain(index, xreg) => self.get_domain(code, index, xreg),
+            Operation::SetDomain(index) => self.set_domain(code, index),
+            Operation::UnifyDomain(index) => self.unify_domain(code, index),
+            Operation::PutList(xreg) => self.put_list(xreg),
+            Operation::GetList(xreg) => self.get_list(xreg),
+            Operation::SwitchOnTerm(var, constant, list, structure) => {
+                self.switch_on_term(var, constant, list, structure)
+            }
+            Operation::SwitchOnConstant(_, default) => self.switch_on_constant(code, default),
+            Operation::SwitchOnStructure(_, default) => self.switch_on_structure(code, default),
+            Operation::TryClause(clause) => self.try_clause(clause, op),
+            Operation::RetryClause(clause) => self.retry_clause(clause, op),
+            Operation::TrustClause(clause) => self.trust_clause(clause),
+            Operation::SwitchOnHead(tree) => self.switch_on_head(code.knowledge, tree),
+            Operation::NeckCut => self.neck_cut(),
+            Operation::GetLevel(reg) => self.get_level(reg),
+            Operation::Cut(reg) => self.cut(reg),
+            Operation::Mark(reg) => self.mark(reg),
+            Operation::TryElse(offset) => self.try_me_else(self.preg + offset),
+            Operation::Jump(offset) => self.jump(self.preg + offset),
         };
 
         self.preg += op.advance();
         res
     }
 
-    fn put_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> bool {
+    fn put_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> Result<(), Error> {
         let cell = self.storage.push_struct(ident, arity);
-        self.storage[xreg] = cell;
-        true
+        self.set_register(Register::X(xreg), cell)
     }
 
-    fn set_variable(&mut self, xreg: usize) -> bool {
+    fn set_variable(&mut self, reg: Register) -> Result<(), Error> {
         let cell = self.storage.push_var();
-        self.storage[xreg] = cell;
-        true
+        self.set_register(reg, cell)
     }
 
-    fn set_value(&mut self, xreg: usize) -> bool {
-        self.storage.push_cell(self.storage[xreg]);
-        true
+    fn set_value(&mut self, reg: Register) -> Result<(), Error> {
+        let cell = self.register(reg)?;
+        self.storage.push_cell(cell);
+        Ok(())
     }
 
-    fn get_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> bool {
-        let item = if let Some(item) = self.storage.deref(xreg) {
-            item
-        } else {
-            return false;
-        };
+    fn get_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(xreg))?;
 
-        match item {
+        match self.storage.deref_cell(cell)? {
             Cell::Ref(r) => {
-                let idx = self.storage.len();
-                self.storage.push_struct(ident, arity);
-                self.storage.bind(r, idx);
+                let cell = self.storage.bind_struct(r, ident, arity);
                 self.unification_state = UnificationState::Write;
-                true
+                self.building = Some(cell);
+                Ok(())
             }
-            Cell::Struct(a) => {
-                if Cell::Funct(ident, arity) == self.storage[a] {
-                    self.sreg = a + 1;
-                    self.unification_state = UnificationState::Read;
-                    true
-                } else {
-                    false
-                }
+            Cell::Struct(a) if Cell::Funct(ident, arity) == self.storage.cell(a)? => {
+                self.sreg = a + 1;
+                self.unification_state = UnificationState::Read;
+                self.building = None;
+                Ok(())
+            }
+            Cell::Funct(_, _) => Err(Error::MalformedTerm),
+            term => Err(self.storage.record_clash(Clash::Expected {
+                term,
+                functor: (ident, arity),
+            })),
+        }
+    }
+
+    fn put_list(&mut self, xreg: usize) -> Result<(), Error> {
+        let cell = Cell::List(self.storage.len());
+        self.set_register(Register::X(xreg), cell)
+    }
+
+    fn get_list(&mut self, xreg: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(xreg))?;
+
+        match self.storage.deref_cell(cell)? {
+            Cell::Ref(r) => {
+                let cell = self.storage.bind_list(r);
+                self.unification_state = UnificationState::Write;
+                self.building = Some(cell);
+                Ok(())
+            }
+            Cell::List(a) => {
+                self.sreg = a;
+                self.unification_state = UnificationState::Read;
+                self.building = None;
+                Ok(())
             }
-            _ => false,
+            Cell::Funct(_, _) => Err(Error::MalformedTerm),
+            term => Err(self.storage.record_clash(Clash::Expected {
+                term,
+                functor: (builtin::CONS, 2),
+            })),
         }
     }
 
-    fn unify_variable(&mut self, xreg: usize) -> bool {
-        match self.unification_state {
+    fn unify_variable(&mut self, reg: Register) -> Result<(), Error> {
+        let cell = match self.unification_state {
+            UnificationState::Read => self.storage.cell(self.sreg)?,
+            UnificationState::Write => self.storage.push_var(),
+        };
+        self.sreg += 1;
+        self.set_register(reg, cell)
+    }
+
+    fn unify_value(&mut self, reg: Register) -> Result<(), Error> {
+        let cell = self.register(reg)?;
+
+        let res = match self.unification_state {
             UnificationState::Read => {
-                self.storage[xreg] = self.storage[self.sreg];
+                let other = self.storage.cell(self.sreg)?;
+                self.storage.unify(cell, other)
             }
             UnificationState::Write => {
-                self.storage[xreg] = self.storage.push_var();
+                // Structure or list is already bound to variable, so
+                // it can't contain itself
+                if let Some(building) = self.building {
+                    self.storage.check_occurs(building, cell)?;
+                }
+                self.storage.push_cell(cell);
+                Ok(())
             }
-        }
+        };
         self.sreg += 1;
-        true
+        res
+    }
+
+    fn put_variable(&mut self, reg: Register, areg: usize) -> Result<(), Error> {
+        let cell = self.storage.push_var();
+        self.set_register(Register::X(areg), cell)?;
+        self.set_register(reg, cell)
+    }
+
+    fn put_value(&mut self, reg: Register, areg: usize) -> Result<(), Error> {
+        let cell = self.register(reg)?;
+        self.set_register(Register::X(areg), cell)
+    }
+
+    fn get_variable(&mut self, reg: Register, areg: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(areg))?;
+        self.set_register(reg, cell)
+    }
+
+    fn get_value(&mut self, reg: Register, areg: usize) -> Result<(), Error> {
+        let cell = self.register(reg)?;
+        let arg = self.register(Register::X(areg))?;
+        self.storage.unify(cell, arg)
+    }
+
+    fn put_constant(&mut self, ident: usize, xreg: usize) -> Result<(), Error> {
+        self.set_register(Register::X(xreg), Cell::Con(ident))
+    }
+
+    fn get_constant(&mut self, ident: usize, xreg: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(xreg))?;
+        self.storage.unify(cell, Cell::Con(ident))
+    }
+
+    fn set_constant(&mut self, ident: usize) -> Result<(), Error> {
+        self.storage.push_cell(Cell::Con(ident));
+        Ok(())
     }
 
-    fn unify_value(&mut self, xreg: usize) -> bool {
-        match self.unification_state {
+    fn unify_constant(&mut self, ident: usize) -> Result<(), Error> {
+        let res = match self.unification_state {
             UnificationState::Read => {
-                self.storage.unify(xreg, self.sreg);
+                let cell = self.storage.cell(self.sreg)?;
+                self.storage.unify(cell, Cell::Con(ident))
             }
             UnificationState::Write => {
-                self.storage.push_cell(self.storage[xreg]);
+                self.storage.push_cell(Cell::Con(ident));
+                Ok(())
             }
-        }
+        };
         self.sreg += 1;
-        true
+        res
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Machine;
-    use crate::query::QueryBuilder;
-    use crate::statement::StatementBuilder;
-    use crate::knowledge::Knowledge;
-    use crate::test_utils::ast::{Builder as TermBuilder, Term};
+    fn put_integer(&mut self, value: isize, xreg: usize) -> Result<(), Error> {
+        self.set_register(Register::X(xreg), Cell::Int(value))
+    }
 
-    #[test]
-    fn l0_query() {
-        // p(Z, h(Z, W), f(W))
-        // p/3 := 2
-        // h/2 := 1
-        // f/1 := 0
-        let mut builder = QueryBuilder::new();
-        let w = builder.variable();
-        let z = builder.variable();
-        let h = builder.structure(1, vec![z, w]);
-        let f = builder.structure(0, vec![w]);
-        let p = builder.structure(2, vec![z, h, f]);
+    fn get_integer(&mut self, value: isize, xreg: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(xreg))?;
+        self.storage.unify(cell, Cell::Int(value))
+    }
 
-        let query = builder.build(p);
+    fn set_integer(&mut self, value: isize) -> Result<(), Error> {
+        self.storage.push_cell(Cell::Int(value));
+        Ok(())
+    }
 
-        let mut machine = Machine::new();
-        machine.query(query, &Default::default());
-        let term = machine
-            .build_term(machine.storage[p.0], &mut TermBuilder)
-            .unwrap();
+    fn unify_integer(&mut self, value: isize) -> Result<(), Error> {
+        let res = match self.unification_state {
+            UnificationState::Read => {
+                let cell = self.storage.cell(self.sreg)?;
+                self.storage.unify(cell, Cell::Int(value))
+            }
+            UnificationState::Write => {
+                self.storage.push_cell(Cell::Int(value));
+                Ok(())
+            }
+        };
+        self.sreg += 1;
+        res
+    }
 
-        // _2(?0, _1(?0, ?1), _0(?1))
-        let expected_term = Term::Struct(
-            2,
-            vec![
-                Term::Var(0),
-                Term::Struct(1, vec![Term::Var(0), Term::Var(1)]),
-                Term::Struct(0, vec![Term::Var(1)]),
-            ],
-        );
+    // Interns constant domain value used by current operation
+    fn value(&mut self, code: &Executable<C>, index: usize) -> Result<Cell, Error> {
+        let value = code.value(self.preg, index)?.clone();
+        Ok(self.storage.intern(value))
+    }
 
-        assert_eq!(expected_term, term);
+    fn put_domain(&mut self, code: &Executable<C>, index: usize, xreg: usize) -> Result<(), Error> {
+        let value = self.value(code, index)?;
+        self.set_register(Register::X(xreg), value)
     }
 
-    #[test]
-    fn l0_program() {
-        // p/3 := 2
-        // h/2 := 1
-        // f/1 := 0
-        // a/0 := 3
+    fn get_domain(&mut self, code: &Executable<C>, index: usize, xreg: usize) -> Result<(), Error> {
+        let value = self.value(code, index)?;
+        let cell = self.register(Register::X(xreg))?;
+        self.storage.unify(cell, value)
+    }
 
-        let fact = {
-            // p(f(X), h(Y, f(a)), Y)
-            let mut builder = StatementBuilder::new();
-            let x = builder.variable();
-            let f0 = builder.structure(0, vec![x]);
-            let y = builder.variable();
-            let a = builder.constant(3);
-            let f1 = builder.structure(0, vec![a]);
-            let h = builder.structure(2, vec![y, f1]);
-            let p = builder.structure(3, vec![f0, h, y]);
+    fn set_domain(&mut self, code: &Executable<C>, index: usize) -> Result<(), Error> {
+        let value = self.value(code, index)?;
+        self.storage.push_cell(value);
+        Ok(())
+    }
 
-            builder.build(p)
+    fn unify_domain(&mut self, code: &Executable<C>, index: usize) -> Result<(), Error> {
+        let value = self.value(code, index)?;
+        let res = match self.unification_state {
+            UnificationState::Read => {
+                let cell = self.storage.cell(self.sreg)?;
+                self.storage.unify(cell, value)
+            }
+            UnificationState::Write => {
+                self.storage.push_cell(value);
+                Ok(())
+            }
         };
+        self.sreg += 1;
+        res
+    }
 
-        let (query, p) = {
-            // p(Z, h(Z, W), f(W))
-            let mut builder = QueryBuilder::new();
-            let w = builder.variable();
-            let z = builder.variable();
-            let h = builder.structure(1, vec![z, w]);
-            let f = builder.structure(0, vec![w]);
-            let p = builder.structure(2, vec![z, h, f]);
+    fn call(
+        &mut self,
+        code: &Code<C>,
+        ident: usize,
+        arity: usize,
+        live: usize,
+        op: Operation,
+    ) -> Result<(), Error> {
+        let goals = self.wake()?;
+        self.trim(live);
+        let (ident, arity) = self.interrupt(goals, ident, arity)?;
+        let (ident, arity) = self.meta_call(code, ident, arity)?;
+
+        // Built-ins are executed in place, continuing with next goal
+        if let Some(builtin) = Builtin::new(ident, arity) {
+            self.preg += op.size();
+            return self.builtin(builtin);
+        }
+
+        let procedure = code
+            .procedure((ident, arity))
+            .ok_or(Error::UnknownPredicate(ident, arity))?;
+
+        self.b0 = self.choice_points.len();
+        self.cpreg = self.preg + op.size();
+        self.args = arity;
+        self.preg = procedure;
+        Ok(())
+    }
+
+    fn execute(&mut self, code: &Code<C>, ident: usize, arity: usize) -> Result<(), Error> {
+        let goals = self.wake()?;
+        let (ident, arity) = self.interrupt(goals, ident, arity)?;
+        let (ident, arity) = self.meta_call(code, ident, arity)?;
 
-            (builder.build(p), p)
+        // Continuation of clause is already restored by deallocation
+        if let Some(builtin) = Builtin::new(ident, arity) {
+            self.preg = self.cpreg;
+            return self.builtin(builtin);
+        }
+
+        let procedure = code
+            .procedure((ident, arity))
+            .ok_or(Error::UnknownPredicate(ident, arity))?;
+
+        self.b0 = self.choice_points.len();
+        self.args = arity;
+        self.preg = procedure;
+        Ok(())
+    }
+
+    /// Prepends goals added by attribute hooks to called goal, so they
+    /// are proven first
+    ///
+    /// Called goal is rebuilt from argument registers, and the
+    /// conjunction is called with `call/1` instead.
+    fn interrupt(
+        &mut self,
+        goals: Vec<Cell>,
+        ident: usize,
+        arity: usize,
+    ) -> Result<(usize, usize), Error> {
+        if goals.is_empty() {
+            return Ok((ident, arity));
+        }
+
+        let goal = if arity == 0 {
+            Cell::Con(ident)
+        } else {
+            let args = (0..arity)
+                .map(|xreg| self.register(Register::X(xreg)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let goal = self.storage.push_struct(ident, arity);
+            for arg in args {
+                self.storage.push_cell(arg);
+            }
+            goal
         };
 
-        let mut machine = Machine::new();
-        machine.query(query, Knowledge::new().add(fact));
-        let term = machine
-            .build_term(machine.storage[p.0], &mut TermBuilder)
-            .unwrap();
+        let goal = self.conjunction(goals, goal);
+        self.set_register(Register::X(0), goal)?;
+        Ok((builtin::CALL, 1))
+    }
+
+    /// Pushes conjunction of given goals, followed by the last one
+    fn conjunction(&mut self, goals: Vec<Cell>, last: Cell) -> Cell {
+        goals.into_iter().rev().fold(last, |rest, goal| {
+            let conjunction = self.storage.push_struct(builtin::AND, 2);
+            self.storage.push_cell(goal);
+            self.storage.push_cell(rest);
+            conjunction
+        })
+    }
+
+    /// Resolves `call/1` and `'$call'/2` to functor of goal term it
+    /// calls, with arguments of goal loaded into argument registers
+    ///
+    /// Cut in called goal is local to it, so `call/1` records number of
+    /// choice points as cut level of its goal. Control constructs are
+    /// proven by `'$call'/2` library clauses, which pass the level to
+    /// their goals, and cut discards choice points above it.
+    ///
+    /// Fails with `Error::Instantiation` if goal is unbound, and with
+    /// `Error::NotCallable` if it is not a constant or structure.
+    fn meta_call(
+        &mut self,
+        code: &Code<C>,
+        mut ident: usize,
+        mut arity: usize,
+    ) -> Result<(usize, usize), Error> {
+        loop {
+            let level = match (ident, arity) {
+                (builtin::CALL, 1) => Cell::Int(self.choice_points.len() as isize),
+                (builtin::META_CALL, 2) => {
+                    let level = self.register(Register::X(1))?;
+                    self.storage.deref_cell(level)?
+                }
+                _ => return Ok((ident, arity)),
+            };
 
-        // ???
-        // _2(?24, _1(?24, ?23), _0(?23))
-        let expected_term = Term::Const(0);
+            let goal = self.register(Register::X(0))?;
+            let (addr, functor) = match self.storage.deref_cell(goal)? {
+                Cell::Con(ident) => (0, (ident, 0)),
+                Cell::Struct(addr) => (addr + 1, self.storage.funct(addr)?),
+                Cell::Ref(_) => return Err(Error::Instantiation),
+                _ => return Err(Error::NotCallable),
+            };
 
-        assert_eq!(expected_term, term);
+            match functor {
+                (builtin::CUT, 0) => {
+                    match level {
+                        Cell::Int(level) => self.cut_to(level as usize),
+                        _ => return Err(Error::InvalidRegister(Register::X(1))),
+                    }
+                    return Ok((builtin::TRUE, 0));
+                }
+                (builtin::AND | builtin::OR | builtin::IF, 2) | (builtin::NOT, 1) => {
+                    self.set_register(Register::X(1), level)?;
+                    return Ok((builtin::META_CALL, 2));
+                }
+                _ => (),
+            }
+
+            // Goal without procedure may have more arguments than
+            // there are registers
+            if Builtin::new(functor.0, functor.1).is_none()
+                && functor != (builtin::CALL, 1)
+                && code.procedure(functor).is_none()
+            {
+                return Err(Error::UnknownPredicate(functor.0, functor.1));
+            }
+
+            for xreg in 0..functor.1 {
+                let arg = self.storage.cell(addr + xreg)?;
+                self.set_register(Register::X(xreg), arg)?;
+            }
+            (ident, arity) = functor;
+        }
+    }
+
+    /// Discards permanent variables of current environment but given
+    /// number of first ones
+    ///
+    /// Environment protected by choice point is not trimmed, as code
+    /// using discarded variables may be resumed on backtracking
+    fn trim(&mut self, live: usize) {
+        let e = match self.ereg {
+            Some(e) => e,
+            None => return,
+        };
+
+        let protected = self.choice_points.last().is_some_and(|cp| cp.stack > e);
+        if !protected {
+            if let Some(env) = self.storage.environment_mut(e) {
+                env.vars.truncate(live);
+            }
+        }
+    }
+
+    /// Executes built-in predicate on arguments in argument registers
+    fn builtin(&mut self, builtin: Builtin) -> Result<(), Error> {
+        match builtin {
+            Builtin::True => Ok(()),
+            Builtin::Fail => Err(Error::UnificationFailure),
+            Builtin::Is => {
+                let left = self.register(Register::X(0))?;
+                let right = self.register(Register::X(1))?;
+                let value = self.storage.evaluate(right)?;
+                let value = self.storage.integer(value);
+                self.storage.unify(left, value)
+            }
+            Builtin::Dif => {
+                let left = self.register(Register::X(0))?;
+                let right = self.register(Register::X(1))?;
+                self.dif(left, right)
+            }
+            Builtin::In => {
+                let var = self.register(Register::X(0))?;
+                let domain = self.register(Register::X(1))?;
+                self.storage.fd_in(var, domain)
+            }
+            Builtin::Linear(ident) => {
+                let left = self.register(Register::X(0))?;
+                let right = self.register(Register::X(1))?;
+                self.storage.post(ident, &[left, right])
+            }
+            Builtin::AllDifferent => {
+                let list = self.register(Register::X(0))?;
+                self.storage.post(builtin::ALL_DIFFERENT, &[list])
+            }
+            Builtin::FdInf => {
+                let var = self.register(Register::X(0))?;
+                let inf = self.register(Register::X(1))?;
+                self.storage.fd_inf(var, inf)
+            }
+            Builtin::Rational => {
+                let constraints = self.register(Register::X(0))?;
+                self.storage.post_q(constraints)
+            }
+            Builtin::Sat => {
+                let expr = self.register(Register::X(0))?;
+                self.storage.sat(expr)
+            }
+            Builtin::Taut => {
+                let expr = self.register(Register::X(0))?;
+                let truth = self.register(Register::X(1))?;
+                self.storage.taut(expr, truth)
+            }
+            Builtin::PutAttr => {
+                let var = self.register(Register::X(0))?;
+                let module = self.register(Register::X(1))?;
+                let value = self.register(Register::X(2))?;
+                self.storage.put_attr(var, module, value)
+            }
+            Builtin::GetAttr => {
+                let var = self.register(Register::X(0))?;
+                let module = self.register(Register::X(1))?;
+                let value = self.register(Register::X(2))?;
+                self.storage.get_attr(var, module, value)
+            }
+            Builtin::DelAttr => {
+                let var = self.register(Register::X(0))?;
+                let module = self.register(Register::X(1))?;
+                self.storage.del_attr(var, module)
+            }
+            Builtin::Suspend => {
+                let cond = self.register(Register::X(0))?;
+                let suspended = self.register(Register::X(1))?;
+                let goal = self.register(Register::X(2))?;
+                let result = self.register(Register::X(3))?;
+                self.storage.suspend(cond, suspended, goal, result)
+            }
+            Builtin::Compare(ident) => {
+                let left = self.register(Register::X(0))?;
+                let right = self.register(Register::X(1))?;
+                let ordering = self
+                    .storage
+                    .evaluate(left)?
+                    .cmp(&self.storage.evaluate(right)?);
+                if builtin::compare(ident, ordering) {
+                    Ok(())
+                } else {
+                    Err(Error::UnificationFailure)
+                }
+            }
+        }
+    }
+
+    /// Checks `dif/2` constraint of given terms, suspending it on
+    /// variables which binding may make terms identical
+    ///
+    /// Succeeds once terms can't be unified, and fails if they are
+    /// already identical
+    fn dif(&mut self, left: Cell, right: Cell) -> Result<(), Error> {
+        let vars = match self.storage.unifier(left, right)? {
+            None => return Ok(()),
+            Some(vars) if vars.is_empty() => return Err(Error::UnificationFailure),
+            Some(vars) => vars,
+        };
+
+        let left = self.storage.deref_cell(left)?;
+        let right = self.storage.deref_cell(right)?;
+        let goal = self.storage.push_struct(builtin::DIF, 2);
+        self.storage.push_cell(left);
+        self.storage.push_cell(right);
+
+        // Suspended goals of variable are kept in list of its attribute
+        for var in vars {
+            let goals = self
+                .storage
+                .attribute(var, builtin::DIF)
+                .unwrap_or(Cell::Con(builtin::NIL));
+            let list = Cell::List(self.storage.len());
+            self.storage.push_cell(goal);
+            self.storage.push_cell(goals);
+            self.storage.put_attribute(var, builtin::DIF, Some(list));
+        }
+
+        Ok(())
+    }
+
+    /// Rechecks constraints suspended on attributed variables bound
+    /// since they were last woken, and calls their hooks
+    ///
+    /// Constraints are woken before every call and on procedure exit,
+    /// so terms bound by unification are complete. Woken constraints
+    /// may bind further variables, so it is repeated until none is.
+    ///
+    /// Returns woken delayed goals and goals added by hooks, which are
+    /// to be proven before execution continues.
+    fn wake(&mut self) -> Result<Vec<Cell>, Error> {
+        let mut goals = vec![];
+        loop {
+            let woken = self.storage.take_woken();
+            if woken.is_empty() {
+                return Ok(goals);
+            }
+
+            for var in woken {
+                for (left, right) in self.difs(var)? {
+                    self.dif(left, right)?;
+                }
+                self.storage.wake_fd(var)?;
+                self.storage.wake_q(var)?;
+                self.storage.wake_sat(var)?;
+                goals.extend(self.storage.wake_suspended(var)?);
+
+                for (module, hook) in &mut self.hooks {
+                    let attribute = match self.storage.attribute(var, *module) {
+                        Some(attribute) => attribute,
+                        None => continue,
+                    };
+
+                    let mut attributes = Attributes::new(&mut self.storage, &mut goals);
+                    let bound = TermRef(Cell::Ref(var));
+                    if !hook(&mut attributes, TermRef(attribute), bound)? {
+                        return Err(Error::UnificationFailure);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns terms of `dif/2` constraints suspended on variable on
+    /// given address
+    fn difs(&self, var: usize) -> Result<Vec<(Cell, Cell)>, Error> {
+        let mut difs = vec![];
+        let mut goals = self.storage.attribute(var, builtin::DIF);
+        while let Some(Cell::List(addr)) = goals {
+            if let Cell::Struct(goal) = self.storage.cell(addr)? {
+                difs.push((self.storage.cell(goal + 1)?, self.storage.cell(goal + 2)?));
+            }
+            goals = Some(self.storage.cell(addr + 1)?);
+        }
+        Ok(difs)
+    }
+
+    /// Pushes residual goals of constraints suspended on unbound
+    /// variables, after query is solved
+    ///
+    /// `dif/2` constraint is residual until its terms become
+    /// identical or non-unifiable. Linear constraints over rationals
+    /// are projected onto variables of given query terms. Delayed goals
+    /// still suspended are residual as they were called.
+    fn residual_goals(&mut self, query: &[Cell]) -> Result<Vec<Cell>, Error> {
+        let mut goals = self.storage.fd_residual_goals()?;
+        goals.extend(self.storage.q_residual_goals(query)?);
+        goals.extend(self.storage.sat_residual_goals()?);
+        goals.extend(self.storage.suspended_goals()?);
+
+        let mut difs = vec![];
+        for (var, _) in self.storage.with_attribute(builtin::DIF) {
+            for dif in self.difs(var)? {
+                if !difs.contains(&dif) {
+                    difs.push(dif);
+                }
+            }
+        }
+
+        for (left, right) in difs {
+            if let Some(vars) = self.storage.unifier(left, right)? {
+                if !vars.is_empty() {
+                    goals.push(self.storage.push_struct(builtin::DIF, 2));
+                    self.storage.push_cell(left);
+                    self.storage.push_cell(right);
+                }
+            }
+        }
+
+        Ok(goals)
+    }
+
+    fn proceed(&mut self, code: &Code<C>) -> Result<(), Error> {
+        let mut goals = self.wake()?;
+        let last = match goals.pop() {
+            Some(last) => last,
+            None => {
+                self.preg = self.cpreg;
+                return Ok(());
+            }
+        };
+
+        // Goals added by hooks are proven before returning to
+        // continuation, as if they were the last call of procedure
+        let goal = self.conjunction(goals, last);
+        self.set_register(Register::X(0), goal)?;
+        self.execute(code, builtin::CALL, 1)
+    }
+
+    fn allocate(&mut self, permanent: usize) -> Result<(), Error> {
+        // Environments protected by choice point can't be discarded,
+        // as they would be needed after backtracking. Without current
+        // environment query one is deallocated already, but it is kept
+        // for goals woken after its last goal.
+        let top = std::cmp::max(
+            self.ereg.map_or(self.storage.stack_len().min(1), |e| e + 1),
+            self.choice_points.last().map_or(0, |cp| cp.stack),
+        );
+
+        let env = Environment {
+            ce: self.ereg,
+            cp: self.cpreg,
+            vars: vec![Default::default(); permanent],
+        };
+        self.ereg = Some(self.storage.allocate(top, env));
+        Ok(())
+    }
+
+    fn deallocate(&mut self) -> Result<(), Error> {
+        let storage = &self.storage;
+        let env = self
+            .ereg
+            .and_then(|e| storage.environment(e))
+            .ok_or(Error::InvalidRegister(Register::Y(0)))?;
+
+        self.cpreg = env.cp;
+        self.ereg = env.ce;
+        Ok(())
+    }
+
+    fn try_me_else(&mut self, alternative: usize) -> Result<(), Error> {
+        let heap = self.storage.len();
+        let args = self
+            .storage
+            .get(0..self.args)
+            .ok_or(Error::InvalidRegister(Register::X(self.args)))?
+            .to_vec();
+
+        self.choice_points.push(ChoicePoint {
+            args,
+            alternative,
+            heap,
+            trail: self.storage.trail_len(),
+            env: self.ereg,
+            cp: self.cpreg,
+            stack: self.storage.stack_len(),
+            b0: self.b0,
+            candidates: vec![],
+            #[cfg(feature = "bigint")]
+            bigints: self.storage.bigints_len(),
+        });
+        self.storage.set_hb(heap);
+        Ok(())
+    }
+
+    fn retry_me_else(&mut self, alternative: usize) -> Result<(), Error> {
+        self.restore_choice_point();
+        if let Some(choice_point) = self.choice_points.last_mut() {
+            choice_point.alternative = alternative;
+        }
+        Ok(())
+    }
+
+    fn trust_me(&mut self) -> Result<(), Error> {
+        self.pop_choice_point();
+        Ok(())
+    }
+
+    fn switch_on_head(&mut self, code: &Code<C>, tree: usize) -> Result<(), Error> {
+        let tree = code.tree(tree).ok_or(Error::MalformedBytecode(self.preg))?;
+        let args = self
+            .storage
+            .get(0..self.args)
+            .ok_or(Error::InvalidRegister(Register::X(self.args)))?;
+        let mut candidates = tree.retrieve(&self.storage, args)?;
+
+        candidates.reverse();
+        let clause = candidates.pop().unwrap_or(FAIL);
+        if !candidates.is_empty() {
+            self.try_me_else(FAIL)?;
+            if let Some(choice_point) = self.choice_points.last_mut() {
+                choice_point.candidates = candidates;
+            }
+        }
+        self.jump(clause)
+    }
+
+    /// Continues with code on given address, or fails if it is `FAIL`
+    fn jump(&mut self, addr: usize) -> Result<(), Error> {
+        if addr == FAIL {
+            return Err(Error::UnificationFailure);
+        }
+
+        self.preg = addr;
+        Ok(())
+    }
+
+    fn switch_on_term(
+        &mut self,
+        var: usize,
+        constant: usize,
+        list: usize,
+        structure: usize,
+    ) -> Result<(), Error> {
+        let cell = self.register(Register::X(0))?;
+
+        // Unindexed constants are tried against all clauses
+        let addr = match self.storage.key(cell)? {
+            Key::Var | Key::Value => var,
+            Key::Const(_) | Key::Int(_) => constant,
+            Key::List => list,
+            Key::Struct(_, _) => structure,
+        };
+        self.jump(addr)
+    }
+
+    fn switch_on_constant(&mut self, code: &Executable<C>, default: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(0))?;
+        let key = self.storage.key(cell)?.constant();
+        self.switch(code, key, default)
+    }
+
+    fn switch_on_structure(&mut self, code: &Executable<C>, default: usize) -> Result<(), Error> {
+        let cell = self.register(Register::X(0))?;
+        let key = self.storage.key(cell)?.structure();
+        self.switch(code, key, default)
+    }
+
+    // Jumps to address of given key in current switch table, or to
+    // default one if there is no such key
+    fn switch(
+        &mut self,
+        code: &Executable<C>,
+        key: Option<(usize, usize)>,
+        default: usize,
+    ) -> Result<(), Error> {
+        let key = key.ok_or(Error::MalformedBytecode(self.preg))?;
+        let addr = code.switch(self.preg, key)?.unwrap_or(default);
+        self.jump(addr)
+    }
+
+    fn try_clause(&mut self, clause: usize, op: Operation) -> Result<(), Error> {
+        self.try_me_else(self.preg + op.size())?;
+        self.preg = clause;
+        Ok(())
+    }
+
+    fn retry_clause(&mut self, clause: usize, op: Operation) -> Result<(), Error> {
+        self.retry_me_else(self.preg + op.size())?;
+        self.preg = clause;
+        Ok(())
+    }
+
+    fn trust_clause(&mut self, clause: usize) -> Result<(), Error> {
+        self.trust_me()?;
+        self.preg = clause;
+        Ok(())
+    }
+
+    /// Restores machine state saved in last choice point, and
+    /// discards it
+    fn pop_choice_point(&mut self) {
+        self.restore_choice_point();
+        self.choice_points.pop();
+        let hb = self.choice_points.last().map_or(0, |cp| cp.heap);
+        self.storage.set_hb(hb);
+    }
+
+    /// Restores machine state saved in last choice point
+    fn restore_choice_point(&mut self) {
+        if let Some(choice_point) = self.choice_points.last() {
+            self.storage[0..choice_point.args.len()].copy_from_slice(&choice_point.args);
+            self.storage.truncate(choice_point.heap);
+            #[cfg(feature = "bigint")]
+            self.storage.truncate_bigints(choice_point.bigints);
+            self.storage.unwind_trail(choice_point.trail);
+            self.storage.set_hb(choice_point.heap);
+            self.ereg = choice_point.env;
+            self.cpreg = choice_point.cp;
+            self.b0 = choice_point.b0;
+        }
+    }
+
+    fn neck_cut(&mut self) -> Result<(), Error> {
+        self.cut_to(self.b0);
+        Ok(())
+    }
+
+    fn get_level(&mut self, reg: Register) -> Result<(), Error> {
+        self.set_register(reg, Cell::Int(self.b0 as isize))
+    }
+
+    fn cut(&mut self, reg: Register) -> Result<(), Error> {
+        match self.register(reg)? {
+            Cell::Int(level) => {
+                self.cut_to(level as usize);
+                Ok(())
+            }
+            _ => Err(Error::InvalidRegister(reg)),
+        }
+    }
+
+    fn mark(&mut self, reg: Register) -> Result<(), Error> {
+        self.set_register(reg, Cell::Int(self.choice_points.len() as isize))
+    }
+
+    /// Discards choice points above given level
+    fn cut_to(&mut self, level: usize) {
+        if level < self.choice_points.len() {
+            self.choice_points.truncate(level);
+            let hb = self.choice_points.last().map_or(0, |cp| cp.heap);
+            self.storage.set_hb(hb);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Machine;
+    use crate::builtin::{ADD, CONS, DIV, GE, IS, LT, MOD, MUL, NIL, SUB};
+    use crate::{Error, OccursCheck};
+    use crate::query::{Query, QueryBuilder, QueryRef};
+    use crate::statement::{RuleBuilder, StatementBuilder, StatementRef};
+    use crate::knowledge::Knowledge;
+    use crate::test_utils::ast::{Builder as TermBuilder, Term};
+
+    #[test]
+    fn l0_query() {
+        // p(Z, h(Z, W), f(W))
+        // p/3 := 2
+        // h/2 := 1
+        // f/1 := 0
+        let mut builder = QueryBuilder::new();
+        let w = builder.variable();
+        let z = builder.variable();
+        let h = builder.structure(1, vec![z, w]);
+        let f = builder.structure(0, vec![w]);
+        let p = builder.structure(2, vec![z, h, f]);
+
+        let query = builder.build(p).unwrap();
+
+        // p(A, B, C) - query has to be proven to be extracted
+        let fact = {
+            let mut builder = StatementBuilder::new();
+            let args: Vec<_> = (0..3).map(|_| builder.variable()).collect();
+            let p = builder.structure(2, args);
+            builder.build(p)
+        };
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact);
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        let term = result.build_term(p, &mut TermBuilder).unwrap();
+
+        // _2(?0, _1(?0, ?1), _0(?1))
+        let expected_term = Term::Struct(
+            2,
+            vec![
+                Term::Var(0),
+                Term::Struct(1, vec![Term::Var(0), Term::Var(1)]),
+                Term::Struct(0, vec![Term::Var(1)]),
+            ],
+        );
+
+        assert_eq!(expected_term, term);
+
+        // Query part from another query
+        let other = QueryRef(5);
+        assert_eq!(
+            Err(Error::InvalidQueryRef(5)),
+            result.build_term(other, &mut TermBuilder)
+        );
+    }
+
+    #[test]
+    fn l0_program() {
+        // p/3 := 2
+        // h/2 := 1
+        // f/1 := 0
+        // a/0 := 3
+
+        let fact = {
+            // p(f(X), h(Y, f(a)), Y)
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let f0 = builder.structure(0, vec![x]);
+            let y = builder.variable();
+            let a = builder.constant(3);
+            let f1 = builder.structure(0, vec![a]);
+            let h = builder.structure(1, vec![y, f1]);
+            let p = builder.structure(2, vec![f0, h, y]);
+
+            builder.build(p)
+        };
+
+        let (query, p) = {
+            // p(Z, h(Z, W), f(W))
+            let mut builder = QueryBuilder::new();
+            let w = builder.variable();
+            let z = builder.variable();
+            let h = builder.structure(1, vec![z, w]);
+            let f = builder.structure(0, vec![w]);
+            let p = builder.structure(2, vec![z, h, f]);
+
+            (builder.build(p).unwrap(), p)
+        };
+
+        let mut machine = Machine::new();
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact);
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(p, &mut TermBuilder).unwrap();
+
+        // p(f(f(a)), h(f(f(a)), f(a)), f(f(a)))
+        let ffa = Term::Struct(0, vec![Term::Struct(0, vec![Term::Const(3)])]);
+        let expected_term = Term::Struct(
+            2,
+            vec![
+                ffa.clone(),
+                Term::Struct(1, vec![ffa.clone(), Term::Struct(0, vec![Term::Const(3)])]),
+                ffa,
+            ],
+        );
+
+        assert_eq!(expected_term, term);
+    }
+
+    #[test]
+    fn l0_multiple_facts() {
+        // p/1 := 0
+        // q/1 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // c/0 := 4
+
+        let fact = |p, c| {
+            let mut builder = StatementBuilder::new();
+            let c = builder.constant(c);
+            let p = builder.structure(p, vec![c]);
+            builder.build(p)
+        };
+
+        // q(a). p(b). p(c).
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact(1, 2)).add(fact(0, 3)).add(fact(0, 4));
+
+        let (query, x) = {
+            // p(X)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let p = builder.structure(0, vec![x]);
+
+            (builder.build(p).unwrap(), x)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(x, &mut TermBuilder).unwrap();
+
+        assert_eq!(Term::Const(3), term);
+
+        let (query, x) = {
+            // q(X), p(X)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let q = builder.structure(1, vec![x]);
+            let p = builder.structure(0, vec![x]);
+            let query = builder.conjunction(vec![q, p]);
+
+            (builder.build(query).unwrap(), x)
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(!result.succeeded());
+        assert_eq!(Err(Error::NoSolution), result.build_term(x, &mut TermBuilder));
+
+        let query = {
+            // p(f(c), X) - no fact for p/2
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let c = builder.constant(4);
+            let f = builder.structure(5, vec![c]);
+            let p = builder.structure(0, vec![f, x]);
+
+            builder.build(p).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge);
+        assert_eq!(Some(Error::UnknownPredicate(0, 2)), result.err());
+    }
+
+    #[test]
+    fn l0_failed_fact_bindings_dropped() {
+        // p/3 := 0
+        // a/0 := 1
+        // b/0 := 2
+        // c/0 := 3
+
+        // p(X, X, a). p(Z, W, c).
+        let same = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let a = builder.constant(1);
+            let p = builder.structure(0, vec![x, x, a]);
+            builder.build(p)
+        };
+
+        let any = {
+            let mut builder = StatementBuilder::new();
+            let z = builder.variable();
+            let w = builder.variable();
+            let c = builder.constant(3);
+            let p = builder.structure(0, vec![z, w, c]);
+            builder.build(p)
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(same).add(any);
+
+        let (query, y) = {
+            // p(b, Y, c) - first fact binds Y to b before failing
+            let mut builder = QueryBuilder::new();
+            let b = builder.constant(2);
+            let y = builder.variable();
+            let c = builder.constant(3);
+            let p = builder.structure(0, vec![b, y, c]);
+
+            (builder.build(p).unwrap(), y)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(y, &mut TermBuilder).unwrap();
+
+        assert_eq!(Term::Var(0), term);
+    }
+
+    #[test]
+    fn failure_and_errors() {
+        use crate::builtin::CALL;
+
+        // p/1 := 0
+        // q/1 := 1
+        // r/0 := 2
+        // a/0 := 3
+        // b/0 := 4
+
+        // p(a).
+        let mut builder = StatementBuilder::new();
+        let a = builder.constant(3);
+        let p = builder.structure(0, vec![a]);
+        let fact = builder.build(p);
+
+        // r :- q(a).
+        let mut builder = RuleBuilder::new();
+        let head = builder.constant(2);
+        let a = builder.constant(3);
+        let q = builder.structure(1, vec![a]);
+        let rule = builder.build(head, vec![q]).unwrap();
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact).add(rule);
+        let mut machine = Machine::new();
+
+        // Failing query is no error
+        // p(b)
+        let mut builder = QueryBuilder::new();
+        let b = builder.constant(4);
+        let p = builder.structure(0, vec![b]);
+        let result = machine.query(builder.build(p).unwrap(), &knowledge);
+        assert!(matches!(result, Ok(ref result) if !result.succeeded()));
+
+        // q(a), call(q(a)), r
+        let queries: [fn(&mut QueryBuilder) -> QueryRef; 3] = [
+            |builder| {
+                let a = builder.constant(3);
+                builder.structure(1, vec![a])
+            },
+            |builder| {
+                let a = builder.constant(3);
+                let q = builder.structure(1, vec![a]);
+                builder.structure(CALL, vec![q])
+            },
+            |builder| builder.constant(2),
+        ];
+        for query in queries {
+            let mut builder = QueryBuilder::new();
+            let query = query(&mut builder);
+            let result = machine.query(builder.build(query).unwrap(), &knowledge);
+            assert_eq!(Some(Error::UnknownPredicate(1, 1)), result.err());
+        }
+    }
+
+    #[test]
+    fn l3_backtracking() {
+        // q/2 := 0
+        // f/1 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // c/0 := 4
+
+        // q(f(X), a).
+        let first = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let f = builder.structure(1, vec![x]);
+            let a = builder.constant(2);
+            let q = builder.structure(0, vec![f, a]);
+            builder.build(q)
+        };
+
+        // q(f(b), b).
+        let second = {
+            let mut builder = StatementBuilder::new();
+            let b = builder.constant(3);
+            let f = builder.structure(1, vec![b]);
+            let b = builder.constant(3);
+            let q = builder.structure(0, vec![f, b]);
+            builder.build(q)
+        };
+
+        // q(Y, c).
+        let third = {
+            let mut builder = StatementBuilder::new();
+            let y = builder.variable();
+            let c = builder.constant(4);
+            let q = builder.structure(0, vec![y, c]);
+            builder.build(q)
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(first).add(second).add(third);
+
+        let (query, z) = {
+            // q(f(Z), c) - second fact binds Z to b before failing
+            let mut builder = QueryBuilder::new();
+            let z = builder.variable();
+            let f = builder.structure(1, vec![z]);
+            let c = builder.constant(4);
+            let q = builder.structure(0, vec![f, c]);
+
+            (builder.build(q).unwrap(), z)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(z, &mut TermBuilder).unwrap();
+
+        assert_eq!(Term::Var(0), term);
+    }
+
+    #[test]
+    fn l2_rules() {
+        // typeof/2 := 0
+        // app/2 := 1
+        // fn/2 := 2
+        // zero/0 := 3
+        // succ/0 := 4
+        // nat/0 := 5
+
+        // typeof(zero, nat).
+        let zero = {
+            let mut builder = StatementBuilder::new();
+            let zero = builder.constant(3);
+            let nat = builder.constant(5);
+            let typeof_ = builder.structure(0, vec![zero, nat]);
+            builder.build(typeof_)
+        };
+
+        // typeof(succ, fn(nat, nat)).
+        let succ = {
+            let mut builder = StatementBuilder::new();
+            let succ = builder.constant(4);
+            let nat = builder.constant(5);
+            let fn_ = builder.structure(2, vec![nat, nat]);
+            let typeof_ = builder.structure(0, vec![succ, fn_]);
+            builder.build(typeof_)
+        };
+
+        // typeof(app(F, X), T) :- typeof(F, fn(A, T)), typeof(X, A).
+        let app = {
+            let mut builder = RuleBuilder::new();
+            let f = builder.variable();
+            let x = builder.variable();
+            let t = builder.variable();
+            let a = builder.variable();
+            let app = builder.structure(1, vec![f, x]);
+            let head = builder.structure(0, vec![app, t]);
+            let fn_ = builder.structure(2, vec![a, t]);
+            let g1 = builder.structure(0, vec![f, fn_]);
+            let g2 = builder.structure(0, vec![x, a]);
+            builder.build(head, vec![g1, g2]).unwrap()
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(zero).add(succ).add(app);
+
+        let (query, t) = {
+            // typeof(app(succ, app(succ, zero)), T)
+            let mut builder = QueryBuilder::new();
+            let t = builder.variable();
+            let succ = builder.constant(4);
+            let zero = builder.constant(3);
+            let inner = builder.structure(1, vec![succ, zero]);
+            let outer = builder.structure(1, vec![succ, inner]);
+            let typeof_ = builder.structure(0, vec![outer, t]);
+
+            (builder.build(typeof_).unwrap(), t)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(t, &mut TermBuilder).unwrap();
+
+        assert_eq!(Term::Const(5), term);
+
+        let query = {
+            // typeof(app(zero, succ), T)
+            let mut builder = QueryBuilder::new();
+            let t = builder.variable();
+            let succ = builder.constant(4);
+            let zero = builder.constant(3);
+            let app = builder.structure(1, vec![zero, succ]);
+            let typeof_ = builder.structure(0, vec![app, t]);
+
+            builder.build(typeof_).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(!result.succeeded());
+    }
+
+    #[test]
+    fn l3_backtracking_into_rule_body() {
+        // parent/2 := 0
+        // grandparent/2 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // c/0 := 4
+        // d/0 := 5
+
+        let parent = |p, c| {
+            let mut builder = StatementBuilder::new();
+            let p = builder.constant(p);
+            let c = builder.constant(c);
+            let parent = builder.structure(0, vec![p, c]);
+            builder.build(parent)
+        };
+
+        // grandparent(X, Z) :- parent(X, Y), parent(Y, Z).
+        let grandparent = {
+            let mut builder = RuleBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let z = builder.variable();
+            let head = builder.structure(1, vec![x, z]);
+            let g1 = builder.structure(0, vec![x, y]);
+            let g2 = builder.structure(0, vec![y, z]);
+            builder.build(head, vec![g1, g2]).unwrap()
+        };
+
+        // parent(a, c). parent(a, b). parent(b, c). parent(b, d).
+        let mut knowledge = Knowledge::new();
+        knowledge
+            .add(parent(2, 4))
+            .add(parent(2, 3))
+            .add(parent(3, 4))
+            .add(parent(3, 5))
+            .add(grandparent);
+
+        let (query, x) = {
+            // grandparent(X, d)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let d = builder.constant(5);
+            let grandparent = builder.structure(1, vec![x, d]);
+
+            (builder.build(grandparent).unwrap(), x)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        let term = result.build_term(x, &mut TermBuilder).unwrap();
+
+        assert_eq!(Term::Const(2), term);
+    }
+
+    #[test]
+    fn l3_all_solutions() {
+        // parent/2 := 0
+        // grandparent/2 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // c/0 := 4
+        // d/0 := 5
+
+        let parent = |p, c| {
+            let mut builder = StatementBuilder::new();
+            let p = builder.constant(p);
+            let c = builder.constant(c);
+            let parent = builder.structure(0, vec![p, c]);
+            builder.build(parent)
+        };
+
+        // grandparent(X, Z) :- parent(X, Y), parent(Y, Z).
+        let grandparent = {
+            let mut builder = RuleBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let z = builder.variable();
+            let head = builder.structure(1, vec![x, z]);
+            let g1 = builder.structure(0, vec![x, y]);
+            let g2 = builder.structure(0, vec![y, z]);
+            builder.build(head, vec![g1, g2]).unwrap()
+        };
+
+        // parent(a, c). parent(a, b). parent(b, c). parent(b, d).
+        let mut knowledge = Knowledge::new();
+        knowledge
+            .add(parent(2, 4))
+            .add(parent(2, 3))
+            .add(parent(3, 4))
+            .add(parent(3, 5))
+            .add(grandparent);
+
+        let query = |gp| {
+            // grandparent(X, Z) or parent(X, Z)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let z = builder.variable();
+            let goal = builder.structure(gp, vec![x, z]);
+
+            (builder.build(goal).unwrap(), x, z)
+        };
+
+        let mut machine = Machine::new();
+
+        let (q, x, z) = query(0);
+        let solutions: Vec<_> = machine
+            .query(q, &knowledge)
+            .unwrap()
+            .map(|s| {
+                let s = s.unwrap();
+                (
+                    s.build_term(x, &mut TermBuilder).unwrap(),
+                    s.build_term(z, &mut TermBuilder).unwrap(),
+                )
+            })
+            .collect();
+
+        let expected = vec![
+            (Term::Const(2), Term::Const(4)),
+            (Term::Const(2), Term::Const(3)),
+            (Term::Const(3), Term::Const(4)),
+            (Term::Const(3), Term::Const(5)),
+        ];
+        assert_eq!(expected, solutions);
+
+        let (q, x, z) = query(1);
+        let mut result = machine.query(q, &knowledge).unwrap();
+        let first = result.next().unwrap().unwrap();
+        let second = result.next().unwrap().unwrap();
+        assert!(result.next().is_none());
+        assert!(result.next().is_none());
+
+        // Solutions stay valid after further backtracking
+        assert_eq!(Term::Const(2), first.build_term(x, &mut TermBuilder).unwrap());
+        assert_eq!(Term::Const(4), first.build_term(z, &mut TermBuilder).unwrap());
+        assert_eq!(Term::Const(2), second.build_term(x, &mut TermBuilder).unwrap());
+        assert_eq!(Term::Const(5), second.build_term(z, &mut TermBuilder).unwrap());
+
+        // Only requested solutions are searched
+        let (q, _, _) = query(1);
+        assert_eq!(1, machine.query(q, &knowledge).unwrap().take(1).count());
+    }
+
+    #[test]
+    fn failure_report() {
+        // eq/2 := 0
+        // f/2 := 1
+        // g/1 := 2
+        // a/0 := 3
+        // b/0 := 4
+        // c/0 := 5
+        // h/1 := 6
+        // k/1 := 7
+
+        // eq(X, X).
+        let eq = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let eq = builder.structure(0, vec![x, x]);
+            builder.build(eq)
+        };
+
+        // k(g(X)).
+        let k = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let g = builder.structure(2, vec![x]);
+            let k = builder.structure(7, vec![g]);
+            builder.build(k)
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(eq).add(k);
+
+        let query = {
+            // eq(f(a, g(b)), f(a, g(c)))
+            let mut builder = QueryBuilder::new();
+            let a = builder.constant(3);
+            let b = builder.constant(4);
+            let g = builder.structure(2, vec![b]);
+            let left = builder.structure(1, vec![a, g]);
+            let a = builder.constant(3);
+            let c = builder.constant(5);
+            let g = builder.structure(2, vec![c]);
+            let right = builder.structure(1, vec![a, g]);
+            let eq = builder.structure(0, vec![left, right]);
+
+            builder.build(eq).unwrap()
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(!result.succeeded());
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+
+        assert_eq!((Some((4, 0)), Some((5, 0))), report.functors);
+        assert_eq!(vec![1, 0], report.path);
+        assert_eq!((Term::Const(4), Term::Const(5)), report.terms);
+
+        let query = {
+            // eq(h(X), g(a))
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let h = builder.structure(6, vec![x]);
+            let a = builder.constant(3);
+            let g = builder.structure(2, vec![a]);
+            let eq = builder.structure(0, vec![h, g]);
+
+            builder.build(eq).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+
+        assert_eq!((Some((6, 1)), Some((2, 1))), report.functors);
+        assert!(report.path.is_empty());
+        assert_eq!(
+            (
+                Term::Struct(6, vec![Term::Var(0)]),
+                Term::Struct(2, vec![Term::Const(3)]),
+            ),
+            report.terms
+        );
+
+        let query = {
+            // k(a)
+            let mut builder = QueryBuilder::new();
+            let a = builder.constant(3);
+            let k = builder.structure(7, vec![a]);
+
+            builder.build(k).unwrap()
+        };
+
+        // Structure expected by clause head is reported, though it is
+        // never built
+        let result = machine.query(query, &knowledge).unwrap();
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+
+        assert_eq!((Some((3, 0)), Some((2, 1))), report.functors);
+        assert!(report.path.is_empty());
+        assert!(matches!(
+            report.terms,
+            (Term::Const(3), Term::Struct(2, ref args)) if matches!(args[..], [Term::Var(_)])
+        ));
+
+        let query = {
+            // eq(a, a)
+            let mut builder = QueryBuilder::new();
+            let a = builder.constant(3);
+            let eq = builder.structure(0, vec![a, a]);
+
+            builder.build(eq).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        assert!(result.failure_report(&mut TermBuilder).unwrap().is_none());
+    }
+
+    #[test]
+    fn occurs_check() {
+        // eq/2 := 0
+        // p/2 := 1
+        // f/1 := 2
+
+        // eq(X, X).
+        let eq = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let eq = builder.structure(0, vec![x, x]);
+            builder.build(eq)
+        };
+
+        // p(X, f(X)).
+        let p = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let f = builder.structure(2, vec![x]);
+            let p = builder.structure(1, vec![x, f]);
+            builder.build(p)
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(eq).add(p);
+
+        // eq(Y, f(Y))
+        let eq = |occurs_check| {
+            let mut builder = QueryBuilder::new();
+            let y = builder.variable();
+            let f = builder.structure(2, vec![y]);
+            let eq = builder.structure(0, vec![y, f]);
+            if let Some(occurs_check) = occurs_check {
+                builder.occurs_check(occurs_check);
+            }
+            builder.build(eq).unwrap()
+        };
+
+        // p(Y, Y)
+        let p = |occurs_check| {
+            let mut builder = QueryBuilder::new();
+            let y = builder.variable();
+            let p = builder.structure(1, vec![y, y]);
+            if let Some(occurs_check) = occurs_check {
+                builder.occurs_check(occurs_check);
+            }
+            builder.build(p).unwrap()
+        };
+
+        let mut machine = Machine::new();
+        assert!(machine.query(eq(None), &knowledge).unwrap().succeeded());
+        assert!(machine.query(p(None), &knowledge).unwrap().succeeded());
+
+        let query = eq(Some(OccursCheck::Fail));
+        assert!(!machine.query(query, &knowledge).unwrap().succeeded());
+        let query = p(Some(OccursCheck::Fail));
+        assert!(!machine.query(query, &knowledge).unwrap().succeeded());
+
+        machine.set_occurs_check(OccursCheck::Error);
+        assert!(matches!(
+            machine.query(eq(None), &knowledge),
+            Err(Error::OccursCheck(_))
+        ));
+        assert!(matches!(
+            machine.query(p(None), &knowledge),
+            Err(Error::OccursCheck(_))
+        ));
+
+        let query = eq(Some(OccursCheck::Off));
+        assert!(machine.query(query, &knowledge).unwrap().succeeded());
+    }
+
+    #[test]
+    fn cyclic_terms() {
+        // eq/2 := 0
+        // t/2 := 1
+        // f/1 := 2
+
+        // eq(X, X).
+        let eq = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let eq = builder.structure(0, vec![x, x]);
+            builder.build(eq)
+        };
+
+        // t(X, Y) :- eq(X, f(X)), eq(Y, f(Y)), eq(X, Y).
+        let t = {
+            let mut builder = RuleBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let head = builder.structure(1, vec![x, y]);
+            let fx = builder.structure(2, vec![x]);
+            let g1 = builder.structure(0, vec![x, fx]);
+            let fy = builder.structure(2, vec![y]);
+            let g2 = builder.structure(0, vec![y, fy]);
+            let g3 = builder.structure(0, vec![x, y]);
+            builder.build(head, vec![g1, g2, g3]).unwrap()
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(eq).add(t);
+
+        let (query, x) = {
+            // t(X, Y)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let t = builder.structure(1, vec![x, y]);
+
+            (builder.build(t).unwrap(), x)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+
+        // X = f(X)
+        match result.build_term(x, &mut TermBuilder).unwrap() {
+            Term::Cyclic(id, term) => {
+                assert_eq!(Term::Struct(2, vec![Term::Cycle(id)]), *term)
+            }
+            term => panic!("Expected cyclic term, got {:?}", term),
+        }
+    }
+
+    #[test]
+    fn l0_constants() {
+        // rainy/0 := 0
+        // weather/2 := 1
+        // today/0 := 2
+        // f/1 := 3
+
+        // rainy.
+        let rainy = {
+            let mut builder = StatementBuilder::new();
+            let rainy = builder.constant(0);
+            builder.build(rainy)
+        };
+
+        // weather(today, f(rainy)).
+        let weather = {
+            let mut builder = StatementBuilder::new();
+            let today = builder.constant(2);
+            let rainy = builder.constant(0);
+            let f = builder.structure(3, vec![rainy]);
+            let weather = builder.structure(1, vec![today, f]);
+            builder.build(weather)
+        };
+        assert!(weather.assembly().contains("GetConstant(2, 0)"));
+        assert!(weather.assembly().contains("UnifyConstant(0)"));
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(rainy).add(weather);
+
+        let mut machine = Machine::new();
+
+        // rainy
+        let query = {
+            let mut builder = QueryBuilder::new();
+            let rainy = builder.constant(0);
+            builder.build(rainy).unwrap()
+        };
+        assert!(machine.query(query, &knowledge).unwrap().succeeded());
+
+        let (query, x) = {
+            // weather(today, f(X))
+            let mut builder = QueryBuilder::new();
+            let today = builder.constant(2);
+            let x = builder.variable();
+            let f = builder.structure(3, vec![x]);
+            let weather = builder.structure(1, vec![today, f]);
+
+            (builder.build(weather).unwrap(), x)
+        };
+        assert!(query.assembly().contains("PutConstant(2, 0)"));
+
+        let result = machine.query(query, &knowledge).unwrap();
+        let term = result.build_term(x, &mut TermBuilder).unwrap();
+        assert_eq!(Term::Const(0), term);
+
+        let query = {
+            // weather(f(rainy), today)
+            let mut builder = QueryBuilder::new();
+            let today = builder.constant(2);
+            let rainy = builder.constant(0);
+            let f = builder.structure(3, vec![rainy]);
+            let weather = builder.structure(1, vec![f, today]);
+
+            builder.build(weather).unwrap()
+        };
+        assert!(query.assembly().contains("SetConstant(0)"));
+
+        let result = machine.query(query, &knowledge).unwrap();
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+        assert_eq!((Some((3, 1)), Some((2, 0))), report.functors);
+    }
+
+    #[test]
+    fn arithmetic() {
+        // len/2 := 0
+        // nil/0 := 1
+        // cons/2 := 2
+
+        // len(nil, 0).
+        let empty = {
+            let mut builder = StatementBuilder::new();
+            let nil = builder.constant(1);
+            let zero = builder.integer(0);
+            let len = builder.structure(0, vec![nil, zero]);
+            builder.build(len)
+        };
+
+        // len(cons(H, T), N) :- len(T, M), N is M + 1.
+        let cons = {
+            let mut builder = RuleBuilder::new();
+            let h = builder.variable();
+            let t = builder.variable();
+            let n = builder.variable();
+            let m = builder.variable();
+            let cons = builder.structure(2, vec![h, t]);
+            let head = builder.structure(0, vec![cons, n]);
+            let len = builder.structure(0, vec![t, m]);
+            let one = builder.integer(1);
+            let sum = builder.structure(ADD, vec![m, one]);
+            let is = builder.structure(IS, vec![n, sum]);
+            builder.build(head, vec![len, is]).unwrap()
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(empty).add(cons);
+
+        let (query, n) = {
+            // len(cons(1, cons(-2, cons(3, nil))), N)
+            let mut builder = QueryBuilder::new();
+            let n = builder.variable();
+            let nil = builder.constant(1);
+            let list = [3, -2, 1].iter().fold(nil, |tail, head| {
+                let head = builder.integer(*head);
+                builder.structure(2, vec![head, tail])
+            });
+            let len = builder.structure(0, vec![list, n]);
+
+            (builder.build(len).unwrap(), n)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert_eq!(Term::Int(3), result.build_term(n, &mut TermBuilder).unwrap());
+
+        // X is (7 - -9) * 2 // 5 mod -4
+        let eval = |machine: &mut Machine, divisor| {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let seven = builder.integer(7);
+            let nine = builder.integer(9);
+            let minus = builder.structure(SUB, vec![nine]);
+            let sub = builder.structure(SUB, vec![seven, minus]);
+            let two = builder.integer(2);
+            let mul = builder.structure(MUL, vec![sub, two]);
+            let five = builder.integer(divisor);
+            let div = builder.structure(DIV, vec![mul, five]);
+            let four = builder.integer(-4);
+            let rem = builder.structure(MOD, vec![div, four]);
+            let is = builder.structure(IS, vec![x, rem]);
+
+            machine
+                .query(builder.build(is).unwrap(), &knowledge)
+                .and_then(|result| result.build_term(x, &mut TermBuilder))
+        };
+
+        assert_eq!(Ok(Term::Int(-2)), eval(&mut machine, 5));
+        assert_eq!(Err(Error::ZeroDivisor), eval(&mut machine, 0));
+
+        // X < 1 + 1
+        let query = {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let one = builder.integer(1);
+            let sum = builder.structure(ADD, vec![one, one]);
+            let lt = builder.structure(LT, vec![x, sum]);
+            builder.build(lt).unwrap()
+        };
+        assert!(matches!(
+            machine.query(query, &knowledge),
+            Err(Error::Instantiation)
+        ));
+
+        // nil >= 1
+        let query = {
+            let mut builder = QueryBuilder::new();
+            let nil = builder.constant(1);
+            let one = builder.integer(1);
+            let ge = builder.structure(GE, vec![nil, one]);
+            builder.build(ge).unwrap()
+        };
+        assert!(matches!(
+            machine.query(query, &knowledge),
+            Err(Error::NotEvaluable(1, 0))
+        ));
+
+        // 1 < 2, 2 >= 3, 4 is 3, 3 is 3
+        let query = |machine: &mut Machine, ident, left, right| {
+            let mut builder = QueryBuilder::new();
+            let left = builder.integer(left);
+            let right = builder.integer(right);
+            let goal = builder.structure(ident, vec![left, right]);
+
+            machine
+                .query(builder.build(goal).unwrap(), &knowledge)
+                .map(|result| result.succeeded())
+        };
+
+        assert_eq!(Ok(true), query(&mut machine, LT, 1, 2));
+        assert_eq!(Ok(false), query(&mut machine, GE, 2, 3));
+        assert_eq!(Ok(false), query(&mut machine, IS, 4, 3));
+        assert_eq!(Ok(true), query(&mut machine, IS, 3, 3));
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigint"))]
+    fn integer_overflow() {
+        let knowledge = Knowledge::new();
+
+        // X is MAX + 1
+        let query = {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let max = builder.integer(isize::MAX);
+            let one = builder.integer(1);
+            let sum = builder.structure(ADD, vec![max, one]);
+            let is = builder.structure(IS, vec![x, sum]);
+            builder.build(is).unwrap()
+        };
+
+        let mut machine = Machine::new();
+        assert!(matches!(
+            machine.query(query, &knowledge),
+            Err(Error::IntegerOverflow)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "bigint")]
+    fn big_integers() {
+        use crate::builtin::GT;
+        use crate::BigInt;
+
+        // q/1 := 0
+        // r/2 := 1
+
+        let q = |n| {
+            let mut builder = StatementBuilder::new();
+            let n = builder.integer(n);
+            let q = builder.structure(0, vec![n]);
+            builder.build(q)
+        };
+
+        // r(X, Z) :-
+        //     q(N), X is MAX + N, X > MAX + 1,
+        //     Y is X * X, Z is Y // X - N.
+        let r = {
+            let mut builder = RuleBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let z = builder.variable();
+            let n = builder.variable();
+            let head = builder.structure(1, vec![x, z]);
+            let q = builder.structure(0, vec![n]);
+            let max = builder.integer(isize::MAX);
+            let sum = builder.structure(ADD, vec![max, n]);
+            let g1 = builder.structure(IS, vec![x, sum]);
+            let one = builder.integer(1);
+            let sum = builder.structure(ADD, vec![max, one]);
+            let g2 = builder.structure(GT, vec![x, sum]);
+            let mul = builder.structure(MUL, vec![x, x]);
+            let g3 = builder.structure(IS, vec![y, mul]);
+            let div = builder.structure(DIV, vec![y, x]);
+            let sub = builder.structure(SUB, vec![div, n]);
+            let g4 = builder.structure(IS, vec![z, sub]);
+            builder.build(head, vec![q, g1, g2, g3, g4]).unwrap()
+        };
+
+        // q(1). q(2). - first one fails after big integer is created
+        let mut knowledge = Knowledge::new();
+        knowledge.add(q(1)).add(q(2)).add(r);
+
+        let (query, x, z) = {
+            // r(X, Z)
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let z = builder.variable();
+            let r = builder.structure(1, vec![x, z]);
+
+            (builder.build(r).unwrap(), x, z)
+        };
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        let expected = BigInt::from(isize::MAX) + 2;
+        assert_eq!(Term::BigInt(expected), result.build_term(x, &mut TermBuilder).unwrap());
+        // Results fitting in `isize` are never big
+        assert_eq!(Term::Int(isize::MAX), result.build_term(z, &mut TermBuilder).unwrap());
+    }
+
+    #[test]
+    fn const_domain() {
+        // unit/2 := 0
+        // pair/2 := 1
+        // p/1 := 2
+        // eq/2 := 3
+        // length/0 := 4
+        // time/0 := 5
+
+        let unit = |dim, name: &str| {
+            let mut builder = StatementBuilder::default();
+            let dim = builder.constant(dim);
+            let name = builder.value(name.to_owned());
+            let unit = builder.structure(0, vec![dim, name]);
+            builder.build(unit)
+        };
+
+        // pair(p("a"), "b").
+        let pair = {
+            let mut builder = StatementBuilder::default();
+            let a = builder.value("a".to_owned());
+            let p = builder.structure(2, vec![a]);
+            let b = builder.value("b".to_owned());
+            let pair = builder.structure(1, vec![p, b]);
+            builder.build(pair)
+        };
+
+        // eq(X, X).
+        let eq = {
+            let mut builder = StatementBuilder::default();
+            let x = builder.variable();
+            let eq = builder.structure(3, vec![x, x]);
+            builder.build(eq)
+        };
+
+        // unit(length, "metre"). unit(time, "second").
+        let mut knowledge = Knowledge::default();
+        knowledge
+            .add(unit(4, "metre"))
+            .add(unit(5, "second"))
+            .add(pair)
+            .add(eq);
+
+        let (query, x) = {
+            // unit(X, "second")
+            let mut builder = QueryBuilder::default();
+            let x = builder.variable();
+            let second = builder.value("second".to_owned());
+            let unit = builder.structure(0, vec![x, second]);
+
+            (builder.build(unit).unwrap(), x)
+        };
+
+        let mut machine = Machine::<String>::default();
+        let result = machine.query(query, &knowledge).unwrap();
+        assert_eq!(Term::Const(5), result.build_term(x, &mut TermBuilder).unwrap());
+
+        let (query, x) = {
+            // pair(X, "b")
+            let mut builder = QueryBuilder::default();
+            let x = builder.variable();
+            let b = builder.value("b".to_owned());
+            let pair = builder.structure(1, vec![x, b]);
+
+            (builder.build(pair).unwrap(), x)
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert_eq!(
+            Term::Struct(2, vec![Term::Value("\"a\"".to_owned())]),
+            result.build_term(x, &mut TermBuilder).unwrap()
+        );
+
+        let query = {
+            // eq(p("a"), p("b"))
+            let mut builder = QueryBuilder::default();
+            let a = builder.value("a".to_owned());
+            let pa = builder.structure(2, vec![a]);
+            let b = builder.value("b".to_owned());
+            let pb = builder.structure(2, vec![b]);
+            let eq = builder.structure(3, vec![pa, pb]);
+
+            builder.build(eq).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(!result.succeeded());
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+        assert_eq!((None, None), report.functors);
+        assert_eq!(vec![0], report.path);
+        assert_eq!(
+            (
+                Term::Value("\"a\"".to_owned()),
+                Term::Value("\"b\"".to_owned())
+            ),
+            report.terms
+        );
+    }
+
+    #[test]
+    fn lists() {
+        // append/3 := 0
+        // a/0 := 1
+        // b/0 := 2
+        // f/1 := 3
+        // eq/2 := 4
+
+        // append([], L, L).
+        let append_nil = {
+            let mut builder = StatementBuilder::new();
+            let nil = builder.nil();
+            let l = builder.variable();
+            let append = builder.structure(0, vec![nil, l, l]);
+            builder.build(append)
+        };
+
+        // append([H|T], L, [H|R]) :- append(T, L, R).
+        let append_cons = {
+            let mut builder = RuleBuilder::new();
+            let h = builder.variable();
+            let t = builder.variable();
+            let l = builder.variable();
+            let r = builder.variable();
+            let ht = builder.cons(h, t);
+            let hr = builder.cons(h, r);
+            let head = builder.structure(0, vec![ht, l, hr]);
+            let append = builder.structure(0, vec![t, l, r]);
+            builder.build(head, vec![append]).unwrap()
+        };
+        assert!(append_cons.assembly().contains("GetList(0)"));
+
+        // eq(X, X).
+        let eq = {
+            let mut builder = StatementBuilder::new();
+            let x = builder.variable();
+            let eq = builder.structure(4, vec![x, x]);
+            builder.build(eq)
+        };
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(append_nil).add(append_cons).add(eq);
+
+        let (query, x, y) = {
+            // append(X, Y, [a, b])
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let a = builder.constant(1);
+            let b = builder.constant(2);
+            let ab = builder.list(vec![a, b]);
+            let append = builder.structure(0, vec![x, y, ab]);
+
+            (builder.build(append).unwrap(), x, y)
+        };
+        assert!(query.assembly().contains("PutList"));
+
+        let mut machine = Machine::new();
+        let result = machine.query(query, &knowledge).unwrap();
+        let solutions: Vec<_> = result
+            .map(|solution| {
+                let solution = solution.unwrap();
+                (
+                    solution.build_term(x, &mut TermBuilder).unwrap(),
+                    solution.build_term(y, &mut TermBuilder).unwrap(),
+                )
+            })
+            .collect();
+
+        // Lists are built as `CONS/2` structures by default
+        let nil = Term::Const(NIL);
+        let cons = |head, tail| Term::Struct(CONS, vec![head, tail]);
+        let a = || cons(Term::Const(1), nil.clone());
+        let b = || cons(Term::Const(2), nil.clone());
+        let ab = || cons(Term::Const(1), b());
+        assert_eq!(
+            vec![(nil.clone(), ab()), (a(), b()), (ab(), nil.clone())],
+            solutions
+        );
+
+        let query = {
+            // eq([a, b], [a, f(b)])
+            let mut builder = QueryBuilder::new();
+            let a = builder.constant(1);
+            let b = builder.constant(2);
+            let ab = builder.list(vec![a, b]);
+            let fb = builder.structure(3, vec![b]);
+            let afb = builder.list(vec![a, fb]);
+            let eq = builder.structure(4, vec![ab, afb]);
+
+            builder.build(eq).unwrap()
+        };
+
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(!result.succeeded());
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+        assert_eq!((Some((2, 0)), Some((3, 1))), report.functors);
+        assert_eq!(vec![1, 0], report.path);
+
+        let query = {
+            // append([a], [], b)
+            let mut builder = QueryBuilder::new();
+            let a = builder.constant(1);
+            let b = builder.constant(2);
+            let list = builder.list(vec![a]);
+            let nil = builder.nil();
+            let append = builder.structure(0, vec![list, nil, b]);
+
+            builder.build(append).unwrap()
+        };
+
+        // List expected by clause head is reported, though it is never
+        // built
+        let result = machine.query(query, &knowledge).unwrap();
+        let report = result.failure_report(&mut TermBuilder).unwrap().unwrap();
+        assert_eq!((Some((2, 0)), Some((CONS, 2))), report.functors);
+        assert!(report.path.is_empty());
+        assert!(matches!(
+            report.terms,
+            (Term::Const(2), Term::Struct(CONS, ref args))
+                if matches!(args[..], [Term::Var(_), Term::Var(_)])
+        ));
+
+        let query = || {
+            // eq(X, [a|X])
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let a = builder.constant(1);
+            let ax = builder.cons(a, x);
+            let eq = builder.structure(4, vec![x, ax]);
+
+            (builder.build(eq).unwrap(), x)
+        };
+
+        // List would contain itself
+        machine.set_occurs_check(OccursCheck::Fail);
+        let result = machine.query(query().0, &knowledge).unwrap();
+        assert!(!result.succeeded());
+
+        machine.set_occurs_check(OccursCheck::Off);
+        let (query, x) = query();
+        let result = machine.query(query, &knowledge).unwrap();
+        match result.build_term(x, &mut TermBuilder).unwrap() {
+            Term::Cyclic(id, term) => assert_eq!(cons(Term::Const(1), Term::Cycle(id)), *term),
+            term => panic!("Expected cyclic term, got {:?}", term),
+        }
+    }
+
+    #[test]
+    fn deep_terms() {
+        // f/1 := 0
+        // g/0 := 1
+        const LEN: isize = 100_000;
+
+        // f(X). g :- f([0, 1, ..., LEN - 1]).
+        let mut builder = StatementBuilder::new();
+        let x = builder.variable();
+        let f = builder.structure(0, vec![x]);
+        let fact = builder.build(f);
+
+        let mut builder = RuleBuilder::new();
+        let items: Vec<_> = (0..LEN).map(|i| builder.integer(i)).collect();
+        let list = builder.list(items);
+        let f = builder.structure(0, vec![list]);
+        let g = builder.constant(1);
+        let rule = builder.build(g, vec![f]).unwrap();
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact).add(rule);
+        let mut machine = Machine::new();
+
+        // f([0, 1, ..., LEN - 1])
+        let mut builder = QueryBuilder::new();
+        let items: Vec<_> = (0..LEN).map(|i| builder.integer(i)).collect();
+        let list = builder.list(items);
+        let query = builder.structure(0, vec![list]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(result.succeeded());
+
+        // g
+        let mut builder = QueryBuilder::new();
+        let query = builder.constant(1);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(result.succeeded());
+    }
+
+    #[test]
+    fn first_argument_indexing() {
+        // impl/2 := 0
+        // a/0 := 1
+        // b/0 := 2
+        // f/1 := 3
+        // any/0 := 4
+
+        let fact = |first: &dyn Fn(&mut StatementBuilder) -> StatementRef, second| {
+            let mut builder = StatementBuilder::new();
+            let first = first(&mut builder);
+            let second = builder.integer(second);
+            let fact = builder.structure(0, vec![first, second]);
+            builder.build(fact)
+        };
+
+        // impl(a, 0). impl(b, 1). impl(1, 2). impl(f(a), 3). impl([], 4).
+        // impl([a], 5). impl(X, 6). impl(f(b), 7). impl(a, 8).
+        let mut knowledge = Knowledge::new();
+        knowledge
+            .add(fact(&|b| b.constant(1), 0))
+            .add(fact(&|b| b.constant(2), 1))
+            .add(fact(&|b| b.integer(1), 2))
+            .add(fact(
+                &|b| {
+                    let a = b.constant(1);
+                    b.structure(3, vec![a])
+                },
+                3,
+            ))
+            .add(fact(&|b| b.nil(), 4))
+            .add(fact(
+                &|b| {
+                    let a = b.constant(1);
+                    b.list(vec![a])
+                },
+                5,
+            ))
+            .add(fact(&|b| b.variable(), 6))
+            .add(fact(
+                &|b| {
+                    let bc = b.constant(2);
+                    b.structure(3, vec![bc])
+                },
+                7,
+            ))
+            .add(fact(&|b| b.constant(1), 8));
+        assert!(knowledge.code().program.assembly().contains("SwitchOnTerm"));
+
+        let query = |first: &dyn Fn(&mut QueryBuilder) -> QueryRef| {
+            let mut builder = QueryBuilder::new();
+            let first = first(&mut builder);
+            let x = builder.variable();
+            let query = builder.structure(0, vec![first, x]);
+            (builder.build(query).unwrap(), x)
+        };
+
+        let mut machine = Machine::new();
+        let mut solutions = |(query, x): (Query<'static>, QueryRef)| -> Vec<Term> {
+            machine
+                .query(query, &knowledge)
+                .unwrap()
+                .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+                .collect()
+        };
+        let ints = |values: &[isize]| -> Vec<Term> { values.iter().map(|v| Term::Int(*v)).collect() };
+
+        // Clauses with variable first argument match every key, in
+        // order of adding them
+        assert_eq!(ints(&[0, 6, 8]), solutions(query(&|b| b.constant(1))));
+        assert_eq!(ints(&[2, 6]), solutions(query(&|b| b.integer(1))));
+        assert_eq!(ints(&[6]), solutions(query(&|b| b.integer(2))));
+        assert_eq!(ints(&[4, 6]), solutions(query(&|b| b.nil())));
+        assert_eq!(
+            ints(&[5, 6]),
+            solutions(query(&|b| {
+                let x = b.variable();
+                b.list(vec![x])
+            }))
+        );
+        assert_eq!(
+            ints(&[3, 6, 7]),
+            solutions(query(&|b| {
+                let x = b.variable();
+                b.structure(3, vec![x])
+            }))
+        );
+        assert_eq!(
+            ints(&[0, 1, 2, 3, 4, 5, 6, 7, 8]),
+            solutions(query(&|b| b.variable()))
+        );
+
+        // Only matching clause is tried, without choice point
+        let mut knowledge = Knowledge::new();
+        knowledge
+            .add(fact(&|b| b.constant(1), 0))
+            .add(fact(&|b| b.constant(2), 1))
+            .add(fact(&|b| b.nil(), 2));
+
+        let (q, x) = query(&|b| b.constant(2));
+        let result = machine.query(q, &knowledge).unwrap();
+        assert_eq!(Term::Int(1), result.build_term(x, &mut TermBuilder).unwrap());
+        drop(result);
+        assert!(machine.choice_points.is_empty());
+
+        let (q, _) = query(&|b| b.constant(4));
+        let result = machine.query(q, &knowledge).unwrap();
+        assert!(!result.succeeded());
+    }
+
+    #[test]
+    fn deep_indexing() {
+        // impl/2 := 0
+        // vec/1 := 1
+        // option/1 := 2
+        // i32/0 := 3
+        // u8/0 := 4
+        // clone/0 := 5
+        // debug/0 := 6
+
+        let fact = |ty: &dyn Fn(&mut StatementBuilder) -> StatementRef, tr| {
+            let mut builder = StatementBuilder::new();
+            let ty = ty(&mut builder);
+            let tr = builder.constant(tr);
+            let fact = builder.structure(0, vec![ty, tr]);
+            builder.build(fact)
+        };
+        let ty = |outer, inner| {
+            move |b: &mut StatementBuilder| {
+                let inner = b.constant(inner);
+                b.structure(outer, vec![inner])
+            }
+        };
+
+        // impl(vec(i32), clone). impl(vec(i32), debug).
+        // impl(vec(u8), clone). impl(option(T), clone). impl(i32, clone).
+        let mut knowledge = Knowledge::new();
+        knowledge
+            .add(fact(&ty(1, 3), 5))
+            .add(fact(&ty(1, 3), 6))
+            .add(fact(&ty(1, 4), 5))
+            .add(fact(
+                &|b| {
+                    let t = b.variable();
+                    b.structure(2, vec![t])
+                },
+                5,
+            ))
+            .add(fact(&|b| b.constant(3), 5));
+        assert!(knowledge.code().program.assembly().contains("SwitchOnHead"));
+
+        let mut machine = Machine::new();
+
+        // impl(vec(i32), clone) retrieves only clause which matches
+        let query = {
+            let mut builder = QueryBuilder::new();
+            let i32 = builder.constant(3);
+            let vec = builder.structure(1, vec![i32]);
+            let clone = builder.constant(5);
+            let query = builder.structure(0, vec![vec, clone]);
+            builder.build(query).unwrap()
+        };
+        let result = machine.query(query, &knowledge).unwrap();
+        assert!(result.succeeded());
+        drop(result);
+        assert!(machine.choice_points.is_empty());
+
+        let mut solutions = |ty: &dyn Fn(&mut QueryBuilder) -> QueryRef, tr: Option<usize>| {
+            let mut builder = QueryBuilder::new();
+            let ty = ty(&mut builder);
+            let tr = match tr {
+                Some(tr) => builder.constant(tr),
+                None => builder.variable(),
+            };
+            let query = builder.structure(0, vec![ty, tr]);
+            let query = builder.build(query).unwrap();
+
+            machine
+                .query(query, &knowledge)
+                .unwrap()
+                .map(|solution| {
+                    let solution = solution.unwrap();
+                    (
+                        solution.build_term(ty, &mut TermBuilder).unwrap(),
+                        solution.build_term(tr, &mut TermBuilder).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let vec = |inner| Term::Struct(1, vec![Term::Const(inner)]);
+
+        // impl(vec(T), clone)
+        let vec_var = |b: &mut QueryBuilder| {
+            let t = b.variable();
+            b.structure(1, vec![t])
+        };
+        assert_eq!(
+            vec![(vec(3), Term::Const(5)), (vec(4), Term::Const(5))],
+            solutions(&vec_var, Some(5))
+        );
+
+        // impl(T, debug)
+        assert_eq!(
+            vec![(vec(3), Term::Const(6))],
+            solutions(&|b| b.variable(), Some(6))
+        );
+
+        // impl(vec(i32), Trait)
+        assert_eq!(
+            vec![(vec(3), Term::Const(5)), (vec(3), Term::Const(6))],
+            solutions(
+                &|b| {
+                    let i32 = b.constant(3);
+                    b.structure(1, vec![i32])
+                },
+                None
+            )
+        );
+
+        // impl(option(u8), clone)
+        assert_eq!(
+            vec![(Term::Struct(2, vec![Term::Const(4)]), Term::Const(5))],
+            solutions(
+                &|b| {
+                    let u8 = b.constant(4);
+                    b.structure(2, vec![u8])
+                },
+                Some(5)
+            )
+        );
+
+        // impl(vec(u8), debug)
+        assert!(solutions(
+            &|b| {
+                let u8 = b.constant(4);
+                b.structure(1, vec![u8])
+            },
+            Some(6)
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn last_call_optimization() {
+        use crate::builtin::GT;
+
+        // count/1 := 0
+
+        // count(0).
+        let mut builder = StatementBuilder::new();
+        let zero = builder.integer(0);
+        let count = builder.structure(0, vec![zero]);
+        let fact = builder.build(count);
+
+        // count(N) :- N > 0, M is N - 1, count(M).
+        let mut builder = RuleBuilder::new();
+        let n = builder.variable();
+        let m = builder.variable();
+        let count = builder.structure(0, vec![n]);
+        let zero = builder.integer(0);
+        let positive = builder.structure(GT, vec![n, zero]);
+        let one = builder.integer(1);
+        let pred = builder.structure(SUB, vec![n, one]);
+        let is = builder.structure(IS, vec![m, pred]);
+        let next = builder.structure(0, vec![m]);
+        let rule = builder.build(count, vec![positive, is, next]).unwrap();
+
+        // `M` is used longer, so `N` is trimmed after `is/2`, and
+        // recursive call is the last one
+        let assembly = rule.assembly();
+        assert!(assembly.contains(&format!("Call({}, 2, 2)", GT)));
+        assert!(assembly.contains(&format!("Call({}, 2, 1)", IS)));
+        assert!(assembly.contains("Execute(0, 1)"));
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact).add(rule);
+
+        // count(100000)
+        let mut builder = QueryBuilder::new();
+        let n = builder.integer(100000);
+        let query = builder.structure(0, vec![n]);
+        let query = builder.build(query).unwrap();
+
+        let mut machine = Machine::new();
+        assert!(machine.query(query, &knowledge).unwrap().succeeded());
+        // Only query and last rule environments are left
+        assert!(machine.storage.stack_len() <= 2);
+    }
+
+    #[test]
+    fn cut_and_if_then_else() {
+        use crate::builtin::{FAIL, TRUE};
+
+        // color/1 := 0
+        // red/0 := 1
+        // green/0 := 2
+        // blue/0 := 3
+        // eq/2 := 4
+        // first/1 := 5
+        // max/3 := 6
+        // pick/1 := 7
+        // none/0 := 8
+        // classify/2 := 9
+        // warm/0 := 10
+        // cold/0 := 11
+
+        let mut knowledge = Knowledge::new();
+
+        // color(red). color(green). color(blue).
+        for color in 1..=3 {
+            let mut builder = StatementBuilder::new();
+            let color = builder.constant(color);
+            let fact = builder.structure(0, vec![color]);
+            knowledge.add(builder.build(fact));
+        }
+
+        // eq(X, X).
+        let mut builder = StatementBuilder::new();
+        let x = builder.variable();
+        let eq = builder.structure(4, vec![x, x]);
+        knowledge.add(builder.build(eq));
+
+        // first(X) :- color(X), !.
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(5, vec![x]);
+        let color = builder.structure(0, vec![x]);
+        let cut = builder.cut();
+        let first = builder.build(head, vec![color, cut]).unwrap();
+        assert!(first.assembly().contains("GetLevel(Y(0))"));
+        assert!(first.assembly().contains("Cut(Y(0))"));
+        knowledge.add(first);
+
+        // max(X, Y, Z) :- (X >= Y -> Z is X ; Z is Y).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let z = builder.variable();
+        let head = builder.structure(6, vec![x, y, z]);
+        let ge = builder.structure(GE, vec![x, y]);
+        let zx = builder.structure(IS, vec![z, x]);
+        let zy = builder.structure(IS, vec![z, y]);
+        let ite = builder.if_then_else(ge, zx, zy);
+        let max = builder.build(head, vec![ite]).unwrap();
+        assert!(max.assembly().contains("TryElse"));
+        knowledge.add(max);
+
+        // pick(X) :- (color(X), eq(X, green), ! ; eq(X, none)).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(7, vec![x]);
+        let color = builder.structure(0, vec![x]);
+        let green = builder.constant(2);
+        let is_green = builder.structure(4, vec![x, green]);
+        let cut = builder.cut();
+        let left = builder.conjunction(vec![color, is_green, cut]);
+        let none = builder.constant(8);
+        let right = builder.structure(4, vec![x, none]);
+        let either = builder.disjunction(left, right);
+        knowledge.add(builder.build(head, vec![either]).unwrap());
+
+        // classify(red, X) :- !, eq(X, warm).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let red = builder.constant(1);
+        let head = builder.structure(9, vec![red, x]);
+        let cut = builder.cut();
+        let warm = builder.constant(10);
+        let eq = builder.structure(4, vec![x, warm]);
+        let classify = builder.build(head, vec![cut, eq]).unwrap();
+        assert!(classify.assembly().contains("NeckCut"));
+        knowledge.add(classify);
+
+        // classify(C, cold).
+        let mut builder = StatementBuilder::new();
+        let c = builder.variable();
+        let cold = builder.constant(11);
+        let classify = builder.structure(9, vec![c, cold]);
+        knowledge.add(builder.build(classify));
+
+        let mut machine = Machine::new();
+        let mut solutions = |query: &dyn Fn(&mut QueryBuilder) -> (QueryRef, QueryRef)| {
+            let mut builder = QueryBuilder::new();
+            let (goal, x) = query(&mut builder);
+            machine
+                .query(builder.build(goal).unwrap(), &knowledge)
+                .unwrap()
+                .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // first(X)
+        assert_eq!(
+            vec![Term::Const(1)],
+            solutions(&|b| {
+                let x = b.variable();
+                (b.structure(5, vec![x]), x)
+            })
+        );
+
+        // max(3, 5, Z), max(7, 2, Z)
+        for (x, y, max) in [(3, 5, 5), (7, 2, 7)] {
+            assert_eq!(
+                vec![Term::Int(max)],
+                solutions(&|b| {
+                    let x = b.integer(x);
+                    let y = b.integer(y);
+                    let z = b.variable();
+                    (b.structure(6, vec![x, y, z]), z)
+                })
+            );
+        }
+
+        // pick(X)
+        assert_eq!(
+            vec![Term::Const(2)],
+            solutions(&|b| {
+                let x = b.variable();
+                (b.structure(7, vec![x]), x)
+            })
+        );
+
+        // classify(red, X), classify(blue, X)
+        for (color, class) in [(1, 10), (3, 11)] {
+            assert_eq!(
+                vec![Term::Const(class)],
+                solutions(&|b| {
+                    let color = b.constant(color);
+                    let x = b.variable();
+                    (b.structure(9, vec![color, x]), x)
+                })
+            );
+        }
+
+        // color(X), !
+        assert_eq!(
+            vec![Term::Const(1)],
+            solutions(&|b| {
+                let x = b.variable();
+                let color = b.structure(0, vec![x]);
+                let cut = b.cut();
+                (b.conjunction(vec![color, cut]), x)
+            })
+        );
+
+        // color(X), (eq(X, green) ; eq(X, blue))
+        assert_eq!(
+            vec![Term::Const(2), Term::Const(3)],
+            solutions(&|b| {
+                let x = b.variable();
+                let color = b.structure(0, vec![x]);
+                let green = b.constant(2);
+                let blue = b.constant(3);
+                let is_green = b.structure(4, vec![x, green]);
+                let is_blue = b.structure(4, vec![x, blue]);
+                let either = b.disjunction(is_green, is_blue);
+                (b.conjunction(vec![color, either]), x)
+            })
+        );
+
+        // Cut in condition is local to it, so else branch is proven
+        // ((color(X), !, fail) -> true ; eq(X, none))
+        assert_eq!(
+            vec![Term::Const(8)],
+            solutions(&|b| {
+                let x = b.variable();
+                let color = b.structure(0, vec![x]);
+                let cut = b.cut();
+                let fail = b.constant(FAIL);
+                let cond = b.conjunction(vec![color, cut, fail]);
+                let then = b.constant(TRUE);
+                let none = b.constant(8);
+                let otherwise = b.structure(4, vec![x, none]);
+                (b.if_then_else(cond, then, otherwise), x)
+            })
+        );
+
+        // (color(X) -> true)
+        assert_eq!(
+            vec![Term::Const(1)],
+            solutions(&|b| {
+                let x = b.variable();
+                let color = b.structure(0, vec![x]);
+                let then = b.constant(TRUE);
+                (b.if_then(color, then), x)
+            })
+        );
+    }
+
+    #[test]
+    fn non_callable_goals() {
+        use crate::builtin::TRUE;
+
+        // p/0 := 0
+
+        // p :- 1.
+        let mut builder = RuleBuilder::new();
+        let head = builder.constant(0);
+        let one = builder.integer(1);
+        assert_eq!(Err(Error::NotCallable), builder.build(head, vec![one]).map(|_| ()));
+
+        // p :- true ; [true].
+        let mut builder = RuleBuilder::new();
+        let head = builder.constant(0);
+        let t = builder.constant(TRUE);
+        let nil = builder.nil();
+        let list = builder.cons(t, nil);
+        let or = builder.disjunction(t, list);
+        assert_eq!(Err(Error::NotCallable), builder.build(head, vec![or]).map(|_| ()));
+
+        // ?- \+ [X].
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let nil = builder.nil();
+        let list = builder.cons(x, nil);
+        let not = builder.negation(list);
+        assert_eq!(Err(Error::NotCallable), builder.build(not).map(|_| ()));
+    }
+
+    #[test]
+    fn cut_in_called_goals() {
+        use crate::builtin::{AND, CALL, CUT, FAIL, OR, TRUE};
+
+        // eq/2 := 0
+        // r/1 := 1
+
+        let mut knowledge = Knowledge::new();
+
+        // eq(X, X).
+        let mut builder = StatementBuilder::new();
+        let x = builder.variable();
+        let eq = builder.structure(0, vec![x, x]);
+        knowledge.add(builder.build(eq));
+
+        // r(G) :- G.
+        let mut builder = RuleBuilder::new();
+        let g = builder.variable();
+        let head = builder.structure(1, vec![g]);
+        knowledge.add(builder.build(head, vec![g]).unwrap());
+
+        let mut machine = Machine::new();
+
+        // call(((eq(Y, 1) ; eq(Y, 2)), !)), r(((eq(Y, 1) ; eq(Y, 2)), !))
+        for wrapper in [CALL, 1] {
+            let mut builder = QueryBuilder::new();
+            let y = builder.variable();
+            let one = builder.integer(1);
+            let two = builder.integer(2);
+            let eq_one = builder.structure(0, vec![y, one]);
+            let eq_two = builder.structure(0, vec![y, two]);
+            let or = builder.structure(OR, vec![eq_one, eq_two]);
+            let cut = builder.constant(CUT);
+            let goal = builder.structure(AND, vec![or, cut]);
+            let query = builder.structure(wrapper, vec![goal]);
+            let solutions: Vec<_> = machine
+                .query(builder.build(query).unwrap(), &knowledge)
+                .unwrap()
+                .map(|solution| solution.unwrap().build_term(y, &mut TermBuilder).unwrap())
+                .collect();
+            assert_eq!(vec![Term::Int(1)], solutions);
+        }
+
+        // call((!, fail ; true))
+        let mut builder = QueryBuilder::new();
+        let cut = builder.constant(CUT);
+        let fail = builder.constant(FAIL);
+        let t = builder.constant(TRUE);
+        let and = builder.structure(AND, vec![cut, fail]);
+        let or = builder.structure(OR, vec![and, t]);
+        let query = builder.structure(CALL, vec![or]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(!result.succeeded());
+
+        // Cut is local to called goal
+        // (call(!), fail ; true)
+        let mut builder = QueryBuilder::new();
+        let cut = builder.constant(CUT);
+        let call = builder.structure(CALL, vec![cut]);
+        let fail = builder.constant(FAIL);
+        let t = builder.constant(TRUE);
+        let and = builder.conjunction(vec![call, fail]);
+        let query = builder.disjunction(and, t);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(result.succeeded());
+    }
+
+    #[test]
+    fn negation() {
+        use crate::builtin::{FAIL, TRUE};
+
+        // p/1 := 0
+        // q/1 := 1
+        // r/1 := 2
+        // a/0 := 3
+        // b/0 := 4
+        // c/0 := 5
+        // s/1 := 6
+
+        // p(a). p(b). q(b).
+        let fact = |ident, arg| {
+            let mut builder = StatementBuilder::new();
+            let arg = builder.constant(arg);
+            let fact = builder.structure(ident, vec![arg]);
+            builder.build(fact)
+        };
+
+        // r(X) :- p(X), \+ q(X).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(2, vec![x]);
+        let p = builder.structure(0, vec![x]);
+        let q = builder.structure(1, vec![x]);
+        let not_q = builder.negation(q);
+        let r = builder.build(head, vec![p, not_q]).unwrap();
+
+        let mut knowledge = Knowledge::new();
+        knowledge.add(fact(0, 3)).add(fact(0, 4)).add(fact(1, 4)).add(r);
+        assert_eq!(Ok(()), knowledge.check_stratification());
+
+        let mut machine = Machine::new();
+
+        // r(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let query = builder.structure(2, vec![x]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        assert_eq!(vec![Term::Const(3)], solutions);
+
+        // \+ p(c), \+ p(a)
+        for (arg, succeeded) in [(5, true), (3, false)] {
+            let mut builder = QueryBuilder::new();
+            let arg = builder.constant(arg);
+            let p = builder.structure(0, vec![arg]);
+            let query = builder.negation(p);
+            let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+            assert_eq!(succeeded, result.succeeded());
+        }
+
+        // Bindings made by negated goal are dropped
+        // \+ \+ p(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let not_p = builder.negation(p);
+        let query = builder.negation(not_p);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(matches!(result.build_term(x, &mut TermBuilder), Ok(Term::Var(_))));
+
+        // s(X) :- p(X), \+ s(X).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(6, vec![x]);
+        let p = builder.structure(0, vec![x]);
+        let s = builder.structure(6, vec![x]);
+        let not_s = builder.negation(s);
+        knowledge.add(builder.build(head, vec![p, not_s]).unwrap());
+        assert_eq!(Err(Error::NotStratified(6, 1)), knowledge.check_stratification());
+
+        // Negative dependency through if-then-else condition and other
+        // predicate
+        // q(X) :- (r(X) -> fail ; true).
+        let mut knowledge = Knowledge::new();
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(1, vec![x]);
+        let r = builder.structure(2, vec![x]);
+        let fail = builder.constant(FAIL);
+        let t = builder.constant(TRUE);
+        let ite = builder.if_then_else(r, fail, t);
+        knowledge.add(fact(0, 3)).add(builder.build(head, vec![ite]).unwrap());
+        assert_eq!(Ok(()), knowledge.check_stratification());
+
+        // r(X) :- p(X), q(X).
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let head = builder.structure(2, vec![x]);
+        let p = builder.structure(0, vec![x]);
+        let q = builder.structure(1, vec![x]);
+        knowledge.add(builder.build(head, vec![p, q]).unwrap());
+        assert_eq!(Err(Error::NotStratified(1, 1)), knowledge.check_stratification());
+    }
+
+    #[test]
+    fn dif() {
+        use crate::builtin::DIF;
+
+        // eq/2 := 0
+        // p/1 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // f/1 := 4
+
+        // eq(X, X). p(a). p(b).
+        let mut knowledge = Knowledge::new();
+        let mut builder = StatementBuilder::new();
+        let x = builder.variable();
+        let eq = builder.structure(0, vec![x, x]);
+        knowledge.add(builder.build(eq));
+        for arg in [2, 3] {
+            let mut builder = StatementBuilder::new();
+            let arg = builder.constant(arg);
+            let p = builder.structure(1, vec![arg]);
+            knowledge.add(builder.build(p));
+        }
+
+        let mut machine = Machine::new();
+
+        // dif(X, Y), eq(X, a), eq(Y, Other)
+        for (other, succeeded) in [(2, false), (3, true)] {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let dif = builder.structure(DIF, vec![x, y]);
+            let a = builder.constant(2);
+            let eq_x = builder.structure(0, vec![x, a]);
+            let other = builder.constant(other);
+            let eq_y = builder.structure(0, vec![y, other]);
+            let query = builder.conjunction(vec![dif, eq_x, eq_y]);
+            let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+            assert_eq!(succeeded, result.succeeded());
+        }
+
+        // Binding variables to each other makes terms identical
+        // dif(f(X), f(Y)), eq(X, Y)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let fx = builder.structure(4, vec![x]);
+        let fy = builder.structure(4, vec![y]);
+        let dif = builder.structure(DIF, vec![fx, fy]);
+        let eq = builder.structure(0, vec![x, y]);
+        let query = builder.conjunction(vec![dif, eq]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(!result.succeeded());
+
+        // dif(a, b), dif(X, X)
+        for (left, right, succeeded) in [(Some(2), Some(3), true), (None, None, false)] {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let mut arg = |arg| match arg {
+                Some(ident) => builder.constant(ident),
+                None => x,
+            };
+            let (left, right) = (arg(left), arg(right));
+            let query = builder.structure(DIF, vec![left, right]);
+            let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+            assert_eq!(succeeded, result.succeeded());
+        }
+
+        // Suspended constraint is dropped on backtracking with binding
+        // which woke it
+        // dif(X, a), p(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let a = builder.constant(2);
+        let dif = builder.structure(DIF, vec![x, a]);
+        let p = builder.structure(1, vec![x]);
+        let query = builder.conjunction(vec![dif, p]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        assert_eq!(vec![Term::Const(3)], solutions);
+    }
+
+    #[test]
+    fn finite_domains() {
+        use crate::builtin::{ALL_DIFFERENT, FD_EQ, FD_GT, FD_LT, IN, LABEL, RANGE};
+
+        let knowledge = Knowledge::new();
+        let mut machine = Machine::new();
+
+        // N in 1..64, N #= M * 2
+        let mut builder = QueryBuilder::new();
+        let n = builder.variable();
+        let m = builder.variable();
+        let lo = builder.integer(1);
+        let hi = builder.integer(64);
+        let range = builder.structure(RANGE, vec![lo, hi]);
+        let domain = builder.structure(IN, vec![n, range]);
+        let two = builder.integer(2);
+        let double = builder.structure(MUL, vec![m, two]);
+        let eq = builder.structure(FD_EQ, vec![n, double]);
+        let query = builder.conjunction(vec![domain, eq]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+
+        // Both variables stay unbound, with narrowed domains
+        let (n, m) = match (
+            result.build_term(n, &mut TermBuilder),
+            result.build_term(m, &mut TermBuilder),
+        ) {
+            (Ok(Term::Var(n)), Ok(Term::Var(m))) => (n, m),
+            terms => panic!("Unexpected terms {:?}", terms),
+        };
+        let range = |lo, hi| Term::Struct(RANGE, vec![Term::Int(lo), Term::Int(hi)]);
+        let expected = vec![
+            Term::Struct(IN, vec![Term::Var(n), range(2, 64)]),
+            Term::Struct(IN, vec![Term::Var(m), range(1, 32)]),
+            Term::Struct(
+                FD_EQ,
+                vec![Term::Var(n), Term::Struct(MUL, vec![Term::Var(m), Term::Int(2)])],
+            ),
+        ];
+        assert_eq!(Ok(expected), result.residual_goals(&mut TermBuilder));
+
+        // X in 1..3, Y in 1..3, Z in 1..3, all_different([X, Y, Z]),
+        // X #< Y, label([X, Y, Z])
+        let mut builder = QueryBuilder::new();
+        let vars: Vec<_> = (0..3).map(|_| builder.variable()).collect();
+        let mut goals = vec![];
+        for var in &vars {
+            let lo = builder.integer(1);
+            let hi = builder.integer(3);
+            let range = builder.structure(RANGE, vec![lo, hi]);
+            goals.push(builder.structure(IN, vec![*var, range]));
+        }
+        let list = builder.list(vars.clone());
+        goals.push(builder.structure(ALL_DIFFERENT, vec![list]));
+        goals.push(builder.structure(FD_LT, vec![vars[0], vars[1]]));
+        goals.push(builder.structure(LABEL, vec![list]));
+        let query = builder.conjunction(goals);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| {
+                let solution = solution.unwrap();
+                assert_eq!(Ok(vec![]), solution.residual_goals(&mut TermBuilder));
+                vars.iter()
+                    .map(|var| solution.build_term(*var, &mut TermBuilder).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let expected: Vec<_> = [[1, 2, 3], [1, 3, 2], [2, 3, 1]]
+            .iter()
+            .map(|values| values.iter().map(|value| Term::Int(*value)).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(expected, solutions);
+
+        // N in 1..64, N #= M * 2, M #> 30, label([N])
+        let mut builder = QueryBuilder::new();
+        let n = builder.variable();
+        let m = builder.variable();
+        let lo = builder.integer(1);
+        let hi = builder.integer(64);
+        let range = builder.structure(RANGE, vec![lo, hi]);
+        let domain = builder.structure(IN, vec![n, range]);
+        let two = builder.integer(2);
+        let double = builder.structure(MUL, vec![m, two]);
+        let eq = builder.structure(FD_EQ, vec![n, double]);
+        let thirty = builder.integer(30);
+        let gt = builder.structure(FD_GT, vec![m, thirty]);
+        let list = builder.list(vec![n]);
+        let label = builder.structure(LABEL, vec![list]);
+        let query = builder.conjunction(vec![domain, eq, gt, label]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(m, &mut TermBuilder).unwrap())
+            .collect();
+        assert_eq!(vec![Term::Int(31), Term::Int(32)], solutions);
+
+        // Labeling variable without finite domain is an error
+        // label([X])
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let list = builder.list(vec![x]);
+        let query = builder.structure(LABEL, vec![list]);
+        assert!(matches!(
+            machine.query(builder.build(query).unwrap(), &knowledge),
+            Err(Error::Instantiation)
+        ));
+    }
+
+    #[test]
+    fn rationals() {
+        use crate::builtin::{EQ, GT, LE, LINEAR, RDIV};
+
+        // bigger/1 := 0
+
+        // bigger(X) :- {X > Y + 1}, {Y >= 3}.
+        let mut knowledge = Knowledge::new();
+        let mut builder = RuleBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let head = builder.structure(0, vec![x]);
+        let one = builder.integer(1);
+        let sum = builder.structure(ADD, vec![y, one]);
+        let gt = builder.structure(GT, vec![x, sum]);
+        let gt = builder.structure(LINEAR, vec![gt]);
+        let three = builder.integer(3);
+        let ge = builder.structure(GE, vec![y, three]);
+        let ge = builder.structure(LINEAR, vec![ge]);
+        knowledge.add(builder.build(head, vec![gt, ge]).unwrap());
+
+        let mut machine = Machine::new();
+        let constraint = |ident, left, right| {
+            Term::Struct(LINEAR, vec![Term::Struct(ident, vec![left, right])])
+        };
+
+        // Equalities determine both variables
+        // {X + Y =:= 10}, {X - Y =:= 2}
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let ten = builder.integer(10);
+        let sum = builder.structure(ADD, vec![x, y]);
+        let sum = builder.structure(EQ, vec![sum, ten]);
+        let sum = builder.structure(LINEAR, vec![sum]);
+        let two = builder.integer(2);
+        let diff = builder.structure(SUB, vec![x, y]);
+        let diff = builder.structure(EQ, vec![diff, two]);
+        let diff = builder.structure(LINEAR, vec![diff]);
+        let query = builder.conjunction(vec![sum, diff]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert_eq!(Ok(Term::Int(6)), result.build_term(x, &mut TermBuilder));
+        assert_eq!(Ok(Term::Int(4)), result.build_term(y, &mut TermBuilder));
+        assert_eq!(Ok(vec![]), result.residual_goals(&mut TermBuilder));
+
+        // Variable of rule is projected out
+        // bigger(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let query = builder.structure(0, vec![x]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        let x = match result.build_term(x, &mut TermBuilder) {
+            Ok(Term::Var(x)) => x,
+            term => panic!("Unexpected term {:?}", term),
+        };
+        let expected = vec![constraint(GT, Term::Var(x), Term::Int(4))];
+        assert_eq!(Ok(expected), result.residual_goals(&mut TermBuilder));
+
+        // Variable bounded to single value is bound to it, and so is
+        // variable which it determines
+        // {2 * X =:= Y}, {Y =:= 3}
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let two = builder.integer(2);
+        let double = builder.structure(MUL, vec![two, x]);
+        let double = builder.structure(EQ, vec![double, y]);
+        let double = builder.structure(LINEAR, vec![double]);
+        let three = builder.integer(3);
+        let eq = builder.structure(EQ, vec![y, three]);
+        let eq = builder.structure(LINEAR, vec![eq]);
+        let query = builder.conjunction(vec![double, eq]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert_eq!(Ok(Term::Int(3)), result.build_term(y, &mut TermBuilder));
+        let half = Term::Struct(RDIV, vec![Term::Int(3), Term::Int(2)]);
+        assert_eq!(Ok(half), result.build_term(x, &mut TermBuilder));
+        assert_eq!(Ok(vec![]), result.residual_goals(&mut TermBuilder));
+
+        // Bounds of failed branch are restored on backtracking
+        // ({X > 3} ; {X =< 1}), {X =< 2}
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let one = builder.integer(1);
+        let le = builder.structure(LE, vec![x, one]);
+        let le = builder.structure(LINEAR, vec![le]);
+        let three = builder.integer(3);
+        let gt = builder.structure(GT, vec![x, three]);
+        let gt = builder.structure(LINEAR, vec![gt]);
+        let either = builder.disjunction(gt, le);
+        let two = builder.integer(2);
+        let bound = builder.structure(LE, vec![x, two]);
+        let bound = builder.structure(LINEAR, vec![bound]);
+        let query = builder.conjunction(vec![either, bound]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().residual_goals(&mut TermBuilder).unwrap().len())
+            .collect();
+        assert_eq!(vec![1], solutions);
+
+        // {X < Y}, {Y < X}
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let lt = builder.structure(LT, vec![x, y]);
+        let lt = builder.structure(LINEAR, vec![lt]);
+        let gt = builder.structure(GT, vec![x, y]);
+        let gt = builder.structure(LINEAR, vec![gt]);
+        let query = builder.conjunction(vec![lt, gt]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(!result.succeeded());
+    }
+
+    #[test]
+    fn booleans() {
+        use crate::builtin::{EQ, LABELING, LE, SAT, TAUT, XOR};
+
+        let knowledge = Knowledge::new();
+        let mut machine = Machine::new();
+
+        // sat(X * Y)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let and = builder.structure(MUL, vec![x, y]);
+        let query = builder.structure(SAT, vec![and]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert_eq!(Ok(Term::Int(1)), result.build_term(x, &mut TermBuilder));
+        assert_eq!(Ok(Term::Int(1)), result.build_term(y, &mut TermBuilder));
+        assert_eq!(Ok(vec![]), result.residual_goals(&mut TermBuilder));
+
+        // sat(X # Y), labeling([X, Y])
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let xor = builder.structure(XOR, vec![x, y]);
+        let sat = builder.structure(SAT, vec![xor]);
+        let list = builder.list(vec![x, y]);
+        let labeling = builder.structure(LABELING, vec![list]);
+        let query = builder.conjunction(vec![sat, labeling]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| {
+                let solution = solution.unwrap();
+                [x, y].map(|var| solution.build_term(var, &mut TermBuilder).unwrap())
+            })
+            .collect();
+        let expected = vec![
+            [Term::Int(0), Term::Int(1)],
+            [Term::Int(1), Term::Int(0)],
+        ];
+        assert_eq!(expected, solutions);
+
+        // sat(X =< Y), sat(Y =< Z), taut(X =< Z, T)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let z = builder.variable();
+        let t = builder.variable();
+        let xy = builder.structure(LE, vec![x, y]);
+        let xy = builder.structure(SAT, vec![xy]);
+        let yz = builder.structure(LE, vec![y, z]);
+        let yz = builder.structure(SAT, vec![yz]);
+        let xz = builder.structure(LE, vec![x, z]);
+        let taut = builder.structure(TAUT, vec![xz, t]);
+        let query = builder.conjunction(vec![xy, yz, taut]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert_eq!(Ok(Term::Int(1)), result.build_term(t, &mut TermBuilder));
+        assert_eq!(2, result.residual_goals(&mut TermBuilder).unwrap().len());
+
+        // sat(X # Y), sat(X =:= Y)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let xor = builder.structure(XOR, vec![x, y]);
+        let xor = builder.structure(SAT, vec![xor]);
+        let eq = builder.structure(EQ, vec![x, y]);
+        let eq = builder.structure(SAT, vec![eq]);
+        let query = builder.conjunction(vec![xor, eq]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        assert!(!result.succeeded());
+    }
+
+    #[test]
+    fn attribute_hooks() {
+        use crate::builtin::{AND, CALL, GET_ATTR, IF, NOT, OR, PUT_ATTR, TRUE};
+
+        // p/1 := 0
+        // is_atom/1 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // c/0 := 4
+        // kind/0 := 5
+        // atom/0 := 6
+        // other/0 := 7
+        // q/1 := 8
+        // r/1 := 9
+
+        // p(a). p(1). p(c). p(b). is_atom(a). is_atom(b). q(b).
+        let mut knowledge = Knowledge::new();
+        for (ident, arg) in [(0, 2), (0, -1), (0, 4), (0, 3), (1, 2), (1, 3), (8, 3)] {
+            let mut builder = StatementBuilder::new();
+            let arg = if arg < 0 {
+                builder.integer(1)
+            } else {
+                builder.constant(arg as usize)
+            };
+            let fact = builder.structure(ident, vec![arg]);
+            knowledge.add(builder.build(fact));
+        }
+
+        let mut machine = Machine::new();
+
+        // call((p(X), \+ q(X)))
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let q = builder.structure(8, vec![x]);
+        let not_q = builder.structure(NOT, vec![q]);
+        let goal = builder.structure(AND, vec![p, not_q]);
+        let query = builder.structure(CALL, vec![goal]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        let expected = vec![Term::Const(2), Term::Int(1), Term::Const(4)];
+        assert_eq!(expected, solutions);
+
+        // call((p(X) -> true ; fail))
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let t = builder.constant(TRUE);
+        let f = builder.constant(crate::builtin::FAIL);
+        let cond = builder.structure(IF, vec![p, t]);
+        let goal = builder.structure(OR, vec![cond, f]);
+        let query = builder.structure(CALL, vec![goal]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        assert_eq!(vec![Term::Const(2)], solutions);
+
+        // call(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let query = builder.structure(CALL, vec![x]);
+        assert_eq!(
+            Some(Error::Instantiation),
+            machine.query(builder.build(query).unwrap(), &knowledge).err()
+        );
+
+        // X
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        assert_eq!(
+            Some(Error::Instantiation),
+            machine.query(builder.build(x).unwrap(), &knowledge).err()
+        );
+
+        // r(G) :- G, true.
+        let mut builder = RuleBuilder::new();
+        let g = builder.variable();
+        let head = builder.structure(9, vec![g]);
+        let t = builder.constant(TRUE);
+        knowledge.add(builder.build(head, vec![g, t]).unwrap());
+
+        // r(p(X))
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let query = builder.structure(9, vec![p]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        let expected = vec![Term::Const(2), Term::Int(1), Term::Const(4), Term::Const(3)];
+        assert_eq!(expected, solutions);
+
+        // Variables of kind `atom` may only be bound to atoms, checked
+        // with `is_atom/1` goal, and never to integers
+        machine.add_attribute_hook(5, |attributes, kind, value| {
+            match attributes.build_term(value, &mut TermBuilder)? {
+                Term::Var(_) => {
+                    if attributes.get(value, 5)?.is_none() {
+                        attributes.put(value, 5, Some(kind))?;
+                    }
+                    Ok(true)
+                }
+                Term::Int(_) => Ok(false),
+                _ => {
+                    let goal = attributes.structure(1, vec![value]);
+                    attributes.add_goal(goal);
+                    Ok(true)
+                }
+            }
+        });
+
+        // put_attr(X, kind, atom), p(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let kind = builder.constant(5);
+        let atom = builder.constant(6);
+        let put = builder.structure(PUT_ATTR, vec![x, kind, atom]);
+        let p = builder.structure(0, vec![x]);
+        let query = builder.conjunction(vec![put, p]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| solution.unwrap().build_term(x, &mut TermBuilder).unwrap())
+            .collect();
+        assert_eq!(vec![Term::Const(2), Term::Const(3)], solutions);
+
+        // put_attr(X, kind, atom), put_attr(Y, other, V),
+        // get_attr(Y, other, X), get_attr(X, kind, K), true
+        for (value, succeeded) in [(2, true), (4, false)] {
+            let mut builder = QueryBuilder::new();
+            let x = builder.variable();
+            let y = builder.variable();
+            let k = builder.variable();
+            let kind = builder.constant(5);
+            let atom = builder.constant(6);
+            let other = builder.constant(7);
+            let value = builder.constant(value);
+            let put_x = builder.structure(PUT_ATTR, vec![x, kind, atom]);
+            let put_y = builder.structure(PUT_ATTR, vec![y, other, value]);
+            let get_y = builder.structure(GET_ATTR, vec![y, other, x]);
+            let get_x = builder.structure(GET_ATTR, vec![x, kind, k]);
+            let t = builder.constant(TRUE);
+            let query = builder.conjunction(vec![put_x, get_x, put_y, get_y, t]);
+            let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+            assert_eq!(succeeded, result.succeeded());
+            if succeeded {
+                assert_eq!(Ok(Term::Const(6)), result.build_term(k, &mut TermBuilder));
+            }
+        }
+    }
+
+    #[test]
+    fn machine_is_send() {
+        fn send<T: Send>() {}
+        send::<Machine>();
+    }
+
+    #[test]
+    fn knowledge_is_sync() {
+        fn sync<T: Send + Sync>() {}
+        sync::<Knowledge<'static>>();
+    }
+
+    #[test]
+    fn delayed_goals() {
+        use crate::builtin::{DECIDED, FREEZE, GROUND, NONVAR, OR, TRUE, WHEN};
+
+        // p/1 := 0
+        // q/1 := 1
+        // a/0 := 2
+        // b/0 := 3
+        // same/2 := 4
+        // foo/0 := 5
+
+        // p(a). p(b). q(b). same(X, X).
+        let mut knowledge = Knowledge::new();
+        for (ident, arg) in [(0, 2), (0, 3), (1, 3)] {
+            let mut builder = StatementBuilder::new();
+            let arg = builder.constant(arg);
+            let fact = builder.structure(ident, vec![arg]);
+            knowledge.add(builder.build(fact));
+        }
+        let mut builder = StatementBuilder::new();
+        let x = builder.variable();
+        let same = builder.structure(4, vec![x, x]);
+        knowledge.add(builder.build(same));
+
+        let mut machine = Machine::new();
+
+        // freeze(X, p(X))
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let query = builder.structure(FREEZE, vec![x, p]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        let x = result.build_term(x, &mut TermBuilder).unwrap();
+        let expected = Term::Struct(FREEZE, vec![x.clone(), Term::Struct(0, vec![x])]);
+        assert_eq!(Ok(vec![expected]), result.residual_goals(&mut TermBuilder));
+
+        // freeze(X, p(X)), freeze(Y, q(Y)), same(X, Y), p(X)
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let p = builder.structure(0, vec![x]);
+        let freeze_x = builder.structure(FREEZE, vec![x, p]);
+        let q = builder.structure(1, vec![y]);
+        let freeze_y = builder.structure(FREEZE, vec![y, q]);
+        let same = builder.structure(4, vec![x, y]);
+        let p = builder.structure(0, vec![x]);
+        let query = builder.conjunction(vec![freeze_x, freeze_y, same, p]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| {
+                let solution = solution.unwrap();
+                assert_eq!(Ok(vec![]), solution.residual_goals(&mut TermBuilder));
+                solution.build_term(x, &mut TermBuilder).unwrap()
+            })
+            .collect();
+        assert_eq!(vec![Term::Const(3)], solutions);
+
+        // when((nonvar(X) ; ground(Y)), p(Z)), Y is 2
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let z = builder.variable();
+        let nonvar = builder.structure(NONVAR, vec![x]);
+        let ground = builder.structure(GROUND, vec![y]);
+        let cond = builder.structure(OR, vec![nonvar, ground]);
+        let p = builder.structure(0, vec![z]);
+        let when = builder.structure(WHEN, vec![cond, p]);
+        let two = builder.integer(2);
+        let is = builder.structure(IS, vec![y, two]);
+        let query = builder.conjunction(vec![when, is]);
+        let solutions: Vec<_> = machine
+            .query(builder.build(query).unwrap(), &knowledge)
+            .unwrap()
+            .map(|solution| {
+                let solution = solution.unwrap();
+                assert_eq!(Ok(vec![]), solution.residual_goals(&mut TermBuilder));
+                solution.build_term(z, &mut TermBuilder).unwrap()
+            })
+            .collect();
+        assert_eq!(vec![Term::Const(2), Term::Const(3)], solutions);
+
+        // when(?=(X, Y), p(Z)), X is 1
+        let mut builder = QueryBuilder::new();
+        let x = builder.variable();
+        let y = builder.variable();
+        let z = builder.variable();
+        let decided = builder.structure(DECIDED, vec![x, y]);
+        let p = builder.structure(0, vec![z]);
+        let when = builder.structure(WHEN, vec![decided, p]);
+        let one = builder.integer(1);
+        let is = builder.structure(IS, vec![x, one]);
+        let query = builder.conjunction(vec![when, is]);
+        let result = machine.query(builder.build(query).unwrap(), &knowledge).unwrap();
+        let y = result.build_term(y, &mut TermBuilder).unwrap();
+        let z = result.build_term(z, &mut TermBuilder).unwrap();
+        assert!(matches!(z, Term::Var(_)));
+        let decided = Term::Struct(DECIDED, vec![Term::Int(1), y]);
+        let expected = Term::Struct(WHEN, vec![decided, Term::Struct(0, vec![z])]);
+        assert_eq!(Ok(vec![expected]), result.residual_goals(&mut TermBuilder));
+
+        // when(foo, true)
+        let mut builder = QueryBuilder::new();
+        let foo = builder.constant(5);
+        let t = builder.constant(TRUE);
+        let query = builder.structure(WHEN, vec![foo, t]);
+        assert_eq!(
+            Some(Error::InvalidCondition),
+            machine.query(builder.build(query).unwrap(), &knowledge).err()
+        );
     }
 }
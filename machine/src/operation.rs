@@ -1,11 +1,79 @@
+/// Register operand of operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    /// Temporary (X) register
+    X(usize),
+    /// Permanent (Y) variable in current environment
+    Y(usize),
+}
+
+impl Register {
+    /// Encodes register as single program word, with lowest bit
+    /// telling if it is permanent one
+    pub(crate) fn encode(self) -> usize {
+        match self {
+            Self::X(r) => r << 1,
+            Self::Y(r) => r << 1 | 1,
+        }
+    }
+
+    /// Decodes register from program word
+    pub(crate) fn decode(word: usize) -> Self {
+        if word & 1 == 0 {
+            Self::X(word >> 1)
+        } else {
+            Self::Y(word >> 1)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operation {
     PutStructure(usize, usize, usize), // Ident, Arity, XReg
-    SetVariable(usize),                // XReg
-    SetValue(usize),                   // XReg
+    SetVariable(Register),             // Reg
+    SetValue(Register),                // Reg
     GetStructure(usize, usize, usize), // Ident, Arity, XReg
-    UnifyVariable(usize),              // XReg
-    UnifyValue(usize),                 // XReg
+    UnifyVariable(Register),           // Reg
+    UnifyValue(Register),              // Reg
+    PutVariable(Register, usize),      // Reg, AReg
+    PutValue(Register, usize),         // Reg, AReg
+    GetVariable(Register, usize),      // Reg, AReg
+    GetValue(Register, usize),         // Reg, AReg
+    Call(usize, usize, usize),         // Ident, Arity, Live
+    Proceed,
+    Allocate(usize),                   // Permanent variables
+    Deallocate,
+    TryMeElse(usize),                  // Alternative
+    RetryMeElse(usize),                // Alternative
+    TrustMe,
+    PutConstant(usize, usize),         // Ident, XReg
+    GetConstant(usize, usize),         // Ident, XReg
+    SetConstant(usize),                // Ident
+    UnifyConstant(usize),              // Ident
+    PutInteger(isize, usize),          // Value, XReg
+    GetInteger(isize, usize),          // Value, XReg
+    SetInteger(isize),                 // Value
+    UnifyInteger(isize),               // Value
+    PutDomain(usize, usize),           // Value index, XReg
+    GetDomain(usize, usize),           // Value index, XReg
+    SetDomain(usize),                  // Value index
+    UnifyDomain(usize),                // Value index
+    PutList(usize),                    // XReg
+    GetList(usize),                    // XReg
+    SwitchOnTerm(usize, usize, usize, usize), // Var, Const, List, Struct
+    SwitchOnConstant(usize, usize),    // Table length, Default
+    SwitchOnStructure(usize, usize),   // Table length, Default
+    TryClause(usize),                  // Clause
+    RetryClause(usize),                // Clause
+    TrustClause(usize),                // Clause
+    SwitchOnHead(usize),               // Tree
+    Execute(usize, usize),             // Ident, Arity
+    NeckCut,
+    GetLevel(Register),                // Reg
+    Cut(Register),                     // Reg
+    Mark(Register),                    // Reg
+    TryElse(usize),                    // Alternative offset
+    Jump(usize),                       // Offset
 }
 
 impl Operation {
@@ -22,7 +90,46 @@ impl Operation {
             Self::SetValue(_) |
             Self::GetStructure(_, _, _) |
             Self::UnifyVariable(_) |
-            Self::UnifyValue(_) => self.size(),
+            Self::UnifyValue(_) |
+            Self::PutVariable(_, _) |
+            Self::PutValue(_, _) |
+            Self::GetVariable(_, _) |
+            Self::GetValue(_, _) |
+            Self::Allocate(_) |
+            Self::Deallocate |
+            Self::TryMeElse(_) |
+            Self::RetryMeElse(_) |
+            Self::TrustMe |
+            Self::PutConstant(_, _) |
+            Self::GetConstant(_, _) |
+            Self::SetConstant(_) |
+            Self::UnifyConstant(_) |
+            Self::PutInteger(_, _) |
+            Self::GetInteger(_, _) |
+            Self::SetInteger(_) |
+            Self::UnifyInteger(_) |
+            Self::PutDomain(_, _) |
+            Self::GetDomain(_, _) |
+            Self::SetDomain(_) |
+            Self::UnifyDomain(_) |
+            Self::PutList(_) |
+            Self::GetList(_) |
+            Self::NeckCut |
+            Self::GetLevel(_) |
+            Self::Cut(_) |
+            Self::Mark(_) |
+            Self::TryElse(_) => self.size(),
+            Self::Call(_, _, _) |
+            Self::Execute(_, _) |
+            Self::Proceed |
+            Self::SwitchOnTerm(_, _, _, _) |
+            Self::SwitchOnConstant(_, _) |
+            Self::SwitchOnStructure(_, _) |
+            Self::TryClause(_) |
+            Self::RetryClause(_) |
+            Self::TrustClause(_) |
+            Self::SwitchOnHead(_) |
+            Self::Jump(_) => 0,
         }
     }
 
@@ -35,6 +142,46 @@ impl Operation {
             Self::GetStructure(_, _, _) => 4,
             Self::UnifyVariable(_) => 2,
             Self::UnifyValue(_) => 2,
+            Self::PutVariable(_, _) => 3,
+            Self::PutValue(_, _) => 3,
+            Self::GetVariable(_, _) => 3,
+            Self::GetValue(_, _) => 3,
+            Self::Call(_, _, _) => 4,
+            Self::Proceed => 1,
+            Self::Allocate(_) => 2,
+            Self::Deallocate => 1,
+            Self::TryMeElse(_) => 2,
+            Self::RetryMeElse(_) => 2,
+            Self::TrustMe => 1,
+            Self::PutConstant(_, _) => 3,
+            Self::GetConstant(_, _) => 3,
+            Self::SetConstant(_) => 2,
+            Self::UnifyConstant(_) => 2,
+            Self::PutInteger(_, _) => 3,
+            Self::GetInteger(_, _) => 3,
+            Self::SetInteger(_) => 2,
+            Self::UnifyInteger(_) => 2,
+            Self::PutDomain(_, _) => 3,
+            Self::GetDomain(_, _) => 3,
+            Self::SetDomain(_) => 2,
+            Self::UnifyDomain(_) => 2,
+            Self::PutList(_) => 2,
+            Self::GetList(_) => 2,
+            Self::SwitchOnTerm(_, _, _, _) => 5,
+            // Every table entry is key, value and address
+            Self::SwitchOnConstant(len, _) => 3 + len * 3,
+            Self::SwitchOnStructure(len, _) => 3 + len * 3,
+            Self::TryClause(_) => 2,
+            Self::RetryClause(_) => 2,
+            Self::TrustClause(_) => 2,
+            Self::SwitchOnHead(_) => 2,
+            Self::Execute(_, _) => 3,
+            Self::NeckCut => 1,
+            Self::GetLevel(_) => 2,
+            Self::Cut(_) => 2,
+            Self::Mark(_) => 2,
+            Self::TryElse(_) => 2,
+            Self::Jump(_) => 2,
         }
     }
 }
@@ -1,4 +1,6 @@
-use crate::Operation;
+use crate::operation::{Operation, Register};
+use crate::storage::ConstDomain;
+use crate::Error;
 use std::borrow::Cow;
 use std::cmp::max;
 
@@ -6,11 +8,50 @@ use std::cmp::max;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum OpCode {
     PutStructure,  // Op Ident Arity XReg
-    SetVariable,   // Op XReg
-    SetValue,      // Op XReg
+    SetVariable,   // Op Reg
+    SetValue,      // Op Reg
     GetStructure,  // Op Ident Arity XReg
-    UnifyVariable, // Op XReg
-    UnifyValue,    // Op XReg
+    UnifyVariable, // Op Reg
+    UnifyValue,    // Op Reg
+    Proceed,       // Op
+    TryMeElse,     // Op Alternative
+    RetryMeElse,   // Op Alternative
+    TrustMe,       // Op
+    PutVariable,   // Op Reg AReg
+    PutValue,      // Op Reg AReg
+    GetVariable,   // Op Reg AReg
+    GetValue,      // Op Reg AReg
+    Call,          // Op Ident Arity Live
+    Allocate,      // Op Permanent
+    Deallocate,    // Op
+    PutConstant,   // Op Ident XReg
+    GetConstant,   // Op Ident XReg
+    SetConstant,   // Op Ident
+    UnifyConstant, // Op Ident
+    PutInteger,    // Op Value XReg
+    GetInteger,    // Op Value XReg
+    SetInteger,    // Op Value
+    UnifyInteger,  // Op Value
+    PutDomain,     // Op Index XReg
+    GetDomain,     // Op Index XReg
+    SetDomain,     // Op Index
+    UnifyDomain,   // Op Index
+    PutList,       // Op XReg
+    GetList,       // Op XReg
+    SwitchOnTerm,      // Op Var Const List Struct
+    SwitchOnConstant,  // Op Len Default [Tag Value Addr]*Len
+    SwitchOnStructure, // Op Len Default [Ident Arity Addr]*Len
+    TryClause,     // Op Clause
+    RetryClause,   // Op Clause
+    TrustClause,   // Op Clause
+    SwitchOnHead,  // Op Tree
+    Execute,       // Op Ident Arity
+    NeckCut,       // Op
+    GetLevel,      // Op Reg
+    Cut,           // Op Reg
+    Mark,          // Op Reg
+    TryElse,       // Op Offset
+    Jump,          // Op Offset
 }
 
 impl PartialEq<usize> for OpCode {
@@ -25,95 +66,348 @@ impl PartialEq<OpCode> for usize {
     }
 }
 
-pub struct Program<'a> {
+pub struct Program<'a, C = usize> {
     program: Cow<'a, [usize]>,
     xregs: usize, // X registers to alocate
+    // Constant domain values used by program, referenced by their
+    // indexes
+    values: Vec<C>,
 }
 
-impl Default for Program<'static> {
+impl<C> Default for Program<'static, C> {
     fn default() -> Self {
         Self {
             program: Cow::Owned(Default::default()),
             xregs: 0,
+            values: vec![],
         }
     }
 }
 
-impl<'a> Program<'a> {
+impl<'a, C: ConstDomain> Program<'a, C> {
+    // Gives `N` operation arguments following opcode on given
+    // program index
+    fn args<const N: usize>(&self, index: usize) -> Result<[usize; N], Error> {
+        let args = self
+            .program
+            .get(index + 1..index + 1 + N)
+            .ok_or(Error::MalformedBytecode(index))?;
+        let mut res = [0; N];
+        res.copy_from_slice(args);
+        Ok(res)
+    }
+
     // Builds `PutStructure` from given program index
-    fn put_structure(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 3 {
-            let ident = self.program[index + 1];
-            let arity = self.program[index + 2];
-            let xreg = self.program[index + 3];
-            Some(Operation::PutStructure(ident, arity, xreg))
-        } else {
-            None
-        }
+    fn put_structure(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, arity, xreg] = self.args(index)?;
+        Ok(Operation::PutStructure(ident, arity, xreg))
     }
 
     // Builds `SetVariable` from given program index
-    fn set_variable(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 1 {
-            let xreg = self.program[index + 1];
-            Some(Operation::SetVariable(xreg))
-        } else {
-            None
-        }
+    fn set_variable(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::SetVariable(Register::decode(reg)))
     }
 
     // Builds `SetValue` from given program index
-    fn set_value(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 1 {
-            let xreg = self.program[index + 1];
-            Some(Operation::SetValue(xreg))
-        } else {
-            None
-        }
+    fn set_value(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::SetValue(Register::decode(reg)))
     }
 
     // Builds `GetStructure` from given program index
-    fn get_structure(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 3 {
-            let ident = self.program[index + 1];
-            let arity = self.program[index + 2];
-            let xreg = self.program[index + 3];
-            Some(Operation::GetStructure(ident, arity, xreg))
-        } else {
-            None
-        }
+    fn get_structure(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, arity, xreg] = self.args(index)?;
+        Ok(Operation::GetStructure(ident, arity, xreg))
     }
 
     // Builds `UnifyVariable` from given program index
-    fn unify_variable(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 1 {
-            let xreg = self.program[index + 1];
-            Some(Operation::UnifyVariable(xreg))
-        } else {
-            None
-        }
+    fn unify_variable(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::UnifyVariable(Register::decode(reg)))
     }
 
     // Builds `UnifyValue` from given program index
-    fn unify_value(&self, index: usize) -> Option<Operation> {
-        if self.program.len() > index + 1 {
-            let xreg = self.program[index + 1];
-            Some(Operation::UnifyValue(xreg))
-        } else {
-            None
-        }
+    fn unify_value(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::UnifyValue(Register::decode(reg)))
+    }
+
+    // Builds `TryMeElse` from given program index
+    fn try_me_else(&self, index: usize) -> Result<Operation, Error> {
+        let [alternative] = self.args(index)?;
+        Ok(Operation::TryMeElse(alternative))
+    }
+
+    // Builds `RetryMeElse` from given program index
+    fn retry_me_else(&self, index: usize) -> Result<Operation, Error> {
+        let [alternative] = self.args(index)?;
+        Ok(Operation::RetryMeElse(alternative))
+    }
+
+    // Builds `PutVariable` from given program index
+    fn put_variable(&self, index: usize) -> Result<Operation, Error> {
+        let [reg, areg] = self.args(index)?;
+        Ok(Operation::PutVariable(Register::decode(reg), areg))
+    }
+
+    // Builds `PutValue` from given program index
+    fn put_value(&self, index: usize) -> Result<Operation, Error> {
+        let [reg, areg] = self.args(index)?;
+        Ok(Operation::PutValue(Register::decode(reg), areg))
+    }
+
+    // Builds `GetVariable` from given program index
+    fn get_variable(&self, index: usize) -> Result<Operation, Error> {
+        let [reg, areg] = self.args(index)?;
+        Ok(Operation::GetVariable(Register::decode(reg), areg))
+    }
+
+    // Builds `GetValue` from given program index
+    fn get_value(&self, index: usize) -> Result<Operation, Error> {
+        let [reg, areg] = self.args(index)?;
+        Ok(Operation::GetValue(Register::decode(reg), areg))
+    }
+
+    // Builds `Call` from given program index
+    fn call(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, arity, live] = self.args(index)?;
+        Ok(Operation::Call(ident, arity, live))
+    }
+
+    // Builds `Execute` from given program index
+    fn execute(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, arity] = self.args(index)?;
+        Ok(Operation::Execute(ident, arity))
+    }
+
+    // Builds `Allocate` from given program index
+    fn allocate(&self, index: usize) -> Result<Operation, Error> {
+        let [permanent] = self.args(index)?;
+        Ok(Operation::Allocate(permanent))
+    }
+
+    // Builds `PutConstant` from given program index
+    fn put_constant(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, xreg] = self.args(index)?;
+        Ok(Operation::PutConstant(ident, xreg))
+    }
+
+    // Builds `GetConstant` from given program index
+    fn get_constant(&self, index: usize) -> Result<Operation, Error> {
+        let [ident, xreg] = self.args(index)?;
+        Ok(Operation::GetConstant(ident, xreg))
+    }
+
+    // Builds `SetConstant` from given program index
+    fn set_constant(&self, index: usize) -> Result<Operation, Error> {
+        let [ident] = self.args(index)?;
+        Ok(Operation::SetConstant(ident))
+    }
+
+    // Builds `UnifyConstant` from given program index
+    fn unify_constant(&self, index: usize) -> Result<Operation, Error> {
+        let [ident] = self.args(index)?;
+        Ok(Operation::UnifyConstant(ident))
+    }
+
+    // Builds `PutInteger` from given program index
+    fn put_integer(&self, index: usize) -> Result<Operation, Error> {
+        let [value, xreg] = self.args(index)?;
+        Ok(Operation::PutInteger(value as isize, xreg))
+    }
+
+    // Builds `GetInteger` from given program index
+    fn get_integer(&self, index: usize) -> Result<Operation, Error> {
+        let [value, xreg] = self.args(index)?;
+        Ok(Operation::GetInteger(value as isize, xreg))
+    }
+
+    // Builds `SetInteger` from given program index
+    fn set_integer(&self, index: usize) -> Result<Operation, Error> {
+        let [value] = self.args(index)?;
+        Ok(Operation::SetInteger(value as isize))
+    }
+
+    // Builds `UnifyInteger` from given program index
+    fn unify_integer(&self, index: usize) -> Result<Operation, Error> {
+        let [value] = self.args(index)?;
+        Ok(Operation::UnifyInteger(value as isize))
+    }
+
+    // Builds `PutDomain` from given program index
+    fn put_domain(&self, index: usize) -> Result<Operation, Error> {
+        let [value, xreg] = self.args(index)?;
+        Ok(Operation::PutDomain(value, xreg))
+    }
+
+    // Builds `GetDomain` from given program index
+    fn get_domain(&self, index: usize) -> Result<Operation, Error> {
+        let [value, xreg] = self.args(index)?;
+        Ok(Operation::GetDomain(value, xreg))
+    }
+
+    // Builds `SetDomain` from given program index
+    fn set_domain(&self, index: usize) -> Result<Operation, Error> {
+        let [value] = self.args(index)?;
+        Ok(Operation::SetDomain(value))
+    }
+
+    // Builds `UnifyDomain` from given program index
+    fn unify_domain(&self, index: usize) -> Result<Operation, Error> {
+        let [value] = self.args(index)?;
+        Ok(Operation::UnifyDomain(value))
+    }
+
+    // Builds `PutList` from given program index
+    fn put_list(&self, index: usize) -> Result<Operation, Error> {
+        let [xreg] = self.args(index)?;
+        Ok(Operation::PutList(xreg))
+    }
+
+    // Builds `GetList` from given program index
+    fn get_list(&self, index: usize) -> Result<Operation, Error> {
+        let [xreg] = self.args(index)?;
+        Ok(Operation::GetList(xreg))
+    }
+
+    // Builds `SwitchOnTerm` from given program index
+    fn switch_on_term(&self, index: usize) -> Result<Operation, Error> {
+        let [var, constant, list, structure] = self.args(index)?;
+        Ok(Operation::SwitchOnTerm(var, constant, list, structure))
+    }
+
+    // Gives switch table length and default address of switch
+    // operation on given program index, checking if whole table
+    // is in program
+    fn switch_args(&self, index: usize) -> Result<[usize; 2], Error> {
+        let [len, default] = self.args(index)?;
+        len.checked_mul(3)
+            .and_then(|table| self.program.get(index + 3..index + 3 + table))
+            .ok_or(Error::MalformedBytecode(index))?;
+        Ok([len, default])
+    }
+
+    // Builds `SwitchOnConstant` from given program index
+    fn switch_on_constant(&self, index: usize) -> Result<Operation, Error> {
+        let [len, default] = self.switch_args(index)?;
+        Ok(Operation::SwitchOnConstant(len, default))
+    }
+
+    // Builds `SwitchOnStructure` from given program index
+    fn switch_on_structure(&self, index: usize) -> Result<Operation, Error> {
+        let [len, default] = self.switch_args(index)?;
+        Ok(Operation::SwitchOnStructure(len, default))
+    }
+
+    // Builds `TryClause` from given program index
+    fn try_clause(&self, index: usize) -> Result<Operation, Error> {
+        let [clause] = self.args(index)?;
+        Ok(Operation::TryClause(clause))
+    }
+
+    // Builds `RetryClause` from given program index
+    fn retry_clause(&self, index: usize) -> Result<Operation, Error> {
+        let [clause] = self.args(index)?;
+        Ok(Operation::RetryClause(clause))
+    }
+
+    // Builds `TrustClause` from given program index
+    fn trust_clause(&self, index: usize) -> Result<Operation, Error> {
+        let [clause] = self.args(index)?;
+        Ok(Operation::TrustClause(clause))
+    }
+
+    // Builds `SwitchOnHead` from given program index
+    fn switch_on_head(&self, index: usize) -> Result<Operation, Error> {
+        let [tree] = self.args(index)?;
+        Ok(Operation::SwitchOnHead(tree))
+    }
+
+    // Builds `GetLevel` from given program index
+    fn get_level(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::GetLevel(Register::decode(reg)))
+    }
+
+    // Builds `Cut` from given program index
+    fn cut(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::Cut(Register::decode(reg)))
+    }
+
+    // Builds `Mark` from given program index
+    fn mark(&self, index: usize) -> Result<Operation, Error> {
+        let [reg] = self.args(index)?;
+        Ok(Operation::Mark(Register::decode(reg)))
+    }
+
+    // Builds `TryElse` from given program index
+    fn try_else(&self, index: usize) -> Result<Operation, Error> {
+        let [offset] = self.args(index)?;
+        Ok(Operation::TryElse(offset))
+    }
+
+    // Builds `Jump` from given program index
+    fn jump(&self, index: usize) -> Result<Operation, Error> {
+        let [offset] = self.args(index)?;
+        Ok(Operation::Jump(offset))
     }
 
     /// Gives operation from given program index
-    pub fn operation(&self, index: usize) -> Option<Operation> {
-        match self.program.get(index)? {
+    pub fn operation(&self, index: usize) -> Result<Operation, Error> {
+        let op = self
+            .program
+            .get(index)
+            .ok_or(Error::MalformedBytecode(index))?;
+
+        match op {
             op if *op == OpCode::PutStructure => self.put_structure(index),
             op if *op == OpCode::SetVariable => self.set_variable(index),
             op if *op == OpCode::SetValue => self.set_value(index),
             op if *op == OpCode::GetStructure => self.get_structure(index),
             op if *op == OpCode::UnifyVariable => self.unify_variable(index),
             op if *op == OpCode::UnifyValue => self.unify_value(index),
-            _ => None,
+            op if *op == OpCode::Proceed => Ok(Operation::Proceed),
+            op if *op == OpCode::TryMeElse => self.try_me_else(index),
+            op if *op == OpCode::RetryMeElse => self.retry_me_else(index),
+            op if *op == OpCode::TrustMe => Ok(Operation::TrustMe),
+            op if *op == OpCode::PutVariable => self.put_variable(index),
+            op if *op == OpCode::PutValue => self.put_value(index),
+            op if *op == OpCode::GetVariable => self.get_variable(index),
+            op if *op == OpCode::GetValue => self.get_value(index),
+            op if *op == OpCode::Call => self.call(index),
+            op if *op == OpCode::Allocate => self.allocate(index),
+            op if *op == OpCode::Deallocate => Ok(Operation::Deallocate),
+            op if *op == OpCode::PutConstant => self.put_constant(index),
+            op if *op == OpCode::GetConstant => self.get_constant(index),
+            op if *op == OpCode::SetConstant => self.set_constant(index),
+            op if *op == OpCode::UnifyConstant => self.unify_constant(index),
+            op if *op == OpCode::PutInteger => self.put_integer(index),
+            op if *op == OpCode::GetInteger => self.get_integer(index),
+            op if *op == OpCode::SetInteger => self.set_integer(index),
+            op if *op == OpCode::UnifyInteger => self.unify_integer(index),
+            op if *op == OpCode::PutDomain => self.put_domain(index),
+            op if *op == OpCode::GetDomain => self.get_domain(index),
+            op if *op == OpCode::SetDomain => self.set_domain(index),
+            op if *op == OpCode::UnifyDomain => self.unify_domain(index),
+            op if *op == OpCode::PutList => self.put_list(index),
+            op if *op == OpCode::GetList => self.get_list(index),
+            op if *op == OpCode::SwitchOnTerm => self.switch_on_term(index),
+            op if *op == OpCode::SwitchOnConstant => self.switch_on_constant(index),
+            op if *op == OpCode::SwitchOnStructure => self.switch_on_structure(index),
+            op if *op == OpCode::TryClause => self.try_clause(index),
+            op if *op == OpCode::RetryClause => self.retry_clause(index),
+            op if *op == OpCode::TrustClause => self.trust_clause(index),
+            op if *op == OpCode::SwitchOnHead => self.switch_on_head(index),
+            op if *op == OpCode::Execute => self.execute(index),
+            op if *op == OpCode::NeckCut => Ok(Operation::NeckCut),
+            op if *op == OpCode::GetLevel => self.get_level(index),
+            op if *op == OpCode::Cut => self.cut(index),
+            op if *op == OpCode::Mark => self.mark(index),
+            op if *op == OpCode::TryElse => self.try_else(index),
+            op if *op == OpCode::Jump => self.jump(index),
+            _ => Err(Error::MalformedBytecode(index)),
         }
     }
 
@@ -125,11 +419,46 @@ impl<'a> Program<'a> {
         self.xregs
     }
 
+    /// Program length in machine words
+    pub fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    /// Gives constant domain value with given index
+    pub fn value(&self, index: usize) -> Option<&C> {
+        self.values.get(index)
+    }
+
+    /// Searches table of switch operation on given program index for
+    /// given key, returning its address
+    ///
+    /// Tables are sorted by their keys, so they are binary searched
+    pub fn switch(&self, index: usize, key: (usize, usize)) -> Result<Option<usize>, Error> {
+        let [len, _] = self.switch_args(index)?;
+        let entry = |idx: usize| {
+            let base = index + 3 + idx * 3;
+            ((self.program[base], self.program[base + 1]), self.program[base + 2])
+        };
+
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (entry_key, addr) = entry(mid);
+            match entry_key.cmp(&key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(addr)),
+            }
+        }
+
+        Ok(None)
+    }
+
     /// Returns iterator over operations with their indexes
     fn operations(&self) -> impl Iterator<Item=(usize, Operation)> + '_ {
         let mut p = 0;
         std::iter::from_fn(move || -> Option<(usize, Operation)> {
-            let op = self.operation(p)?;
+            let op = self.operation(p).ok()?;
             let oldp = p;
             p += op.size();
             Some((oldp, op))
@@ -145,21 +474,31 @@ impl<'a> Program<'a> {
     }
 }
 
-pub struct ProgramBuilder {
+pub struct ProgramBuilder<C = usize> {
     program: Vec<usize>,
     xregs: usize, // X registers to allocate
+    values: Vec<C>,
 }
 
-impl Default for ProgramBuilder {
+impl<C> Default for ProgramBuilder<C> {
     fn default() -> Self {
         Self {
             program: vec![],
             xregs: 0,
+            values: vec![],
         }
     }
 }
 
-impl ProgramBuilder {
+impl<C: ConstDomain> ProgramBuilder<C> {
+    // Updates number of X registers to allocate if given register
+    // is temporary one
+    fn use_register(&mut self, reg: Register) {
+        if let Register::X(xreg) = reg {
+            self.xregs = max(self.xregs, xreg + 1);
+        }
+    }
+
     pub fn put_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> &mut Self {
         self.xregs = max(self.xregs, xreg + 1);
 
@@ -170,23 +509,25 @@ impl ProgramBuilder {
         self
     }
 
-    pub fn set_variable(&mut self, xreg: usize) -> &mut Self {
-        self.xregs = max(self.xregs, xreg + 1);
+    pub fn set_variable(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
 
         self.program.push(OpCode::SetVariable as usize);
-        self.program.push(xreg);
+        self.program.push(reg.encode());
         self
     }
 
-    pub fn set_value(&mut self, xreg: usize) -> &mut Self {
-        self.xregs = max(self.xregs, xreg + 1);
+    pub fn set_value(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
 
         self.program.push(OpCode::SetValue as usize);
-        self.program.push(xreg);
+        self.program.push(reg.encode());
         self
     }
 
     pub fn get_structure(&mut self, ident: usize, arity: usize, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
         self.program.push(OpCode::GetStructure as usize);
         self.program.push(ident);
         self.program.push(arity);
@@ -194,22 +535,418 @@ impl ProgramBuilder {
         self
     }
 
-    pub fn unify_variable(&mut self, xreg: usize) -> &mut Self {
+    pub fn unify_variable(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
+
         self.program.push(OpCode::UnifyVariable as usize);
-        self.program.push(xreg);
+        self.program.push(reg.encode());
         self
     }
 
-    pub fn unify_value(&mut self, xreg: usize) -> &mut Self {
+    pub fn unify_value(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
+
         self.program.push(OpCode::UnifyValue as usize);
+        self.program.push(reg.encode());
+        self
+    }
+
+    pub fn put_variable(&mut self, reg: Register, areg: usize) -> &mut Self {
+        self.use_register(reg);
+        self.xregs = max(self.xregs, areg + 1);
+
+        self.program.push(OpCode::PutVariable as usize);
+        self.program.push(reg.encode());
+        self.program.push(areg);
+        self
+    }
+
+    pub fn put_value(&mut self, reg: Register, areg: usize) -> &mut Self {
+        self.use_register(reg);
+        self.xregs = max(self.xregs, areg + 1);
+
+        self.program.push(OpCode::PutValue as usize);
+        self.program.push(reg.encode());
+        self.program.push(areg);
+        self
+    }
+
+    pub fn get_variable(&mut self, reg: Register, areg: usize) -> &mut Self {
+        self.use_register(reg);
+        self.xregs = max(self.xregs, areg + 1);
+
+        self.program.push(OpCode::GetVariable as usize);
+        self.program.push(reg.encode());
+        self.program.push(areg);
+        self
+    }
+
+    pub fn get_value(&mut self, reg: Register, areg: usize) -> &mut Self {
+        self.use_register(reg);
+        self.xregs = max(self.xregs, areg + 1);
+
+        self.program.push(OpCode::GetValue as usize);
+        self.program.push(reg.encode());
+        self.program.push(areg);
+        self
+    }
+
+    pub fn put_constant(&mut self, ident: usize, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::PutConstant as usize);
+        self.program.push(ident);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn get_constant(&mut self, ident: usize, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::GetConstant as usize);
+        self.program.push(ident);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn set_constant(&mut self, ident: usize) -> &mut Self {
+        self.program.push(OpCode::SetConstant as usize);
+        self.program.push(ident);
+        self
+    }
+
+    pub fn unify_constant(&mut self, ident: usize) -> &mut Self {
+        self.program.push(OpCode::UnifyConstant as usize);
+        self.program.push(ident);
+        self
+    }
+
+    // Integers are stored in program words in two's complement
+    pub fn put_integer(&mut self, value: isize, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::PutInteger as usize);
+        self.program.push(value as usize);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn get_integer(&mut self, value: isize, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::GetInteger as usize);
+        self.program.push(value as usize);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn set_integer(&mut self, value: isize) -> &mut Self {
+        self.program.push(OpCode::SetInteger as usize);
+        self.program.push(value as usize);
+        self
+    }
+
+    pub fn unify_integer(&mut self, value: isize) -> &mut Self {
+        self.program.push(OpCode::UnifyInteger as usize);
+        self.program.push(value as usize);
+        self
+    }
+
+    // Values are stored aside of program, which refers to their indexes
+    fn add_value(&mut self, value: C) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+
+    pub fn put_domain(&mut self, value: C, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+        let value = self.add_value(value);
+
+        self.program.push(OpCode::PutDomain as usize);
+        self.program.push(value);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn get_domain(&mut self, value: C, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+        let value = self.add_value(value);
+
+        self.program.push(OpCode::GetDomain as usize);
+        self.program.push(value);
         self.program.push(xreg);
         self
     }
 
-    pub fn build(self) -> Program<'static> {
+    pub fn set_domain(&mut self, value: C) -> &mut Self {
+        let value = self.add_value(value);
+
+        self.program.push(OpCode::SetDomain as usize);
+        self.program.push(value);
+        self
+    }
+
+    pub fn unify_domain(&mut self, value: C) -> &mut Self {
+        let value = self.add_value(value);
+
+        self.program.push(OpCode::UnifyDomain as usize);
+        self.program.push(value);
+        self
+    }
+
+    pub fn put_list(&mut self, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::PutList as usize);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn get_list(&mut self, xreg: usize) -> &mut Self {
+        self.xregs = max(self.xregs, xreg + 1);
+
+        self.program.push(OpCode::GetList as usize);
+        self.program.push(xreg);
+        self
+    }
+
+    pub fn switch_on_term(
+        &mut self,
+        var: usize,
+        constant: usize,
+        list: usize,
+        structure: usize,
+    ) -> &mut Self {
+        self.program.push(OpCode::SwitchOnTerm as usize);
+        self.program.push(var);
+        self.program.push(constant);
+        self.program.push(list);
+        self.program.push(structure);
+        self
+    }
+
+    // Switch tables are sorted, so they can be binary searched
+    fn switch_table(
+        &mut self,
+        op: OpCode,
+        table: impl IntoIterator<Item = ((usize, usize), usize)>,
+        default: usize,
+    ) -> &mut Self {
+        let mut table: Vec<_> = table.into_iter().collect();
+        table.sort_unstable();
+
+        self.program.push(op as usize);
+        self.program.push(table.len());
+        self.program.push(default);
+        for ((key, value), addr) in table {
+            self.program.push(key);
+            self.program.push(value);
+            self.program.push(addr);
+        }
+        self
+    }
+
+    /// Switches on constant keys, which are `(0, ident)` for constants
+    /// and `(1, value)` for integers
+    pub fn switch_on_constant(
+        &mut self,
+        table: impl IntoIterator<Item = ((usize, usize), usize)>,
+        default: usize,
+    ) -> &mut Self {
+        self.switch_table(OpCode::SwitchOnConstant, table, default)
+    }
+
+    /// Switches on structure functors (ident and arity)
+    pub fn switch_on_structure(
+        &mut self,
+        table: impl IntoIterator<Item = ((usize, usize), usize)>,
+        default: usize,
+    ) -> &mut Self {
+        self.switch_table(OpCode::SwitchOnStructure, table, default)
+    }
+
+    pub fn try_clause(&mut self, clause: usize) -> &mut Self {
+        self.program.push(OpCode::TryClause as usize);
+        self.program.push(clause);
+        self
+    }
+
+    pub fn retry_clause(&mut self, clause: usize) -> &mut Self {
+        self.program.push(OpCode::RetryClause as usize);
+        self.program.push(clause);
+        self
+    }
+
+    pub fn trust_clause(&mut self, clause: usize) -> &mut Self {
+        self.program.push(OpCode::TrustClause as usize);
+        self.program.push(clause);
+        self
+    }
+
+    /// Retrieves clauses from discrimination tree with given index
+    /// in linked knowledge
+    pub fn switch_on_head(&mut self, tree: usize) -> &mut Self {
+        self.program.push(OpCode::SwitchOnHead as usize);
+        self.program.push(tree);
+        self
+    }
+
+    /// Calls procedure, keeping only given number of first permanent
+    /// variables, which are still used after it
+    pub fn call(&mut self, ident: usize, arity: usize, live: usize) -> &mut Self {
+        self.xregs = max(self.xregs, arity);
+
+        self.program.push(OpCode::Call as usize);
+        self.program.push(ident);
+        self.program.push(arity);
+        self.program.push(live);
+        self
+    }
+
+    /// Calls procedure as last goal of clause, continuing with clause
+    /// continuation
+    pub fn execute(&mut self, ident: usize, arity: usize) -> &mut Self {
+        self.xregs = max(self.xregs, arity);
+
+        self.program.push(OpCode::Execute as usize);
+        self.program.push(ident);
+        self.program.push(arity);
+        self
+    }
+
+    pub fn proceed(&mut self) -> &mut Self {
+        self.program.push(OpCode::Proceed as usize);
+        self
+    }
+
+    pub fn allocate(&mut self, permanent: usize) -> &mut Self {
+        self.program.push(OpCode::Allocate as usize);
+        self.program.push(permanent);
+        self
+    }
+
+    pub fn deallocate(&mut self) -> &mut Self {
+        self.program.push(OpCode::Deallocate as usize);
+        self
+    }
+
+    pub fn try_me_else(&mut self, alternative: usize) -> &mut Self {
+        self.program.push(OpCode::TryMeElse as usize);
+        self.program.push(alternative);
+        self
+    }
+
+    pub fn retry_me_else(&mut self, alternative: usize) -> &mut Self {
+        self.program.push(OpCode::RetryMeElse as usize);
+        self.program.push(alternative);
+        self
+    }
+
+    pub fn trust_me(&mut self) -> &mut Self {
+        self.program.push(OpCode::TrustMe as usize);
+        self
+    }
+
+    /// Cuts choice points created since procedure was called, before
+    /// any call in clause body
+    pub fn neck_cut(&mut self) -> &mut Self {
+        self.program.push(OpCode::NeckCut as usize);
+        self
+    }
+
+    /// Saves choice points level on procedure call, for cutting them
+    /// later in clause body
+    pub fn get_level(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
+
+        self.program.push(OpCode::GetLevel as usize);
+        self.program.push(reg.encode());
+        self
+    }
+
+    /// Cuts choice points above level saved in given register
+    pub fn cut(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
+
+        self.program.push(OpCode::Cut as usize);
+        self.program.push(reg.encode());
+        self
+    }
+
+    /// Saves current choice points level, for cutting choice points
+    /// created after it
+    pub fn mark(&mut self, reg: Register) -> &mut Self {
+        self.use_register(reg);
+
+        self.program.push(OpCode::Mark as usize);
+        self.program.push(reg.encode());
+        self
+    }
+
+    /// Creates choice point which alternative is given number of
+    /// words ahead of this instruction
+    ///
+    /// Offset may be set later with `resolve`
+    pub fn try_else(&mut self, offset: usize) -> &mut Self {
+        self.program.push(OpCode::TryElse as usize);
+        self.program.push(offset);
+        self
+    }
+
+    /// Continues given number of words ahead of this instruction
+    ///
+    /// Offset may be set later with `resolve`
+    pub fn jump(&mut self, offset: usize) -> &mut Self {
+        self.program.push(OpCode::Jump as usize);
+        self.program.push(offset);
+        self
+    }
+
+    /// Sets offset of `TryElse` or `Jump` on given address, so it
+    /// points to the next instruction to be added
+    ///
+    /// Offsets are relative, so program stays relocatable
+    pub fn resolve(&mut self, addr: usize) -> &mut Self {
+        self.program[addr + 1] = self.program.len() - addr;
+        self
+    }
+
+    /// Appends whole other program at the end of built one
+    ///
+    /// Appended program has to be relocatable, which means it
+    /// can't contain any absolute program addresses. Its constant
+    /// domain values are moved after already used ones.
+    pub fn append(&mut self, program: &Program<C>) -> &mut Self {
+        let base = self.program.len();
+        let offset = self.values.len();
+
+        self.xregs = max(self.xregs, program.xregs);
+        self.program.extend_from_slice(&program.program);
+        self.values.extend(program.values.iter().cloned());
+
+        for (idx, op) in program.operations() {
+            if let Operation::PutDomain(_, _)
+            | Operation::GetDomain(_, _)
+            | Operation::SetDomain(_)
+            | Operation::UnifyDomain(_) = op
+            {
+                self.program[base + idx + 1] += offset;
+            }
+        }
+        self
+    }
+
+    /// Current length of built program, which is also an address of
+    /// next instruction to be added
+    pub fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn build(self) -> Program<'static, C> {
         Program {
             program: self.program.into(),
             xregs: self.xregs,
+            values: self.values,
         }
     }
 }
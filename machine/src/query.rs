@@ -1,66 +1,125 @@
-use crate::program::ProgramBuilder;
-use crate::{Cell, Machine, Program, TermBuilder};
+use crate::builtin;
+use crate::compiler::{self, Term};
+use crate::knowledge::Code;
+use crate::storage::{ConstDomain, OccursCheck, Storage};
+use crate::{Cell, Error, Machine, Program, TermBuilder};
+use std::rc::Rc;
 
-/// Reference to query part for building complex (structure)
+/// Reference to query part for building complex (structure or list)
 /// queries, and later for extracting unification result
 #[derive(Clone, Copy)]
 pub struct QueryRef(pub(crate) usize);
 
 /// Result of running query
-pub struct QueryResult<'a> {
-    pub(crate) machine: &'a Machine,
-    pub(crate) regs: Vec<Cell>,
+///
+/// Query is proven up to its first solution when it is run. Iterating
+/// over result gives all its solutions, starting with the first one,
+/// and every next one is searched only when it is requested. Iteration
+/// stops after first error.
+pub struct QueryResult<'a, C = usize> {
+    pub(crate) machine: &'a mut Machine<C>,
+    pub(crate) code: &'a Code<C>,
+    pub(crate) program: Program<'a, C>,
+    pub(crate) terms: Rc<[Term<C>]>,
+    // Permanent variable assigned to every query variable
+    pub(crate) vars: Rc<[Option<usize>]>,
+    pub(crate) first: Option<Solution<C>>,
+    // Clash which caused query failure, if query failed on it
+    pub(crate) failure: Option<Failure<C>>,
+    // If first solution was already given by iterator
+    pub(crate) started: bool,
+    // If there is no more solutions to search for
+    pub(crate) exhausted: bool,
+}
+
+/// Single solution of query
+///
+/// Solution keeps its own copy of terms unified with query, so it
+/// stays valid after searching for further solutions
+#[derive(Clone)]
+pub struct Solution<C = usize> {
+    terms: Rc<[Term<C>]>,
+    // Permanent variable assigned to every query variable
+    vars: Rc<[Option<usize>]>,
+    // Query permanent variables, and terms they are bound to
+    cells: Vec<Cell>,
+    // Residual goals of constraints left suspended
+    goals: Vec<Cell>,
+    storage: Storage<C>,
+}
+
+/// Functor of term - its ident and arity
+pub type Functor = (usize, usize);
+
+/// Explanation of query failure, caused by unification of two
+/// terms (structures, constants or integers) which differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureReport<Term> {
+    /// Functors (ident and arity) of clashing terms, constants have
+    /// zero arity, and integers have no functor
+    pub functors: (Option<Functor>, Option<Functor>),
+    /// Argument positions leading from unified terms to clashing
+    /// ones - empty if unified terms clashed themselves
+    pub path: Vec<usize>,
+    /// Clashing terms, in order of functors
+    pub terms: (Term, Term),
+}
+
+/// Clashing terms copied on query failure
+pub(crate) struct Failure<C> {
+    pub(crate) functors: (Option<Functor>, Option<Functor>),
+    pub(crate) path: Vec<usize>,
+    pub(crate) cells: (Cell, Cell),
+    pub(crate) storage: Storage<C>,
 }
 
 /// Query to be executed
-pub struct Query<'a> {
-    pub(crate) program: Program<'a>,
-    // Register with top-level struct assigned
-    pub(crate) top_level: usize,
+pub struct Query<'a, C = usize> {
+    pub(crate) program: Program<'a, C>,
+    pub(crate) terms: Vec<Term<C>>,
+    // Permanent variable assigned to every query variable
+    pub(crate) vars: Vec<Option<usize>>,
+    // Occurs check mode overriding machine one
+    pub(crate) occurs_check: Option<OccursCheck>,
 }
 
-impl<'a> Query<'a> {
+impl<'a, C: ConstDomain> Query<'a, C> {
     pub fn assembly(&self) -> String {
         self.program.assembly()
     }
 }
 
 /// Builder for structured query
-pub struct QueryBuilder {
-    program: ProgramBuilder,
-    next_register: usize,
+pub struct QueryBuilder<C = usize> {
+    terms: Vec<Term<C>>,
+    occurs_check: Option<OccursCheck>,
 }
 
-impl QueryRef {
-    pub fn id(self) -> usize {
-        self.0
-    }
-}
-
-impl Default for QueryBuilder {
+impl<C> Default for QueryBuilder<C> {
     fn default() -> Self {
         Self {
-            program: Default::default(),
-            // 0 register is reserved for top level term
-            next_register: 1,
+            terms: vec![],
+            occurs_check: None,
         }
     }
 }
 
+impl QueryRef {
+    pub fn id(self) -> usize {
+        self.0
+    }
+}
+
 impl QueryBuilder {
     pub fn new() -> Self {
         Default::default()
     }
+}
 
-    fn next_register(&mut self) -> usize {
-        self.next_register += 1;
-        self.next_register - 1
-    }
-
+impl<C: ConstDomain> QueryBuilder<C> {
     pub fn variable(&mut self) -> QueryRef {
-        let register = self.next_register();
-        self.program.set_variable(register);
-        QueryRef(register)
+        self.terms.push(Term::Var);
+        QueryRef(self.terms.len() - 1)
     }
 
     pub fn structure(
@@ -71,34 +130,284 @@ impl QueryBuilder {
             IntoIter = impl ExactSizeIterator<Item = QueryRef>,
         >,
     ) -> QueryRef {
-        let subterms = subterms.into_iter();
-        let register = self.next_register();
-        self.program.put_structure(ident, subterms.len(), register);
-        for subterm in subterms {
-            let QueryRef(reg) = subterm;
-            self.program.set_value(reg);
-        }
-        QueryRef(register)
+        self.terms.push(Term::Struct(
+            ident,
+            subterms.into_iter().map(|QueryRef(r)| r).collect(),
+        ));
+        QueryRef(self.terms.len() - 1)
     }
 
     pub fn constant(&mut self, ident: usize) -> QueryRef {
-        self.structure(ident, std::iter::empty())
+        self.terms.push(Term::Const(ident));
+        QueryRef(self.terms.len() - 1)
+    }
+
+    pub fn integer(&mut self, value: isize) -> QueryRef {
+        self.terms.push(Term::Int(value));
+        QueryRef(self.terms.len() - 1)
+    }
+
+    pub fn value(&mut self, value: C) -> QueryRef {
+        self.terms.push(Term::Value(value));
+        QueryRef(self.terms.len() - 1)
+    }
+
+    /// List cell with given head and tail
+    pub fn cons(&mut self, head: QueryRef, tail: QueryRef) -> QueryRef {
+        self.terms.push(Term::List([head.0, tail.0]));
+        QueryRef(self.terms.len() - 1)
+    }
+
+    /// Empty list
+    pub fn nil(&mut self) -> QueryRef {
+        self.constant(builtin::NIL)
+    }
+
+    /// Proper list of given items
+    pub fn list(&mut self, items: impl IntoIterator<Item = QueryRef>) -> QueryRef {
+        let items: Vec<_> = items.into_iter().collect();
+        let nil = self.nil();
+        items
+            .into_iter()
+            .rev()
+            .fold(nil, |tail, head| self.cons(head, tail))
+    }
+
+    /// Cut goal, committing to solutions of goals before it
+    pub fn cut(&mut self) -> QueryRef {
+        self.constant(builtin::CUT)
+    }
+
+    /// Conjunction of given goals, proven in order
+    pub fn conjunction(&mut self, goals: impl IntoIterator<Item = QueryRef>) -> QueryRef {
+        let goals: Vec<_> = goals.into_iter().collect();
+        match goals.split_last() {
+            Some((last, goals)) => goals
+                .iter()
+                .rev()
+                .fold(*last, |rest, goal| self.structure(builtin::AND, vec![*goal, rest])),
+            None => self.constant(builtin::TRUE),
+        }
+    }
+
+    /// Disjunction of two goals, proving right one on backtracking
+    /// from left one
+    pub fn disjunction(&mut self, left: QueryRef, right: QueryRef) -> QueryRef {
+        self.structure(builtin::OR, vec![left, right])
+    }
+
+    /// If-then-else goal, proving `then` for first solution of `cond`,
+    /// or `otherwise` if `cond` has no solution
+    pub fn if_then_else(&mut self, cond: QueryRef, then: QueryRef, otherwise: QueryRef) -> QueryRef {
+        let if_then = self.if_then(cond, then);
+        self.disjunction(if_then, otherwise)
     }
 
-    pub fn build(self, QueryRef(r): QueryRef) -> Query<'static> {
-        Query {
-            program: self.program.build(),
-            top_level: r,
+    /// If-then goal, proving `then` for first solution of `cond`, and
+    /// failing if `cond` has no solution
+    pub fn if_then(&mut self, cond: QueryRef, then: QueryRef) -> QueryRef {
+        self.structure(builtin::IF, vec![cond, then])
+    }
+
+    /// Negation as failure of goal, succeeding only if goal has no
+    /// solution
+    pub fn negation(&mut self, goal: QueryRef) -> QueryRef {
+        self.structure(builtin::NOT, vec![goal])
+    }
+
+    /// Sets occurs check mode for this query, overriding machine one
+    pub fn occurs_check(&mut self, occurs_check: OccursCheck) -> &mut Self {
+        self.occurs_check = Some(occurs_check);
+        self
+    }
+
+    /// Builds query for given goal, which may be a conjunction of goals
+    ///
+    /// Goal which is a variable is proven as `call/1` of it.
+    ///
+    /// Fails with `Error::NotCallable` if any of goals is an integer,
+    /// domain value or list
+    pub fn build(self, QueryRef(r): QueryRef) -> Result<Query<'static, C>, Error> {
+        compiler::callable(&self.terms, &[r])?;
+        let (program, vars) = compiler::query(&self.terms, &[r]);
+
+        Ok(Query {
+            program,
+            terms: self.terms,
+            vars,
+            occurs_check: self.occurs_check,
+        })
+    }
+}
+
+impl<'a, C: ConstDomain> QueryResult<'a, C> {
+    /// Returns true if query was proven
+    pub fn succeeded(&self) -> bool {
+        self.first.is_some()
+    }
+
+    /// Builds term unified with given query part in first solution
+    ///
+    /// Fails with `Error::NoSolution` if query didn't succeed
+    pub fn build_term<Builder: TermBuilder<C>>(
+        &self,
+        qref: QueryRef,
+        builder: &mut Builder,
+    ) -> Result<Builder::Term, Error> {
+        self.first
+            .as_ref()
+            .ok_or(Error::NoSolution)?
+            .build_term(qref, builder)
+    }
+
+    /// Builds residual goals of constraints and delayed goals left
+    /// suspended in first solution
+    ///
+    /// Fails with `Error::NoSolution` if query didn't succeed
+    pub fn residual_goals<Builder: TermBuilder<C>>(
+        &self,
+        builder: &mut Builder,
+    ) -> Result<Vec<Builder::Term>, Error> {
+        self.first
+            .as_ref()
+            .ok_or(Error::NoSolution)?
+            .residual_goals(builder)
+    }
+
+    /// Explains why query failed
+    ///
+    /// Returns None if query succeeded, or if its final failure was
+    /// not caused by clashing terms (eg. when first argument indexing
+    /// found no clause which may match the call)
+    pub fn failure_report<Builder: TermBuilder<C>>(
+        &self,
+        builder: &mut Builder,
+    ) -> Result<Option<FailureReport<Builder::Term>>, Error> {
+        let failure = if let Some(failure) = &self.failure {
+            failure
+        } else {
+            return Ok(None);
+        };
+
+        let left = failure.storage.build_term(failure.cells.0, builder)?;
+        let right = failure.storage.build_term(failure.cells.1, builder)?;
+
+        Ok(Some(FailureReport {
+            functors: failure.functors,
+            path: failure.path.clone(),
+            terms: (left, right),
+        }))
+    }
+
+    // Takes solution machine just reached
+    pub(crate) fn solution(&mut self) -> Result<Solution<C>, Error> {
+        let (storage, cells, goals) = self.machine.solution()?;
+
+        Ok(Solution {
+            terms: self.terms.clone(),
+            vars: self.vars.clone(),
+            cells,
+            goals,
+            storage,
+        })
+    }
+}
+
+impl<'a, C: ConstDomain> Iterator for QueryResult<'a, C> {
+    type Item = Result<Solution<C>, Error>;
+
+    fn next(&mut self) -> Option<Result<Solution<C>, Error>> {
+        if !self.started {
+            self.started = true;
+            self.exhausted = self.first.is_none();
+            return self.first.clone().map(Ok);
         }
+
+        if self.exhausted {
+            return None;
+        }
+
+        let solution = match self.machine.resume(self.code, &self.program) {
+            Ok(true) => Some(self.solution()),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        };
+
+        self.exhausted = !matches!(solution, Some(Ok(_)));
+        solution
     }
 }
 
-impl<'a> QueryResult<'a> {
-    pub fn build_term<Builder: TermBuilder>(
+impl<C: ConstDomain> Solution<C> {
+    /// Builds term unified with given query part
+    ///
+    /// Fails if given variable is not part of query goal
+    pub fn build_term<Builder: TermBuilder<C>>(
         &self,
         QueryRef(qref): QueryRef,
         builder: &mut Builder,
-    ) -> Option<Builder::Term> {
-        self.machine.build_term(*self.regs.get(qref)?, builder)
+    ) -> Result<Builder::Term, Error> {
+        // Built terms which are not yet a part of their structures
+        let mut built = vec![];
+        // Query parts to build, and if their subterms are already built
+        let mut pending = vec![(qref, false)];
+
+        while let Some((node, ready)) = pending.pop() {
+            match self.terms.get(node).ok_or(Error::InvalidQueryRef(node))? {
+                Term::Var => {
+                    let cell = self
+                        .vars
+                        .get(node)
+                        .copied()
+                        .flatten()
+                        .and_then(|y| self.cells.get(y))
+                        .ok_or(Error::InvalidQueryRef(node))?;
+                    built.push(self.storage.build_term(*cell, builder)?);
+                }
+                Term::Const(ident) => built.push(builder.constant(*ident)),
+                Term::Int(value) => built.push(builder.integer(*value)),
+                Term::Value(value) => built.push(builder.value(value)),
+                Term::Struct(ident, subterms) if subterms.is_empty() => {
+                    built.push(builder.constant(*ident));
+                }
+                Term::Struct(ident, subterms) if ready => {
+                    let subterms = built.drain(built.len() - subterms.len()..);
+                    let term = builder.structure(*ident, subterms);
+                    built.push(term);
+                }
+                Term::Struct(_, subterms) => {
+                    pending.push((node, true));
+                    pending.extend(subterms.iter().rev().map(|subterm| (*subterm, false)));
+                }
+                Term::List(_) if ready => {
+                    let tail = built.pop().ok_or(Error::InvalidQueryRef(node))?;
+                    let head = built.pop().ok_or(Error::InvalidQueryRef(node))?;
+                    built.push(builder.list(head, tail));
+                }
+                Term::List(subterms) => {
+                    pending.push((node, true));
+                    pending.extend(subterms.iter().rev().map(|subterm| (*subterm, false)));
+                }
+            }
+        }
+
+        built.pop().ok_or(Error::InvalidQueryRef(qref))
+    }
+
+    /// Builds residual goals of constraints left suspended in this
+    /// solution - domains of variables and undecided constraints, with
+    /// linear constraints over rationals projected onto query
+    /// variables, and `freeze/2` and `when/2` goals not woken yet
+    ///
+    /// Variables are built with the same ids as in terms unified with
+    /// query parts
+    pub fn residual_goals<Builder: TermBuilder<C>>(
+        &self,
+        builder: &mut Builder,
+    ) -> Result<Vec<Builder::Term>, Error> {
+        self.goals
+            .iter()
+            .map(|goal| self.storage.build_term(*goal, builder))
+            .collect()
     }
 }
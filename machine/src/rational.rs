@@ -0,0 +1,154 @@
+//! Exact rational numbers
+//!
+//! Rationals are kept in lowest terms with wider integers than cells,
+//! so results of linear constraints over cell integers rarely overflow.
+//! Overflow is still an error, as rationals are never rounded.
+
+use crate::Error;
+use std::cmp::Ordering;
+
+/// Rational number, with positive denominator and in lowest terms, so
+/// equal rationals have equal representations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Rational {
+    num: i128,
+    den: i128,
+}
+
+/// Greatest common divisor, non-negative
+pub(crate) fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a.abs()
+}
+
+fn checked(value: Option<i128>) -> Result<i128, Error> {
+    value.ok_or(Error::IntegerOverflow)
+}
+
+impl Rational {
+    pub(crate) const ZERO: Self = Self { num: 0, den: 1 };
+    pub(crate) const ONE: Self = Self { num: 1, den: 1 };
+
+    /// Rational of given numerator and denominator
+    ///
+    /// Fails with `Error::ZeroDivisor` if denominator is zero
+    pub(crate) fn new(num: i128, den: i128) -> Result<Self, Error> {
+        if den == 0 {
+            return Err(Error::ZeroDivisor);
+        }
+
+        let g = gcd(num, den);
+        let (num, den) = (num / g, den / g);
+        if den < 0 {
+            Ok(Self {
+                num: checked(num.checked_neg())?,
+                den: checked(den.checked_neg())?,
+            })
+        } else {
+            Ok(Self { num, den })
+        }
+    }
+
+    pub(crate) fn integer(value: isize) -> Self {
+        Self {
+            num: value as i128,
+            den: 1,
+        }
+    }
+
+    pub(crate) fn numer(self) -> i128 {
+        self.num
+    }
+
+    pub(crate) fn denom(self) -> i128 {
+        self.den
+    }
+
+    pub(crate) fn is_zero(self) -> bool {
+        self.num == 0
+    }
+
+    pub(crate) fn is_negative(self) -> bool {
+        self.num < 0
+    }
+
+    pub(crate) fn neg(self) -> Result<Self, Error> {
+        Ok(Self {
+            num: checked(self.num.checked_neg())?,
+            den: self.den,
+        })
+    }
+
+    pub(crate) fn add(self, other: Self) -> Result<Self, Error> {
+        let g = gcd(self.den, other.den);
+        let left = checked(self.num.checked_mul(other.den / g))?;
+        let right = checked(other.num.checked_mul(self.den / g))?;
+        let den = checked(self.den.checked_mul(other.den / g))?;
+        Self::new(checked(left.checked_add(right))?, den)
+    }
+
+    pub(crate) fn sub(self, other: Self) -> Result<Self, Error> {
+        self.add(other.neg()?)
+    }
+
+    pub(crate) fn mul(self, other: Self) -> Result<Self, Error> {
+        // Cross reduction keeps intermediate products small
+        let g1 = gcd(self.num, other.den).max(1);
+        let g2 = gcd(other.num, self.den).max(1);
+        let num = checked((self.num / g1).checked_mul(other.num / g2))?;
+        let den = checked((self.den / g2).checked_mul(other.den / g1))?;
+        Self::new(num, den)
+    }
+
+    /// Quotient of rationals
+    ///
+    /// Fails with `Error::ZeroDivisor` if divisor is zero
+    pub(crate) fn div(self, other: Self) -> Result<Self, Error> {
+        if other.is_zero() {
+            return Err(Error::ZeroDivisor);
+        }
+        self.mul(Self::new(other.den, other.num)?)
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    // Compared by continued fractions, so it never overflows
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (mut a, mut b) = (self.num, self.den);
+        let (mut c, mut d) = (other.num, other.den);
+        let mut reversed = false;
+
+        loop {
+            let (q1, r1) = (a.div_euclid(b), a.rem_euclid(b));
+            let (q2, r2) = (c.div_euclid(d), c.rem_euclid(d));
+            let result = match (q1.cmp(&q2), r1, r2) {
+                (Ordering::Equal, 0, 0) => Ordering::Equal,
+                (Ordering::Equal, 0, _) => Ordering::Less,
+                (Ordering::Equal, _, 0) => Ordering::Greater,
+                (Ordering::Equal, _, _) => {
+                    // Fractional parts compare reversed to their
+                    // reciprocals
+                    a = b;
+                    b = r1;
+                    c = d;
+                    d = r2;
+                    reversed = !reversed;
+                    continue;
+                }
+                (result, _, _) => result,
+            };
+
+            return if reversed { result.reverse() } else { result };
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use crate::program::ProgramBuilder;
-use crate::Program;
-use bitvec::{bitbox, bitvec};
+use crate::builtin;
+use crate::compiler::{self, Term};
+use crate::index::Key;
+use crate::storage::ConstDomain;
+use crate::{Error, Program};
 
 /// Reference to statement part for building complex (structure)
 /// statements
@@ -8,34 +10,106 @@ use bitvec::{bitbox, bitvec};
 pub struct StatementRef(pub(crate) usize);
 
 /// Statement to be added to machine state
-pub struct Statement<'a> {
-    pub(crate) program: Program<'a>,
+pub struct Statement<'a, C = usize> {
+    pub(crate) program: Program<'a, C>,
+    // Functor (ident and arity) of statement head, `None` if head
+    // is a variable, an integer, a constant domain value or a list
+    pub(crate) functor: Option<(usize, usize)>,
+    // Arguments of statement head flattened into their symbols, by
+    // which it is indexed
+    pub(crate) head: Vec<Key>,
+    // Predicates called by statement body, and if they are called
+    // negatively
+    pub(crate) calls: Vec<((usize, usize), bool)>,
 }
 
-impl<'a> Statement<'a> {
+impl<'a, C: ConstDomain> Statement<'a, C> {
     pub fn assembly(&self) -> String {
         self.program.assembly()
     }
 }
 
-#[derive(Clone)]
-enum RegisterAllocation {
-    Var,
-    Struct(usize, Vec<usize>),
+// Functor of statement head
+fn functor<C>(terms: &[Term<C>], head: usize) -> Option<(usize, usize)> {
+    match &terms[head] {
+        Term::Struct(ident, subterms) => Some((*ident, subterms.len())),
+        Term::Const(ident) => Some((*ident, 0)),
+        Term::Var | Term::Int(_) | Term::Value(_) | Term::List(_) => None,
+    }
+}
+
+// Arguments of statement head flattened in pre-order into their
+// symbols
+fn head<C>(terms: &[Term<C>], head: usize) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut pending: Vec<_> = match &terms[head] {
+        Term::Struct(_, args) => args.iter().rev().collect(),
+        _ => vec![],
+    };
+
+    while let Some(term) = pending.pop() {
+        let key = match &terms[*term] {
+            Term::Var => Key::Var,
+            Term::Const(ident) => Key::Const(*ident),
+            Term::Int(value) => Key::Int(*value),
+            Term::Value(_) => Key::Value,
+            Term::List(subterms) => {
+                pending.extend(subterms.iter().rev());
+                Key::List
+            }
+            Term::Struct(ident, subterms) => {
+                pending.extend(subterms.iter().rev());
+                Key::Struct(*ident, subterms.len())
+            }
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+// Predicates called by body goals, with flag if they are called
+// negatively - in negated goal, or in condition of if-then-else which
+// else branch is proven when condition fails
+fn calls<C>(terms: &[Term<C>], body: &[usize]) -> Vec<((usize, usize), bool)> {
+    let mut calls = vec![];
+    let mut pending: Vec<_> = body.iter().map(|goal| (*goal, false)).collect();
+
+    while let Some((goal, negative)) = pending.pop() {
+        match &terms[goal] {
+            Term::Const(builtin::CUT) => (),
+            Term::Struct(builtin::OR, args) if args.len() == 2 => {
+                match &terms[args[0]] {
+                    Term::Struct(builtin::IF, cond) if cond.len() == 2 => {
+                        pending.push((cond[0], true));
+                        pending.push((cond[1], negative));
+                    }
+                    _ => pending.push((args[0], negative)),
+                }
+                pending.push((args[1], negative));
+            }
+            Term::Struct(builtin::AND | builtin::IF, args) if args.len() == 2 => {
+                pending.extend(args.iter().map(|arg| (*arg, negative)));
+            }
+            Term::Struct(builtin::NOT, args) if args.len() == 1 => pending.push((args[0], true)),
+            Term::Struct(ident, args) => calls.push(((*ident, args.len()), negative)),
+            Term::Const(ident) => calls.push(((*ident, 0), negative)),
+            Term::Var => calls.push(((builtin::CALL, 1), negative)),
+            Term::Int(_) | Term::Value(_) | Term::List(_) => (),
+        }
+    }
+
+    calls
 }
 
 /// Builder for structured statement
-pub struct StatementBuilder {
-    registers: Vec<RegisterAllocation>,
+pub struct StatementBuilder<C = usize> {
+    terms: Vec<Term<C>>,
 }
 
-impl Default for StatementBuilder {
+impl<C> Default for StatementBuilder<C> {
     fn default() -> Self {
-        Self {
-            // First register is reserved for top-level
-            // structure
-            registers: vec![RegisterAllocation::Var],
-        }
+        Self { terms: vec![] }
     }
 }
 
@@ -43,10 +117,12 @@ impl StatementBuilder {
     pub fn new() -> Self {
         Default::default()
     }
+}
 
+impl<C: ConstDomain> StatementBuilder<C> {
     pub fn variable(&mut self) -> StatementRef {
-        self.registers.push(RegisterAllocation::Var);
-        StatementRef(self.registers.len() - 1)
+        self.terms.push(Term::Var);
+        StatementRef(self.terms.len() - 1)
     }
 
     pub fn structure(
@@ -54,43 +130,203 @@ impl StatementBuilder {
         ident: usize,
         subterms: impl IntoIterator<Item = StatementRef>,
     ) -> StatementRef {
-        self.registers.push(RegisterAllocation::Struct(
+        self.terms.push(Term::Struct(
             ident,
             subterms.into_iter().map(|StatementRef(r)| r).collect(),
         ));
-        StatementRef(self.registers.len() - 1)
+        StatementRef(self.terms.len() - 1)
     }
 
     pub fn constant(&mut self, ident: usize) -> StatementRef {
-        self.structure(ident, std::iter::empty())
+        self.terms.push(Term::Const(ident));
+        StatementRef(self.terms.len() - 1)
+    }
+
+    pub fn integer(&mut self, value: isize) -> StatementRef {
+        self.terms.push(Term::Int(value));
+        StatementRef(self.terms.len() - 1)
     }
 
-    pub fn build(mut self, StatementRef(r): StatementRef) -> Statement<'static> {
-        self.registers.swap(0, r);
+    pub fn value(&mut self, value: C) -> StatementRef {
+        self.terms.push(Term::Value(value));
+        StatementRef(self.terms.len() - 1)
+    }
 
-        let mut stack = vec![0];
-        let mut visited = bitbox![0; self.registers.len()];
-        let mut program = ProgramBuilder::default();
+    /// List cell with given head and tail
+    pub fn cons(&mut self, head: StatementRef, tail: StatementRef) -> StatementRef {
+        self.terms.push(Term::List([head.0, tail.0]));
+        StatementRef(self.terms.len() - 1)
+    }
 
-        while let Some(reg) = stack.pop() {
-            if let RegisterAllocation::Struct(ident, st) = &self.registers[reg] {
-                program.get_structure(*ident, st.len(), reg);
+    /// Empty list
+    pub fn nil(&mut self) -> StatementRef {
+        self.constant(builtin::NIL)
+    }
 
-                for i in st {
-                    if visited.get(*i).unwrap_or(false) {
-                        program.unify_value(*i);
-                    } else {
-                        program.unify_variable(*i);
-                        visited.set(*i, true);
-                    }
+    /// Proper list of given items
+    pub fn list(&mut self, items: impl IntoIterator<Item = StatementRef>) -> StatementRef {
+        let items: Vec<_> = items.into_iter().collect();
+        let nil = self.nil();
+        items
+            .into_iter()
+            .rev()
+            .fold(nil, |tail, head| self.cons(head, tail))
+    }
 
-                    stack.push(*i);
-                }
-            }
+    /// Builds fact with given head
+    ///
+    /// Head should be a structure - fact with variable or list head never
+    /// matches any query
+    pub fn build(self, StatementRef(r): StatementRef) -> Statement<'static, C> {
+        Statement {
+            program: compiler::clause(&self.terms, r, &[]),
+            functor: functor(&self.terms, r),
+            head: head(&self.terms, r),
+            calls: vec![],
+        }
+    }
+}
+
+/// Builder for rule statement (`head :- goal, goal, ...`)
+pub struct RuleBuilder<C = usize> {
+    statement: StatementBuilder<C>,
+}
+
+impl<C> Default for RuleBuilder<C> {
+    fn default() -> Self {
+        Self {
+            statement: Default::default(),
         }
+    }
+}
+
+impl RuleBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl<C: ConstDomain> RuleBuilder<C> {
+    pub fn variable(&mut self) -> StatementRef {
+        self.statement.variable()
+    }
+
+    pub fn structure(
+        &mut self,
+        ident: usize,
+        subterms: impl IntoIterator<Item = StatementRef>,
+    ) -> StatementRef {
+        self.statement.structure(ident, subterms)
+    }
+
+    pub fn constant(&mut self, ident: usize) -> StatementRef {
+        self.statement.constant(ident)
+    }
+
+    pub fn integer(&mut self, value: isize) -> StatementRef {
+        self.statement.integer(value)
+    }
+
+    pub fn value(&mut self, value: C) -> StatementRef {
+        self.statement.value(value)
+    }
+
+    /// List cell with given head and tail
+    pub fn cons(&mut self, head: StatementRef, tail: StatementRef) -> StatementRef {
+        self.statement.cons(head, tail)
+    }
+
+    /// Empty list
+    pub fn nil(&mut self) -> StatementRef {
+        self.statement.nil()
+    }
+
+    /// Proper list of given items
+    pub fn list(&mut self, items: impl IntoIterator<Item = StatementRef>) -> StatementRef {
+        self.statement.list(items)
+    }
+
+    /// Cut goal, committing to this clause and to solutions of goals
+    /// before it
+    pub fn cut(&mut self) -> StatementRef {
+        self.constant(builtin::CUT)
+    }
+
+    /// Conjunction of given goals, proven in order
+    pub fn conjunction(&mut self, goals: impl IntoIterator<Item = StatementRef>) -> StatementRef {
+        let goals: Vec<_> = goals.into_iter().collect();
+        match goals.split_last() {
+            Some((last, goals)) => goals
+                .iter()
+                .rev()
+                .fold(*last, |rest, goal| self.structure(builtin::AND, vec![*goal, rest])),
+            None => self.constant(builtin::TRUE),
+        }
+    }
+
+    /// Disjunction of two goals, proving right one on backtracking
+    /// from left one
+    pub fn disjunction(&mut self, left: StatementRef, right: StatementRef) -> StatementRef {
+        self.structure(builtin::OR, vec![left, right])
+    }
+
+    /// If-then-else goal, proving `then` for first solution of `cond`,
+    /// or `otherwise` if `cond` has no solution
+    pub fn if_then_else(
+        &mut self,
+        cond: StatementRef,
+        then: StatementRef,
+        otherwise: StatementRef,
+    ) -> StatementRef {
+        let if_then = self.if_then(cond, then);
+        self.disjunction(if_then, otherwise)
+    }
+
+    /// If-then goal, proving `then` for first solution of `cond`, and
+    /// failing if `cond` has no solution
+    pub fn if_then(&mut self, cond: StatementRef, then: StatementRef) -> StatementRef {
+        self.structure(builtin::IF, vec![cond, then])
+    }
+
+    /// Negation as failure of goal, succeeding only if goal has no
+    /// solution
+    pub fn negation(&mut self, goal: StatementRef) -> StatementRef {
+        self.structure(builtin::NOT, vec![goal])
+    }
+
+    /// Builds rule with given head and body goals, which are proven
+    /// in given order
+    ///
+    /// Goal which is a variable is proven as `call/1` of it.
+    ///
+    /// Fails with `Error::NotCallable` if any of body goals is an integer,
+    /// domain value or list
+    pub fn build(
+        self,
+        head: StatementRef,
+        body: impl IntoIterator<Item = StatementRef>,
+    ) -> Result<Statement<'static, C>, Error> {
+        let body: Vec<_> = body.into_iter().collect();
+        let goals: Vec<_> = body.iter().map(|StatementRef(r)| *r).collect();
+        compiler::callable(&self.statement.terms, &goals)?;
+        Ok(self.rule(head, body))
+    }
+
+    /// Builds rule of library predicate, which body goals are always
+    /// callable
+    pub(crate) fn rule(
+        self,
+        StatementRef(head): StatementRef,
+        body: impl IntoIterator<Item = StatementRef>,
+    ) -> Statement<'static, C> {
+        let terms = &self.statement.terms;
+        let body: Vec<_> = body.into_iter().map(|StatementRef(r)| r).collect();
 
         Statement {
-            program: program.build(),
+            program: compiler::clause(terms, head, &body),
+            functor: functor(terms, head),
+            head: self::head(terms, head),
+            calls: calls(terms, &body),
         }
     }
 }
@@ -1,5 +1,34 @@
+use crate::builtin;
+use crate::clpq::{Delta, Simplex};
+use crate::Error;
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Number of structure and list pairs unification walks through before
+/// it starts to track visited ones
+///
+/// Without occurs check terms may be cyclic, so visited pairs have to
+/// be tracked for unification to terminate. Hashing every pair would
+/// make unification of common, small terms several times slower, while
+/// walking through first pairs of cyclic terms once more is harmless -
+/// their arguments are already unified.
+const UNTRACKED_PAIRS: usize = 256;
+
+/// Domain of constant values embedded into terms, like interned
+/// strings or type ids
+///
+/// Values are compared by equality and hashed, so equal values are
+/// stored once. Implemented for every type which can do it, with
+/// `usize` being default domain of machine.
+pub trait ConstDomain: Clone + Eq + Hash + Debug {}
+
+impl<T: Clone + Eq + Hash + Debug> ConstDomain for T {}
+
 /// Single Cell in storage for public interface
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Cell {
     /// Reference to another Cell
     Ref(usize),
@@ -7,6 +36,18 @@ pub enum Cell {
     Struct(usize),
     /// Structure Functor (with its ident and arity)
     Funct(usize, usize),
+    /// List cell (with address of its head, followed by its tail)
+    List(usize),
+    /// Constant domain value (with its index in storage values)
+    Value(usize),
+    /// Constant (with its ident)
+    Con(usize),
+    /// Integer (with its value)
+    Int(isize),
+    /// Integer which doesn't fit in `Int` (with its index in storage
+    /// big integers)
+    #[cfg(feature = "bigint")]
+    BigInt(usize),
 }
 
 impl Default for Cell {
@@ -25,9 +66,61 @@ impl Cell {
     }
 }
 
+/// Environment frame of executed rule
+#[derive(Debug, Clone)]
+pub struct Environment {
+    /// Previous (continuation) environment
+    pub ce: Option<usize>,
+    /// Continuation program address
+    pub cp: usize,
+    /// Permanent variables
+    pub vars: Vec<Cell>,
+}
+
+/// Occurs check mode - what happens when variable would be bound to
+/// term containing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OccursCheck {
+    /// Variable is bound, creating cyclic term
+    #[default]
+    Off,
+    /// Unification fails
+    Fail,
+    /// Query is aborted with `Error::OccursCheck`
+    Error,
+}
+
+/// Terms (structures, constants or integers) which failed to unify
+#[derive(Debug, Clone, Copy)]
+pub enum Clash {
+    /// Terms reached by unification of two cells
+    Terms {
+        /// Cells which unification started from
+        roots: (Cell, Cell),
+        /// Clashing terms, in order of unified cells
+        terms: (Cell, Cell),
+    },
+    /// Term which is not a structure or list with functor expected by
+    /// `get_structure` or `get_list`, which never builds expected term
+    Expected { term: Cell, functor: (usize, usize) },
+}
+
+/// Change of storage, reverted on backtracking
+#[derive(Debug, Clone, Copy)]
+enum Trailed {
+    /// Binding of variable on given address
+    Bind(usize),
+    /// Attribute of variable on given address in given module, with
+    /// its previous value
+    Attr(usize, usize, Option<Cell>),
+    /// Upper or lower bound of column of linear constraints, with its
+    /// previous value
+    Bound(usize, bool, Option<Delta>),
+}
+
 /// Address space for machine
-#[derive(Debug)]
-pub struct Storage {
+#[derive(Debug, Clone)]
+pub struct Storage<C = usize> {
     /// Store begins with number of registers, defined before calulation,
     /// followed by heap which grows infienetely
     ///
@@ -38,18 +131,74 @@ pub struct Storage {
 
     /// Number for registers reserved (also index of first heap cell)
     regs: usize,
+
+    /// Cells bound since last choice point was created, and changed
+    /// attributes, which has to be reset on backtracking
+    trail: Vec<Trailed>,
+
+    /// Heap backtrack boundary - cells with lower addresses existed
+    /// before last choice point was created, so binding them has to
+    /// be trailed
+    hb: usize,
+
+    /// Environment stack, kept aside of heap as environments are
+    /// discarded when rule finishes, while heap grows until
+    /// backtracking
+    stack: Vec<Environment>,
+
+    /// Last terms clash, reported if query fails
+    clash: Option<Clash>,
+
+    /// Occurs check mode for binding variables
+    occurs_check: OccursCheck,
+
+    /// Attributes of variables by their addresses and modules, which
+    /// attach constraints to variables
+    attributes: BTreeMap<(usize, usize), Cell>,
+
+    /// Attributed variables bound since their constraints were last
+    /// woken
+    woken: Vec<usize>,
+
+    /// Tableau of linear constraints over rationals, which columns are
+    /// attributes of constrained variables
+    simplex: Simplex,
+
+    /// Big integers, kept out of cells so cells stay single word -
+    /// they are dropped on backtracking like the heap
+    #[cfg(feature = "bigint")]
+    bigints: Vec<BigInt>,
+
+    /// Constant domain values, every distinct value stored once, so
+    /// values are equal if they have the same index
+    values: Vec<C>,
+
+    /// Index of every stored value
+    interned: HashMap<C, usize>,
 }
 
-impl Default for Storage {
+impl<C> Default for Storage<C> {
     fn default() -> Self {
-        Storage {
+        Self {
             store: vec![],
             regs: 0,
+            trail: vec![],
+            hb: 0,
+            stack: vec![],
+            clash: None,
+            occurs_check: OccursCheck::Off,
+            attributes: BTreeMap::new(),
+            woken: vec![],
+            simplex: Simplex::default(),
+            #[cfg(feature = "bigint")]
+            bigints: vec![],
+            values: vec![],
+            interned: HashMap::new(),
         }
     }
 }
 
-impl std::ops::Deref for Storage {
+impl<C> std::ops::Deref for Storage<C> {
     type Target = [Cell];
 
     fn deref(&self) -> &[Cell] {
@@ -57,36 +206,307 @@ impl std::ops::Deref for Storage {
     }
 }
 
-impl std::ops::DerefMut for Storage {
+impl<C> std::ops::DerefMut for Storage<C> {
     fn deref_mut(&mut self) -> &mut [Cell] {
         &mut self.store
     }
 }
 
+#[cfg(test)]
 impl Storage {
-    pub fn new() -> Self {
-        Default::default()
-    }
-
-    #[cfg(test)]
     pub(crate) fn from_iter(regs: usize, store: impl Iterator<Item = Cell>) -> Self {
         Self {
             regs,
             store: store.collect(),
+            ..Default::default()
         }
     }
+}
+
+impl<C: ConstDomain> Storage<C> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
 
     /// Resets storage before execution
     ///
     /// * `regs` - Number of registers to be used in this calculation
     pub fn reset(&mut self, regs: usize) {
         self.regs = regs;
-        self.store.resize_with(regs, Default::default)
+        self.store.resize_with(regs, Default::default);
+        self.trail.clear();
+        self.hb = 0;
+        self.stack.clear();
+        self.clash = None;
+        self.attributes.clear();
+        self.woken.clear();
+        self.simplex = Simplex::default();
+        #[cfg(feature = "bigint")]
+        self.bigints.clear();
+        self.values.clear();
+        self.interned.clear();
     }
 
-    /// Returns slice of all registers
-    pub fn registers(&self) -> &[Cell] {
-        &self.store[0..self.regs]
+    /// Sets occurs check mode for binding variables
+    pub fn set_occurs_check(&mut self, occurs_check: OccursCheck) {
+        self.occurs_check = occurs_check;
+    }
+
+    /// Returns last terms clash, if it happened after last
+    /// call of `clear_clash`
+    pub fn clash(&self) -> Option<Clash> {
+        self.clash
+    }
+
+    /// Forgets last terms clash
+    pub fn clear_clash(&mut self) {
+        self.clash = None;
+    }
+
+    /// Finds argument positions leading from cells unification
+    /// started from to clashing terms
+    ///
+    /// Terms has to be still on the heap, so it has to be called
+    /// before backtracking
+    pub fn clash_path(&self, clash: &Clash) -> Result<Vec<usize>, Error> {
+        let (roots, terms) = match *clash {
+            Clash::Terms { roots, terms } => (roots, terms),
+            // Expected term clashes with register term itself
+            Clash::Expected { .. } => return Ok(vec![]),
+        };
+        let mut pending = vec![(roots.0, roots.1, vec![])];
+        // Pairs already walked through, as terms may be cyclic
+        let mut visited = HashSet::new();
+
+        // Pairs are walked in the same order as unification does, so
+        // first clashing pair found is the one unification failed on
+        while let Some((c1, c2, path)) = pending.pop() {
+            let c1 = self.deref_cell(c1)?;
+            let c2 = self.deref_cell(c2)?;
+
+            if (c1, c2) == terms {
+                return Ok(path);
+            }
+
+            let args = match (c1, c2) {
+                (Cell::Struct(s1), Cell::Struct(s2)) if self.funct(s1)? == self.funct(s2)? => {
+                    Some((s1 + 1, s2 + 1, self.funct(s1)?.1))
+                }
+                (Cell::List(l1), Cell::List(l2)) => Some((l1, l2, 2)),
+                _ => None,
+            };
+
+            if let Some((a1, a2, arity)) = args {
+                if visited.insert((a1, a2)) {
+                    for i in 0..arity {
+                        let mut path = path.clone();
+                        path.push(i);
+                        pending.push((self.cell(a1 + i)?, self.cell(a2 + i)?, path));
+                    }
+                }
+            }
+        }
+
+        // Unification never reaches terms out of unified ones
+        Err(Error::MalformedTerm)
+    }
+
+    /// Returns functor (ident and arity) of structure or constant,
+    /// or `None` for integer or constant domain value
+    pub fn functor(&self, cell: Cell) -> Result<Option<(usize, usize)>, Error> {
+        match self.deref_cell(cell)? {
+            Cell::Struct(addr) => self.funct(addr).map(Some),
+            Cell::List(_) => Ok(Some((builtin::CONS, 2))),
+            Cell::Con(ident) => Ok(Some((ident, 0))),
+            Cell::Int(_) | Cell::Value(_) => Ok(None),
+            #[cfg(feature = "bigint")]
+            Cell::BigInt(_) => Ok(None),
+            _ => Err(Error::MalformedTerm),
+        }
+    }
+
+    /// Places new environment on the stack at given index, discarding
+    /// all environments above it, and returns index of placed
+    /// environment
+    pub fn allocate(&mut self, at: usize, env: Environment) -> usize {
+        self.stack.truncate(at);
+        self.stack.push(env);
+        self.stack.len() - 1
+    }
+
+    /// Returns environment with given index
+    pub fn environment(&self, e: usize) -> Option<&Environment> {
+        self.stack.get(e)
+    }
+
+    /// Returns mutable environment with given index
+    pub fn environment_mut(&mut self, e: usize) -> Option<&mut Environment> {
+        self.stack.get_mut(e)
+    }
+
+    /// Returns environment stack height
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Pushes big integer, and returns cell referencing it
+    #[cfg(feature = "bigint")]
+    pub fn push_bigint(&mut self, value: BigInt) -> Cell {
+        self.bigints.push(value);
+        Cell::BigInt(self.bigints.len() - 1)
+    }
+
+    /// Returns big integer with given index
+    #[cfg(feature = "bigint")]
+    pub fn bigint(&self, idx: usize) -> Result<&BigInt, Error> {
+        self.bigints.get(idx).ok_or(Error::MalformedTerm)
+    }
+
+    /// Returns number of big integers, to be restored on backtracking
+    #[cfg(feature = "bigint")]
+    pub fn bigints_len(&self) -> usize {
+        self.bigints.len()
+    }
+
+    /// Drops all big integers pushed after there were given number
+    /// of them
+    #[cfg(feature = "bigint")]
+    pub fn truncate_bigints(&mut self, len: usize) {
+        self.bigints.truncate(len)
+    }
+
+    /// Stores constant domain value, if it is not stored yet, and
+    /// returns cell referencing it
+    ///
+    /// Values are kept until storage is reset, as they are shared by
+    /// all terms containing them
+    pub fn intern(&mut self, value: C) -> Cell {
+        let values = &mut self.values;
+        let idx = *self.interned.entry(value).or_insert_with_key(|value| {
+            values.push(value.clone());
+            values.len() - 1
+        });
+        Cell::Value(idx)
+    }
+
+    /// Returns constant domain value with given index
+    pub fn value(&self, idx: usize) -> Result<&C, Error> {
+        self.values.get(idx).ok_or(Error::MalformedTerm)
+    }
+
+    /// Drops all heap cells on and above given address
+    pub fn truncate(&mut self, len: usize) {
+        self.store.truncate(std::cmp::max(len, self.regs))
+    }
+
+    /// Sets heap backtrack boundary
+    pub fn set_hb(&mut self, hb: usize) {
+        self.hb = hb;
+    }
+
+    /// Returns trail length, to be restored on backtracking
+    pub fn trail_len(&self) -> usize {
+        self.trail.len()
+    }
+
+    /// Resets all cells and attributes trailed after trail had given
+    /// length
+    ///
+    /// Constraints of variables bound since then are not woken anymore
+    pub fn unwind_trail(&mut self, len: usize) {
+        self.undo(len);
+        self.woken.clear();
+    }
+
+    // Reverts changes trailed after trail had given length
+    fn undo(&mut self, len: usize) {
+        for trailed in self.trail.drain(len..).rev() {
+            match trailed {
+                Trailed::Bind(addr) => self.store[addr] = Cell::Ref(addr),
+                Trailed::Attr(addr, module, Some(value)) => {
+                    self.attributes.insert((addr, module), value);
+                }
+                Trailed::Attr(addr, module, None) => {
+                    self.attributes.remove(&(addr, module));
+                }
+                Trailed::Bound(col, upper, bound) => {
+                    self.simplex.set_bound(col, upper, bound);
+                }
+            }
+        }
+    }
+
+    // Trails binding of variable on given address if it is older than
+    // last choice point, and queues waking its constraints if it is
+    // attributed
+    fn trail(&mut self, addr: usize) {
+        if addr < self.hb {
+            self.trail.push(Trailed::Bind(addr));
+        }
+
+        if self.attributed(addr) {
+            self.woken.push(addr);
+        }
+    }
+
+    /// Returns attribute of variable on given address in given module
+    pub fn attribute(&self, addr: usize, module: usize) -> Option<Cell> {
+        self.attributes.get(&(addr, module)).copied()
+    }
+
+    /// Sets attribute of variable on given address in given module,
+    /// or removes it if value is `None`
+    ///
+    /// Attribute is always trailed, as variable may be dropped on
+    /// backtracking, and its address reused by another one
+    pub fn put_attribute(&mut self, addr: usize, module: usize, value: Option<Cell>) {
+        let previous = match value {
+            Some(value) => self.attributes.insert((addr, module), value),
+            None => self.attributes.remove(&(addr, module)),
+        };
+        self.trail.push(Trailed::Attr(addr, module, previous));
+    }
+
+    /// Returns addresses of variables with attribute in given module,
+    /// and their attributes, in order of addresses
+    pub fn with_attribute(&self, module: usize) -> Vec<(usize, Cell)> {
+        self.attributes
+            .iter()
+            .filter(|((_, m), _)| *m == module)
+            .map(|((addr, _), value)| (*addr, *value))
+            .collect()
+    }
+
+    // Checks if variable on given address has any attribute
+    fn attributed(&self, addr: usize) -> bool {
+        self.attributes
+            .range((addr, 0)..=(addr, usize::MAX))
+            .next()
+            .is_some()
+    }
+
+    pub(crate) fn simplex(&self) -> &Simplex {
+        &self.simplex
+    }
+
+    pub(crate) fn simplex_mut(&mut self) -> &mut Simplex {
+        &mut self.simplex
+    }
+
+    /// Sets upper or lower bound of column of linear constraints,
+    /// or removes it if bound is `None`
+    ///
+    /// Bound is always trailed, as columns are never dropped
+    pub(crate) fn set_bound(&mut self, col: usize, upper: bool, bound: Option<Delta>) {
+        let previous = self.simplex.set_bound(col, upper, bound);
+        self.trail.push(Trailed::Bound(col, upper, previous));
+    }
+
+    /// Takes addresses of attributed variables bound since this was
+    /// last called, which constraints has to be woken
+    pub fn take_woken(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.woken)
     }
 
     /// Pushes struct to heap, and returns pushed struct cell
@@ -109,82 +529,327 @@ impl Storage {
         *self.last().unwrap()
     }
 
-    /// Dereferences cell from given index, and returns
-    /// index of destinated cell
+    /// Dereferences cell, and returns destinated cell value
     ///
-    /// Returns None if index is out of bound, or if
-    /// referencing cell out of bound
-    pub fn deref_idx(&self, mut addr: usize) -> Option<usize> {
-        while let Cell::Ref(a) = self.store.get(addr)? {
-            if *a == addr {
-                return Some(*a);
-            } else {
-                addr = *a
+    /// For unbound variable, this is its self referencing cell
+    ///
+    /// Fails if referencing cell out of bound
+    pub fn deref_cell(&self, mut cell: Cell) -> Result<Cell, Error> {
+        while let Cell::Ref(addr) = cell {
+            let target = self.cell(addr)?;
+            if target == cell {
+                break;
             }
+            cell = target;
         }
 
-        Some(addr)
+        Ok(cell)
+    }
+
+    /// Returns cell on given address
+    pub fn cell(&self, addr: usize) -> Result<Cell, Error> {
+        self.store.get(addr).copied().ok_or(Error::InvalidAddress(addr))
     }
 
-    /// Dereferences cell from given index, and returns
-    /// contained cell value
+    /// Returns functor (ident and arity) on given address
+    pub fn funct(&self, addr: usize) -> Result<(usize, usize), Error> {
+        self.cell(addr)?
+            .to_funct()
+            .ok_or(Error::InvalidAddress(addr))
+    }
+
+    /// Copies terms of given cells into new storage without any
+    /// registers, so they are not affected by further execution
     ///
-    /// Returns None if index is out of bound, or if
-    /// referencing cell out of bound
-    pub fn deref(&self, addr: usize) -> Option<Cell> {
-        self.deref_idx(addr).map(|idx| self.store[idx])
+    /// Returns new storage with given cells relocated into it. Fails
+    /// if any of cells references out of bound
+    pub fn copy_terms(&self, cells: &[Cell]) -> Result<(Storage<C>, Vec<Cell>), Error> {
+        let mut copy = Self::new();
+        // Address of every copied variable, structure and list in the
+        // copy - list may start on the same address as its head variable
+        let mut copied = HashMap::new();
+        // First arguments of copied structures and lists, and number of
+        // arguments still to be copied
+        let mut pending = vec![];
+
+        let mut copy_cell = |cell, copy: &mut Self, pending: &mut Vec<_>| {
+            let cell = self.deref_cell(cell)?;
+            match cell {
+                Cell::Ref(_) => {
+                    let target = match copied.get(&cell) {
+                        Some(target) => *target,
+                        None => {
+                            let target = copy.len();
+                            copy.push_var();
+                            copied.insert(cell, target);
+                            target
+                        }
+                    };
+                    Ok(Cell::Ref(target))
+                }
+                Cell::Struct(addr) => {
+                    if let Some(target) = copied.get(&cell) {
+                        return Ok(Cell::Struct(*target));
+                    }
+
+                    // Arguments are reserved, and filled later
+                    let (ident, arity) = self.funct(addr)?;
+                    let target = copy.len();
+                    copy.push_cell(Cell::Funct(ident, arity));
+                    copy.store.resize_with(target + 1 + arity, Default::default);
+                    copied.insert(cell, target);
+                    pending.push((addr + 1, target + 1, arity));
+                    Ok(Cell::Struct(target))
+                }
+                Cell::List(addr) => {
+                    if let Some(target) = copied.get(&cell) {
+                        return Ok(Cell::List(*target));
+                    }
+
+                    let target = copy.len();
+                    copy.store.resize_with(target + 2, Default::default);
+                    copied.insert(cell, target);
+                    pending.push((addr, target, 2));
+                    Ok(Cell::List(target))
+                }
+                Cell::Con(ident) => Ok(Cell::Con(ident)),
+                Cell::Int(value) => Ok(Cell::Int(value)),
+                Cell::Value(idx) => Ok(copy.intern(self.value(idx)?.clone())),
+                #[cfg(feature = "bigint")]
+                Cell::BigInt(idx) => Ok(copy.push_bigint(self.bigint(idx)?.clone())),
+                // Functor is only a part of structure, never a term itself
+                Cell::Funct(_, _) => Err(Error::MalformedTerm),
+            }
+        };
+
+        let roots: Result<Vec<_>, _> = cells
+            .iter()
+            .map(|cell| copy_cell(*cell, &mut copy, &mut pending))
+            .collect();
+        let roots = roots?;
+
+        while let Some((addr, target, arity)) = pending.pop() {
+            for i in 0..arity {
+                let cell = self.cell(addr + i)?;
+                copy.store[target + i] = copy_cell(cell, &mut copy, &mut pending)?;
+            }
+        }
+
+        Ok((copy, roots))
     }
 
-    /// Binds self referenced cell to the other cell if one of
-    /// given cell is self referencing
-    pub fn bind(&mut self, a1: usize, a2: usize) {
-        match (self.store[a1], self.store[a2]) {
-            (Cell::Ref(r1), _) if r1 == a1 => self.store[a1] = Cell::Ref(a2),
-            (_, Cell::Ref(r2)) if r2 == a2 => self.store[a2] = Cell::Ref(a1),
-            _ => (),
+    /// Checks if term (unbound variable, structure or list) occurs
+    /// in term of given cell
+    pub fn occurs(&self, term: Cell, cell: Cell) -> Result<bool, Error> {
+        let mut pending = vec![cell];
+
+        while let Some(cell) = pending.pop() {
+            match self.deref_cell(cell)? {
+                cell if cell == term => return Ok(true),
+                Cell::Struct(a) => {
+                    let (_, arity) = self.funct(a)?;
+                    for i in 1..=arity {
+                        pending.push(self.cell(a + i)?);
+                    }
+                }
+                Cell::List(a) => {
+                    pending.push(self.cell(a)?);
+                    pending.push(self.cell(a + 1)?);
+                }
+                _ => (),
+            }
         }
+
+        Ok(false)
     }
 
-    fn unify_struct(&mut self, s1: usize, s2: usize, pld: &mut Vec<(usize, usize)>) -> Option<()> {
-        let (f1, n1) = self.store.get(s1)?.to_funct()?;
-        let (f2, n2) = self.store.get(s2)?.to_funct()?;
+    /// Performs occurs check, if it is enabled, for binding term
+    /// (unbound variable, or structure or list being built) to term
+    /// of given cell
+    pub fn check_occurs(&self, term: Cell, cell: Cell) -> Result<(), Error> {
+        // Unbound variable never contains another cell
+        let occurs = match (self.occurs_check, self.deref_cell(cell)?) {
+            (OccursCheck::Off, _) | (_, Cell::Ref(_)) => false,
+            (_, cell) => self.occurs(term, cell)?,
+        };
+
+        match (self.occurs_check, term) {
+            _ if !occurs => Ok(()),
+            (OccursCheck::Error, Cell::Ref(addr) | Cell::Struct(addr) | Cell::List(addr)) => {
+                Err(Error::OccursCheck(addr))
+            }
+            _ => Err(Error::UnificationFailure),
+        }
+    }
+
+    /// Binds unbound variable on given address to given cell
+    ///
+    /// Variable becomes copy of the cell instead of reference to
+    /// its address, as cell may be taken from a register which would
+    /// be overwritten later
+    ///
+    /// Fails if occurs check is enabled, and variable occurs in
+    /// bound term
+    pub fn bind(&mut self, addr: usize, cell: Cell) -> Result<(), Error> {
+        self.check_occurs(Cell::Ref(addr), cell)?;
+        self.store[addr] = cell;
+        self.trail(addr);
+        Ok(())
+    }
+
+    /// Binds unbound variable on given address to new structure
+    /// pushed on heap, and returns pushed struct cell
+    ///
+    /// Arguments of structure are not pushed yet, so variable can't
+    /// occur in it
+    pub fn bind_struct(&mut self, addr: usize, ident: usize, arity: usize) -> Cell {
+        let cell = self.push_struct(ident, arity);
+        self.store[addr] = cell;
+        self.trail(addr);
+        cell
+    }
+
+    /// Binds unbound variable on given address to new list starting
+    /// on heap top, and returns list cell
+    ///
+    /// List head and tail are not pushed yet, so variable can't
+    /// occur in it
+    pub fn bind_list(&mut self, addr: usize) -> Cell {
+        let cell = Cell::List(self.store.len());
+        self.store[addr] = cell;
+        self.trail(addr);
+        cell
+    }
+
+    fn unify_struct(
+        &mut self,
+        s1: usize,
+        s2: usize,
+        roots: (Cell, Cell),
+        pld: &mut Vec<(Cell, Cell)>,
+    ) -> Result<(), Error> {
+        let (f1, n1) = self.funct(s1)?;
+        let (f2, n2) = self.funct(s2)?;
 
         if f1 == f2 && n1 == n2 {
             for i in 1..=n1 {
-                pld.push((s1 + i, s2 + i))
+                pld.push((self.cell(s1 + i)?, self.cell(s2 + i)?))
             }
+            Ok(())
         } else {
-            None?
-        };
+            Err(self.record_clash(Clash::Terms {
+                roots,
+                terms: (Cell::Struct(s1), Cell::Struct(s2)),
+            }))
+        }
+    }
 
-        Some(())
+    /// Records clash of terms, returning unification failure
+    pub(crate) fn record_clash(&mut self, clash: Clash) -> Error {
+        self.clash = Some(clash);
+        Error::UnificationFailure
     }
 
-    /// Unifies two cells in storage
+    /// Unifies two cells
     ///
-    /// Returns true if unification succeed, false otherwise
-    pub fn unify(&mut self, a1: usize, a2: usize) -> bool {
-        // Try block workaround
-        || -> Option<()> {
-            let mut pld = vec![(a1, a2)];
+    /// Fails with `Error::UnificationFailure` if cells are not
+    /// unifiable
+    pub fn unify(&mut self, c1: Cell, c2: Cell) -> Result<(), Error> {
+        let roots = (c1, c2);
+        let mut pld = vec![roots];
+        // Without occurs check terms may be cyclic (rational trees), so
+        // the same structures may be reached again
+        let cyclic = self.occurs_check == OccursCheck::Off;
+        let mut visited = HashSet::new();
+        let mut pairs = 0;
+        let mut first = |pair| {
+            pairs += 1;
+            !cyclic || pairs <= UNTRACKED_PAIRS || visited.insert(pair)
+        };
 
-            while let Some((d1, d2)) = pld.pop() {
-                let d1 = self.deref_idx(d1)?;
-                let d2 = self.deref_idx(d2)?;
+        while let Some((c1, c2)) = pld.pop() {
+            let c1 = self.deref_cell(c1)?;
+            let c2 = self.deref_cell(c2)?;
 
-                if d1 != d2 {
-                    match (self.store[d1], self.store[d2]) {
-                        (Cell::Ref(_), _) | (_, Cell::Ref(_)) => self.bind(d1, d2),
-                        (Cell::Struct(v1), Cell::Struct(v2)) => {
-                            self.unify_struct(v1, v2, &mut pld)?
+            if c1 != c2 {
+                match (c1, c2) {
+                    // Younger variable is bound to older one, so
+                    // binding is never trailed without need - unless
+                    // only the older one is attributed, as binding
+                    // plain variable keeps attributes without waking
+                    // their constraints
+                    (Cell::Ref(a1), Cell::Ref(a2)) => {
+                        match (self.attributed(a1), self.attributed(a2)) {
+                            (true, false) => self.bind(a2, c1)?,
+                            (false, true) => self.bind(a1, c2)?,
+                            _ if a1 < a2 => self.bind(a2, c1)?,
+                            _ => self.bind(a1, c2)?,
                         }
-                        _ => None?,
+                    }
+                    (Cell::Ref(a), cell) | (cell, Cell::Ref(a)) => self.bind(a, cell)?,
+                    (Cell::Struct(v1), Cell::Struct(v2)) => {
+                        if first((v1, v2)) {
+                            self.unify_struct(v1, v2, roots, &mut pld)?
+                        }
+                    }
+                    (Cell::List(l1), Cell::List(l2)) => {
+                        if first((l1, l2)) {
+                            pld.push((self.cell(l1)?, self.cell(l2)?));
+                            pld.push((self.cell(l1 + 1)?, self.cell(l2 + 1)?));
+                        }
+                    }
+                    (Cell::Funct(_, _), _) | (_, Cell::Funct(_, _)) => {
+                        return Err(Error::MalformedTerm)
+                    }
+                    // Big integers are compared by value, as the same
+                    // value may be stored more than once
+                    #[cfg(feature = "bigint")]
+                    (Cell::BigInt(b1), Cell::BigInt(b2)) if self.bigint(b1)? == self.bigint(b2)? => (),
+                    _ => {
+                        let terms = (c1, c2);
+                        return Err(self.record_clash(Clash::Terms { roots, terms }));
                     }
                 }
             }
+        }
+
+        Ok(())
+    }
 
-            Some(())
-        }()
-        .is_some()
+    /// Finds variables which unification of two cells would bind,
+    /// without binding them
+    ///
+    /// Returns `None` if cells are not unifiable, and no variables if
+    /// they are identical. Variable which would be bound to another
+    /// one is given together with it, as binding any of them changes
+    /// the result.
+    pub fn unifier(&mut self, c1: Cell, c2: Cell) -> Result<Option<Vec<usize>>, Error> {
+        let (hb, trail, woken, clash) = (self.hb, self.trail.len(), self.woken.len(), self.clash);
+        // Every binding is trailed, so all of them are reverted
+        self.hb = self.store.len();
+        let unified = self.unify(c1, c2);
+
+        let mut vars = vec![];
+        for trailed in &self.trail[trail..] {
+            if let Trailed::Bind(addr) = *trailed {
+                vars.push(addr);
+                if let Cell::Ref(target) = self.deref_cell(Cell::Ref(addr))? {
+                    vars.push(target);
+                }
+            }
+        }
+        vars.sort_unstable();
+        vars.dedup();
+
+        self.undo(trail);
+        self.woken.truncate(woken);
+        self.hb = hb;
+        self.clash = clash;
+
+        match unified {
+            Ok(()) => Ok(Some(vars)),
+            Err(Error::UnificationFailure) => Ok(None),
+            Err(err) => Err(err),
+        }
     }
 }
@@ -1,6 +1,11 @@
-use crate::{Cell, Machine};
+use crate::builtin;
+use crate::storage::{ConstDomain, Storage};
+use crate::{Cell, Error};
+#[cfg(feature = "bigint")]
+use num_bigint::{BigInt, Sign};
+use std::collections::HashMap;
 
-pub trait TermBuilder {
+pub trait TermBuilder<C = usize> {
     type Term;
 
     fn variable(&mut self, id: usize) -> Self::Term;
@@ -9,57 +14,195 @@ pub trait TermBuilder {
     fn constant(&mut self, ident: usize) -> Self::Term {
         self.structure(ident, std::iter::empty())
     }
+    fn integer(&mut self, value: isize) -> Self::Term;
+
+    /// List cell with given head and tail
+    ///
+    /// By default it is built as `builtin::CONS/2` structure
+    fn list(&mut self, head: Self::Term, tail: Self::Term) -> Self::Term {
+        self.structure(builtin::CONS, vec![head, tail].into_iter())
+    }
+
+    /// Constant domain value
+    fn value(&mut self, value: &C) -> Self::Term;
+
+    /// Integer which doesn't fit in `isize`
+    ///
+    /// By default it is built as arithmetic expression of 16-bit
+    /// digits, `+(*(D1, 65536), D0)`, which evaluates to the integer,
+    /// so builders don't have to know about big integers
+    #[cfg(feature = "bigint")]
+    fn big_integer(&mut self, value: &BigInt) -> Self::Term {
+        let (sign, digits) = value.to_u32_digits();
+        let mut digits = digits
+            .iter()
+            .rev()
+            .flat_map(|digit| vec![digit >> 16, digit & 0xffff])
+            .skip_while(|digit| *digit == 0);
+
+        let first = digits.next().unwrap_or(0);
+        let mut term = self.integer(first as isize);
+        for digit in digits {
+            let base = self.integer(0x10000);
+            let shifted = self.structure(builtin::MUL, vec![term, base].into_iter());
+            let digit = self.integer(digit as isize);
+            term = self.structure(builtin::ADD, vec![shifted, digit].into_iter());
+        }
+
+        if sign == Sign::Minus {
+            term = self.structure(builtin::SUB, vec![term].into_iter());
+        }
+        term
+    }
+
+    /// Back reference to structure (or list) with given id, which
+    /// contains this reference (so the term is cyclic)
+    ///
+    /// By default it is built as variable with structure id
+    fn cycle(&mut self, id: usize) -> Self::Term {
+        self.variable(id)
+    }
+
+    /// Structure with given id, which is referenced by `cycle` inside
+    /// of it
+    ///
+    /// By default structure is returned as it is
+    fn cyclic(&mut self, _id: usize, term: Self::Term) -> Self::Term {
+        term
+    }
+}
+
+/// Structure or list which subterms are being built
+struct Frame {
+    addr: usize,
+    // Address of first subterm
+    args: usize,
+    // Structure ident, `None` for list
+    ident: Option<usize>,
+    arity: usize,
+    // Next subterm to build
+    next: usize,
+    // If structure is referenced by cycle inside of it
+    cyclic: bool,
 }
 
-impl Machine {
-    pub(crate) fn build_term<Builder: TermBuilder>(
+impl<C: ConstDomain> Storage<C> {
+    /// Builds term of given cell
+    ///
+    /// Term is built without recursion, so it may be arbitrarily
+    /// deep. Subterms are built before their structures, so builder
+    /// gets terms bottom-up.
+    pub(crate) fn build_term<Builder: TermBuilder<C>>(
         &self,
         cell: Cell,
         builder: &mut Builder,
-    ) -> Option<Builder::Term> {
-        match cell {
-            Cell::Ref(idx) => {
-                let target = self.storage().deref(idx)?;
+    ) -> Result<Builder::Term, Error> {
+        // Built terms which are not yet a part of their structures
+        let mut built = vec![];
+        let mut frames: Vec<Frame> = vec![];
+        // Frames of structures being built, by their addresses
+        let mut building = HashMap::new();
+        let mut next = Some(cell);
 
-                if let Cell::Ref(idx) = target {
-                    Some(builder.variable(idx))
-                } else {
-                    self.build_term(target, builder)
+        loop {
+            if let Some(cell) = next.take() {
+                match self.deref_cell(cell)? {
+                    Cell::Ref(idx) => built.push(builder.variable(idx)),
+                    Cell::Con(ident) => built.push(builder.constant(ident)),
+                    Cell::Int(value) => built.push(builder.integer(value)),
+                    Cell::Value(idx) => built.push(builder.value(self.value(idx)?)),
+                    #[cfg(feature = "bigint")]
+                    Cell::BigInt(idx) => built.push(builder.big_integer(self.bigint(idx)?)),
+                    Cell::Struct(idx) => {
+                        let (ident, arity) = self
+                            .cell(idx)?
+                            .to_funct()
+                            .ok_or(Error::InvalidAddress(idx))?;
+
+                        if let Some(frame) = building.get(&idx) {
+                            let frame: &mut Frame = &mut frames[*frame];
+                            frame.cyclic = true;
+                            built.push(builder.cycle(idx));
+                        } else if arity == 0 {
+                            built.push(builder.constant(ident));
+                        } else {
+                            building.insert(idx, frames.len());
+                            frames.push(Frame {
+                                addr: idx,
+                                args: idx + 1,
+                                ident: Some(ident),
+                                arity,
+                                next: 0,
+                                cyclic: false,
+                            });
+                        }
+                    }
+                    Cell::List(idx) => {
+                        if let Some(frame) = building.get(&idx) {
+                            let frame: &mut Frame = &mut frames[*frame];
+                            frame.cyclic = true;
+                            built.push(builder.cycle(idx));
+                        } else {
+                            building.insert(idx, frames.len());
+                            frames.push(Frame {
+                                addr: idx,
+                                args: idx,
+                                ident: None,
+                                arity: 2,
+                                next: 0,
+                                cyclic: false,
+                            });
+                        }
+                    }
+                    Cell::Funct(_, _) => return Err(Error::MalformedTerm),
                 }
             }
-            Cell::Struct(idx) => {
-                if let Cell::Funct(ident, arity) = self.storage().get(idx)? {
-                    if *arity == 0 {
-                        Some(builder.constant(*ident))
-                    } else {
-                        let subterms: Option<Vec<_>> = self.storage()[idx + 1..=idx + arity]
-                            .iter()
-                            .map(|cell| self.build_term(*cell, builder))
-                            .collect();
-                        let subterms = subterms?;
-
-                        Some(builder.structure(*ident, subterms.into_iter()))
+
+            let frame = if let Some(frame) = frames.last_mut() {
+                frame
+            } else {
+                break;
+            };
+
+            if frame.next < frame.arity {
+                next = Some(self.cell(frame.args + frame.next)?);
+                frame.next += 1;
+            } else {
+                let frame = frames.pop().ok_or(Error::MalformedTerm)?;
+                building.remove(&frame.addr);
+
+                let mut subterms = built.drain(built.len() - frame.arity..);
+                let term = match frame.ident {
+                    Some(ident) => builder.structure(ident, subterms),
+                    None => {
+                        let head = subterms.next().ok_or(Error::MalformedTerm)?;
+                        let tail = subterms.next().ok_or(Error::MalformedTerm)?;
+                        drop(subterms);
+                        builder.list(head, tail)
                     }
+                };
+                if frame.cyclic {
+                    built.push(builder.cyclic(frame.addr, term));
                 } else {
-                    None
+                    built.push(term);
                 }
             }
-            _ => None,
         }
+
+        built.pop().ok_or(Error::MalformedTerm)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::test_utils::ast::{Builder, Term};
-    use crate::{storage::Storage, Cell, Machine};
+    use crate::{storage::Storage, Cell, TermBuilder};
 
     #[test]
     fn single_const() {
         let storage = Storage::from_iter(0, vec![Cell::Struct(1), Cell::Funct(0, 0)].into_iter());
 
-        let machine = Machine::with_storage(storage);
-        let term = machine
+        let term = storage
             .build_term(Cell::Struct(1), &mut Builder)
             .unwrap();
         let expected = Term::Const(0);
@@ -71,8 +214,7 @@ mod tests {
     fn single_var() {
         let storage = Storage::from_iter(0, vec![Cell::Ref(0)].into_iter());
 
-        let machine = Machine::with_storage(storage);
-        let term = machine
+        let term = storage
             .build_term(Cell::Ref(0), &mut Builder)
             .unwrap();
         let expected = Term::Var(0);
@@ -101,8 +243,7 @@ mod tests {
             .into_iter(),
         );
 
-        let machine =  Machine::with_storage(storage);
-        let term = machine
+        let term = storage
             .build_term(Cell::Struct(8), &mut Builder)
             .unwrap();
 
@@ -117,4 +258,144 @@ mod tests {
 
         assert_eq!(expected, term);
     }
+
+    #[test]
+    fn cyclic_term() {
+        // X = f(a, g(X))
+        let storage = Storage::from_iter(
+            0,
+            vec![
+                Cell::Struct(1),
+                Cell::Funct(0, 2),
+                Cell::Struct(4),
+                Cell::Struct(6),
+                Cell::Funct(1, 0),
+                Cell::Struct(6),
+                Cell::Funct(2, 1),
+                Cell::Struct(1),
+            ]
+            .into_iter(),
+        );
+
+        let term = storage
+            .build_term(Cell::Struct(1), &mut Builder)
+            .unwrap();
+
+        let expected = Term::Cyclic(
+            1,
+            Box::new(Term::Struct(
+                0,
+                vec![
+                    Term::Const(1),
+                    Term::Struct(2, vec![Term::Cycle(1)]),
+                ],
+            )),
+        );
+
+        assert_eq!(expected, term);
+    }
+
+    #[test]
+    fn deep_term() {
+        // Depth of term
+        struct Depth;
+
+        impl TermBuilder for Depth {
+            type Term = usize;
+
+            fn variable(&mut self, _id: usize) -> usize {
+                1
+            }
+
+            fn integer(&mut self, _value: isize) -> usize {
+                1
+            }
+
+            fn value(&mut self, _value: &usize) -> usize {
+                1
+            }
+
+            #[cfg(feature = "bigint")]
+            fn big_integer(&mut self, _value: &crate::BigInt) -> usize {
+                1
+            }
+
+            fn structure(&mut self, _ident: usize, subterms: impl Iterator<Item = usize>) -> usize {
+                subterms.max().unwrap_or(0) + 1
+            }
+        }
+
+        // f(f(f(...f(a)...)))
+        const DEPTH: usize = 1_000_000;
+        let cells = (0..DEPTH)
+            .flat_map(|i| vec![Cell::Struct(2 * i + 1), Cell::Funct(0, 1)])
+            .chain(vec![Cell::Struct(2 * DEPTH + 1), Cell::Funct(1, 0)]);
+        let storage = Storage::from_iter(0, cells);
+
+        let depth = storage.build_term(Cell::Struct(1), &mut Depth).unwrap();
+
+        assert_eq!(DEPTH + 1, depth);
+    }
+
+    #[test]
+    #[cfg(feature = "bigint")]
+    fn default_big_integer() {
+        use crate::builtin::{ADD, MUL, SUB};
+
+        // Builder which doesn't know about big integers
+        struct Plain;
+
+        impl TermBuilder for Plain {
+            type Term = Term;
+
+            fn variable(&mut self, id: usize) -> Term {
+                Term::Var(id)
+            }
+
+            fn integer(&mut self, value: isize) -> Term {
+                Term::Int(value)
+            }
+
+            fn value(&mut self, value: &usize) -> Term {
+                Term::Value(format!("{:?}", value))
+            }
+
+            fn structure(&mut self, ident: usize, subterms: impl Iterator<Item = Term>) -> Term {
+                Term::Struct(ident, subterms.collect())
+            }
+        }
+
+        // -(2^32 + 5)
+        let mut storage = Storage::new();
+        let value = -(crate::BigInt::from(1u64 << 32) + 5u32);
+        let cell = storage.push_bigint(value.clone());
+
+        let shifted = |term| Term::Struct(MUL, vec![term, Term::Int(0x10000)]);
+        let high = Term::Struct(ADD, vec![shifted(Term::Int(1)), Term::Int(0)]);
+        let expected = Term::Struct(
+            SUB,
+            vec![Term::Struct(ADD, vec![shifted(high), Term::Int(5)])],
+        );
+        assert_eq!(Ok(expected), storage.build_term(cell, &mut Plain));
+
+        // Built expression evaluates back to the integer
+        fn push(storage: &mut Storage, term: &Term) -> Cell {
+            match term {
+                Term::Struct(ident, subterms) => {
+                    let subterms: Vec<_> = subterms.iter().map(|t| push(storage, t)).collect();
+                    let cell = storage.push_struct(*ident, subterms.len());
+                    for subterm in subterms {
+                        storage.push_cell(subterm);
+                    }
+                    cell
+                }
+                Term::Int(value) => Cell::Int(*value),
+                _ => unreachable!(),
+            }
+        }
+
+        let term = storage.build_term(cell, &mut Plain).unwrap();
+        let expression = push(&mut storage, &term);
+        assert_eq!(Ok(value), storage.evaluate(expression));
+    }
 }
@@ -1,16 +1,25 @@
 use crate::term_builder::TermBuilder;
+#[cfg(feature = "bigint")]
+use num_bigint::BigInt;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Clone)]
 pub enum Term {
     Var(usize),
     Const(usize),
+    Int(isize),
+    // Debug representation of constant domain value
+    Value(String),
+    #[cfg(feature = "bigint")]
+    BigInt(BigInt),
     Struct(usize, Vec<Term>),
+    Cycle(usize),
+    Cyclic(usize, Box<Term>),
 }
 
 pub struct Builder;
 
-impl TermBuilder for Builder {
+impl<C: std::fmt::Debug> TermBuilder<C> for Builder {
     type Term = Term;
 
     fn variable(&mut self, id: usize) -> Term {
@@ -21,9 +30,30 @@ impl TermBuilder for Builder {
         Term::Const(ident)
     }
 
+    fn integer(&mut self, value: isize) -> Term {
+        Term::Int(value)
+    }
+
+    fn value(&mut self, value: &C) -> Term {
+        Term::Value(format!("{:?}", value))
+    }
+
+    #[cfg(feature = "bigint")]
+    fn big_integer(&mut self, value: &BigInt) -> Term {
+        Term::BigInt(value.clone())
+    }
+
     fn structure(&mut self, ident: usize, subterms: impl Iterator<Item = Term>) -> Term {
         Term::Struct(ident, subterms.collect())
     }
+
+    fn cycle(&mut self, id: usize) -> Term {
+        Term::Cycle(id)
+    }
+
+    fn cyclic(&mut self, id: usize, term: Term) -> Term {
+        Term::Cyclic(id, Box::new(term))
+    }
 }
 
 impl std::fmt::Debug for Term {
@@ -31,11 +61,17 @@ impl std::fmt::Debug for Term {
         match self {
             Self::Var(id) => write!(f, "?{}", id),
             Self::Const(ident) => write!(f, "_{}", ident),
+            Self::Int(value) => write!(f, "{}", value),
+            Self::Value(value) => write!(f, "{}", value),
+            #[cfg(feature = "bigint")]
+            Self::BigInt(value) => write!(f, "{}", value),
             Self::Struct(ident, subterms) => {
-                let subterms: Vec<_> = subterms.into_iter().map(|st| format!("{:?}", st)).collect();
+                let subterms: Vec<_> = subterms.iter().map(|st| format!("{:?}", st)).collect();
                 let subterms = subterms.join(", ");
                 write!(f, "_{}({})", ident, subterms)
             }
+            Self::Cycle(id) => write!(f, "@{}", id),
+            Self::Cyclic(id, term) => write!(f, "@{} = {:?}", id, term),
         }
     }
 }
@@ -45,9 +81,15 @@ impl Term {
         match (self, other) {
             (Self::Var(s), Self::Var(o)) => mapping.entry(*s).or_insert(*o) == o,
             (Self::Const(s), Self::Const(o)) if s == o => true,
+            (Self::Int(s), Self::Int(o)) if s == o => true,
+            (Self::Value(s), Self::Value(o)) if s == o => true,
+            #[cfg(feature = "bigint")]
+            (Self::BigInt(s), Self::BigInt(o)) if s == o => true,
             (Self::Struct(s, ss), Self::Struct(o, so)) if s == o => {
                 ss.iter().zip(so.iter()).all(|(s, o)| s.same(o, mapping))
             }
+            (Self::Cycle(s), Self::Cycle(o)) => s == o,
+            (Self::Cyclic(s, st), Self::Cyclic(o, ot)) if s == o => st.same(ot, mapping),
             _ => false,
         }
     }
@@ -60,7 +102,7 @@ impl PartialEq for Term {
         let mut mapping = Default::default();
         let same = self.same(other, &mut mapping);
         let mappings = mapping.len();
-        let mapping: HashSet<_> = mapping.into_iter().map(|(_, o)| o).collect();
+        let mapping: HashSet<_> = mapping.into_values().collect();
         same && mapping.len() == mappings
     }
 }
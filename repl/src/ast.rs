@@ -2,7 +2,12 @@
 pub enum Term {
     Var(String),
     Const(String),
+    Int(isize),
     Struct(String, Vec<Term>),
+    /// List items, and its tail (`[]` for proper list)
+    List(Vec<Term>, Box<Term>),
+    /// Structure referenced by variable inside of it
+    Cyclic(String, Box<Term>),
 }
 
 impl std::fmt::Debug for Term {
@@ -10,11 +15,21 @@ impl std::fmt::Debug for Term {
         match self {
             Self::Var(id) => write!(f, "?{}", id),
             Self::Const(id) => write!(f, "{}", id),
+            Self::Int(value) => write!(f, "{}", value),
             Self::Struct(id, subterms) => {
                 let subterms: Vec<_> = subterms.iter().map(|st| format!("{:?}", st)).collect();
                 let subterms = subterms.join(", ");
                 write!(f, "{}({})", id, subterms)
             }
+            Self::List(items, tail) => {
+                let items: Vec<_> = items.iter().map(|it| format!("{:?}", it)).collect();
+                let items = items.join(", ");
+                match &**tail {
+                    Self::Const(id) if id == "[]" => write!(f, "[{}]", items),
+                    tail => write!(f, "[{} | {:?}]", items, tail),
+                }
+            }
+            Self::Cyclic(id, term) => write!(f, "?{} where ?{} = {:?}", id, id, term),
         }
     }
 }
@@ -23,6 +38,7 @@ impl std::fmt::Debug for Term {
 pub enum Statement {
     Query(Term),
     Fact(Term),
+    Rule(Term, Vec<Term>),
 }
 
 #[derive(Debug)]
@@ -30,3 +46,39 @@ pub enum Directive {
     Statement(Statement),
     Assembly(Statement),
 }
+
+impl Term {
+    /// Checks if term can be called as goal - integers and lists
+    /// can't, and neither can control constructs with such goals
+    fn callable(&self) -> bool {
+        match self {
+            Self::Int(_) | Self::List(_, _) => false,
+            Self::Struct(op, goals) if [",", ";", "->"].contains(&op.as_str()) && goals.len() == 2 => {
+                goals.iter().all(Term::callable)
+            }
+            Self::Struct(op, goals) if op == "\\+" && goals.len() == 1 => goals[0].callable(),
+            _ => true,
+        }
+    }
+}
+
+impl Statement {
+    /// Checks if all goals of statement are callable, as
+    /// integers and lists can't be called
+    fn callable(&self) -> bool {
+        let callable = Term::callable;
+        match self {
+            Self::Query(q) => callable(q),
+            Self::Fact(_) => true,
+            Self::Rule(_, body) => body.iter().all(callable),
+        }
+    }
+}
+
+impl Directive {
+    pub fn callable(&self) -> bool {
+        match self {
+            Self::Statement(s) | Self::Assembly(s) => s.callable(),
+        }
+    }
+}
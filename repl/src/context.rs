@@ -2,8 +2,8 @@ use crate::ast::Term;
 use bimap::BiMap;
 use std::collections::HashMap;
 use warren::query::{Query, QueryBuilder, QueryRef};
-use warren::statement::{Statement, StatementBuilder, StatementRef};
-use warren::TermBuilder;
+use warren::statement::{RuleBuilder, Statement, StatementBuilder, StatementRef};
+use warren::{builtin, Error, TermBuilder};
 
 pub struct Context {
     terms_mapping: BiMap<String, usize>,
@@ -11,8 +11,69 @@ pub struct Context {
 
 impl Default for Context {
     fn default() -> Self {
+        let builtins = [
+            ("is", builtin::IS),
+            ("<", builtin::LT),
+            ("=<", builtin::LE),
+            (">", builtin::GT),
+            (">=", builtin::GE),
+            ("=:=", builtin::EQ),
+            ("=\\=", builtin::NE),
+            ("true", builtin::TRUE),
+            ("fail", builtin::FAIL),
+            ("!", builtin::CUT),
+            (",", builtin::AND),
+            (";", builtin::OR),
+            ("->", builtin::IF),
+            ("\\+", builtin::NOT),
+            ("dif", builtin::DIF),
+            ("call", builtin::CALL),
+            ("put_attr", builtin::PUT_ATTR),
+            ("get_attr", builtin::GET_ATTR),
+            ("del_attr", builtin::DEL_ATTR),
+            ("freeze", builtin::FREEZE),
+            ("when", builtin::WHEN),
+            ("nonvar", builtin::NONVAR),
+            ("ground", builtin::GROUND),
+            ("?=", builtin::DECIDED),
+            ("in", builtin::IN),
+            ("..", builtin::RANGE),
+            ("\\/", builtin::UNION),
+            ("inf", builtin::INF),
+            ("sup", builtin::SUP),
+            ("#=", builtin::FD_EQ),
+            ("#\\=", builtin::FD_NE),
+            ("#<", builtin::FD_LT),
+            ("#=<", builtin::FD_LE),
+            ("#>", builtin::FD_GT),
+            ("#>=", builtin::FD_GE),
+            ("all_different", builtin::ALL_DIFFERENT),
+            ("label", builtin::LABEL),
+            ("indomain", builtin::INDOMAIN),
+            ("fd_inf", builtin::FD_INF),
+            ("{}", builtin::LINEAR),
+            ("sat", builtin::SAT),
+            ("taut", builtin::TAUT),
+            ("labeling", builtin::LABELING),
+            ("~", builtin::BNOT),
+            ("#", builtin::XOR),
+            ("+", builtin::ADD),
+            ("-", builtin::SUB),
+            ("*", builtin::MUL),
+            ("//", builtin::DIV),
+            ("/", builtin::RDIV),
+            ("mod", builtin::MOD),
+            ("abs", builtin::ABS),
+            ("min", builtin::MIN),
+            ("max", builtin::MAX),
+            ("[]", builtin::NIL),
+        ];
+
         Self {
-            terms_mapping: Default::default(),
+            terms_mapping: builtins
+                .iter()
+                .map(|(name, ident)| (name.to_string(), *ident))
+                .collect(),
         }
     }
 }
@@ -43,25 +104,38 @@ impl Context {
                 let id = self.get_id(id);
                 builder.constant(id)
             }
+            Term::Int(value) => builder.integer(value),
             Term::Struct(id, st) => {
                 let id = self.get_id(id);
                 let subterms: Vec<_> = st
                     .into_iter()
                     .map(|st| self.build_query_ref(st, builder, variables))
                     .collect();
-                builder.structure(id, subterms.into_iter())
+                builder.structure(id, subterms)
             }
+            Term::List(items, tail) => {
+                let tail = self.build_query_ref(*tail, builder, variables);
+                let items: Vec<_> = items
+                    .into_iter()
+                    .map(|it| self.build_query_ref(it, builder, variables))
+                    .collect();
+                items
+                    .into_iter()
+                    .rev()
+                    .fold(tail, |tail, head| builder.cons(head, tail))
+            }
+            Term::Cyclic(_, _) => unreachable!("Cyclic terms are never parsed"),
         }
     }
 
     pub fn build_query(&mut self, term: Term) ->
-        (Query, HashMap<String, QueryRef>)
+        Result<(Query<'static>, HashMap<String, QueryRef>), Error>
     {
         let mut builder = Default::default();
         let mut variables = Default::default();
         let term = self.build_query_ref(term, &mut builder, &mut variables);
 
-        (builder.build(term), variables)
+        Ok((builder.build(term)?, variables))
     }
 
     fn build_fact_ref(
@@ -78,18 +152,69 @@ impl Context {
                 let id = self.get_id(id);
                 builder.constant(id)
             },
+            Term::Int(value) => builder.integer(value),
             Term::Struct(id, st) => {
                 let id = self.get_id(id);
                 let subterms: Vec<_> = st
                     .into_iter()
                     .map(|st| self.build_fact_ref(st, builder, variables))
                     .collect();
-                builder.structure(id, subterms.into_iter())
+                builder.structure(id, subterms)
+            }
+            Term::List(items, tail) => {
+                let tail = self.build_fact_ref(*tail, builder, variables);
+                let items: Vec<_> = items
+                    .into_iter()
+                    .map(|it| self.build_fact_ref(it, builder, variables))
+                    .collect();
+                items
+                    .into_iter()
+                    .rev()
+                    .fold(tail, |tail, head| builder.cons(head, tail))
+            }
+            Term::Cyclic(_, _) => unreachable!("Cyclic terms are never parsed"),
+        }
+    }
+
+    fn build_rule_ref(
+        &mut self,
+        term: Term,
+        builder: &mut RuleBuilder,
+        variables: &mut HashMap<String, StatementRef>,
+    ) -> StatementRef {
+        match term {
+            Term::Var(v) => *variables
+                .entry(v)
+                .or_insert_with(|| builder.variable()),
+            Term::Const(id) => {
+                let id = self.get_id(id);
+                builder.constant(id)
+            },
+            Term::Int(value) => builder.integer(value),
+            Term::Struct(id, st) => {
+                let id = self.get_id(id);
+                let subterms: Vec<_> = st
+                    .into_iter()
+                    .map(|st| self.build_rule_ref(st, builder, variables))
+                    .collect();
+                builder.structure(id, subterms)
+            }
+            Term::List(items, tail) => {
+                let tail = self.build_rule_ref(*tail, builder, variables);
+                let items: Vec<_> = items
+                    .into_iter()
+                    .map(|it| self.build_rule_ref(it, builder, variables))
+                    .collect();
+                items
+                    .into_iter()
+                    .rev()
+                    .fold(tail, |tail, head| builder.cons(head, tail))
             }
+            Term::Cyclic(_, _) => unreachable!("Cyclic terms are never parsed"),
         }
     }
 
-    pub fn build_fact(&mut self, term: Term) -> Statement
+    pub fn build_fact(&mut self, term: Term) -> Statement<'static>
     {
         let mut builder = Default::default();
         let term = self.build_fact_ref(
@@ -100,6 +225,19 @@ impl Context {
 
         builder.build(term)
     }
+
+    pub fn build_rule(&mut self, head: Term, body: Vec<Term>) -> Result<Statement<'static>, Error>
+    {
+        let mut builder = Default::default();
+        let mut variables = Default::default();
+        let head = self.build_rule_ref(head, &mut builder, &mut variables);
+        let body: Vec<_> = body
+            .into_iter()
+            .map(|goal| self.build_rule_ref(goal, &mut builder, &mut variables))
+            .collect();
+
+        builder.build(head, body)
+    }
 }
 
 impl TermBuilder for Context {
@@ -126,4 +264,39 @@ impl TermBuilder for Context {
             .unwrap_or_else(|| format!("_{}", ident));
         Term::Const(id)
     }
+
+    fn integer(&mut self, value: isize) -> Term {
+        Term::Int(value)
+    }
+
+    // Tail of proper list is flattened into it, so list is printed
+    // as `[a, b]` instead of `[a | [b]]`
+    fn list(&mut self, head: Term, tail: Term) -> Term {
+        match tail {
+            Term::List(mut items, tail) => {
+                items.insert(0, head);
+                Term::List(items, tail)
+            }
+            tail => Term::List(vec![head], Box::new(tail)),
+        }
+    }
+
+    // Constant domain values are never parsed, so they are only printed
+    fn value(&mut self, value: &usize) -> Term {
+        Term::Const(format!("#{}", value))
+    }
+
+    // Big integers are never parsed, so they are only printed
+    #[cfg(feature = "bigint")]
+    fn big_integer(&mut self, value: &warren::BigInt) -> Term {
+        Term::Const(value.to_string())
+    }
+
+    fn cycle(&mut self, id: usize) -> Term {
+        Term::Var(format!("S{}", id))
+    }
+
+    fn cyclic(&mut self, id: usize, term: Term) -> Term {
+        Term::Cyclic(format!("S{}", id), Box::new(term))
+    }
 }
@@ -1,6 +1,6 @@
 use rustyline::{error::ReadlineError, Editor};
 
-use warren::Machine;
+use warren::{Error, Knowledge, Machine, TermBuilder};
 
 mod ast;
 mod context;
@@ -11,37 +11,113 @@ use context::Context;
 fn handle_query(
     query: ast::Term,
     ctx: &mut Context,
-    machine: &mut Machine
+    machine: &mut Machine,
+    knowledge: &Knowledge<'static>,
+    rl: &mut Editor<()>,
 ) {
-    let (query, variables) = ctx.build_query(query);
-    let query_result = machine.query(query, &Default::default());
-
-    for (var, qref) in variables {
-        if let Some(unification) = query_result.build_term(qref, ctx) {
-            println!("{} := {:?}", var, unification);
-        } else {
-            println!("Invalid unification for {}", var);
+    let (query, variables) = match ctx.build_query(query) {
+        Ok(query) => query,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    let query_result = match machine.query(query, knowledge) {
+        Ok(query_result) => query_result,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+
+    if !query_result.succeeded() {
+        match query_result.failure_report(ctx) {
+            Ok(Some(report)) => println!(
+                "No: {:?} doesn't unify with {:?} at {:?}",
+                report.terms.0, report.terms.1, report.path
+            ),
+            _ => println!("No"),
+        }
+        return;
+    }
+
+    // Next solution is searched for only if user asks for it, as there
+    // may be infinitely many of them
+    for solution in query_result {
+        let solution = match solution {
+            Ok(solution) => solution,
+            Err(err) => {
+                println!("Error: {}", err);
+                return;
+            }
+        };
+
+        for (var, qref) in &variables {
+            match solution.build_term(*qref, ctx) {
+                Ok(unification) => println!("{} := {:?}", var, unification),
+                Err(err) => println!("Invalid unification for {}: {}", var, err),
+            }
+        }
+
+        match solution.residual_goals(ctx) {
+            Ok(goals) => goals.iter().for_each(|goal| println!("{:?}", goal)),
+            Err(err) => println!("Invalid residual goals: {}", err),
+        }
+
+        match rl.readline("; ") {
+            Ok(line) if line.trim() == ";" => (),
+            _ => return,
         }
     }
+
+    println!("No more solutions");
 }
 
 fn handle_fact(
     fact: ast::Term,
     ctx: &mut Context,
-    _machine: &mut Machine
+    knowledge: &mut Knowledge<'static>,
 ) {
-    let _fact = ctx.build_fact(fact);
-    unimplemented!()
+    let fact = ctx.build_fact(fact);
+    knowledge.add(fact);
+}
+
+fn handle_rule(
+    head: ast::Term,
+    body: Vec<ast::Term>,
+    ctx: &mut Context,
+    knowledge: &mut Knowledge<'static>,
+) {
+    let rule = match ctx.build_rule(head, body) {
+        Ok(rule) => rule,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+    knowledge.add(rule);
+
+    // Rule is kept anyway, as it may be fixed by rules added later
+    if let Err(Error::NotStratified(ident, arity)) = knowledge.check_stratification() {
+        println!(
+            "Warning: {:?}/{} depends negatively on itself",
+            ctx.constant(ident),
+            arity
+        );
+    }
 }
 
 fn handle_stmt(
     stmt: ast::Statement,
     ctx: &mut Context,
-    machine: &mut Machine
+    machine: &mut Machine,
+    knowledge: &mut Knowledge<'static>,
+    rl: &mut Editor<()>,
 ) {
     match stmt {
-        ast::Statement::Query(q) => handle_query(q, ctx, machine),
-        ast::Statement::Fact(f) => handle_fact(f, ctx, machine),
+        ast::Statement::Query(q) => handle_query(q, ctx, machine, knowledge, rl),
+        ast::Statement::Fact(f) => handle_fact(f, ctx, knowledge),
+        ast::Statement::Rule(h, b) => handle_rule(h, b, ctx, knowledge),
     }
 }
 
@@ -51,18 +127,25 @@ fn handle_assembly(
 ) {
     let asm = match stmt {
         ast::Statement::Query(q) =>
-            ctx.build_query(q).0.assembly(),
+            ctx.build_query(q).map(|(query, _)| query.assembly()),
         ast::Statement::Fact(f) =>
-            ctx.build_fact(f).assembly(),
+            Ok(ctx.build_fact(f).assembly()),
+        ast::Statement::Rule(h, b) =>
+            ctx.build_rule(h, b).map(|rule| rule.assembly()),
     };
 
-    println!("{}", asm);
+    match asm {
+        Ok(asm) => println!("{}", asm),
+        Err(err) => println!("Error: {}", err),
+    }
 }
 
 fn handle_directive(
     d: Option<ast::Directive>,
     ctx: &mut Context,
-    machine: &mut Machine
+    machine: &mut Machine,
+    knowledge: &mut Knowledge<'static>,
+    rl: &mut Editor<()>,
 ) {
     let d = if let Some(d) = d {
         d
@@ -71,8 +154,13 @@ fn handle_directive(
         return;
     };
 
+    if !d.callable() {
+        println!("Goals has to be terms or variables, not integers or lists");
+        return;
+    }
+
     match d {
-        ast::Directive::Statement(s) => handle_stmt(s, ctx, machine),
+        ast::Directive::Statement(s) => handle_stmt(s, ctx, machine, knowledge, rl),
         ast::Directive::Assembly(s) => handle_assembly(s, ctx),
     }
 }
@@ -81,6 +169,7 @@ fn main() {
     let mut rl = Editor::<()>::new();
     let mut context = Context::default();
     let mut machine = Machine::new();
+    let mut knowledge = Knowledge::new();
 
     rl.load_history("history").ok();
 
@@ -89,7 +178,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(line.as_str());
                 let ast = parser::parse(line.as_str());
-                handle_directive(ast.ok(), &mut context, &mut machine);
+                handle_directive(ast.ok(), &mut context, &mut machine, &mut knowledge, &mut rl);
             }
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
             Err(err) => {
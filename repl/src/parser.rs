@@ -2,17 +2,17 @@ use crate::ast::{Directive, Statement, Term};
 use nom::{
     branch::alt,
     bytes::complete::{take_while, take_while1, tag},
-    character::complete::{char, multispace0 as ws},
-    combinator::map,
+    character::complete::{char, digit1, multispace0 as ws},
+    combinator::{map, map_res, opt, recognize},
     multi::separated_nonempty_list,
-    sequence::{delimited, terminated, tuple, preceded},
+    sequence::{delimited, pair, terminated, tuple, preceded},
 };
 
 type IResult<I, O> = nom::IResult<I, O, nom::error::VerboseError<I>>;
 
-fn ident(s: &str) -> IResult<&str, String> {
-    let head_pred = |c: char| (c.is_alphabetic() || c == '_');
-    let tail_pred = |c: char| (c.is_alphanumeric() || c == '_');
+fn name(s: &str) -> IResult<&str, String> {
+    let head_pred = |c: char| c.is_alphabetic() || c == '_';
+    let tail_pred = |c: char| c.is_alphanumeric() || c == '_';
 
     map(
         tuple((take_while1(head_pred), take_while(tail_pred))),
@@ -20,12 +20,28 @@ fn ident(s: &str) -> IResult<&str, String> {
     )(s)
 }
 
+fn symbol(s: &str) -> IResult<&str, String> {
+    let symbol = take_while1(|c: char| "+-*/\\<>=:#~".contains(c));
+    // Dots alone would end the statement
+    map(alt((tag(".."), tag("{}"), tag("?="), symbol)), String::from)(s)
+}
+
+fn ident(s: &str) -> IResult<&str, String> {
+    alt((name, symbol))(s)
+}
+
 fn constant(s: &str) -> IResult<&str, Term> {
     map(ident, Term::Const)(s)
 }
 
+fn integer(s: &str) -> IResult<&str, Term> {
+    map_res(recognize(pair(opt(char('-')), digit1)), |i: &str| {
+        i.parse().map(Term::Int)
+    })(s)
+}
+
 fn variable(s: &str) -> IResult<&str, Term> {
-    map(tuple((char('?'), ident)), |(_, c)| Term::Var(c))(s)
+    map(tuple((char('?'), name)), |(_, c)| Term::Var(c))(s)
 }
 
 fn structure(s: &str) -> IResult<&str, Term> {
@@ -41,27 +57,122 @@ fn structure(s: &str) -> IResult<&str, Term> {
     )(s)
 }
 
+fn nil() -> Term {
+    Term::Const("[]".to_owned())
+}
+
+fn list(s: &str) -> IResult<&str, Term> {
+    map(
+        tuple((
+            char('['),
+            ws,
+            opt(pair(
+                separated_nonempty_list(char(','), delimited(ws, term, ws)),
+                opt(preceded(char('|'), delimited(ws, term, ws))),
+            )),
+            char(']'),
+        )),
+        |(_, _, items, _)| match items {
+            Some((items, tail)) => Term::List(items, Box::new(tail.unwrap_or_else(nil))),
+            None => nil(),
+        },
+    )(s)
+}
+
 fn term(s: &str) -> IResult<&str, Term> {
-    alt((structure, variable, constant))(s)
+    alt((structure, list, variable, integer, constant))(s)
+}
+
+fn cut(s: &str) -> IResult<&str, Term> {
+    map(char('!'), |_| Term::Const("!".to_owned()))(s)
+}
+
+fn negation(s: &str) -> IResult<&str, Term> {
+    map(preceded(pair(tag("\\+"), ws), primary), |goal| {
+        Term::Struct("\\+".to_owned(), vec![goal])
+    })(s)
+}
+
+// Goal which is not a conjunction, disjunction nor if-then
+fn primary(s: &str) -> IResult<&str, Term> {
+    alt((
+        delimited(pair(char('('), ws), disjunction, pair(ws, char(')'))),
+        cut,
+        negation,
+        term,
+    ))(s)
+}
+
+// Joins goals with binary control construct, right-associatively
+fn control<'a>(
+    op: &'static str,
+    goal: impl Fn(&'a str) -> IResult<&'a str, Term>,
+) -> impl Fn(&'a str) -> IResult<&'a str, Term> {
+    map(
+        separated_nonempty_list(tuple((ws, tag(op), ws)), goal),
+        move |goals| {
+            let mut goals = goals.into_iter().rev();
+            let last = goals.next().unwrap();
+            goals.fold(last, |rest, goal| Term::Struct(op.to_owned(), vec![goal, rest]))
+        },
+    )
+}
+
+fn conjunction(s: &str) -> IResult<&str, Term> {
+    control(",", primary)(s)
+}
+
+fn if_then(s: &str) -> IResult<&str, Term> {
+    control("->", conjunction)(s)
+}
+
+fn disjunction(s: &str) -> IResult<&str, Term> {
+    control(";", if_then)(s)
+}
+
+// Splits goal into conjunction goals
+fn goals(goal: Term) -> Vec<Term> {
+    match goal {
+        Term::Struct(op, mut args) if op == "," && args.len() == 2 => {
+            let rest = args.pop().unwrap();
+            let mut goals = vec![args.pop().unwrap()];
+            goals.extend(self::goals(rest));
+            goals
+        }
+        goal => vec![goal],
+    }
 }
 
 fn query(s: &str) -> IResult<&str, Statement> {
-    map(terminated(term, char('?')), Statement::Query)(s)
+    map(terminated(disjunction, char('?')), Statement::Query)(s)
 }
 
 fn fact(s: &str) -> IResult<&str, Statement> {
     map(terminated(term, char('.')), Statement::Fact)(s)
 }
 
+fn rule(s: &str) -> IResult<&str, Statement> {
+    map(
+        tuple((
+            term,
+            ws,
+            tag(":-"),
+            delimited(ws, disjunction, ws),
+            char('.'),
+        )),
+        |(head, _, _, body, _)| Statement::Rule(head, goals(body)),
+    )(s)
+}
+
 pub fn statement(s: &str) -> IResult<&str, Directive> {
-    map(alt((query, fact)), Directive::Statement)(s)
+    map(alt((query, fact, rule)), Directive::Statement)(s)
 }
 
 pub fn assembly(s: &str) -> IResult<&str, Directive> {
     map(
         preceded(
             tuple((tag("@asm"), ws)),
-            alt((query, fact))
+            alt((query, fact, rule))
         ),
         Directive::Assembly
     )(s)